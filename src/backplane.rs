@@ -0,0 +1,21 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::Stream;
+use websocket::message::OwnedMessage;
+
+/// Room messages published by other hubs, as `(room, message)` pairs.
+pub type BackplaneMessages = Box<Stream<Item = (String, OwnedMessage), Error = ()>>;
+
+/// Relays room broadcasts between `Hub`s, typically in other processes, so
+/// that a broadcast reaches every member of a room wherever it's connected.
+pub trait Backplane {
+    /// Passes on a message broadcast locally. Implementations must not hand
+    /// the message back out of their own `subscribe` stream.
+    fn publish(&self, room: &str, msg: &OwnedMessage);
+
+    /// Returns the messages published by other hubs. Called once, when the
+    /// backplane is attached to a hub.
+    fn subscribe(&self) -> BackplaneMessages;
+}
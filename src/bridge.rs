@@ -0,0 +1,289 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use base64;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use hyper::{Request, StatusCode};
+use std::fmt;
+use std::net::SocketAddr;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{copy, shutdown};
+use websocket::client::async::Client;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use super::{Omitted, WsHandshake, WsResponse};
+use super::byte_stream::ByteStream;
+use super::engineio::query_param;
+use super::server::{WsContext, WsEndpoint};
+
+/// The subprotocol for raw bytes in binary messages.
+pub const BINARY_PROTOCOL: &str = "binary";
+/// The subprotocol for base64-encoded bytes in text messages, for older
+/// clients without binary message support.
+pub const BASE64_PROTOCOL: &str = "base64";
+
+const DEFAULT_CHUNK_SIZE: usize = 16 * 1024;
+
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// A websockify-style bridge that connects each WebSocket to a TCP target
+/// and copies bytes both ways, reading from either side only as fast as the
+/// other side takes them.
+///
+/// The target is the one set with `target`, unless the client names another
+/// with a `target` route parameter or query parameter, as `ip:port`. Named
+/// targets must have been allowed with `allow`. When the TCP side closes,
+/// the WebSocket is closed, and the other way round.
+///
+/// As a `WsEndpoint`, handshakes naming a forbidden target are refused with
+/// `403 Forbidden`. Clients that ask for subprotocols get `binary`, or
+/// `base64` if enabled and they don't support `binary`, and are refused with
+/// `400 Bad Request` if they support neither. To bridge a
+/// `WsRoute`, call `serve` from its handler, and list those subprotocols on
+/// the route:
+///
+/// ```ignore
+/// let bridge = TcpBridge::new(&handle).target(vnc_addr);
+/// WsRoute::new(move |ctx, websocket| bridge.serve(&ctx, websocket))
+///     .protocols(vec![BINARY_PROTOCOL, BASE64_PROTOCOL])
+/// ```
+#[derive(Clone)]
+pub struct TcpBridge {
+    handle: Handle,
+    target: Option<SocketAddr>,
+    allowed: Vec<SocketAddr>,
+    base64: bool,
+    chunk_size: usize,
+}
+
+impl fmt::Debug for TcpBridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TcpBridge")
+            .field("handle", &Omitted)
+            .field("target", &self.target)
+            .field("allowed", &self.allowed)
+            .field("base64", &self.base64)
+            .field("chunk_size", &self.chunk_size)
+            .finish()
+    }
+}
+
+impl TcpBridge {
+    pub fn new(handle: &Handle) -> Self {
+        TcpBridge {
+            handle: handle.clone(),
+            target: None,
+            allowed: Vec::new(),
+            base64: false,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    /// The target for clients that don't name one. Without it, clients
+    /// must name an allowed target.
+    pub fn target(mut self, target: SocketAddr) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Lets clients name `target`.
+    pub fn allow(mut self, target: SocketAddr) -> Self {
+        self.allowed.push(target);
+        self
+    }
+
+    /// Whether to negotiate the `base64` subprotocol. Defaults to false.
+    pub fn base64(mut self, base64: bool) -> Self {
+        self.base64 = base64;
+        self
+    }
+
+    /// The most bytes sent in one WebSocket message. Defaults to 16 KiB.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "hyper-websocket: TcpBridge chunk size must be nonzero");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Returns the target to connect to, if the client may reach it.
+    fn resolve(&self, requested: Option<&str>) -> Option<SocketAddr> {
+        match requested {
+            None => self.target,
+            Some(requested) => match requested.parse() {
+                Ok(addr) if self.target == Some(addr) || self.allowed.contains(&addr) => {
+                    Some(addr)
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// Bridges an accepted connection, using base64 text messages if the
+    /// `base64` subprotocol was selected. A connection naming a forbidden
+    /// target, or using `base64` without it being enabled, is closed with
+    /// 1008, and one whose target can't be reached is closed with 1011.
+    pub fn serve<S>(
+        &self,
+        ctx: &WsContext,
+        websocket: S,
+    ) -> Box<Future<Item = (), Error = WebSocketError>>
+    where
+        S: Stream<Item = OwnedMessage, Error = WebSocketError>
+            + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>
+            + 'static,
+    {
+        let requested = ctx.param("target").or_else(|| query_param(ctx.query(), "target"));
+        let target = match self.resolve(requested) {
+            None => return close(websocket, CLOSE_POLICY_VIOLATION, "Target not allowed"),
+            Some(target) => target,
+        };
+        let base64 = ctx.protocol() == Some(BASE64_PROTOCOL);
+        if base64 && !self.base64 {
+            return close(websocket, CLOSE_POLICY_VIOLATION, "Subprotocol not enabled");
+        }
+        let chunk_size = self.chunk_size;
+        Box::new(TcpStream::connect(&target, &self.handle).then(move |result| match result {
+            Err(err) => {
+                warn!("hyper-websocket: TCP bridge could not reach {}: {}", target, err);
+                close(websocket, CLOSE_INTERNAL_ERROR, "Target unreachable")
+            }
+            Ok(tcp) => {
+                debug!("hyper-websocket: TCP bridge connected to {}", target);
+                if base64 {
+                    pipe(ByteStream::new(Base64Frames(websocket), chunk_size), tcp)
+                } else {
+                    pipe(ByteStream::new(websocket, chunk_size), tcp)
+                }
+            }
+        }))
+    }
+}
+
+impl WsEndpoint for TcpBridge {
+    type Future = Box<Future<Item = (), Error = WebSocketError>>;
+
+    fn negotiate(&self, req: &Request, handshake: WsHandshake) -> WsResponse {
+        if self.resolve(query_param(req.query(), "target")).is_none() {
+            return WsResponse::reject_with_status(handshake, StatusCode::Forbidden);
+        }
+        if handshake.protocols().is_empty() {
+            return WsResponse::accept(handshake);
+        }
+
+        let protocol = {
+            let offers = |protocol| handshake.protocols().iter().any(|offer| offer == protocol);
+            if offers(BINARY_PROTOCOL) {
+                Some(BINARY_PROTOCOL)
+            } else if self.base64 && offers(BASE64_PROTOCOL) {
+                Some(BASE64_PROTOCOL)
+            } else {
+                None
+            }
+        };
+        match protocol {
+            None => WsResponse::reject_with_status(handshake, StatusCode::BadRequest),
+            Some(protocol) => WsResponse::accept(handshake.use_protocol(protocol)),
+        }
+    }
+
+    fn handle(&self, ctx: WsContext, websocket: Client<TcpStream>) -> Self::Future {
+        self.serve(&ctx, websocket)
+    }
+}
+
+/// Copies bytes both ways until either side finishes. The TCP side
+/// finishing shuts down the WebSocket, sending a close frame.
+fn pipe<S>(
+    websocket: ByteStream<S>,
+    tcp: TcpStream,
+) -> Box<Future<Item = (), Error = WebSocketError>>
+where
+    ByteStream<S>: AsyncRead + AsyncWrite,
+    S: 'static,
+{
+    let (ws_reader, ws_writer) = websocket.split();
+    let (tcp_reader, tcp_writer) = tcp.split();
+    let upstream = copy(ws_reader, tcp_writer).map(|_| ());
+    let downstream = copy(tcp_reader, ws_writer)
+        .and_then(|(_len, _tcp_reader, ws_writer)| shutdown(ws_writer))
+        .map(|_ws_writer| ());
+    Box::new(
+        upstream
+            .select(downstream)
+            .map(|_| ())
+            .map_err(|(err, _other)| WebSocketError::IoError(err)),
+    )
+}
+
+fn close<S>(
+    websocket: S,
+    code: u16,
+    reason: &str,
+) -> Box<Future<Item = (), Error = WebSocketError>>
+where
+    S: Sink<SinkItem = OwnedMessage, SinkError = WebSocketError> + 'static,
+{
+    let close = OwnedMessage::Close(Some(CloseData::new(code, reason.to_owned())));
+    Box::new(websocket.send(close).map(|_websocket| ()))
+}
+
+/// Carries binary payloads as base64 in text messages.
+struct Base64Frames<S>(S);
+
+impl<S> Stream for Base64Frames<S>
+where
+    S: Stream<Item = OwnedMessage, Error = WebSocketError>,
+{
+    type Item = OwnedMessage;
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match try_ready!(self.0.poll()) {
+            Some(OwnedMessage::Text(text)) => match base64::decode(&text) {
+                Ok(data) => Ok(Async::Ready(Some(OwnedMessage::Binary(data)))),
+                Err(_) => Err(WebSocketError::ProtocolError("Invalid base64 payload")),
+            },
+            Some(OwnedMessage::Binary(_)) => {
+                Err(WebSocketError::ProtocolError("Binary message in base64 mode"))
+            }
+            maybe_msg => Ok(Async::Ready(maybe_msg)),
+        }
+    }
+}
+
+impl<S> Sink for Base64Frames<S>
+where
+    S: Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    type SinkItem = OwnedMessage;
+    type SinkError = WebSocketError;
+
+    fn start_send(&mut self, msg: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let data = match msg {
+            OwnedMessage::Binary(data) => data,
+            msg => return self.0.start_send(msg),
+        };
+        let text = OwnedMessage::Text(base64::encode(&data));
+        match self.0.start_send(text)? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(OwnedMessage::Binary(data))),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.0.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.0.close()
+    }
+}
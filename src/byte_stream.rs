@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::BytesMut;
+use futures::{Async, AsyncSink, Poll, Sink, Stream};
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use super::Omitted;
+
+const CLOSE_NORMAL: u16 = 1000;
+
+/// Wraps a WebSocket connection as a byte stream, so that any
+/// `tokio_io` codec can run over it.
+///
+/// Reads concatenate the payloads of binary messages, and hit end of file
+/// once the peer closes. Writes are sent as binary messages of at most
+/// `chunk_size` bytes, and shutting down sends a close frame. Pings are
+/// answered and pongs ignored; a text message fails the read with
+/// `InvalidData`.
+pub struct ByteStream<S> {
+    inner: S,
+    chunk_size: usize,
+    read_buf: BytesMut,
+    /// A pong or close that the sink didn't have room for yet.
+    pending: Option<OwnedMessage>,
+    read_eof: bool,
+    close_sent: bool,
+}
+
+impl<S> fmt::Debug for ByteStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ByteStream")
+            .field("inner", &Omitted)
+            .field("chunk_size", &self.chunk_size)
+            .field("read_buf", &self.read_buf.len())
+            .field("pending", &self.pending)
+            .field("read_eof", &self.read_eof)
+            .field("close_sent", &self.close_sent)
+            .finish()
+    }
+}
+
+impl<S> ByteStream<S> {
+    pub fn new(inner: S, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "hyper-websocket: ByteStream chunk size must be nonzero");
+        ByteStream {
+            inner: inner,
+            chunk_size: chunk_size,
+            read_buf: BytesMut::new(),
+            pending: None,
+            read_eof: false,
+            close_sent: false,
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Returns the connection along with any bytes that were received but
+    /// not yet read.
+    pub fn into_inner(self) -> (S, BytesMut) {
+        (self.inner, self.read_buf)
+    }
+}
+
+impl<S> ByteStream<S>
+where
+    S: Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    /// Queues a control message, replacing a pong still waiting to go out
+    /// since only the latest ping needs an answer. A pending close is never
+    /// replaced.
+    fn queue_control(&mut self, msg: OwnedMessage) -> io::Result<()> {
+        match self.pending {
+            Some(OwnedMessage::Close(_)) => {}
+            _ => self.pending = Some(msg),
+        }
+        match self.poll_flush() {
+            Err(err) => Err(err),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    fn poll_pending(&mut self) -> Poll<(), io::Error> {
+        if let Some(msg) = self.pending.take() {
+            if let AsyncSink::NotReady(msg) = self.inner.start_send(msg).map_err(into_io_error)? {
+                self.pending = Some(msg);
+                return Ok(Async::NotReady);
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_flush(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.poll_pending());
+        self.inner.poll_complete().map_err(into_io_error)
+    }
+}
+
+impl<S> Read for ByteStream<S>
+where
+    S: Stream<Item = OwnedMessage, Error = WebSocketError>
+        + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if dst.is_empty() {
+            return Ok(0);
+        }
+        self.poll_flush()?;
+
+        loop {
+            if !self.read_buf.is_empty() {
+                let len = cmp::min(dst.len(), self.read_buf.len());
+                dst[..len].copy_from_slice(&self.read_buf.split_to(len));
+                return Ok(len);
+            }
+            if self.read_eof {
+                return Ok(0);
+            }
+
+            match self.inner.poll().map_err(into_io_error)? {
+                Async::NotReady => return Err(io::ErrorKind::WouldBlock.into()),
+                Async::Ready(None) => self.read_eof = true,
+                Async::Ready(Some(msg)) => match msg {
+                    OwnedMessage::Binary(data) => self.read_buf.extend_from_slice(&data),
+                    OwnedMessage::Text(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "text message on a byte stream",
+                        ))
+                    }
+                    OwnedMessage::Ping(data) => self.queue_control(OwnedMessage::Pong(data))?,
+                    OwnedMessage::Pong(_) => {}
+                    OwnedMessage::Close(data) => {
+                        self.read_eof = true;
+                        if !self.close_sent {
+                            // Echo the peer's close frame to complete the
+                            // handshake.
+                            self.close_sent = true;
+                            self.queue_control(OwnedMessage::Close(data))?;
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl<S> Write for ByteStream<S>
+where
+    S: Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.close_sent {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.poll_pending()?.is_not_ready() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        let len = cmp::min(buf.len(), self.chunk_size);
+        let msg = OwnedMessage::Binary(buf[..len].to_vec());
+        match self.inner.start_send(msg).map_err(into_io_error)? {
+            AsyncSink::Ready => {
+                // Start writing it out now: callers like `io::copy` only
+                // flush once their source runs dry, which a connection that
+                // stays open never does.
+                self.inner.poll_complete().map_err(into_io_error)?;
+                Ok(len)
+            }
+            AsyncSink::NotReady(_) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.poll_flush()? {
+            Async::Ready(()) => Ok(()),
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S> AsyncRead for ByteStream<S>
+where
+    S: Stream<Item = OwnedMessage, Error = WebSocketError>
+        + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+}
+
+impl<S> AsyncWrite for ByteStream<S>
+where
+    S: Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if !self.close_sent {
+            self.close_sent = true;
+            let close = CloseData::new(CLOSE_NORMAL, String::new());
+            self.queue_control(OwnedMessage::Close(Some(close)))?;
+        }
+        self.poll_flush()
+    }
+}
+
+fn into_io_error(err: WebSocketError) -> io::Error {
+    match err {
+        WebSocketError::IoError(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+    }
+}
@@ -0,0 +1,513 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::sync::mpsc::{self, Receiver, Sender};
+use rand;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::client::async::Client;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+use websocket::ws::Message;
+
+use super::Omitted;
+
+/// The `capacity` this crate's own endpoints split their connections with.
+pub(crate) const QUEUE_CAPACITY: usize = 16;
+
+/// Splits an accepted connection into a `WsSender` and a `WsReceiver`,
+/// spawning a task onto `handle` that does the actual IO.
+///
+/// At most `capacity` outgoing messages are queued, plus one per `WsSender`
+/// clone. Incoming messages are not read off the socket while `capacity` of
+/// them are waiting in the `WsReceiver`.
+///
+/// This is for the server's end of a connection only. Use
+/// `spawn_client_channel` for connections made with `ConnectWsHandshake`.
+pub fn spawn_channel<T>(
+    handle: &Handle,
+    client: Client<T>,
+    capacity: usize,
+) -> (WsSender, WsReceiver)
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    let (driver, sender, receiver) = channel(client, capacity, false);
+    handle.spawn(driver);
+    (sender, receiver)
+}
+
+/// Like `spawn_channel`, but for the client's end of a connection, such as
+/// one made with `ConnectWsHandshake`. `SharedFrame`s sent through it are
+/// masked as they're written, as frames from a client have to be.
+pub fn spawn_client_channel<T>(
+    handle: &Handle,
+    client: Client<T>,
+    capacity: usize,
+) -> (WsSender, WsReceiver)
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    let (driver, sender, receiver) = channel(client, capacity, true);
+    handle.spawn(driver);
+    (sender, receiver)
+}
+
+/// Sets up a channel without spawning its driver. `masked` is set for the
+/// client's end of a connection.
+pub(crate) fn channel<T>(
+    client: Client<T>,
+    capacity: usize,
+    masked: bool,
+) -> (ChannelDriver<T>, WsSender, WsReceiver)
+where
+    T: AsyncRead + AsyncWrite,
+{
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(capacity);
+    let (incoming_tx, incoming_rx) = mpsc::channel(capacity);
+    let driver = ChannelDriver {
+        client: client,
+        masked: masked,
+        outgoing: outgoing_rx,
+        outgoing_done: false,
+        writing: VecDeque::new(),
+        raw: None,
+        incoming: Some(incoming_tx),
+        reading: None,
+        read_done: false,
+        close_sent: false,
+        close_received: false,
+    };
+    (driver, WsSender(outgoing_tx), WsReceiver(incoming_rx))
+}
+
+/// A message encoded once as an unmasked, server-to-client frame, so that it
+/// can be written to any number of connections without re-encoding it.
+/// Channels for the client's end of a connection mask it again for each
+/// write.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SharedFrame(Bytes);
+
+impl SharedFrame {
+    pub fn new(msg: &OwnedMessage) -> Self {
+        let mut buf = Vec::with_capacity(msg.message_size(false));
+        msg.serialize(&mut buf, false)
+            .expect("hyper-websocket: in-memory frame serialization failed");
+        SharedFrame(buf.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Copies the frame with its payload masked by `key`.
+    fn masked(&self, key: [u8; 4]) -> SharedFrame {
+        let bytes = self.as_bytes();
+        // The payload length takes up 7 bits, or 16 or 64 more.
+        let header_len = match bytes[1] & 0x7f {
+            126 => 4,
+            127 => 10,
+            _ => 2,
+        };
+        let mut buf = Vec::with_capacity(bytes.len() + key.len());
+        buf.extend_from_slice(&bytes[..header_len]);
+        buf[1] |= 0x80;
+        buf.extend_from_slice(&key);
+        let payload = &bytes[header_len..];
+        buf.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ key[i % 4]));
+        SharedFrame(buf.into())
+    }
+}
+
+pub(crate) enum Outgoing {
+    Message(OwnedMessage),
+    Frame(SharedFrame),
+}
+
+#[derive(Clone, PartialEq)]
+pub enum WsSendError {
+    /// The queue is full; try again once the driver has caught up.
+    Full(OwnedMessage),
+    /// The connection has closed, or is closing, and takes no more messages.
+    Closed(OwnedMessage),
+}
+
+impl WsSendError {
+    pub fn is_full(&self) -> bool {
+        match *self {
+            WsSendError::Full(_) => true,
+            WsSendError::Closed(_) => false,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.is_full()
+    }
+
+    pub fn into_inner(self) -> OwnedMessage {
+        match self {
+            WsSendError::Full(msg) | WsSendError::Closed(msg) => msg,
+        }
+    }
+}
+
+impl fmt::Debug for WsSendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WsSendError::Full(_) => f.debug_tuple("Full").field(&Omitted).finish(),
+            WsSendError::Closed(_) => f.debug_tuple("Closed").field(&Omitted).finish(),
+        }
+    }
+}
+
+impl fmt::Display for WsSendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for WsSendError {
+    fn description(&self) -> &str {
+        match *self {
+            WsSendError::Full(_) => "WebSocket send queue is full",
+            WsSendError::Closed(_) => "WebSocket connection is closed",
+        }
+    }
+}
+
+/// A cloneable handle for queueing messages onto a connection split by
+/// `spawn_channel`. Use it as a `Sink` to wait for room in the queue.
+#[derive(Clone)]
+pub struct WsSender(Sender<Outgoing>);
+
+impl fmt::Debug for WsSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("WsSender").field(&Omitted).finish()
+    }
+}
+
+impl WsSender {
+    pub fn try_send(&mut self, msg: OwnedMessage) -> Result<(), WsSendError> {
+        self.0.try_send(Outgoing::Message(msg)).map_err(|err| {
+            let full = err.is_full();
+            match err.into_inner() {
+                Outgoing::Message(msg) if full => WsSendError::Full(msg),
+                Outgoing::Message(msg) => WsSendError::Closed(msg),
+                Outgoing::Frame(_) => unreachable!(),
+            }
+        })
+    }
+
+    /// Like `try_send`, but for a pre-encoded frame. Returns whether the
+    /// connection is closed if the frame could not be queued.
+    pub(crate) fn try_send_frame(&mut self, frame: SharedFrame) -> Result<(), bool> {
+        self.0
+            .try_send(Outgoing::Frame(frame))
+            .map_err(|err| err.is_disconnected())
+    }
+
+    /// Like `Sink::start_send`, but for a pre-encoded frame. Fails once the
+    /// connection is closed.
+    pub(crate) fn start_send_frame(
+        &mut self,
+        frame: SharedFrame,
+    ) -> Result<AsyncSink<SharedFrame>, ()> {
+        match self.0.start_send(Outgoing::Frame(frame)) {
+            Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
+            Ok(AsyncSink::NotReady(Outgoing::Frame(frame))) => Ok(AsyncSink::NotReady(frame)),
+            Ok(AsyncSink::NotReady(Outgoing::Message(_))) => unreachable!(),
+            Err(_) => Err(()),
+        }
+    }
+
+    /// Checks for room to queue one more message, registering the current
+    /// task to be woken once there is. Fails once the connection is closed.
+    pub(crate) fn poll_ready(&mut self) -> Poll<(), ()> {
+        self.0.poll_ready().map_err(|_| ())
+    }
+
+    /// Starts the close handshake. This skips ahead of the capacity limit, so
+    /// it only fails once the connection is already closed or closing.
+    pub fn close(&self, code: u16, reason: &str) -> Result<(), WsSendError> {
+        let msg = OwnedMessage::Close(Some(CloseData::new(code, reason.to_owned())));
+        // A fresh clone is always allowed one message past the capacity.
+        self.clone().try_send(msg)
+    }
+}
+
+impl Sink for WsSender {
+    type SinkItem = OwnedMessage;
+    type SinkError = WsSendError;
+
+    fn start_send(&mut self, msg: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match self.0.start_send(Outgoing::Message(msg)) {
+            Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
+            Ok(AsyncSink::NotReady(Outgoing::Message(msg))) => Ok(AsyncSink::NotReady(msg)),
+            Ok(AsyncSink::NotReady(Outgoing::Frame(_))) => unreachable!(),
+            Err(err) => match err.into_inner() {
+                Outgoing::Message(msg) => Err(WsSendError::Closed(msg)),
+                Outgoing::Frame(_) => unreachable!(),
+            },
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        // Completion of the underlying channel can't fail, and there's no
+        // message to hand back if it did.
+        Ok(self.0.poll_complete().unwrap_or(Async::Ready(())))
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// The incoming messages of a connection split by `spawn_channel`, ending
+/// after the peer's close frame or once the connection drops. Pings are
+/// answered by the driver but still passed along here.
+pub struct WsReceiver(Receiver<Result<OwnedMessage, WebSocketError>>);
+
+impl fmt::Debug for WsReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("WsReceiver").field(&Omitted).finish()
+    }
+}
+
+impl Stream for WsReceiver {
+    type Item = OwnedMessage;
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.0.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(Some(Ok(msg)))) => Ok(Async::Ready(Some(msg))),
+            Ok(Async::Ready(Some(Err(err)))) => Err(err),
+            Ok(Async::Ready(None)) | Err(()) => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+pub(crate) struct ChannelDriver<T> {
+    client: Client<T>,
+    /// Whether this is the client's end, which masks what it writes.
+    masked: bool,
+    outgoing: Receiver<Outgoing>,
+    outgoing_done: bool,
+    /// Messages taken off `outgoing`, plus automatic pongs and close replies,
+    /// waiting to be written.
+    writing: VecDeque<Outgoing>,
+    /// A pre-encoded frame being written straight to the socket, and how much
+    /// of it has been written so far.
+    raw: Option<(SharedFrame, usize)>,
+    incoming: Option<Sender<Result<OwnedMessage, WebSocketError>>>,
+    /// A message read off the socket that `incoming` has no room for yet.
+    reading: Option<Result<OwnedMessage, WebSocketError>>,
+    read_done: bool,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<T> ChannelDriver<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    fn stop_sending(&mut self) {
+        if !self.outgoing_done {
+            self.outgoing.close();
+            self.outgoing_done = true;
+        }
+    }
+
+    /// Writes out a pre-encoded frame, bypassing the codec. Only called while
+    /// the codec's own write buffer is empty, so frames can't interleave.
+    fn poll_write_raw(&mut self) -> Poll<(), io::Error> {
+        while let Some((frame, written)) = self.raw.take() {
+            match self.client.get_mut().write(&frame.as_bytes()[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => if written + n < frame.len() {
+                    self.raw = Some((frame, written + n));
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.raw = Some((frame, written));
+                    return Ok(Async::NotReady);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+
+    /// Returns whether everything queued so far has been written out.
+    fn poll_write(&mut self) -> Result<bool, WebSocketError> {
+        loop {
+            if !self.poll_write_raw()?.is_ready() {
+                return Ok(false);
+            }
+            if self.writing.is_empty() && !self.outgoing_done {
+                match self.outgoing.poll() {
+                    Ok(Async::Ready(Some(msg))) => self.writing.push_back(msg),
+                    Ok(Async::NotReady) => {}
+                    Ok(Async::Ready(None)) | Err(()) => self.outgoing_done = true,
+                }
+            }
+
+            let msg = match self.writing.pop_front() {
+                None => break,
+                Some(Outgoing::Message(msg)) => msg,
+                Some(Outgoing::Frame(frame)) => {
+                    if self.close_sent {
+                        continue;
+                    }
+                    if !self.client.poll_complete()?.is_ready() {
+                        self.writing.push_front(Outgoing::Frame(frame));
+                        break;
+                    }
+                    let frame = if self.masked {
+                        frame.masked(rand::random())
+                    } else {
+                        frame
+                    };
+                    self.raw = Some((frame, 0));
+                    continue;
+                }
+            };
+            if self.close_sent {
+                // Nothing may follow a close frame.
+                continue;
+            }
+            let is_close = match msg {
+                OwnedMessage::Close(_) => true,
+                _ => false,
+            };
+            if let AsyncSink::NotReady(msg) = self.client.start_send(msg)? {
+                self.writing.push_front(Outgoing::Message(msg));
+                break;
+            }
+            if is_close {
+                self.close_sent = true;
+                self.stop_sending();
+            }
+        }
+        Ok(self.client.poll_complete()?.is_ready() && self.writing.is_empty())
+    }
+
+    /// Hands a message to the receiver, dropping it if the receiver is gone.
+    /// Returns `false` if the receiver has no room for it yet.
+    fn deliver(&mut self, msg: Result<OwnedMessage, WebSocketError>) -> bool {
+        let start_send = match self.incoming {
+            None => return true,
+            Some(ref mut incoming) => incoming.start_send(msg),
+        };
+        match start_send {
+            Ok(AsyncSink::Ready) => true,
+            Ok(AsyncSink::NotReady(msg)) => {
+                self.reading = Some(msg);
+                false
+            }
+            Err(_) => {
+                self.incoming = None;
+                true
+            }
+        }
+    }
+
+    /// Returns whether the connection is done, one way or another.
+    fn poll_read(&mut self) -> bool {
+        loop {
+            if let Some(msg) = self.reading.take() {
+                if !self.deliver(msg) {
+                    return false;
+                }
+            }
+            if let Some(ref mut incoming) = self.incoming {
+                // Nothing useful can come of a failed flush; `deliver` notices
+                // the receiver going away on its own.
+                let _ = incoming.poll_complete();
+            }
+            if self.close_received {
+                return true;
+            }
+
+            let msg = match self.client.poll() {
+                Ok(Async::NotReady) => return false,
+                Ok(Async::Ready(None)) => return true,
+                Ok(Async::Ready(Some(msg))) => msg,
+                Err(err) => {
+                    self.deliver(Err(err));
+                    return true;
+                }
+            };
+            match msg {
+                OwnedMessage::Ping(ref data) => {
+                    let pong = OwnedMessage::Pong(data.clone());
+                    self.writing.push_back(Outgoing::Message(pong));
+                }
+                OwnedMessage::Close(ref data) => {
+                    self.close_received = true;
+                    if !self.close_sent {
+                        self.stop_sending();
+                        let close = OwnedMessage::Close(data.clone());
+                        self.writing.push_back(Outgoing::Message(close));
+                    }
+                }
+                _ => {}
+            }
+            if !self.deliver(Ok(msg)) {
+                return false;
+            }
+        }
+    }
+}
+
+impl<T> Future for ChannelDriver<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let flushed = match self.poll_write() {
+                Ok(flushed) => flushed,
+                Err(err) => {
+                    self.deliver(Err(err));
+                    return Ok(Async::Ready(()));
+                }
+            };
+            if self.read_done {
+                // Finish writing the reply to a close frame, but don't wait
+                // on a connection that dropped.
+                if flushed || !self.close_received {
+                    return Ok(Async::Ready(()));
+                }
+                return Ok(Async::NotReady);
+            }
+            if self.outgoing_done && self.incoming.is_none() && !self.close_sent {
+                // Nobody is left to send or receive anything.
+                return Ok(Async::Ready(()));
+            }
+
+            let writing = self.writing.len();
+            self.read_done = self.poll_read();
+            if !self.read_done && self.writing.len() == writing {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
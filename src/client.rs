@@ -0,0 +1,337 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::BytesMut;
+use futures::{Async, Future, Poll};
+use httparse;
+use hyper::{self, HttpVersion, Method, StatusCode, Uri};
+use hyper::header::{self, Headers};
+use std::ascii::AsciiExt;
+use std::fmt;
+use std::io;
+use std::mem;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Framed, FramedParts};
+use tokio_io::io::{write_all, WriteAll};
+use websocket::client::async::Client;
+use websocket::codec::ws::{Context, MessageCodec};
+use websocket::header::WebSocketKey;
+use websocket::result::WebSocketError;
+
+use super::Omitted;
+
+/// Responses with heads larger than this are rejected rather than buffered
+/// indefinitely.
+const MAX_RESPONSE_HEAD_LEN: usize = 8192;
+
+const MAX_RESPONSE_HEADERS: usize = 64;
+
+/// The client side of the opening handshake. `connect` performs it over any
+/// stream and yields the same `Client` type as `AcceptWsHandshake`.
+///
+/// `request` and `validate` only build and check the HTTP messages, for
+/// use with other HTTP clients: hyper's own client doesn't hand over the
+/// connection after a `101 Switching Protocols` response, so it can't be used
+/// to open a WebSocket.
+#[derive(Clone, Debug)]
+pub struct WsClientHandshake {
+    uri: Uri,
+    key: Vec<u8>,
+    protocols: Vec<String>,
+    extensions: Vec<String>,
+    headers: Headers,
+}
+
+impl WsClientHandshake {
+    pub fn new(uri: Uri) -> Self {
+        WsClientHandshake::with_key(uri, WebSocketKey::new().serialize().into_bytes())
+    }
+
+    pub fn with_key(uri: Uri, key: Vec<u8>) -> Self {
+        WsClientHandshake {
+            uri: uri,
+            key: key,
+            protocols: Vec::new(),
+            extensions: Vec::new(),
+            headers: Headers::new(),
+        }
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn protocols(&self) -> &[String] {
+        &self.protocols
+    }
+
+    pub fn extensions(&self) -> &[String] {
+        &self.extensions
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub fn add_protocol<P>(mut self, protocol: P) -> Self
+    where
+        P: Into<String>,
+    {
+        self.protocols.push(protocol.into());
+        self
+    }
+
+    pub fn add_extension<E>(mut self, extension: E) -> Self
+    where
+        E: Into<String>,
+    {
+        self.extensions.push(extension.into());
+        self
+    }
+
+    pub fn request(&self) -> hyper::Request {
+        let mut req = hyper::Request::new(Method::Get, self.uri.clone());
+        *req.headers_mut() = self.request_headers();
+        req
+    }
+
+    pub fn request_bytes(&self) -> Vec<u8> {
+        let mut headers = self.request_headers();
+        if let Some(authority) = self.uri.authority() {
+            headers.set_raw("Host", authority.to_owned());
+        }
+        let target = match self.uri.query() {
+            None => self.uri.path().to_owned(),
+            Some(query) => format!("{}?{}", self.uri.path(), query),
+        };
+        format!("GET {} {}\r\n{}\r\n", target, HttpVersion::Http11, headers).into_bytes()
+    }
+
+    pub fn validate<B>(
+        &self,
+        res: &hyper::Response<B>,
+    ) -> Result<WsClientResponse, WebSocketError> {
+        let switching_protocols = res.status() == StatusCode::SwitchingProtocols;
+        self.validate_parts(switching_protocols, res.headers())
+    }
+
+    pub fn connect<T>(self, io: T) -> ConnectWsHandshake<T>
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        let request = self.request_bytes();
+        ConnectWsHandshake {
+            handshake: self,
+            state: ConnectState::Writing(write_all(io, request)),
+        }
+    }
+
+    fn request_headers(&self) -> Headers {
+        let mut headers = self.headers.clone();
+        headers.set(header::Upgrade(vec![
+            header::Protocol::new(header::ProtocolName::WebSocket, None),
+        ]));
+        headers.set(header::Connection(vec![
+            "Upgrade".parse().expect("hyper-websocket: ConnectionOption parse failed"),
+        ]));
+        headers.set_raw("Sec-WebSocket-Version", "13");
+        headers.set_raw("Sec-WebSocket-Key", self.key.clone());
+        if !self.protocols.is_empty() {
+            headers.set_raw("Sec-WebSocket-Protocol", self.protocols.join(", "));
+        }
+        if !self.extensions.is_empty() {
+            headers.set_raw("Sec-WebSocket-Extensions", self.extensions.join(", "));
+        }
+        headers
+    }
+
+    fn validate_parts(
+        &self,
+        switching_protocols: bool,
+        headers: &Headers,
+    ) -> Result<WsClientResponse, WebSocketError> {
+        if !switching_protocols {
+            return Err(WebSocketError::ResponseError("Status code must be Switching Protocols"));
+        }
+
+        match headers.get::<header::Upgrade>() {
+            Some(&header::Upgrade(ref protocols))
+                if protocols
+                    .iter()
+                    .any(|protocol| protocol.name == header::ProtocolName::WebSocket) => {}
+            _ => return Err(WebSocketError::ResponseError("Upgrade field must be WebSocket")),
+        }
+
+        match headers.get::<header::Connection>() {
+            Some(&header::Connection(ref options)) if options.iter().any(is_upgrade_option) => {}
+            _ => return Err(WebSocketError::ResponseError("Connection field must be 'Upgrade'")),
+        }
+
+        let expected_accept = super::accept_key_for(&self.key);
+        match headers.get_raw("sec-websocket-accept").and_then(|raw| raw.one()) {
+            Some(accept) if accept == expected_accept.as_bytes() => {}
+            _ => return Err(WebSocketError::ResponseError("Sec-WebSocket-Accept is invalid")),
+        }
+
+        let mut protocols = super::split_header_values(headers, "sec-websocket-protocol");
+        if protocols.len() > 1 {
+            return Err(WebSocketError::ResponseError("Server selected multiple subprotocols"));
+        }
+        let protocol = protocols.pop();
+        if let Some(ref protocol) = protocol {
+            if !self.protocols.contains(protocol) {
+                return Err(WebSocketError::ResponseError(
+                    "Server selected unrequested subprotocol",
+                ));
+            }
+        }
+
+        let extensions = super::split_header_values(headers, "sec-websocket-extensions");
+        let requested_names = self.extensions.iter().map(extension_name).collect::<Vec<_>>();
+        if extensions
+            .iter()
+            .any(|ext| !requested_names.contains(&extension_name(ext)))
+        {
+            return Err(WebSocketError::ResponseError("Server selected unrequested extension"));
+        }
+
+        Ok(WsClientResponse {
+            protocol: protocol,
+            extensions: extensions,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WsClientResponse {
+    pub protocol: Option<String>,
+    pub extensions: Vec<String>,
+}
+
+pub struct ConnectWsHandshake<T> {
+    handshake: WsClientHandshake,
+    state: ConnectState<T>,
+}
+
+enum ConnectState<T> {
+    Writing(WriteAll<T, Vec<u8>>),
+    Reading(T, BytesMut),
+    Done,
+}
+
+impl<T> fmt::Debug for ConnectWsHandshake<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectWsHandshake")
+            .field("handshake", &self.handshake)
+            .field("state", &Omitted)
+            .finish()
+    }
+}
+
+impl<T> Future for ConnectWsHandshake<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    type Item = (Client<T>, WsClientResponse);
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match mem::replace(&mut self.state, ConnectState::Done) {
+                ConnectState::Writing(mut write) => match write.poll()? {
+                    Async::NotReady => {
+                        self.state = ConnectState::Writing(write);
+                        return Ok(Async::NotReady);
+                    }
+                    Async::Ready((io, _)) => {
+                        self.state = ConnectState::Reading(io, BytesMut::with_capacity(1024));
+                    }
+                },
+                ConnectState::Reading(mut io, mut buf) => {
+                    if let Some((head_len, response)) = self.parse_response(&buf)? {
+                        buf.split_to(head_len);
+                        let parts = FramedParts {
+                            inner: io,
+                            readbuf: buf,
+                            writebuf: BytesMut::new(),
+                        };
+                        let codec = MessageCodec::default(Context::Client);
+                        return Ok(Async::Ready((Framed::from_parts(parts, codec), response)));
+                    }
+
+                    if buf.len() >= MAX_RESPONSE_HEAD_LEN {
+                        return Err(WebSocketError::ResponseError("Response head too large"));
+                    }
+
+                    buf.reserve(1024);
+                    match io.read_buf(&mut buf)? {
+                        Async::NotReady => {
+                            self.state = ConnectState::Reading(io, buf);
+                            return Ok(Async::NotReady);
+                        }
+                        Async::Ready(0) => {
+                            return Err(
+                                io::Error::new(
+                                    io::ErrorKind::UnexpectedEof,
+                                    "connection closed during WebSocket handshake",
+                                ).into(),
+                            );
+                        }
+                        Async::Ready(_) => {
+                            self.state = ConnectState::Reading(io, buf);
+                        }
+                    }
+                }
+                ConnectState::Done => panic!("ConnectWsHandshake polled after completion"),
+            }
+        }
+    }
+}
+
+impl<T> ConnectWsHandshake<T> {
+    fn parse_response(
+        &self,
+        buf: &[u8],
+    ) -> Result<Option<(usize, WsClientResponse)>, WebSocketError> {
+        let mut raw_headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+        let mut res = httparse::Response::new(&mut raw_headers);
+        let head_len = match res.parse(buf) {
+            Ok(httparse::Status::Complete(head_len)) => head_len,
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(_) => return Err(WebSocketError::ResponseError("Malformed response head")),
+        };
+
+        let mut headers = Headers::new();
+        for raw_header in res.headers.iter() {
+            headers.append_raw(raw_header.name.to_owned(), raw_header.value.to_owned());
+        }
+
+        let response = self.handshake.validate_parts(res.code == Some(101), &headers)?;
+        Ok(Some((head_len, response)))
+    }
+}
+
+fn is_upgrade_option(option: &header::ConnectionOption) -> bool {
+    match *option {
+        header::ConnectionOption::ConnectionHeader(ref value) => {
+            value.as_ref().eq_ignore_ascii_case("upgrade")
+        }
+        _ => false,
+    }
+}
+
+fn extension_name<E>(extension: &E) -> &str
+where
+    E: AsRef<str> + ?Sized,
+{
+    extension.as_ref().split(';').next().unwrap_or("").trim()
+}
@@ -0,0 +1,801 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{BigEndian, BufMut, ByteOrder};
+use futures::{future, stream, Future, Stream};
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
+use rand;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::{Rc, Weak};
+use std::str;
+use std::time::Duration;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::AsyncRead;
+use tokio_io::io::{read_exact, write_all};
+use websocket::message::OwnedMessage;
+
+use super::backplane::{Backplane, BackplaneMessages};
+use super::channel::WsSender;
+use super::registry::ConnectionId;
+
+/// Frames larger than this end the link they arrive on.
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// A link with this many frames waiting to be written is dropped, and the
+/// node that dialed it redials and catches the other node up from scratch.
+const LINK_CAPACITY: usize = 1024;
+
+/// A dropped link is redialed after this long, doubling on each failure up to
+/// `MAX_REDIAL_DELAY_MS`, until `MAX_REDIALS` attempts have failed.
+const REDIAL_DELAY_MS: u64 = 500;
+const MAX_REDIAL_DELAY_MS: u64 = 30_000;
+const MAX_REDIALS: u32 = 10;
+
+const FRAME_HELLO: u8 = 1;
+const FRAME_NODES: u8 = 2;
+const FRAME_ATTACH: u8 = 3;
+const FRAME_DETACH: u8 = 4;
+const FRAME_ROOM: u8 = 5;
+const FRAME_DIRECT: u8 = 6;
+
+const OPCODE_TEXT: u8 = 1;
+const OPCODE_BINARY: u8 = 2;
+
+/// Identifies one node of a `Cluster`. Picked at random when the node starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(u64);
+
+impl NodeId {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+#[derive(Clone)]
+enum Frame {
+    Hello(NodeId, SocketAddr),
+    Nodes(Vec<(NodeId, SocketAddr)>),
+    Attach(ConnectionId, String),
+    Detach(ConnectionId),
+    Room(String, OwnedMessage),
+    Direct(ConnectionId, OwnedMessage),
+}
+
+struct Link {
+    serial: u64,
+    dialed_by: NodeId,
+    addr: SocketAddr,
+    tx: mpsc::Sender<Frame>,
+}
+
+struct Local {
+    identity: String,
+    sender: WsSender,
+}
+
+struct Inner {
+    node: NodeId,
+    addr: SocketAddr,
+    /// The address gossiped to other nodes.
+    advertised: SocketAddr,
+    handle: Handle,
+    next_serial: u64,
+    links: HashMap<NodeId, Link>,
+    local: HashMap<ConnectionId, Local>,
+    remote: BTreeMap<(NodeId, ConnectionId), String>,
+    rooms: Option<mpsc::UnboundedSender<(String, OwnedMessage)>>,
+}
+
+impl Inner {
+    /// Queues a frame on every link, and returns the nodes whose links are too
+    /// far behind to take it.
+    fn broadcast(&mut self, frame: &Frame) -> Vec<NodeId> {
+        self.links
+            .iter_mut()
+            .filter_map(|(&node, link)| match link.tx.try_send(frame.clone()) {
+                Err(ref err) if err.is_full() => Some(node),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// One node of a group of servers linked over plain TCP. Nodes gossip their
+/// membership so that every node ends up linked to every other, and share
+/// which connections each of them holds.
+///
+/// As a `Backplane`, a cluster forwards a `Hub`'s room broadcasts to every
+/// other node. Connections attached to any node can be looked up by identity
+/// and sent direct messages from anywhere in the cluster. Only text and binary
+/// messages cross between nodes. Clones share the same node.
+///
+/// Each link queues a bounded number of frames. A link that falls further
+/// behind is dropped, and a dropped link is redialed by the node that dialed
+/// it in the first place.
+#[derive(Clone)]
+pub struct Cluster {
+    inner: Rc<RefCell<Inner>>,
+}
+
+impl fmt::Debug for Cluster {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Cluster")
+            .field("node", &inner.node)
+            .field("addr", &inner.addr)
+            .field("advertised", &inner.advertised)
+            .field("links", &inner.links.len())
+            .field("local", &inner.local.len())
+            .field("remote", &inner.remote.len())
+            .finish()
+    }
+}
+
+impl Cluster {
+    /// Starts a node listening for other nodes on `addr`. It stays on its own
+    /// until it joins another node or another node joins it.
+    ///
+    /// Other nodes are told to join this one at the address it listens on,
+    /// unless `advertise` says otherwise.
+    pub fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr, handle)?;
+        let addr = listener.local_addr()?;
+        let cluster = Cluster {
+            inner: Rc::new(RefCell::new(Inner {
+                node: NodeId(rand::random()),
+                addr: addr,
+                advertised: addr,
+                handle: handle.clone(),
+                next_serial: 0,
+                links: HashMap::new(),
+                local: HashMap::new(),
+                remote: BTreeMap::new(),
+                rooms: None,
+            })),
+        };
+
+        let weak = Rc::downgrade(&cluster.inner);
+        let accept = listener.incoming().for_each(move |(tcp, _remote_addr)| {
+            match upgrade(&weak) {
+                None => Err(io::Error::new(io::ErrorKind::Other, "cluster dropped")),
+                Some(cluster) => {
+                    cluster.start_link(tcp, false);
+                    Ok(())
+                }
+            }
+        });
+        handle.spawn(accept.map_err(|err| {
+            debug!("hyper-websocket: cluster listener stopped: {}", err);
+        }));
+
+        Ok(cluster)
+    }
+
+    /// Links up with the node at `addr`, and through it with the rest of its
+    /// cluster.
+    pub fn join(&self, addr: &SocketAddr) {
+        let weak = Rc::downgrade(&self.inner);
+        let handle = self.inner.borrow().handle.clone();
+        let addr = *addr;
+        handle.spawn(TcpStream::connect(&addr, &handle).then(move |result| {
+            match result {
+                Err(err) => {
+                    error!("hyper-websocket: cluster connect to {} failed: {}", addr, err);
+                }
+                Ok(tcp) => if let Some(cluster) = upgrade(&weak) {
+                    cluster.start_link(tcp, true);
+                },
+            }
+            Ok(())
+        }));
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.inner.borrow().node
+    }
+
+    /// The address this node listens for other nodes on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.borrow().addr
+    }
+
+    /// Sets the address other nodes are told to join this one at, for when
+    /// the one it listens on can't be dialed, such as `0.0.0.0` or an address
+    /// behind NAT. Only links made afterwards hear of it, so set it before
+    /// joining.
+    pub fn advertise(&self, addr: &SocketAddr) {
+        self.inner.borrow_mut().advertised = *addr;
+    }
+
+    pub fn advertised_addr(&self) -> SocketAddr {
+        self.inner.borrow().advertised
+    }
+
+    /// Lists every node currently linked to this one, and this one, ordered
+    /// by ID.
+    pub fn nodes(&self) -> Vec<NodeId> {
+        let inner = self.inner.borrow();
+        let mut nodes = inner.links.keys().cloned().collect::<Vec<_>>();
+        nodes.push(inner.node);
+        nodes.sort();
+        nodes
+    }
+
+    /// Makes a local connection known to the cluster under `identity`,
+    /// replacing any previous connection with the same ID.
+    pub fn attach<I>(&self, id: ConnectionId, identity: I, sender: WsSender)
+    where
+        I: Into<String>,
+    {
+        let identity = identity.into();
+        let lagging = {
+            let mut inner = self.inner.borrow_mut();
+            let lagging = inner.broadcast(&Frame::Attach(id, identity.clone()));
+            inner.local.insert(
+                id,
+                Local {
+                    identity: identity,
+                    sender: sender,
+                },
+            );
+            lagging
+        };
+        self.drop_lagging(lagging);
+    }
+
+    /// Returns `false` if there is no such local connection.
+    pub fn detach(&self, id: ConnectionId) -> bool {
+        let lagging = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.local.remove(&id).is_none() {
+                return false;
+            }
+            inner.broadcast(&Frame::Detach(id))
+        };
+        self.drop_lagging(lagging);
+        true
+    }
+
+    /// Finds every connection attached under `identity` anywhere in the
+    /// cluster, ordered by node and connection ID.
+    pub fn locate(&self, identity: &str) -> Vec<(NodeId, ConnectionId)> {
+        let inner = self.inner.borrow();
+        let mut found = inner
+            .local
+            .iter()
+            .filter(|&(_, local)| local.identity == identity)
+            .map(|(&id, _)| (inner.node, id))
+            .chain(
+                inner
+                    .remote
+                    .iter()
+                    .filter(|&(_, remote)| remote == identity)
+                    .map(|(&key, _)| key),
+            )
+            .collect::<Vec<_>>();
+        found.sort();
+        found
+    }
+
+    pub fn is_connected(&self, identity: &str) -> bool {
+        !self.locate(identity).is_empty()
+    }
+
+    /// Queues a message for a connection on any node. Returns `false` if the
+    /// node isn't linked, the connection isn't known to be attached, the
+    /// message can't cross between nodes, or the link to the node is full.
+    pub fn send(&self, node: NodeId, id: ConnectionId, msg: OwnedMessage) -> bool {
+        if node == self.node_id() {
+            return self.deliver(id, msg);
+        }
+        let mut inner = self.inner.borrow_mut();
+        if !inner.remote.contains_key(&(node, id)) || !is_relayable(&msg) {
+            return false;
+        }
+        match inner.links.get_mut(&node) {
+            None => false,
+            Some(link) => link.tx.try_send(Frame::Direct(id, msg)).is_ok(),
+        }
+    }
+
+    /// Sends a message to every connection attached under `identity`, and
+    /// returns how many it was queued for.
+    pub fn send_to_identity(&self, identity: &str, msg: &OwnedMessage) -> usize {
+        self.locate(identity)
+            .into_iter()
+            .filter(|&(node, id)| self.send(node, id, msg.clone()))
+            .count()
+    }
+
+    fn deliver(&self, id: ConnectionId, msg: OwnedMessage) -> bool {
+        let mut sender = match self.inner.borrow().local.get(&id) {
+            None => return false,
+            Some(local) => local.sender.clone(),
+        };
+        match sender.try_send(msg) {
+            Ok(()) => true,
+            Err(ref err) if err.is_closed() => {
+                self.detach(id);
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn start_link(&self, tcp: TcpStream, dialed: bool) {
+        let _ = tcp.set_nodelay(true);
+        let (reader, writer) = tcp.split();
+        let (tx, rx) = mpsc::channel(LINK_CAPACITY);
+        let (sync_tx, sync_rx) = oneshot::channel();
+
+        let (serial, hello, handle) = {
+            let mut inner = self.inner.borrow_mut();
+            let serial = inner.next_serial;
+            inner.next_serial += 1;
+            let hello = encode_frame(&Frame::Hello(inner.node, inner.advertised));
+            (serial, hello, inner.handle.clone())
+        };
+
+        // The hello goes first, then what the peer needs to catch up on once
+        // it has said hello back, then everything queued for the link since.
+        // Catching up skips the queue, since it grows with the number of
+        // attached connections.
+        let write = write_all(writer, hello)
+            .and_then(|(writer, _)| {
+                sync_rx
+                    .map(|frames| (writer, frames))
+                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "cluster link refused"))
+            })
+            .and_then(|(writer, frames)| {
+                stream::iter_ok(frames).fold(writer, |writer, frame: Frame| {
+                    write_all(writer, encode_frame(&frame)).map(|(writer, _)| writer)
+                })
+            })
+            .and_then(|writer| {
+                rx.map_err(|()| -> io::Error { unreachable!() })
+                    .fold(writer, |writer, frame| {
+                        write_all(writer, encode_frame(&frame)).map(|(writer, _)| writer)
+                    })
+            })
+            .map(|_| ());
+
+        let weak = Rc::downgrade(&self.inner);
+        let peer = Rc::new(RefCell::new(None));
+        let read_peer = peer.clone();
+        let mut pending = Some((tx, sync_tx));
+        let read = stream::unfold(reader, |reader| {
+            let frame = read_exact(reader, [0; 4]).and_then(|(reader, len)| {
+                let len = BigEndian::read_u32(&len) as usize;
+                if len > MAX_FRAME_SIZE {
+                    return Either::A(future::err(invalid_data()));
+                }
+                Either::B(read_exact(reader, vec![0; len]))
+            });
+            Some(frame.and_then(|(reader, body)| {
+                decode_frame(&body).map(|frame| (frame, reader))
+            }))
+        }).for_each(move |frame| {
+            let cluster = match upgrade(&weak) {
+                None => return Err(io::Error::new(io::ErrorKind::Other, "cluster dropped")),
+                Some(cluster) => cluster,
+            };
+            let mut peer = read_peer.borrow_mut();
+            match (*peer, frame) {
+                (None, Frame::Hello(node, addr)) => {
+                    let (tx, sync_tx) = pending.take().expect("hyper-websocket: hello sent twice");
+                    if !cluster.accept_link(serial, dialed, node, addr, tx, sync_tx) {
+                        return Err(invalid_data());
+                    }
+                    *peer = Some(node);
+                    Ok(())
+                }
+                (None, _) | (Some(_), Frame::Hello(..)) => Err(invalid_data()),
+                (Some(node), frame) => {
+                    // A link dropped for falling behind stops reading too.
+                    if !cluster.is_linked(serial, node) {
+                        return Err(io::Error::new(io::ErrorKind::Other, "cluster link dropped"));
+                    }
+                    cluster.handle_frame(node, frame);
+                    Ok(())
+                }
+            }
+        });
+
+        let weak = Rc::downgrade(&self.inner);
+        handle.spawn(read.select2(write).then(move |result| {
+            match result {
+                Err(Either::A((err, _))) | Err(Either::B((err, _))) => {
+                    // The peer hanging up shows up as an unexpected EOF.
+                    if err.kind() != io::ErrorKind::UnexpectedEof {
+                        debug!("hyper-websocket: cluster link error: {}", err);
+                    }
+                }
+                Ok(_) => {}
+            }
+            if let (Some(cluster), Some(node)) = (upgrade(&weak), *peer.borrow()) {
+                cluster.drop_link(serial, node);
+            }
+            Ok(())
+        }));
+    }
+
+    /// Registers a link once its peer has said hello. Returns `false` if the
+    /// link should be dropped instead.
+    fn accept_link(
+        &self,
+        serial: u64,
+        dialed: bool,
+        node: NodeId,
+        addr: SocketAddr,
+        tx: mpsc::Sender<Frame>,
+        sync_tx: oneshot::Sender<Vec<Frame>>,
+    ) -> bool {
+        let lagging = {
+            let mut inner = self.inner.borrow_mut();
+            if node == inner.node {
+                debug!("hyper-websocket: cluster node {} linked to itself", node);
+                return false;
+            }
+
+            let dialed_by = if dialed { inner.node } else { node };
+            // When two nodes dial each other at once, both keep the link
+            // dialed by the node with the lower ID.
+            let preferred = inner.node.min(node);
+            if let Some(existing) = inner.links.get(&node) {
+                if existing.dialed_by == preferred || dialed_by != preferred {
+                    return false;
+                }
+            }
+
+            let mut nodes = inner
+                .links
+                .iter()
+                .filter(|&(&other, _)| other != node)
+                .map(|(&other, link)| (other, link.addr))
+                .collect::<Vec<_>>();
+            nodes.push((inner.node, inner.advertised));
+            let mut sync = vec![Frame::Nodes(nodes)];
+            for (&id, local) in &inner.local {
+                sync.push(Frame::Attach(id, local.identity.clone()));
+            }
+            let _ = sync_tx.send(sync);
+
+            let lagging = inner
+                .links
+                .iter_mut()
+                .filter(|&(&other, _)| other != node)
+                .filter_map(|(&other, link)| {
+                    match link.tx.try_send(Frame::Nodes(vec![(node, addr)])) {
+                        Err(ref err) if err.is_full() => Some(other),
+                        _ => None,
+                    }
+                })
+                .collect();
+
+            inner.links.insert(
+                node,
+                Link {
+                    serial: serial,
+                    dialed_by: dialed_by,
+                    addr: addr,
+                    tx: tx,
+                },
+            );
+            lagging
+        };
+        self.drop_lagging(lagging);
+        true
+    }
+
+    fn is_linked(&self, serial: u64, node: NodeId) -> bool {
+        self.inner.borrow().links.get(&node).map(|link| link.serial) == Some(serial)
+    }
+
+    fn handle_frame(&self, node: NodeId, frame: Frame) {
+        match frame {
+            Frame::Hello(..) => {}
+            Frame::Nodes(nodes) => {
+                let to_dial = {
+                    let inner = self.inner.borrow();
+                    nodes
+                        .into_iter()
+                        .filter(|&(other, _)| {
+                            // Only the lower of two IDs dials, so that nodes
+                            // learning of each other at once make one link.
+                            other != inner.node && inner.node < other
+                                && !inner.links.contains_key(&other)
+                        })
+                        .map(|(_, addr)| addr)
+                        .collect::<Vec<_>>()
+                };
+                for addr in to_dial {
+                    self.join(&addr);
+                }
+            }
+            Frame::Attach(id, identity) => {
+                self.inner.borrow_mut().remote.insert((node, id), identity);
+            }
+            Frame::Detach(id) => {
+                self.inner.borrow_mut().remote.remove(&(node, id));
+            }
+            Frame::Room(room, msg) => {
+                let inner = self.inner.borrow();
+                if let Some(ref rooms) = inner.rooms {
+                    let _ = rooms.unbounded_send((room, msg));
+                }
+            }
+            Frame::Direct(id, msg) => {
+                self.deliver(id, msg);
+            }
+        }
+    }
+
+    fn drop_link(&self, serial: u64, node: NodeId) {
+        // A newer link may have replaced this one already.
+        if self.is_linked(serial, node) {
+            self.unlink(node);
+        }
+    }
+
+    /// Drops the links that fell too far behind. Dropping a link's queue ends
+    /// its writer once the frames already queued are written.
+    fn drop_lagging(&self, lagging: Vec<NodeId>) {
+        for node in lagging {
+            warn!("hyper-websocket: cluster link to {} fell behind, dropping it", node);
+            self.unlink(node);
+        }
+    }
+
+    /// Forgets a node, and redials it if this node dialed the link.
+    fn unlink(&self, node: NodeId) {
+        let redial = {
+            let mut inner = self.inner.borrow_mut();
+            let link = match inner.links.remove(&node) {
+                None => return,
+                Some(link) => link,
+            };
+            let gone = inner
+                .remote
+                .keys()
+                .filter(|&&(other, _)| other == node)
+                .cloned()
+                .collect::<Vec<_>>();
+            for key in gone {
+                inner.remote.remove(&key);
+            }
+            if link.dialed_by == inner.node {
+                Some(link.addr)
+            } else {
+                None
+            }
+        };
+        if let Some(addr) = redial {
+            self.redial(addr, 0);
+        }
+    }
+
+    /// Dials a dropped link's address again after a delay, backing off while
+    /// it fails. Gives up once a link to that address is up again, whether
+    /// dialed from either side.
+    fn redial(&self, addr: SocketAddr, attempt: u32) {
+        let handle = self.inner.borrow().handle.clone();
+        let delay = (REDIAL_DELAY_MS << attempt).min(MAX_REDIAL_DELAY_MS);
+        let timeout = match Timeout::new(Duration::from_millis(delay), &handle) {
+            Err(err) => {
+                error!("hyper-websocket: cluster redial timer error: {}", err);
+                return;
+            }
+            Ok(timeout) => timeout,
+        };
+
+        let weak = Rc::downgrade(&self.inner);
+        let connect_handle = handle.clone();
+        handle.spawn(timeout.then(move |_| {
+            let cluster = match upgrade(&weak) {
+                None => return Either::A(future::ok(())),
+                Some(cluster) => cluster,
+            };
+            if cluster.inner.borrow().links.values().any(|link| link.addr == addr) {
+                return Either::A(future::ok(()));
+            }
+            Either::B(TcpStream::connect(&addr, &connect_handle).then(move |result| {
+                let cluster = match upgrade(&weak) {
+                    None => return Ok(()),
+                    Some(cluster) => cluster,
+                };
+                match result {
+                    Ok(tcp) => cluster.start_link(tcp, true),
+                    Err(ref err) if attempt + 1 >= MAX_REDIALS => {
+                        error!("hyper-websocket: cluster redial to {} failed: {}", addr, err);
+                    }
+                    Err(err) => {
+                        debug!("hyper-websocket: cluster redial to {} failed: {}", addr, err);
+                        cluster.redial(addr, attempt + 1);
+                    }
+                }
+                Ok(())
+            }))
+        }));
+    }
+}
+
+impl Backplane for Cluster {
+    fn publish(&self, room: &str, msg: &OwnedMessage) {
+        if is_relayable(msg) {
+            let lagging = self.inner
+                .borrow_mut()
+                .broadcast(&Frame::Room(room.to_owned(), msg.clone()));
+            self.drop_lagging(lagging);
+        }
+    }
+
+    fn subscribe(&self) -> BackplaneMessages {
+        let (tx, rx) = mpsc::unbounded();
+        let mut inner = self.inner.borrow_mut();
+        assert!(inner.rooms.is_none(), "hyper-websocket: Cluster subscribed to twice");
+        inner.rooms = Some(tx);
+        Box::new(rx)
+    }
+}
+
+fn upgrade(weak: &Weak<RefCell<Inner>>) -> Option<Cluster> {
+    weak.upgrade().map(|inner| Cluster { inner: inner })
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed cluster frame")
+}
+
+fn is_relayable(msg: &OwnedMessage) -> bool {
+    match *msg {
+        OwnedMessage::Text(_) | OwnedMessage::Binary(_) => true,
+        _ => false,
+    }
+}
+
+fn encode_frame(frame: &Frame) -> Vec<u8> {
+    let mut body = Vec::new();
+    match *frame {
+        Frame::Hello(node, addr) => {
+            body.put_u8(FRAME_HELLO);
+            body.put_u64::<BigEndian>(node.0);
+            put_str(&mut body, &addr.to_string());
+        }
+        Frame::Nodes(ref nodes) => {
+            body.put_u8(FRAME_NODES);
+            body.put_u32::<BigEndian>(nodes.len() as u32);
+            for &(node, addr) in nodes {
+                body.put_u64::<BigEndian>(node.0);
+                put_str(&mut body, &addr.to_string());
+            }
+        }
+        Frame::Attach(id, ref identity) => {
+            body.put_u8(FRAME_ATTACH);
+            body.put_u64::<BigEndian>(id.as_u64());
+            put_str(&mut body, identity);
+        }
+        Frame::Detach(id) => {
+            body.put_u8(FRAME_DETACH);
+            body.put_u64::<BigEndian>(id.as_u64());
+        }
+        Frame::Room(ref room, ref msg) => {
+            body.put_u8(FRAME_ROOM);
+            put_str(&mut body, room);
+            put_message(&mut body, msg);
+        }
+        Frame::Direct(id, ref msg) => {
+            body.put_u8(FRAME_DIRECT);
+            body.put_u64::<BigEndian>(id.as_u64());
+            put_message(&mut body, msg);
+        }
+    }
+
+    let mut packet = Vec::with_capacity(4 + body.len());
+    packet.put_u32::<BigEndian>(body.len() as u32);
+    packet.extend_from_slice(&body);
+    packet
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.put_u32::<BigEndian>(s.len() as u32);
+    buf.put_slice(s.as_bytes());
+}
+
+/// Writes a text or binary message as an opcode and the rest of the frame.
+fn put_message(buf: &mut Vec<u8>, msg: &OwnedMessage) {
+    match *msg {
+        OwnedMessage::Text(ref text) => {
+            buf.put_u8(OPCODE_TEXT);
+            buf.put_slice(text.as_bytes());
+        }
+        OwnedMessage::Binary(ref data) => {
+            buf.put_u8(OPCODE_BINARY);
+            buf.put_slice(data);
+        }
+        _ => unreachable!("hyper-websocket: control message sent between cluster nodes"),
+    }
+}
+
+fn decode_frame(body: &[u8]) -> io::Result<Frame> {
+    let mut reader = FrameReader(body);
+    let frame = match reader.u8()? {
+        FRAME_HELLO => Frame::Hello(NodeId(reader.u64()?), reader.addr()?),
+        FRAME_NODES => {
+            let count = reader.u32()?;
+            let mut nodes = Vec::new();
+            for _ in 0..count {
+                nodes.push((NodeId(reader.u64()?), reader.addr()?));
+            }
+            Frame::Nodes(nodes)
+        }
+        FRAME_ATTACH => Frame::Attach(ConnectionId::new(reader.u64()?), reader.string()?),
+        FRAME_DETACH => Frame::Detach(ConnectionId::new(reader.u64()?)),
+        FRAME_ROOM => Frame::Room(reader.string()?, reader.message()?),
+        FRAME_DIRECT => Frame::Direct(ConnectionId::new(reader.u64()?), reader.message()?),
+        _ => return Err(invalid_data()),
+    };
+    Ok(frame)
+}
+
+struct FrameReader<'a>(&'a [u8]);
+
+impl<'a> FrameReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.take(4).map(BigEndian::read_u32)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        self.take(8).map(BigEndian::read_u64)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        str::from_utf8(bytes)
+            .map(str::to_owned)
+            .map_err(|_| invalid_data())
+    }
+
+    fn addr(&mut self) -> io::Result<SocketAddr> {
+        self.string()?.parse().map_err(|_| invalid_data())
+    }
+
+    /// Reads a message from the rest of the frame.
+    fn message(&mut self) -> io::Result<OwnedMessage> {
+        let opcode = self.u8()?;
+        let payload = self.take(self.0.len())?;
+        match opcode {
+            OPCODE_TEXT => str::from_utf8(payload)
+                .map(|text| OwnedMessage::Text(text.to_owned()))
+                .map_err(|_| invalid_data()),
+            OPCODE_BINARY => Ok(OwnedMessage::Binary(payload.to_vec())),
+            _ => Err(invalid_data()),
+        }
+    }
+}
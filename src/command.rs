@@ -0,0 +1,120 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use tokio_core::reactor::Handle;
+use websocket::message::OwnedMessage;
+
+use super::channel::WsSender;
+
+const CLOSE_NORMAL: u16 = 1000;
+
+/// The command an endpoint runs for each connection, as set up through its
+/// builder.
+#[derive(Clone, Debug)]
+pub(crate) struct CommandSpec {
+    program: String,
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+    current_dir: Option<PathBuf>,
+}
+
+impl CommandSpec {
+    pub fn new(program: &str) -> Self {
+        CommandSpec {
+            program: program.to_owned(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            current_dir: None,
+        }
+    }
+
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn arg(&mut self, arg: &str) {
+        self.args.push(arg.to_owned());
+    }
+
+    pub fn env(&mut self, key: &str, value: &str) {
+        self.envs.push((key.to_owned(), value.to_owned()));
+    }
+
+    pub fn current_dir(&mut self, dir: &Path) {
+        self.current_dir = Some(dir.to_owned());
+    }
+
+    /// Builds a `Command` with everything set so far, leaving its stdio for
+    /// the caller.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        if let Some(ref dir) = self.current_dir {
+            command.current_dir(dir);
+        }
+        for &(ref key, ref value) in &self.envs {
+            command.env(key, value);
+        }
+        command
+    }
+}
+
+/// Writes whatever is sent on the returned channel to `writer` from a thread
+/// of its own, since writing to a child may block. The thread ends once the
+/// channel is dropped or a write fails.
+pub(crate) fn spawn_writer<W>(writer: W, capacity: usize) -> mpsc::Sender<Vec<u8>>
+where
+    W: Write + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<Vec<u8>>(capacity);
+    thread::spawn(move || {
+        let mut writer = writer;
+        for data in rx.wait() {
+            let written = match data {
+                Err(()) => break,
+                Ok(data) => writer.write_all(&data).and_then(|()| writer.flush()),
+            };
+            if written.is_err() {
+                break;
+            }
+        }
+    });
+    tx
+}
+
+/// Sends the child's output down the connection, and closes it with 1000
+/// once the output ends.
+pub(crate) fn forward_output<S>(handle: &Handle, output: S, sender: WsSender)
+where
+    S: Stream<Item = OwnedMessage, Error = ()> + 'static,
+{
+    let closer = sender.clone();
+    let downstream = output.forward(sender.sink_map_err(|_| ())).then(move |_| {
+        let _ = closer.close(CLOSE_NORMAL, "");
+        Ok(())
+    });
+    handle.spawn(downstream);
+}
+
+/// Kills a child with `kill` and waits for it off the event loop, so it
+/// doesn't linger as a zombie.
+pub(crate) fn reap<K>(child: Child, kill: K)
+where
+    K: FnOnce(&mut Child) + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut child = child;
+        kill(&mut child);
+        match child.wait() {
+            Ok(status) => debug!("hyper-websocket: process {} ended: {}", child.id(), status),
+            Err(err) => error!("hyper-websocket: process {} wait error: {}", child.id(), err),
+        }
+    });
+}
@@ -0,0 +1,893 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use base64;
+use futures::{future, stream, Async, Future, IntoFuture, Poll, Stream};
+use futures::future::Either;
+use futures::sync::{mpsc, oneshot};
+use hyper::{self, Body, Method, Request, Response, StatusCode};
+use hyper::header::ContentLength;
+use rand;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Interval, Timeout};
+use tokio_service::Service;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use super::{Omitted, WsHandshake, WsResponse};
+use super::channel::{spawn_channel, WsSendError, WsSender, QUEUE_CAPACITY};
+use super::polling::{read_body, Outbox};
+use super::server::{WsContext, WsEndpoint};
+
+const DEFAULT_PATH: &str = "/socket.io/";
+const DEFAULT_PING_INTERVAL_MS: u64 = 25_000;
+const DEFAULT_PING_TIMEOUT_MS: u64 = 20_000;
+const DEFAULT_MAX_PAYLOAD: usize = 1_000_000;
+
+/// Separates the packets of a long-polling payload.
+const RECORD_SEPARATOR: char = '\x1e';
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+// Error codes sent in the bodies of failed long-polling requests.
+const UNKNOWN_TRANSPORT: u8 = 0;
+const UNKNOWN_SID: u8 = 1;
+const BAD_HANDSHAKE_METHOD: u8 = 2;
+const BAD_REQUEST: u8 = 3;
+const UNSUPPORTED_PROTOCOL_VERSION: u8 = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Packet {
+    Open(String),
+    Close,
+    Ping(String),
+    Pong(String),
+    Text(String),
+    Binary(Vec<u8>),
+    Upgrade,
+    Noop,
+}
+
+impl Packet {
+    fn parse(text: &str) -> Option<Self> {
+        if text.is_empty() || !text.is_char_boundary(1) {
+            return None;
+        }
+        let (kind, data) = text.split_at(1);
+        Some(match kind {
+            "0" => Packet::Open(data.to_owned()),
+            "1" => Packet::Close,
+            "2" => Packet::Ping(data.to_owned()),
+            "3" => Packet::Pong(data.to_owned()),
+            "4" => Packet::Text(data.to_owned()),
+            "5" => Packet::Upgrade,
+            "6" => Packet::Noop,
+            _ => return None,
+        })
+    }
+
+    /// Parses one packet of a long-polling payload, where binary messages
+    /// are base64-encoded behind a `b`.
+    fn parse_polling(text: &str) -> Option<Self> {
+        if text.starts_with('b') {
+            base64::decode(&text[1..]).ok().map(Packet::Binary)
+        } else {
+            Packet::parse(text)
+        }
+    }
+
+    /// Returns `Ok(None)` for control frames, which aren't packets.
+    fn from_ws(msg: OwnedMessage) -> Result<Option<Self>, ()> {
+        match msg {
+            OwnedMessage::Text(text) => Packet::parse(&text).map(Some).ok_or(()),
+            OwnedMessage::Binary(data) => Ok(Some(Packet::Binary(data))),
+            _ => Ok(None),
+        }
+    }
+
+    fn encode_polling(&self) -> String {
+        match *self {
+            Packet::Open(ref data) => format!("0{}", data),
+            Packet::Close => "1".to_owned(),
+            Packet::Ping(ref data) => format!("2{}", data),
+            Packet::Pong(ref data) => format!("3{}", data),
+            Packet::Text(ref text) => format!("4{}", text),
+            Packet::Binary(ref data) => format!("b{}", base64::encode(data)),
+            Packet::Upgrade => "5".to_owned(),
+            Packet::Noop => "6".to_owned(),
+        }
+    }
+
+    fn into_ws(self) -> OwnedMessage {
+        match self {
+            Packet::Binary(data) => OwnedMessage::Binary(data),
+            packet => OwnedMessage::Text(packet.encode_polling()),
+        }
+    }
+}
+
+enum Transport {
+    Polling,
+    WebSocket(WsSender),
+}
+
+struct Session {
+    transport: Transport,
+    /// Packets waiting for the next poll.
+    outbox: Outbox<Packet>,
+    /// The WebSocket a polling session is being upgraded to.
+    upgrade: Option<WsSender>,
+    incoming: mpsc::Sender<OwnedMessage>,
+    pings: u64,
+    awaiting_pong: bool,
+    /// Dropping this stops the heart-beat timer.
+    _heart_beat: Option<oneshot::Sender<()>>,
+}
+
+impl Session {
+    /// Queues a packet, returning `false` if the client has let its
+    /// WebSocket's queue, or the packets waiting for its next poll, fill up.
+    fn send(&mut self, packet: Packet) -> bool {
+        match self.transport {
+            Transport::WebSocket(ref mut sender) => match sender.try_send(packet.into_ws()) {
+                Err(WsSendError::Full(_)) => false,
+                // A closed WebSocket ends the session by itself.
+                Ok(()) | Err(WsSendError::Closed(_)) => true,
+            },
+            Transport::Polling => {
+                if self.outbox.len() >= QUEUE_CAPACITY {
+                    return false;
+                }
+                self.outbox.push(packet);
+                self.outbox.flush(false);
+                true
+            }
+        }
+    }
+}
+
+type OnConnection = Fn(EngineIoSocket) -> Box<Future<Item = (), Error = ()>>;
+
+struct EngineInner {
+    handle: Handle,
+    path: String,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    max_payload: usize,
+    on_connection: Rc<OnConnection>,
+    sessions: HashMap<String, Session>,
+}
+
+impl EngineInner {
+    /// Queues a packet, returning whether the session is still open. Senders
+    /// don't wait for room, so a client that falls behind is disconnected
+    /// rather than have packets go missing.
+    fn send(&mut self, id: &str, packet: Packet) -> bool {
+        let kept_up = match self.sessions.get_mut(id) {
+            None => return false,
+            Some(session) => session.send(packet),
+        };
+        if !kept_up {
+            debug!("hyper-websocket: closing Engine.IO session {}, which fell behind", id);
+            self.close_with(id, CLOSE_POLICY_VIOLATION, "Too slow");
+        }
+        kept_up
+    }
+
+    /// Handles a packet from the client, returning the message to hand to
+    /// the session's socket, if it carries one.
+    fn receive(&mut self, id: &str, packet: Packet) -> Option<OwnedMessage> {
+        match packet {
+            Packet::Close => self.close(id),
+            Packet::Ping(data) => {
+                self.send(id, Packet::Pong(data));
+            }
+            Packet::Pong(_) => if let Some(session) = self.sessions.get_mut(id) {
+                session.awaiting_pong = false;
+            },
+            Packet::Text(text) => return Some(OwnedMessage::Text(text)),
+            Packet::Binary(data) => return Some(OwnedMessage::Binary(data)),
+            Packet::Open(_) | Packet::Upgrade | Packet::Noop => {}
+        }
+        None
+    }
+
+    /// Switches a session over to the WebSocket it was being upgraded to,
+    /// returning whether there was one.
+    fn upgrade(&mut self, id: &str) -> bool {
+        let kept_up = {
+            let session = match self.sessions.get_mut(id) {
+                None => return false,
+                Some(session) => session,
+            };
+            let sender = match session.upgrade.take() {
+                None => return false,
+                Some(sender) => sender,
+            };
+            // The last poll gets whatever is queued, and anything left goes
+            // out over the WebSocket.
+            session.outbox.flush(true);
+            let outbox = session.outbox.take();
+            session.transport = Transport::WebSocket(sender);
+            outbox.into_iter().all(|packet| session.send(packet))
+        };
+        if !kept_up {
+            self.close_with(id, CLOSE_POLICY_VIOLATION, "Too slow");
+        }
+        true
+    }
+
+    /// Ends a session, telling the client if it's still listening. Its
+    /// socket's stream of messages then ends.
+    fn close(&mut self, id: &str) {
+        self.close_with(id, CLOSE_NORMAL, "");
+    }
+
+    /// Like `close`, but closes a WebSocket with `code` and `reason`.
+    fn close_with(&mut self, id: &str, code: u16, reason: &str) {
+        let mut session = match self.sessions.remove(id) {
+            None => return,
+            Some(session) => session,
+        };
+        match session.transport {
+            Transport::WebSocket(ref mut sender) => {
+                // This goes missing if the queue is full, but the close frame
+                // still gets through.
+                let _ = sender.try_send(Packet::Close.into_ws());
+                let _ = sender.close(code, reason);
+            }
+            Transport::Polling => {
+                session.outbox.push(Packet::Close);
+                session.outbox.flush(false);
+            }
+        }
+        if let Some(ref sender) = session.upgrade {
+            let _ = sender.close(CLOSE_NORMAL, "");
+        }
+    }
+}
+
+/// An Engine.IO v4 server, the transport under Socket.IO. Clients connect
+/// either by HTTP long-polling, served by this as a hyper `Service`, or by
+/// WebSocket, served by this as a `WsEndpoint`, and long-polling clients may
+/// then upgrade to a WebSocket. Use clones of one server for both roles:
+///
+/// ```ignore
+/// let engine = EngineIoServer::new(&handle, |socket| { ... });
+/// WsServer::from_endpoint(engine.clone(), engine).serve(listener, &handle)
+/// ```
+///
+/// Requests for paths other than the Engine.IO path get `404 Not Found`.
+/// Each new session is handed to the connection handler as an
+/// `EngineIoSocket`, and the future the handler returns is spawned.
+#[derive(Clone)]
+pub struct EngineIoServer {
+    inner: Rc<RefCell<EngineInner>>,
+}
+
+impl fmt::Debug for EngineIoServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("EngineIoServer")
+            .field("path", &inner.path)
+            .field("ping_interval", &inner.ping_interval)
+            .field("ping_timeout", &inner.ping_timeout)
+            .field("max_payload", &inner.max_payload)
+            .field("on_connection", &Omitted)
+            .field("sessions", &inner.sessions.len())
+            .finish()
+    }
+}
+
+impl EngineIoServer {
+    pub fn new<H, F>(handle: &Handle, on_connection: H) -> Self
+    where
+        H: Fn(EngineIoSocket) -> F + 'static,
+        F: IntoFuture<Item = (), Error = ()>,
+        F::Future: 'static,
+    {
+        let on_connection = move |socket: EngineIoSocket| -> Box<Future<Item = (), Error = ()>> {
+            Box::new(on_connection(socket).into_future())
+        };
+        EngineIoServer {
+            inner: Rc::new(RefCell::new(EngineInner {
+                handle: handle.clone(),
+                path: DEFAULT_PATH.to_owned(),
+                ping_interval: Duration::from_millis(DEFAULT_PING_INTERVAL_MS),
+                ping_timeout: Duration::from_millis(DEFAULT_PING_TIMEOUT_MS),
+                max_payload: DEFAULT_MAX_PAYLOAD,
+                on_connection: Rc::new(on_connection),
+                sessions: HashMap::new(),
+            })),
+        }
+    }
+
+    /// The path Engine.IO is served under. Defaults to `/socket.io/`.
+    pub fn path(self, path: &str) -> Self {
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.path = path.to_owned();
+            if !inner.path.ends_with('/') {
+                inner.path.push('/');
+            }
+        }
+        self
+    }
+
+    /// How often each client is pinged. Defaults to 25 seconds.
+    pub fn ping_interval(self, ping_interval: Duration) -> Self {
+        self.inner.borrow_mut().ping_interval = ping_interval;
+        self
+    }
+
+    /// How long a client has to answer a ping before its session is closed.
+    /// Defaults to 20 seconds.
+    pub fn ping_timeout(self, ping_timeout: Duration) -> Self {
+        self.inner.borrow_mut().ping_timeout = ping_timeout;
+        self
+    }
+
+    /// The largest long-polling request body accepted, in bytes. Defaults to
+    /// 1 MB.
+    pub fn max_payload(self, max_payload: usize) -> Self {
+        self.inner.borrow_mut().max_payload = max_payload;
+        self
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        let inner = self.inner.borrow();
+        path == inner.path || path == inner.path.trim_right_matches('/')
+    }
+
+    fn handshake(&self, query: Option<&str>) -> Response {
+        let id = open_session(&self.inner, Transport::Polling, query);
+        let mut inner = self.inner.borrow_mut();
+        let packets = match inner.sessions.get_mut(&id) {
+            None => vec![Packet::Close],
+            Some(session) => session.outbox.take(),
+        };
+        payload_response(&packets)
+    }
+
+    fn poll(&self, id: &str) -> Box<Future<Item = Response, Error = hyper::Error>> {
+        let mut inner = self.inner.borrow_mut();
+        let duplicate = match inner.sessions.get_mut(id) {
+            None => return Box::new(future::ok(error_response(UNKNOWN_SID))),
+            Some(session) => {
+                if let Transport::WebSocket(_) = session.transport {
+                    return Box::new(future::ok(error_response(BAD_REQUEST)));
+                }
+                if !session.outbox.is_held() && !session.outbox.is_empty() {
+                    let packets = session.outbox.take();
+                    return Box::new(future::ok(payload_response(&packets)));
+                }
+                session.outbox.is_held()
+            }
+        };
+        if duplicate {
+            // Overlapping polls are a protocol violation.
+            inner.close(id);
+            return Box::new(future::ok(error_response(BAD_REQUEST)));
+        }
+
+        let (poll_tx, poll_rx) = oneshot::channel();
+        if let Some(session) = inner.sessions.get_mut(id) {
+            session.outbox.hold(poll_tx);
+        }
+        Box::new(poll_rx.then(|result| {
+            let mut packets = result.unwrap_or_else(|_| vec![Packet::Close]);
+            // A poll answered with nothing gets a noop.
+            if packets.is_empty() {
+                packets.push(Packet::Noop);
+            }
+            Ok(payload_response(&packets))
+        }))
+    }
+
+    /// Answers once the socket has taken every message in the payload, so a
+    /// connection handler that falls behind holds up the client's next send.
+    fn post(&self, id: String, body: Body) -> Box<Future<Item = Response, Error = hyper::Error>> {
+        let max_payload = self.inner.borrow().max_payload;
+        let inner_rc = self.inner.clone();
+        Box::new(read_body(body, max_payload).and_then(move |buf| {
+            let messages = {
+                let mut inner = inner_rc.borrow_mut();
+                if !inner.sessions.contains_key(&id) {
+                    return Either::A(future::ok(error_response(UNKNOWN_SID)));
+                }
+                let text = match buf.map(String::from_utf8) {
+                    None => {
+                        inner.close(&id);
+                        let response = error_response(BAD_REQUEST)
+                            .with_status(StatusCode::PayloadTooLarge);
+                        return Either::A(future::ok(response));
+                    }
+                    Some(Err(_)) => {
+                        inner.close(&id);
+                        return Either::A(future::ok(error_response(BAD_REQUEST)));
+                    }
+                    Some(Ok(text)) => text,
+                };
+                let mut packets = Vec::new();
+                for entry in text.split(RECORD_SEPARATOR) {
+                    match Packet::parse_polling(entry) {
+                        None => {
+                            inner.close(&id);
+                            return Either::A(future::ok(error_response(BAD_REQUEST)));
+                        }
+                        Some(packet) => packets.push(packet),
+                    }
+                }
+                packets
+                    .into_iter()
+                    .filter_map(|packet| inner.receive(&id, packet))
+                    .collect::<Vec<_>>()
+            };
+            let delivered = stream::iter_ok(messages)
+                .for_each(move |msg| deliver(&inner_rc, &id, msg))
+                .map(|()| text_response(StatusCode::Ok, "ok".to_owned()));
+            Either::B(delivered)
+        }))
+    }
+}
+
+impl Service for EngineIoServer {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if !self.matches_path(req.path()) {
+            return Box::new(future::ok(Response::new().with_status(StatusCode::NotFound)));
+        }
+        if query_param(req.query(), "EIO") != Some("4") {
+            return Box::new(future::ok(error_response(UNSUPPORTED_PROTOCOL_VERSION)));
+        }
+        if query_param(req.query(), "transport") != Some("polling") {
+            // WebSocket requests that get here weren't upgrades.
+            return Box::new(future::ok(error_response(UNKNOWN_TRANSPORT)));
+        }
+
+        let id = query_param(req.query(), "sid").map(str::to_owned);
+        match (req.method().clone(), id) {
+            (Method::Get, None) => Box::new(future::ok(self.handshake(req.query()))),
+            (_, None) => Box::new(future::ok(error_response(BAD_HANDSHAKE_METHOD))),
+            (Method::Get, Some(id)) => self.poll(&id),
+            (Method::Post, Some(id)) => self.post(id, req.body()),
+            (_, Some(_)) => Box::new(future::ok(error_response(BAD_REQUEST))),
+        }
+    }
+}
+
+impl WsEndpoint for EngineIoServer {
+    type Future = Box<Future<Item = (), Error = WebSocketError>>;
+
+    fn negotiate(&self, req: &Request, handshake: WsHandshake) -> WsResponse {
+        let valid = self.matches_path(req.path())
+            && query_param(req.query(), "EIO") == Some("4")
+            && query_param(req.query(), "transport") == Some("websocket");
+        let upgradable = match query_param(req.query(), "sid") {
+            None => true,
+            Some(id) => match self.inner.borrow().sessions.get(id) {
+                Some(&Session {
+                    transport: Transport::Polling,
+                    upgrade: None,
+                    ..
+                }) => true,
+                _ => false,
+            },
+        };
+        if valid && upgradable {
+            WsResponse::accept(handshake)
+        } else {
+            WsResponse::reject(handshake)
+        }
+    }
+
+    fn handle(&self, ctx: WsContext, websocket: Client<TcpStream>) -> Self::Future {
+        let handle = self.inner.borrow().handle.clone();
+        let (mut sender, receiver) = spawn_channel(&handle, websocket, QUEUE_CAPACITY);
+        let inner = self.inner.clone();
+
+        let id = match query_param(ctx.query(), "sid") {
+            Some(id) => id.to_owned(),
+            None => {
+                let id = open_session(&self.inner, Transport::WebSocket(sender), ctx.query());
+                let close_inner = inner.clone();
+                let close_id = id.clone();
+                let serve = receiver
+                    .for_each(move |msg| receive_ws(&inner, &id, msg))
+                    .then(move |result| {
+                        close_inner.borrow_mut().close(&close_id);
+                        result
+                    });
+                return Box::new(serve);
+            }
+        };
+
+        let accepted = match self.inner.borrow_mut().sessions.get_mut(&id) {
+            Some(ref mut session) if session.upgrade.is_none() => {
+                session.upgrade = Some(sender.clone());
+                true
+            }
+            _ => false,
+        };
+        if !accepted {
+            let _ = sender.close(CLOSE_NORMAL, "");
+            return Box::new(receiver.for_each(|_| Ok(())));
+        }
+
+        // Until the upgrade packet, only the probe is answered here; the
+        // session keeps using long-polling.
+        let upgraded = Rc::new(Cell::new(false));
+        let close_inner = inner.clone();
+        let close_id = id.clone();
+        let close_upgraded = upgraded.clone();
+        let serve = receiver
+            .for_each(move |msg| {
+                if upgraded.get() {
+                    return Either::A(receive_ws(&inner, &id, msg));
+                }
+                let mut inner = inner.borrow_mut();
+                match Packet::from_ws(msg) {
+                    Ok(Some(Packet::Ping(ref data))) if data == "probe" => {
+                        let pong = Packet::Pong("probe".to_owned());
+                        let _ = sender.try_send(pong.into_ws());
+                        // Let the client's poll return so it can pause.
+                        if let Some(session) = inner.sessions.get_mut(&id) {
+                            session.outbox.flush(true);
+                        }
+                    }
+                    Ok(Some(Packet::Upgrade)) => upgraded.set(inner.upgrade(&id)),
+                    _ => {}
+                }
+                Either::B(future::ok(()))
+            })
+            .then(move |result| {
+                let mut inner = close_inner.borrow_mut();
+                if close_upgraded.get() {
+                    inner.close(&close_id);
+                } else if let Some(session) = inner.sessions.get_mut(&close_id) {
+                    session.upgrade = None;
+                }
+                result
+            });
+        Box::new(serve)
+    }
+}
+
+fn receive_ws(
+    inner_rc: &Rc<RefCell<EngineInner>>,
+    id: &str,
+    msg: OwnedMessage,
+) -> Box<Future<Item = (), Error = WebSocketError>> {
+    let msg = {
+        let mut inner = inner_rc.borrow_mut();
+        match Packet::from_ws(msg) {
+            Ok(None) => None,
+            Ok(Some(packet)) => inner.receive(id, packet),
+            Err(()) => {
+                inner.close(id);
+                None
+            }
+        }
+    };
+    match msg {
+        None => Box::new(future::ok(())),
+        Some(msg) => deliver(inner_rc, id, msg),
+    }
+}
+
+/// Hands a message to a session's socket once its queue has room, so that a
+/// connection handler that falls behind stops the reading rather than have
+/// messages pile up. Messages for a closed session are dropped.
+fn deliver<E>(
+    inner: &Rc<RefCell<EngineInner>>,
+    id: &str,
+    msg: OwnedMessage,
+) -> Box<Future<Item = (), Error = E>>
+where
+    E: 'static,
+{
+    let inner = inner.clone();
+    let id = id.to_owned();
+    let mut msg = Some(msg);
+    Box::new(future::poll_fn(move || {
+        let mut inner = inner.borrow_mut();
+        let session = match inner.sessions.get_mut(&id) {
+            None => return Ok(Async::Ready(())),
+            Some(session) => session,
+        };
+        match session.incoming.poll_ready() {
+            // The socket was dropped.
+            Err(_) => return Ok(Async::Ready(())),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {}
+        }
+        if let Some(msg) = msg.take() {
+            let _ = session.incoming.try_send(msg);
+        }
+        Ok(Async::Ready(()))
+    }))
+}
+
+/// Starts a session, sending its open packet and handing its socket to the
+/// connection handler.
+fn open_session(
+    inner_rc: &Rc<RefCell<EngineInner>>,
+    transport: Transport,
+    query: Option<&str>,
+) -> String {
+    let id = random_id();
+    let (incoming_tx, incoming_rx) = mpsc::channel(QUEUE_CAPACITY);
+
+    let (on_connection, handle) = {
+        let mut inner = inner_rc.borrow_mut();
+        let upgrades = match transport {
+            Transport::Polling => json!(["websocket"]),
+            Transport::WebSocket(_) => json!([]),
+        };
+        let open = json!({
+            "sid": id,
+            "upgrades": upgrades,
+            "pingInterval": millis(inner.ping_interval),
+            "pingTimeout": millis(inner.ping_timeout),
+            "maxPayload": inner.max_payload,
+        });
+        let mut session = Session {
+            transport: transport,
+            outbox: Outbox::new(),
+            upgrade: None,
+            incoming: incoming_tx,
+            pings: 0,
+            awaiting_pong: false,
+            _heart_beat: spawn_heart_beat(inner_rc, &inner, &id),
+        };
+        session.send(Packet::Open(open.to_string()));
+        inner.sessions.insert(id.clone(), session);
+        (inner.on_connection.clone(), inner.handle.clone())
+    };
+
+    let socket = EngineIoSocket {
+        sender: EngineIoSender {
+            id: id.clone(),
+            inner: Rc::downgrade(inner_rc),
+        },
+        query: query.map(str::to_owned),
+        incoming: incoming_rx,
+    };
+    handle.spawn(on_connection(socket));
+    id
+}
+
+/// Pings the client every ping interval, closing the session if a ping goes
+/// unanswered for the ping timeout.
+fn spawn_heart_beat(
+    inner_rc: &Rc<RefCell<EngineInner>>,
+    inner: &EngineInner,
+    id: &str,
+) -> Option<oneshot::Sender<()>> {
+    let interval = match Interval::new(inner.ping_interval, &inner.handle) {
+        Err(err) => {
+            error!("hyper-websocket: Engine.IO heart-beat timer error: {}", err);
+            return None;
+        }
+        Ok(interval) => interval,
+    };
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    let weak = Rc::downgrade(inner_rc);
+    let handle = inner.handle.clone();
+    let ping_timeout = inner.ping_timeout;
+    let id = id.to_owned();
+    let beat = interval.map_err(|_| ()).for_each(move |()| {
+        let inner = match weak.upgrade() {
+            None => return Err(()),
+            Some(inner) => inner,
+        };
+        let serial = match inner.borrow_mut().sessions.get_mut(&id) {
+            None => return Err(()),
+            // The unanswered ping's timeout will close the session.
+            Some(ref session) if session.awaiting_pong => return Ok(()),
+            Some(session) => {
+                session.pings += 1;
+                session.awaiting_pong = true;
+                // If the queue is full the ping is lost, and its timeout
+                // closes the session.
+                session.send(Packet::Ping(String::new()));
+                session.pings
+            }
+        };
+        let timeout = match Timeout::new(ping_timeout, &handle) {
+            Err(err) => {
+                error!("hyper-websocket: Engine.IO ping timer error: {}", err);
+                return Ok(());
+            }
+            Ok(timeout) => timeout,
+        };
+        let weak = Rc::downgrade(&inner);
+        let id = id.clone();
+        handle.spawn(timeout.then(move |_| {
+            if let Some(inner) = weak.upgrade() {
+                let mut inner = inner.borrow_mut();
+                let timed_out = inner.sessions.get(&id).map_or(false, |session| {
+                    session.awaiting_pong && session.pings == serial
+                });
+                if timed_out {
+                    debug!("hyper-websocket: Engine.IO ping timeout for {}", id);
+                    inner.close(&id);
+                }
+            }
+            Ok(())
+        }));
+        Ok(())
+    });
+    inner.handle.spawn(beat.select2(cancel_rx).then(|_| Ok(())));
+    Some(cancel_tx)
+}
+
+/// One client's Engine.IO session, as a stream of the messages it sends. The
+/// stream ends once the session closes. Only a few messages wait to be read,
+/// and while they do the session stops reading from the client.
+pub struct EngineIoSocket {
+    sender: EngineIoSender,
+    query: Option<String>,
+    incoming: mpsc::Receiver<OwnedMessage>,
+}
+
+impl fmt::Debug for EngineIoSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EngineIoSocket")
+            .field("sender", &self.sender)
+            .field("query", &self.query)
+            .field("incoming", &Omitted)
+            .finish()
+    }
+}
+
+impl EngineIoSocket {
+    /// The session ID.
+    pub fn id(&self) -> &str {
+        self.sender.id()
+    }
+
+    /// The query string of the request that opened the session.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_ref().map(String::as_str)
+    }
+
+    pub fn sender(&self) -> EngineIoSender {
+        self.sender.clone()
+    }
+
+    pub fn send(&self, msg: OwnedMessage) -> bool {
+        self.sender.send(msg)
+    }
+
+    pub fn close(&self) {
+        self.sender.close()
+    }
+}
+
+impl Stream for EngineIoSocket {
+    type Item = OwnedMessage;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.incoming.poll()
+    }
+}
+
+/// A cloneable handle for sending to an Engine.IO session.
+#[derive(Clone)]
+pub struct EngineIoSender {
+    id: String,
+    inner: Weak<RefCell<EngineInner>>,
+}
+
+impl fmt::Debug for EngineIoSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EngineIoSender")
+            .field("id", &self.id)
+            .field("inner", &Omitted)
+            .finish()
+    }
+}
+
+impl EngineIoSender {
+    /// The session ID.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Queues a text or binary message, returning whether the session is
+    /// still open. Other kinds of message are ignored.
+    pub fn send(&self, msg: OwnedMessage) -> bool {
+        let packet = match msg {
+            OwnedMessage::Text(text) => Packet::Text(text),
+            OwnedMessage::Binary(data) => Packet::Binary(data),
+            _ => return self.is_open(),
+        };
+        match self.inner.upgrade() {
+            None => false,
+            Some(inner) => inner.borrow_mut().send(&self.id, packet),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        match self.inner.upgrade() {
+            None => false,
+            Some(inner) => inner.borrow().sessions.contains_key(&self.id),
+        }
+    }
+
+    pub fn close(&self) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner.borrow_mut().close(&self.id);
+        }
+    }
+}
+
+/// Generates a URL-safe session ID.
+pub(crate) fn random_id() -> String {
+    let bytes: [u8; 15] = rand::random();
+    base64::encode_config(&bytes, base64::URL_SAFE)
+}
+
+pub(crate) fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query.and_then(|query| {
+        query
+            .split('&')
+            .map(|pair| {
+                let mut parts = pair.splitn(2, '=');
+                (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+            })
+            .find(|&(key, _)| key == name)
+            .map(|(_, value)| value)
+    })
+}
+
+fn text_response(status: StatusCode, body: String) -> Response {
+    let mut res = Response::new()
+        .with_status(status)
+        .with_header(ContentLength(body.len() as u64));
+    res.headers_mut().set_raw("Content-Type", "text/plain; charset=UTF-8");
+    res.with_body(body)
+}
+
+fn payload_response(packets: &[Packet]) -> Response {
+    let payload = packets
+        .iter()
+        .map(Packet::encode_polling)
+        .collect::<Vec<_>>()
+        .join(&RECORD_SEPARATOR.to_string());
+    text_response(StatusCode::Ok, payload)
+}
+
+fn error_response(code: u8) -> Response {
+    let message = match code {
+        UNKNOWN_TRANSPORT => "Transport unknown",
+        UNKNOWN_SID => "Session ID unknown",
+        BAD_HANDSHAKE_METHOD => "Bad handshake method",
+        UNSUPPORTED_PROTOCOL_VERSION => "Unsupported protocol version",
+        _ => "Bad request",
+    };
+    let body = json!({"code": code, "message": message}).to_string();
+    let mut res = Response::new()
+        .with_status(StatusCode::BadRequest)
+        .with_header(ContentLength(body.len() as u64));
+    res.headers_mut().set_raw("Content-Type", "application/json");
+    res.with_body(body)
+}
+
+pub(crate) fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)
+}
@@ -0,0 +1,395 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{future, Future, Poll, Sink, Stream};
+use futures::future::Either;
+use futures::sync::oneshot;
+use hyper::StatusCode;
+use serde_json::{self, Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use super::{Omitted, WsHandshake, WsResponse};
+use super::channel::{spawn_channel, WsSender, QUEUE_CAPACITY};
+
+/// The subprotocol name of the `graphql-transport-ws` protocol.
+pub const GRAPHQL_TRANSPORT_WS_PROTOCOL: &str = "graphql-transport-ws";
+
+const DEFAULT_INIT_TIMEOUT_MS: u64 = 3000;
+
+// Close codes defined by the protocol.
+const CLOSE_BAD_REQUEST: u16 = 4400;
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+const CLOSE_FORBIDDEN: u16 = 4403;
+const CLOSE_INIT_TIMEOUT: u16 = 4408;
+const CLOSE_SUBSCRIBER_EXISTS: u16 = 4409;
+const CLOSE_TOO_MANY_INITS: u16 = 4429;
+
+const INVALID_MESSAGE: &str = "Invalid message received";
+
+/// Selects `GRAPHQL_TRANSPORT_WS_PROTOCOL` for `WsServer::negotiate`. Clients
+/// that only speak the older `graphql-ws` protocol, or nothing at all, get
+/// `400 Bad Request`.
+pub fn negotiate_graphql(handshake: WsHandshake) -> WsResponse {
+    let offered = handshake
+        .protocols()
+        .iter()
+        .any(|protocol| protocol == GRAPHQL_TRANSPORT_WS_PROTOCOL);
+    if offered {
+        WsResponse::accept(handshake.use_protocol(GRAPHQL_TRANSPORT_WS_PROTOCOL))
+    } else {
+        WsResponse::reject_with_status(handshake, StatusCode::BadRequest)
+    }
+}
+
+/// The execution results of one operation, each sent as a `next` message. An
+/// error ends the operation with an `error` message carrying the GraphQL
+/// errors, and otherwise it ends with `complete`.
+pub type GraphQlStream = Box<Stream<Item = Value, Error = Vec<Value>>>;
+
+/// The operation a client subscribed to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GraphQlRequest {
+    query: String,
+    operation_name: Option<String>,
+    variables: Option<Map<String, Value>>,
+    extensions: Option<Map<String, Value>>,
+}
+
+impl GraphQlRequest {
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    pub fn operation_name(&self) -> Option<&str> {
+        self.operation_name.as_ref().map(String::as_str)
+    }
+
+    pub fn variables(&self) -> Option<&Map<String, Value>> {
+        self.variables.as_ref()
+    }
+
+    pub fn extensions(&self) -> Option<&Map<String, Value>> {
+        self.extensions.as_ref()
+    }
+
+    fn from_json(payload: Value) -> Option<Self> {
+        let mut payload = match payload {
+            Value::Object(payload) => payload,
+            _ => return None,
+        };
+        let query = match payload.remove("query") {
+            Some(Value::String(query)) => query,
+            _ => return None,
+        };
+        let operation_name = match payload.remove("operationName") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(name)) => Some(name),
+            Some(_) => return None,
+        };
+        let variables = match payload.remove("variables") {
+            None | Some(Value::Null) => None,
+            Some(Value::Object(variables)) => Some(variables),
+            Some(_) => return None,
+        };
+        let extensions = match payload.remove("extensions") {
+            None | Some(Value::Null) => None,
+            Some(Value::Object(extensions)) => Some(extensions),
+            Some(_) => return None,
+        };
+        Some(GraphQlRequest {
+            query: query,
+            operation_name: operation_name,
+            variables: variables,
+            extensions: extensions,
+        })
+    }
+}
+
+/// Runs the operations a `GraphQlServer` receives.
+pub trait GraphQlExecutor {
+    /// Decides whether to acknowledge a `connection_init` with the given
+    /// payload, returning the payload for the `connection_ack` if so.
+    /// Refusing closes the connection as forbidden.
+    fn on_connect(&self, _payload: Option<&Value>) -> Result<Option<Value>, ()> {
+        Ok(None)
+    }
+
+    /// Starts an operation, or fails it straight away with GraphQL errors,
+    /// eg. when the query doesn't validate.
+    fn execute(&self, request: GraphQlRequest) -> Result<GraphQlStream, Vec<Value>>;
+}
+
+/// Serves GraphQL operations over connections speaking the
+/// `graphql-transport-ws` protocol, negotiated with `negotiate_graphql`.
+pub struct GraphQlServer<E> {
+    executor: Rc<E>,
+    init_timeout: Duration,
+}
+
+impl<E> Clone for GraphQlServer<E> {
+    fn clone(&self) -> Self {
+        GraphQlServer {
+            executor: self.executor.clone(),
+            init_timeout: self.init_timeout,
+        }
+    }
+}
+
+impl<E> fmt::Debug for GraphQlServer<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GraphQlServer")
+            .field("executor", &Omitted)
+            .field("init_timeout", &self.init_timeout)
+            .finish()
+    }
+}
+
+impl<E> GraphQlServer<E>
+where
+    E: GraphQlExecutor + 'static,
+{
+    pub fn new(executor: E) -> Self {
+        GraphQlServer {
+            executor: Rc::new(executor),
+            init_timeout: Duration::from_millis(DEFAULT_INIT_TIMEOUT_MS),
+        }
+    }
+
+    /// How long a client has to send `connection_init` before the connection
+    /// is closed. Defaults to 3 seconds.
+    pub fn init_timeout(mut self, init_timeout: Duration) -> Self {
+        self.init_timeout = init_timeout;
+        self
+    }
+
+    /// Speaks the protocol over an accepted connection, spawning the tasks
+    /// that do the IO onto `handle`.
+    pub fn serve<T>(&self, handle: &Handle, client: Client<T>) -> GraphQlConnection
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let (sender, receiver) = spawn_channel(handle, client, QUEUE_CAPACITY);
+        let conn = Rc::new(RefCell::new(ConnState {
+            executor: self.executor.clone(),
+            handle: handle.clone(),
+            sender: sender,
+            init_received: false,
+            acknowledged: false,
+            next_serial: 0,
+            active: HashMap::new(),
+        }));
+
+        let weak = Rc::downgrade(&conn);
+        match Timeout::new(self.init_timeout, handle) {
+            Err(err) => error!("hyper-websocket: GraphQL init timer error: {}", err),
+            Ok(timeout) => handle.spawn(timeout.then(move |_| {
+                if let Some(conn) = weak.upgrade() {
+                    let conn = conn.borrow();
+                    if !conn.init_received {
+                        conn.close(CLOSE_INIT_TIMEOUT, "Connection initialisation timeout");
+                    }
+                }
+                Ok(())
+            })),
+        }
+
+        let dispatch_conn = conn.clone();
+        let serve = receiver
+            .for_each(move |msg| {
+                match msg {
+                    OwnedMessage::Text(text) => dispatch(&dispatch_conn, &text),
+                    OwnedMessage::Binary(_) => {
+                        dispatch_conn.borrow().close(CLOSE_BAD_REQUEST, INVALID_MESSAGE)
+                    }
+                    _ => {}
+                }
+                Ok(())
+            })
+            .then(move |result| {
+                // Cancel whatever operations the client left running.
+                conn.borrow_mut().active.clear();
+                result
+            });
+        GraphQlConnection(Box::new(serve))
+    }
+}
+
+/// One client's `graphql-transport-ws` session, as returned by
+/// `GraphQlServer::serve`. Resolves when the socket closes.
+pub struct GraphQlConnection(Box<Future<Item = (), Error = WebSocketError>>);
+
+impl fmt::Debug for GraphQlConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("GraphQlConnection").field(&Omitted).finish()
+    }
+}
+
+impl Future for GraphQlConnection {
+    type Item = ();
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+struct ConnState<E> {
+    executor: Rc<E>,
+    handle: Handle,
+    sender: WsSender,
+    init_received: bool,
+    acknowledged: bool,
+    next_serial: u64,
+    /// The running operations by client-chosen ID, each with a serial number
+    /// telling it apart from later operations reusing the ID.
+    active: HashMap<String, (u64, oneshot::Sender<()>)>,
+}
+
+impl<E> ConnState<E> {
+    /// Queues a reply, closing the connection if the client has let the
+    /// queue fill up.
+    fn send(&mut self, msg: Value) {
+        let msg = OwnedMessage::Text(msg.to_string());
+        if let Err(err) = self.sender.try_send(msg) {
+            if err.is_full() {
+                self.close(CLOSE_BAD_REQUEST, "Too slow");
+            }
+        }
+    }
+
+    fn close(&self, code: u16, reason: &str) {
+        // Closing twice is harmless.
+        let _ = self.sender.close(code, reason);
+    }
+}
+
+fn dispatch<E>(conn: &Rc<RefCell<ConnState<E>>>, text: &str)
+where
+    E: GraphQlExecutor + 'static,
+{
+    let mut object = match serde_json::from_str::<Value>(text) {
+        Ok(Value::Object(object)) => object,
+        _ => return conn.borrow().close(CLOSE_BAD_REQUEST, INVALID_MESSAGE),
+    };
+    let kind = match object.remove("type") {
+        Some(Value::String(kind)) => kind,
+        _ => return conn.borrow().close(CLOSE_BAD_REQUEST, INVALID_MESSAGE),
+    };
+    let payload = object.remove("payload");
+    let id = match object.remove("id") {
+        Some(Value::String(id)) => Some(id),
+        _ => None,
+    };
+
+    match (kind.as_str(), id) {
+        ("connection_init", _) => init(conn, payload.as_ref()),
+        ("ping", _) => conn.borrow_mut().send(json!({"type": "pong"})),
+        ("pong", _) => {}
+        ("subscribe", Some(id)) => match payload.and_then(GraphQlRequest::from_json) {
+            None => conn.borrow().close(CLOSE_BAD_REQUEST, "Invalid subscribe payload"),
+            Some(request) => subscribe(conn, id, request),
+        },
+        ("complete", Some(id)) => {
+            // Dropping the cancel handle stops the operation, if it's still
+            // running.
+            conn.borrow_mut().active.remove(&id);
+        }
+        _ => conn.borrow().close(CLOSE_BAD_REQUEST, INVALID_MESSAGE),
+    }
+}
+
+fn init<E>(conn: &Rc<RefCell<ConnState<E>>>, payload: Option<&Value>)
+where
+    E: GraphQlExecutor,
+{
+    let mut conn = conn.borrow_mut();
+    if conn.init_received {
+        return conn.close(CLOSE_TOO_MANY_INITS, "Too many initialisation requests");
+    }
+    conn.init_received = true;
+    match conn.executor.on_connect(payload) {
+        Err(()) => conn.close(CLOSE_FORBIDDEN, "Forbidden"),
+        Ok(payload) => {
+            conn.acknowledged = true;
+            let ack = match payload {
+                None => json!({"type": "connection_ack"}),
+                Some(payload) => json!({"type": "connection_ack", "payload": payload}),
+            };
+            conn.send(ack);
+        }
+    }
+}
+
+fn subscribe<E>(conn_rc: &Rc<RefCell<ConnState<E>>>, id: String, request: GraphQlRequest)
+where
+    E: GraphQlExecutor + 'static,
+{
+    let mut conn = conn_rc.borrow_mut();
+    if !conn.acknowledged {
+        return conn.close(CLOSE_UNAUTHORIZED, "Unauthorized");
+    }
+    if conn.active.contains_key(&id) {
+        let reason = format!("Subscriber for {} already exists", id);
+        return conn.close(CLOSE_SUBSCRIBER_EXISTS, &reason);
+    }
+    let stream = match conn.executor.execute(request) {
+        Err(errors) => return conn.send(json!({"id": id, "type": "error", "payload": errors})),
+        Ok(stream) => stream,
+    };
+
+    let serial = conn.next_serial;
+    conn.next_serial += 1;
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    conn.active.insert(id.clone(), (serial, cancel_tx));
+
+    let next_id = id.clone();
+    let end_id = id.clone();
+    let end_sender = conn.sender.clone();
+    // A `None` error means the connection closed.
+    let feed = stream
+        .map(move |result| {
+            let next = json!({"id": next_id, "type": "next", "payload": result});
+            OwnedMessage::Text(next.to_string())
+        })
+        .map_err(Some)
+        .forward(conn.sender.clone().sink_map_err(|_| None::<Vec<Value>>))
+        .then(move |result| {
+            let end = match result {
+                Ok(_) => json!({"id": end_id, "type": "complete"}),
+                Err(Some(errors)) => json!({"id": end_id, "type": "error", "payload": errors}),
+                Err(None) => return Either::A(future::ok::<(), ()>(())),
+            };
+            let end = OwnedMessage::Text(end.to_string());
+            Either::B(end_sender.send(end).then(|_| Ok(())))
+        });
+
+    // The operation stops early if the client completes it or the
+    // connection closes.
+    let weak = Rc::downgrade(conn_rc);
+    conn.handle.spawn(feed.select2(cancel_rx).then(move |_| {
+        finish(&weak, &id, serial);
+        Ok(())
+    }));
+}
+
+fn finish<E>(conn: &Weak<RefCell<ConnState<E>>>, id: &str, serial: u64) {
+    let conn = match conn.upgrade() {
+        None => return,
+        Some(conn) => conn,
+    };
+    let mut conn = conn.borrow_mut();
+    let current = conn.active.get(id).map(|&(current, _)| current);
+    if current == Some(serial) {
+        conn.active.remove(id);
+    }
+}
@@ -0,0 +1,301 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::client::async::Client;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use super::{AcceptWsHandshake, Omitted};
+
+/// The close code reported to `WsHandler::on_close` when the connection went
+/// away without a close handshake, per RFC 6455 section 7.4.1.
+pub const CLOSE_ABNORMAL: u16 = 1006;
+
+/// Callbacks for a single WebSocket connection, driven by `DriveWsHandler`.
+///
+/// Pings are answered automatically and are not passed to `on_message`.
+/// Once `on_open` has run, `on_close` is called exactly once, however the
+/// connection ends.
+pub trait WsHandler {
+    type State;
+
+    fn on_open(&self, _ctx: &mut WsHandlerContext<Self::State>) {}
+
+    fn on_message(&self, ctx: &mut WsHandlerContext<Self::State>, msg: OwnedMessage);
+
+    fn on_error(&self, _ctx: &mut WsHandlerContext<Self::State>, _err: &WebSocketError) {}
+
+    fn on_close(
+        &self,
+        _ctx: &mut WsHandlerContext<Self::State>,
+        _code: Option<u16>,
+        _reason: &str,
+    ) {
+    }
+}
+
+impl<H> WsHandler for Rc<H>
+where
+    H: WsHandler + ?Sized,
+{
+    type State = H::State;
+
+    fn on_open(&self, ctx: &mut WsHandlerContext<Self::State>) {
+        (**self).on_open(ctx)
+    }
+
+    fn on_message(&self, ctx: &mut WsHandlerContext<Self::State>, msg: OwnedMessage) {
+        (**self).on_message(ctx, msg)
+    }
+
+    fn on_error(&self, ctx: &mut WsHandlerContext<Self::State>, err: &WebSocketError) {
+        (**self).on_error(ctx, err)
+    }
+
+    fn on_close(&self, ctx: &mut WsHandlerContext<Self::State>, code: Option<u16>, reason: &str) {
+        (**self).on_close(ctx, code, reason)
+    }
+}
+
+/// The handle a `WsHandler` uses to talk back to its connection. Messages
+/// are queued and written once the callback returns.
+#[derive(Debug)]
+pub struct WsHandlerContext<S> {
+    outgoing: VecDeque<OwnedMessage>,
+    close_sent: bool,
+    state: S,
+}
+
+impl<S> WsHandlerContext<S> {
+    fn new(state: S) -> Self {
+        WsHandlerContext {
+            outgoing: VecDeque::new(),
+            close_sent: false,
+            state: state,
+        }
+    }
+
+    /// Queues a message. Messages queued after `close` are discarded.
+    pub fn send(&mut self, msg: OwnedMessage) {
+        if !self.close_sent {
+            self.outgoing.push_back(msg);
+        }
+    }
+
+    /// Starts the close handshake. `on_close` is called once the peer
+    /// answers with its own close frame or the connection drops.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if !self.close_sent {
+            let data = CloseData::new(code, reason.to_owned());
+            self.outgoing.push_back(OwnedMessage::Close(Some(data)));
+            self.close_sent = true;
+        }
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.close_sent
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut S {
+        &mut self.state
+    }
+
+    pub fn into_state(self) -> S {
+        self.state
+    }
+}
+
+/// Drives a `WsHandler` over an accepted connection, resolving once the
+/// connection has closed.
+pub struct DriveWsHandler<T, H>
+where
+    H: WsHandler,
+{
+    accept: Option<AcceptWsHandshake<T>>,
+    client: Option<Client<T>>,
+    handler: H,
+    ctx: WsHandlerContext<H::State>,
+    closed: bool,
+}
+
+impl<T, H> fmt::Debug for DriveWsHandler<T, H>
+where
+    H: WsHandler,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DriveWsHandler")
+            .field("accept", &self.accept.as_ref().map(|_| Omitted))
+            .field("client", &self.client.as_ref().map(|_| Omitted))
+            .field("handler", &Omitted)
+            .field("outgoing", &self.ctx.outgoing.len())
+            .field("close_sent", &self.ctx.close_sent)
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+impl<T, H> DriveWsHandler<T, H>
+where
+    T: AsyncRead + AsyncWrite,
+    H: WsHandler,
+{
+    pub fn new(client: Client<T>, handler: H, state: H::State) -> Self {
+        let mut drive = DriveWsHandler {
+            accept: None,
+            client: Some(client),
+            handler: handler,
+            ctx: WsHandlerContext::new(state),
+            closed: false,
+        };
+        drive.handler.on_open(&mut drive.ctx);
+        drive
+    }
+
+    fn from_accept(accept: AcceptWsHandshake<T>, handler: H, state: H::State) -> Self {
+        DriveWsHandler {
+            accept: Some(accept),
+            client: None,
+            handler: handler,
+            ctx: WsHandlerContext::new(state),
+            closed: false,
+        }
+    }
+
+    pub fn state(&self) -> &H::State {
+        &self.ctx.state
+    }
+
+    fn close(&mut self, code: Option<u16>, reason: &str) {
+        self.closed = true;
+        self.handler.on_close(&mut self.ctx, code, reason);
+    }
+
+    fn fail(&mut self, err: WebSocketError) -> WebSocketError {
+        self.handler.on_error(&mut self.ctx, &err);
+        if !self.closed {
+            self.close(Some(CLOSE_ABNORMAL), "");
+        }
+        err
+    }
+
+    /// Returns whether everything queued has been written out.
+    fn poll_flush(&mut self) -> Poll<(), WebSocketError> {
+        let client = self.client
+            .as_mut()
+            .expect("hyper-websocket: DriveWsHandler polled after completion");
+        while let Some(msg) = self.ctx.outgoing.pop_front() {
+            if let AsyncSink::NotReady(msg) = client.start_send(msg)? {
+                self.ctx.outgoing.push_front(msg);
+                return Ok(Async::NotReady);
+            }
+        }
+        client.poll_complete()
+    }
+
+    fn poll_message(&mut self) -> Poll<Option<OwnedMessage>, WebSocketError> {
+        self.client
+            .as_mut()
+            .expect("hyper-websocket: DriveWsHandler polled after completion")
+            .poll()
+    }
+}
+
+impl<T, H> Future for DriveWsHandler<T, H>
+where
+    T: AsyncRead + AsyncWrite,
+    H: WsHandler,
+{
+    type Item = ();
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(mut accept) = self.accept.take() {
+            match accept.poll() {
+                Ok(Async::NotReady) => {
+                    self.accept = Some(accept);
+                    return Ok(Async::NotReady);
+                }
+                Ok(Async::Ready(client)) => {
+                    self.client = Some(client);
+                    self.handler.on_open(&mut self.ctx);
+                }
+                Err(err) => {
+                    // The connection never opened, so there is nothing to
+                    // report a close for.
+                    self.closed = true;
+                    return Err(self.fail(err));
+                }
+            }
+        }
+
+        loop {
+            let flushed = match self.poll_flush() {
+                Ok(flushed) => flushed.is_ready(),
+                Err(err) => return Err(self.fail(err)),
+            };
+            if self.closed {
+                if flushed {
+                    self.client = None;
+                    return Ok(Async::Ready(()));
+                }
+                return Ok(Async::NotReady);
+            }
+
+            let msg = match self.poll_message() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Some(msg))) => msg,
+                Ok(Async::Ready(None)) => {
+                    self.close(Some(CLOSE_ABNORMAL), "");
+                    self.client = None;
+                    return Ok(Async::Ready(()));
+                }
+                Err(err) => return Err(self.fail(err)),
+            };
+
+            match msg {
+                OwnedMessage::Ping(data) => self.ctx.outgoing.push_back(OwnedMessage::Pong(data)),
+                OwnedMessage::Close(data) => {
+                    if !self.ctx.close_sent {
+                        // Echo the peer's close frame to complete the
+                        // handshake.
+                        self.ctx.outgoing.push_back(OwnedMessage::Close(data.clone()));
+                        self.ctx.close_sent = true;
+                    }
+                    match data {
+                        None => self.close(None, ""),
+                        Some(data) => self.close(Some(data.status_code), &data.reason),
+                    }
+                }
+                msg => {
+                    if !self.ctx.close_sent {
+                        self.handler.on_message(&mut self.ctx, msg);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T> AcceptWsHandshake<T>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    /// Completes the handshake and then hands the connection to `handler`,
+    /// starting it off with `state`.
+    pub fn handle_with<H>(self, handler: H, state: H::State) -> DriveWsHandler<T, H>
+    where
+        H: WsHandler,
+    {
+        DriveWsHandler::from_accept(self, handler, state)
+    }
+}
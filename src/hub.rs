@@ -0,0 +1,652 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{Async, AsyncSink, Future, Poll, Stream};
+use futures::task::{self, Task};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::iter;
+use std::rc::Rc;
+use tokio_core::reactor::Handle;
+use websocket::message::OwnedMessage;
+
+use super::Omitted;
+use super::backplane::Backplane;
+use super::channel::{SharedFrame, WsSender};
+use super::registry::ConnectionId;
+
+/// The close code sent to subscribers dropped under
+/// `SlowConsumerPolicy::Disconnect`: "policy violation", per RFC 6455 section
+/// 7.4.1.
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+/// What a broadcast does when a subscriber's send queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Skip the message for that subscriber.
+    Drop,
+    /// Close the subscriber's connection and remove it from the hub.
+    Disconnect,
+    /// Hold the broadcast until the subscriber has room for it. Messages
+    /// brought in by a backplane are dropped instead.
+    Backpressure,
+}
+
+/// A change in who is in a room, sent to its members when presence tracking
+/// is enabled. Members are named by their identity, or by their connection ID
+/// if they have none.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresenceEvent {
+    Join { room: String, member: String },
+    Leave { room: String, member: String },
+    /// Sent only to a member that just joined, listing everyone in the room
+    /// including itself.
+    Snapshot { room: String, members: Vec<String> },
+}
+
+impl PresenceEvent {
+    /// Encodes the event as a JSON text message, such as
+    /// `{"type":"join","room":"lobby","member":"alice"}`.
+    pub fn to_json(&self) -> OwnedMessage {
+        let json = match *self {
+            PresenceEvent::Join {
+                ref room,
+                ref member,
+            } => format!(
+                r#"{{"type":"join","room":{},"member":{}}}"#,
+                json_string(room),
+                json_string(member)
+            ),
+            PresenceEvent::Leave {
+                ref room,
+                ref member,
+            } => format!(
+                r#"{{"type":"leave","room":{},"member":{}}}"#,
+                json_string(room),
+                json_string(member)
+            ),
+            PresenceEvent::Snapshot {
+                ref room,
+                ref members,
+            } => format!(
+                r#"{{"type":"snapshot","room":{},"members":[{}]}}"#,
+                json_string(room),
+                members.iter().map(|member| json_string(member)).collect::<Vec<_>>().join(",")
+            ),
+        };
+        OwnedMessage::Text(json)
+    }
+}
+
+type PresenceEncoder = Fn(&PresenceEvent) -> OwnedMessage;
+
+struct Member {
+    sender: WsSender,
+    policy: SlowConsumerPolicy,
+    identity: Option<String>,
+    rooms: BTreeSet<String>,
+    /// Tasks waiting for room in `sender`'s queue. The channel itself only
+    /// wakes the last task to try `sender`, so whichever one gets through
+    /// wakes the rest.
+    waiters: Vec<Task>,
+}
+
+impl Member {
+    fn name(&self, id: ConnectionId) -> String {
+        self.identity.clone().unwrap_or_else(|| id.to_string())
+    }
+
+    fn wait(&mut self) {
+        if !self.waiters.iter().any(Task::will_notify_current) {
+            self.waiters.push(task::current());
+        }
+    }
+
+    fn wake(&mut self) {
+        for waiter in self.waiters.drain(..) {
+            waiter.notify();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Room {
+    members: BTreeSet<ConnectionId>,
+    history: VecDeque<SharedFrame>,
+}
+
+#[derive(Default)]
+struct HubInner {
+    members: HashMap<ConnectionId, Member>,
+    rooms: HashMap<String, Room>,
+    history_size: usize,
+    presence: Option<Rc<PresenceEncoder>>,
+    backplane: Option<Rc<Backplane>>,
+}
+
+impl HubInner {
+    fn remove(&mut self, id: ConnectionId) -> Option<Member> {
+        let mut member = self.members.remove(&id);
+        if let Some(ref mut member) = member {
+            member.wake();
+            for room in &member.rooms {
+                self.leave_room(id, member, room);
+            }
+        }
+        member
+    }
+
+    fn leave_room(&mut self, id: ConnectionId, member: &Member, room: &str) {
+        let remaining = match self.rooms.get_mut(room) {
+            None => return,
+            Some(room) => {
+                room.members.remove(&id);
+                room.members.iter().cloned().collect::<Vec<_>>()
+            }
+        };
+        if remaining.is_empty() {
+            // Keep the room around while it still has history to replay.
+            let history_empty = self.rooms.get(room).map_or(true, |room| room.history.is_empty());
+            if history_empty {
+                self.rooms.remove(room);
+            }
+            return;
+        }
+        self.notify(
+            &remaining,
+            &PresenceEvent::Leave {
+                room: room.to_owned(),
+                member: member.name(id),
+            },
+        );
+    }
+
+    /// Sends a presence event to each of `ids`, if presence tracking is on.
+    fn notify(&mut self, ids: &[ConnectionId], event: &PresenceEvent) {
+        let frame = match self.presence {
+            None => return,
+            Some(ref encode) => SharedFrame::new(&encode(event)),
+        };
+        for id in ids {
+            self.send_best_effort(*id, &frame);
+        }
+    }
+
+    /// Queues a frame outside of the slow consumer policy, for presence
+    /// events. Frames that don't fit are dropped.
+    fn send_best_effort(&mut self, id: ConnectionId, frame: &SharedFrame) {
+        if let Some(member) = self.members.get_mut(&id) {
+            if member.sender.try_send_frame(frame.clone()).is_err() {
+                debug!("hyper-websocket: dropping room event for {}", id);
+            }
+        }
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Fans messages out to named rooms of connections. Clones share the same
+/// hub.
+#[derive(Clone, Default)]
+pub struct Hub {
+    inner: Rc<RefCell<HubInner>>,
+}
+
+impl fmt::Debug for Hub {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("Hub")
+            .field("members", &inner.members.len())
+            .field("rooms", &inner.rooms.len())
+            .field("history_size", &inner.history_size)
+            .field("presence", &inner.presence.as_ref().map(|_| Omitted))
+            .field("backplane", &inner.backplane.as_ref().map(|_| Omitted))
+            .finish()
+    }
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Hub::default()
+    }
+
+    /// Keeps the last `history_size` messages broadcast to each room and
+    /// replays them to members as they join. Defaults to 0.
+    pub fn history(self, history_size: usize) -> Self {
+        {
+            let mut inner = self.inner.borrow_mut();
+            inner.history_size = history_size;
+            for room in inner.rooms.values_mut() {
+                while room.history.len() > history_size {
+                    room.history.pop_front();
+                }
+            }
+        }
+        self
+    }
+
+    /// Sends `PresenceEvent`s, encoded with `PresenceEvent::to_json`, as
+    /// members join and leave rooms.
+    pub fn presence(self) -> Self {
+        self.presence_with(PresenceEvent::to_json)
+    }
+
+    /// Like `presence`, but with a custom encoding for the events.
+    pub fn presence_with<E>(self, encode: E) -> Self
+    where
+        E: Fn(&PresenceEvent) -> OwnedMessage + 'static,
+    {
+        self.inner.borrow_mut().presence = Some(Rc::new(encode));
+        self
+    }
+
+    /// Relays broadcasts through `backplane`, and delivers the room messages
+    /// it brings in from elsewhere to local members.
+    ///
+    /// Those messages reach each member in the order the backplane brings
+    /// them in. They never wait for room in a member's queue, so that one
+    /// slow member can't hold up every room: members under
+    /// `SlowConsumerPolicy::Backpressure` miss them when full, as under
+    /// `SlowConsumerPolicy::Drop`.
+    pub fn backplane<B>(self, handle: &Handle, backplane: B) -> Self
+    where
+        B: Backplane + 'static,
+    {
+        let hub = self.clone();
+        handle.spawn(backplane.subscribe().for_each(move |(room, msg)| {
+            // Nothing waits, so the broadcast is already done.
+            let _ = hub.broadcast_local(&room, &msg, None, false);
+            Ok(())
+        }));
+        self.inner.borrow_mut().backplane = Some(Rc::new(backplane));
+        self
+    }
+
+    /// Adds a connection to the hub, replacing any previous connection with
+    /// the same ID. It receives nothing until it joins a room.
+    pub fn add(&self, id: ConnectionId, sender: WsSender, policy: SlowConsumerPolicy) {
+        let mut inner = self.inner.borrow_mut();
+        inner.remove(id);
+        inner.members.insert(
+            id,
+            Member {
+                sender: sender,
+                policy: policy,
+                identity: None,
+                rooms: BTreeSet::new(),
+                waiters: Vec::new(),
+            },
+        );
+    }
+
+    /// Names a connection in presence events, typically after a user name
+    /// taken from its handshake. Returns `false` if the connection hasn't been
+    /// added to the hub.
+    pub fn identify<I>(&self, id: ConnectionId, identity: I) -> bool
+    where
+        I: Into<String>,
+    {
+        match self.inner.borrow_mut().members.get_mut(&id) {
+            None => false,
+            Some(member) => {
+                member.identity = Some(identity.into());
+                true
+            }
+        }
+    }
+
+    pub fn identity(&self, id: ConnectionId) -> Option<String> {
+        self.inner
+            .borrow()
+            .members
+            .get(&id)
+            .and_then(|member| member.identity.clone())
+    }
+
+    /// Removes a connection from the hub and every room it was in.
+    pub fn remove(&self, id: ConnectionId) -> bool {
+        self.inner.borrow_mut().remove(id).is_some()
+    }
+
+    pub fn contains(&self, id: ConnectionId) -> bool {
+        self.inner.borrow().members.contains_key(&id)
+    }
+
+    /// Adds a connection to a room. With presence tracking on, the newcomer
+    /// is sent a snapshot of the room and everyone else a join event; then any
+    /// history is replayed to the newcomer.
+    ///
+    /// History that doesn't fit in the newcomer's queue is replayed as the
+    /// returned future is polled, whatever the slow consumer policy; messages
+    /// broadcast in the meantime may arrive ahead of it. The future resolves
+    /// to the number of history messages replayed, which is 0 if the
+    /// connection hasn't been added to the hub or was already in the room.
+    pub fn join(&self, id: ConnectionId, room: &str) -> Broadcast {
+        let mut inner = self.inner.borrow_mut();
+        let name = match inner.members.get_mut(&id) {
+            None => return Broadcast::done(self),
+            Some(member) => {
+                if !member.rooms.insert(room.to_owned()) {
+                    return Broadcast::done(self);
+                }
+                member.name(id)
+            }
+        };
+
+        let (others, history) = {
+            let entry = inner.rooms.entry(room.to_owned()).or_insert_with(Room::default);
+            let others = entry.members.iter().cloned().collect::<Vec<_>>();
+            entry.members.insert(id);
+            (others, entry.history.iter().cloned().collect::<VecDeque<_>>())
+        };
+
+        if inner.presence.is_some() {
+            let mut members = others
+                .iter()
+                .filter_map(|other| inner.members.get(other).map(|member| member.name(*other)))
+                .collect::<Vec<_>>();
+            members.push(name.clone());
+            let snapshot = PresenceEvent::Snapshot {
+                room: room.to_owned(),
+                members: members,
+            };
+            inner.notify(&[id], &snapshot);
+            let join = PresenceEvent::Join {
+                room: room.to_owned(),
+                member: name,
+            };
+            inner.notify(&others, &join);
+        }
+        let mut history = history;
+        let mut delivered = 0;
+        if let Some(member) = inner.members.get_mut(&id) {
+            while let Some(frame) = history.pop_front() {
+                match member.sender.try_send_frame(frame.clone()) {
+                    Ok(()) => delivered += 1,
+                    Err(true) => history.clear(),
+                    Err(false) => {
+                        history.push_front(frame);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut waiting = Vec::new();
+        if !history.is_empty() {
+            waiting.push((id, history));
+        }
+        Broadcast {
+            hub: self.clone(),
+            waiting: waiting,
+            delivered: delivered,
+        }
+    }
+
+    /// Returns `false` if the connection wasn't in the room.
+    pub fn leave(&self, id: ConnectionId, room: &str) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        // Take the member out so `leave_room` can look at it while the rest
+        // of the hub is borrowed mutably.
+        let mut member = match inner.members.remove(&id) {
+            None => return false,
+            Some(member) => member,
+        };
+        let was_member = member.rooms.remove(room);
+        if was_member {
+            inner.leave_room(id, &member, room);
+        }
+        inner.members.insert(id, member);
+        was_member
+    }
+
+    /// Lists the rooms that currently have members.
+    pub fn rooms(&self) -> Vec<String> {
+        let mut rooms = self.inner
+            .borrow()
+            .rooms
+            .iter()
+            .filter(|&(_, room)| !room.members.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        rooms.sort();
+        rooms
+    }
+
+    pub fn rooms_of(&self, id: ConnectionId) -> Vec<String> {
+        self.inner
+            .borrow()
+            .members
+            .get(&id)
+            .map(|member| member.rooms.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn members(&self, room: &str) -> Vec<ConnectionId> {
+        self.inner
+            .borrow()
+            .rooms
+            .get(room)
+            .map(|room| room.members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Names the members of a room, as they appear in presence events.
+    pub fn presence_of(&self, room: &str) -> Vec<String> {
+        let inner = self.inner.borrow();
+        self.members(room)
+            .into_iter()
+            .filter_map(|id| inner.members.get(&id).map(|member| member.name(id)))
+            .collect()
+    }
+
+    /// Sends `msg` to everyone in `room` except `except`, typically the
+    /// connection the message came from. The message is encoded only once,
+    /// and kept in the room's history if there is one.
+    ///
+    /// Subscribers that can take the message right away get it immediately.
+    /// The returned future only needs to be polled to deliver to subscribers
+    /// under `SlowConsumerPolicy::Backpressure`; it resolves to the number of
+    /// subscribers the message was queued for.
+    pub fn broadcast(
+        &self,
+        room: &str,
+        msg: &OwnedMessage,
+        except: Option<ConnectionId>,
+    ) -> Broadcast {
+        let backplane = self.inner.borrow().backplane.clone();
+        if let Some(backplane) = backplane {
+            backplane.publish(room, msg);
+        }
+        self.broadcast_local(room, msg, except, true)
+    }
+
+    /// Sends to the local members of a room. Unless `wait` is set, members
+    /// under `SlowConsumerPolicy::Backpressure` are treated as under
+    /// `SlowConsumerPolicy::Drop`.
+    fn broadcast_local(
+        &self,
+        room: &str,
+        msg: &OwnedMessage,
+        except: Option<ConnectionId>,
+        wait: bool,
+    ) -> Broadcast {
+        let ids = self.members(room)
+            .into_iter()
+            .filter(|&id| Some(id) != except)
+            .collect::<Vec<_>>();
+        let frame = SharedFrame::new(msg);
+        {
+            let mut inner = self.inner.borrow_mut();
+            let history_size = inner.history_size;
+            if history_size > 0 {
+                if let Some(room) = inner.rooms.get_mut(room) {
+                    if room.history.len() == history_size {
+                        room.history.pop_front();
+                    }
+                    room.history.push_back(frame.clone());
+                }
+            }
+        }
+        self.send_frame(&ids, &frame, wait)
+    }
+
+    /// Sends a message to a single connection in the hub, following its slow
+    /// consumer policy.
+    pub fn send_to(&self, id: ConnectionId, msg: &OwnedMessage) -> Broadcast {
+        self.send_frame(&[id], &SharedFrame::new(msg), true)
+    }
+
+    fn send_frame(&self, ids: &[ConnectionId], frame: &SharedFrame, wait: bool) -> Broadcast {
+        let mut delivered = 0;
+        let mut waiting = Vec::new();
+        let mut gone = Vec::new();
+        {
+            let mut inner = self.inner.borrow_mut();
+            for &id in ids {
+                let member = match inner.members.get_mut(&id) {
+                    None => continue,
+                    Some(member) => member,
+                };
+                match member.sender.try_send_frame(frame.clone()) {
+                    Ok(()) => delivered += 1,
+                    Err(true) => gone.push(id),
+                    Err(false) => match member.policy {
+                        SlowConsumerPolicy::Backpressure if wait => {
+                            waiting.push((id, iter::once(frame.clone()).collect()));
+                        }
+                        SlowConsumerPolicy::Drop | SlowConsumerPolicy::Backpressure => {
+                            debug!("hyper-websocket: dropping message for slow consumer {}", id);
+                        }
+                        SlowConsumerPolicy::Disconnect => {
+                            debug!("hyper-websocket: disconnecting slow consumer {}", id);
+                            let _ = member.sender.close(CLOSE_POLICY_VIOLATION, "Too slow");
+                            gone.push(id);
+                        }
+                    },
+                }
+            }
+            for id in gone {
+                inner.remove(id);
+            }
+        }
+
+        Broadcast {
+            hub: self.clone(),
+            waiting: waiting,
+            delivered: delivered,
+        }
+    }
+}
+
+/// Finishes delivering a broadcast to subscribers applying backpressure, or
+/// replaying history to a member that just joined a room.
+#[must_use = "futures do nothing unless polled"]
+pub struct Broadcast {
+    hub: Hub,
+    /// The frames each member still has to be sent, in order.
+    waiting: Vec<(ConnectionId, VecDeque<SharedFrame>)>,
+    delivered: usize,
+}
+
+impl Broadcast {
+    fn done(hub: &Hub) -> Self {
+        Broadcast {
+            hub: hub.clone(),
+            waiting: Vec::new(),
+            delivered: 0,
+        }
+    }
+}
+
+impl fmt::Debug for Broadcast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Broadcast")
+            .field("hub", &Omitted)
+            .field("waiting", &self.waiting.len())
+            .field("delivered", &self.delivered)
+            .finish()
+    }
+}
+
+impl Future for Broadcast {
+    type Item = usize;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut inner = self.hub.inner.borrow_mut();
+        let mut gone = Vec::new();
+        for &mut (id, ref mut frames) in &mut self.waiting {
+            let member = match inner.members.get_mut(&id) {
+                None => {
+                    // Removed from the hub in the meantime.
+                    frames.clear();
+                    continue;
+                }
+                Some(member) => member,
+            };
+            while let Some(frame) = frames.pop_front() {
+                match member.sender.start_send_frame(frame) {
+                    Ok(AsyncSink::Ready) => {
+                        self.delivered += 1;
+                        member.wake();
+                    }
+                    Ok(AsyncSink::NotReady(frame)) => {
+                        frames.push_front(frame);
+                        member.wait();
+                        break;
+                    }
+                    Err(()) => {
+                        frames.clear();
+                        gone.push(id);
+                    }
+                }
+            }
+        }
+        for id in gone {
+            inner.remove(id);
+        }
+
+        self.waiting.retain(|&(_, ref frames)| !frames.is_empty());
+        if self.waiting.is_empty() {
+            Ok(Async::Ready(self.delivered))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl Drop for Broadcast {
+    fn drop(&mut self) {
+        if self.waiting.is_empty() {
+            return;
+        }
+        // This may have been the task the channel would have woken, so let
+        // the others waiting on the same members try again.
+        if let Ok(mut inner) = self.hub.inner.try_borrow_mut() {
+            for &(id, _) in &self.waiting {
+                if let Some(member) = inner.members.get_mut(&id) {
+                    member.wake();
+                }
+            }
+        }
+    }
+}
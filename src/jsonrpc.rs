@@ -0,0 +1,508 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{future, Future, IntoFuture, Poll, Sink, Stream};
+use futures::future::Either;
+use futures::sync::oneshot;
+use serde_json::{self, Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use super::Omitted;
+use super::channel::{spawn_channel, WsSendError, WsSender, QUEUE_CAPACITY};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// From the range the spec reserves for implementation-defined server errors.
+const CONNECTION_CLOSED: i64 = -32000;
+
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+/// A JSON-RPC 2.0 error object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new<M>(code: i64, message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        RpcError {
+            code: code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn parse_error() -> Self {
+        RpcError::new(PARSE_ERROR, "Parse error")
+    }
+
+    pub fn invalid_request() -> Self {
+        RpcError::new(INVALID_REQUEST, "Invalid Request")
+    }
+
+    pub fn method_not_found() -> Self {
+        RpcError::new(METHOD_NOT_FOUND, "Method not found")
+    }
+
+    pub fn invalid_params() -> Self {
+        RpcError::new(INVALID_PARAMS, "Invalid params")
+    }
+
+    pub fn internal_error() -> Self {
+        RpcError::new(INTERNAL_ERROR, "Internal error")
+    }
+
+    /// The error an `RpcCall` fails with when the connection closes before
+    /// the response arrives.
+    pub fn connection_closed() -> Self {
+        RpcError::new(CONNECTION_CLOSED, "Connection closed")
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn code(&self) -> i64 {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn data(&self) -> Option<&Value> {
+        self.data.as_ref()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut error = Map::new();
+        error.insert("code".to_owned(), self.code.into());
+        error.insert("message".to_owned(), self.message.clone().into());
+        if let Some(ref data) = self.data {
+            error.insert("data".to_owned(), data.clone());
+        }
+        Value::Object(error)
+    }
+
+    /// Reads an error object sent by the peer, making do with whatever parts
+    /// of it are well-formed.
+    fn from_json(error: &Value) -> Self {
+        let code = error.get("code").and_then(Value::as_i64);
+        let message = error.get("message").and_then(Value::as_str);
+        match (code, message) {
+            (Some(code), Some(message)) => RpcError {
+                code: code,
+                message: message.to_owned(),
+                data: error.get("data").cloned(),
+            },
+            _ => RpcError::internal_error().with_data(error.clone()),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JSON-RPC error {}: {}", self.code, self.message)
+    }
+}
+
+impl Error for RpcError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+type Method = Fn(&RpcPeer, Value) -> Box<Future<Item = Value, Error = RpcError>>;
+
+/// A set of JSON-RPC 2.0 methods, to be served over any number of
+/// connections.
+#[derive(Clone)]
+pub struct RpcServer {
+    methods: HashMap<String, Rc<Method>>,
+    queue_capacity: usize,
+}
+
+impl Default for RpcServer {
+    fn default() -> Self {
+        RpcServer {
+            methods: HashMap::new(),
+            queue_capacity: QUEUE_CAPACITY,
+        }
+    }
+}
+
+impl fmt::Debug for RpcServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut methods = self.methods.keys().collect::<Vec<_>>();
+        methods.sort();
+        f.debug_struct("RpcServer")
+            .field("methods", &methods)
+            .field("queue_capacity", &self.queue_capacity)
+            .finish()
+    }
+}
+
+impl RpcServer {
+    pub fn new() -> Self {
+        RpcServer::default()
+    }
+
+    /// Registers a method, replacing any previous one with the same name.
+    /// Its handler gets the request's `params`, or `null` if there were none,
+    /// along with the connection's peer so it can call back the client.
+    pub fn method<F, R>(mut self, name: &str, handler: F) -> Self
+    where
+        F: Fn(&RpcPeer, Value) -> R + 'static,
+        R: IntoFuture<Item = Value, Error = RpcError>,
+        R::Future: 'static,
+    {
+        let method = move |peer: &RpcPeer, params: Value| {
+            Box::new(handler(peer, params).into_future()) as Box<Future<Item = _, Error = _>>
+        };
+        self.methods.insert(name.to_owned(), Rc::new(method));
+        self
+    }
+
+    /// Sets how many outgoing messages may wait for each connection before
+    /// notifications fail with `WsSendError::Full` and a client that isn't
+    /// reading its responses is closed. Defaults to 16.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    /// Serves the methods over an accepted connection, spawning the tasks
+    /// that do the IO onto `handle`. Requests are handled concurrently, and
+    /// text messages that are responses to the server's own calls are routed
+    /// to the `RpcCall`s awaiting them.
+    pub fn serve<T>(&self, handle: &Handle, client: Client<T>) -> RpcConnection
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let (sender, receiver) = spawn_channel(handle, client, self.queue_capacity);
+        let peer = RpcPeer {
+            inner: Rc::new(RefCell::new(PeerInner {
+                sender: sender,
+                next_id: 0,
+                pending: HashMap::new(),
+                closed: false,
+            })),
+        };
+
+        let methods = Rc::new(self.methods.clone());
+        let handle = handle.clone();
+        let dispatch_peer = peer.clone();
+        let close_peer = peer.clone();
+        let serve = receiver
+            .for_each(move |msg| {
+                let text = match msg {
+                    OwnedMessage::Text(text) => text,
+                    _ => return Ok(()),
+                };
+                let reply_peer = dispatch_peer.clone();
+                let reply = dispatch_text(&methods, &dispatch_peer, &text).map(move |response| {
+                    if let Some(response) = response {
+                        reply_peer.reply(&response);
+                    }
+                });
+                handle.spawn(reply);
+                Ok(())
+            })
+            .then(move |result| {
+                close_peer.shut_down();
+                result
+            });
+
+        RpcConnection {
+            peer: peer,
+            inner: Box::new(serve),
+        }
+    }
+}
+
+/// A client being answered by an `RpcServer`. Poll it for as long as the
+/// client should be served; it resolves when the client hangs up.
+pub struct RpcConnection {
+    peer: RpcPeer,
+    inner: Box<Future<Item = (), Error = WebSocketError>>,
+}
+
+impl fmt::Debug for RpcConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RpcConnection")
+            .field("peer", &self.peer)
+            .field("inner", &Omitted)
+            .finish()
+    }
+}
+
+impl RpcConnection {
+    /// The client at the other end, for calls from outside method handlers.
+    pub fn peer(&self) -> &RpcPeer {
+        &self.peer
+    }
+}
+
+impl Future for RpcConnection {
+    type Item = ();
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.inner.poll()
+    }
+}
+
+struct PeerInner {
+    sender: WsSender,
+    next_id: u64,
+    pending: HashMap<u64, oneshot::Sender<Result<Value, RpcError>>>,
+    closed: bool,
+}
+
+/// The client end of a JSON-RPC connection, which the server can send calls
+/// and notifications to. Clones share the connection.
+#[derive(Clone)]
+pub struct RpcPeer {
+    inner: Rc<RefCell<PeerInner>>,
+}
+
+impl fmt::Debug for RpcPeer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("RpcPeer")
+            .field("pending", &inner.pending.len())
+            .field("closed", &inner.closed)
+            .finish()
+    }
+}
+
+impl RpcPeer {
+    /// Calls a method on the client. `params` is left out of the request if
+    /// it's `null`.
+    pub fn call(&self, method: &str, params: Value) -> RpcCall {
+        let (tx, rx) = oneshot::channel();
+        let (id, sender) = {
+            let mut inner = self.inner.borrow_mut();
+            if inner.closed {
+                return RpcCall(Box::new(future::err(RpcError::connection_closed())));
+            }
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.pending.insert(id, tx);
+            (id, inner.sender.clone())
+        };
+
+        let request = request(Some(id), method, params);
+        let peer = self.clone();
+        let call = sender
+            .send(OwnedMessage::Text(request.to_string()))
+            .then(move |result| {
+                if result.is_err() {
+                    peer.inner.borrow_mut().pending.remove(&id);
+                    return Either::A(future::err(RpcError::connection_closed()));
+                }
+                Either::B(rx.then(|result| match result {
+                    Ok(result) => result,
+                    Err(_canceled) => Err(RpcError::connection_closed()),
+                }))
+            });
+        RpcCall(Box::new(call))
+    }
+
+    /// Sends the client a notification, which gets no response. Fails with
+    /// `WsSendError::Full` if the client has let the queue fill up.
+    pub fn notify(&self, method: &str, params: Value) -> Result<(), WsSendError> {
+        let request = request(None, method, params);
+        self.inner
+            .borrow_mut()
+            .sender
+            .try_send(OwnedMessage::Text(request.to_string()))
+    }
+
+    /// Queues a response, closing the connection if the client has let the
+    /// queue fill up rather than leave its calls unanswered.
+    fn reply(&self, response: &Value) {
+        let mut inner = self.inner.borrow_mut();
+        let msg = OwnedMessage::Text(response.to_string());
+        if let Err(err) = inner.sender.try_send(msg) {
+            if err.is_full() {
+                let _ = inner.sender.close(CLOSE_POLICY_VIOLATION, "Too slow");
+            }
+        }
+    }
+
+    fn resolve(&self, response: &Map<String, Value>) {
+        let id = match response.get("id").and_then(Value::as_u64) {
+            None => {
+                debug!("hyper-websocket: JSON-RPC response without a usable id");
+                return;
+            }
+            Some(id) => id,
+        };
+        let tx = match self.inner.borrow_mut().pending.remove(&id) {
+            None => {
+                debug!("hyper-websocket: JSON-RPC response to unknown call {}", id);
+                return;
+            }
+            Some(tx) => tx,
+        };
+        let result = match response.get("error") {
+            Some(error) => Err(RpcError::from_json(error)),
+            None => Ok(response.get("result").cloned().unwrap_or(Value::Null)),
+        };
+        // The caller may have lost interest already.
+        let _ = tx.send(result);
+    }
+
+    fn shut_down(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.closed = true;
+        // Dropping the senders fails the calls still waiting.
+        inner.pending.clear();
+    }
+}
+
+/// The result of a call made with `RpcPeer::call`.
+pub struct RpcCall(Box<Future<Item = Value, Error = RpcError>>);
+
+impl fmt::Debug for RpcCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("RpcCall").field(&Omitted).finish()
+    }
+}
+
+impl Future for RpcCall {
+    type Item = Value;
+    type Error = RpcError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+type Reply = Box<Future<Item = Option<Value>, Error = ()>>;
+
+fn request(id: Option<u64>, method: &str, params: Value) -> Value {
+    let mut request = Map::new();
+    request.insert("jsonrpc".to_owned(), "2.0".into());
+    request.insert("method".to_owned(), method.into());
+    if !params.is_null() {
+        request.insert("params".to_owned(), params);
+    }
+    if let Some(id) = id {
+        request.insert("id".to_owned(), id.into());
+    }
+    Value::Object(request)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "result": result, "id": id}),
+        Err(error) => json!({"jsonrpc": "2.0", "error": error.to_json(), "id": id}),
+    }
+}
+
+/// Works out the reply to a text message, if it needs one.
+fn dispatch_text(methods: &Rc<HashMap<String, Rc<Method>>>, peer: &RpcPeer, text: &str) -> Reply {
+    let value = match serde_json::from_str::<Value>(text) {
+        Err(_) => {
+            let reply = response(Value::Null, Err(RpcError::parse_error()));
+            return Box::new(future::ok(Some(reply)));
+        }
+        Ok(value) => value,
+    };
+
+    let batch = match value {
+        Value::Array(batch) => batch,
+        value => return dispatch_one(methods, peer, value),
+    };
+    if batch.is_empty() {
+        let reply = response(Value::Null, Err(RpcError::invalid_request()));
+        return Box::new(future::ok(Some(reply)));
+    }
+    let replies = batch
+        .into_iter()
+        .map(|value| dispatch_one(methods, peer, value))
+        .collect::<Vec<_>>();
+    Box::new(future::join_all(replies).map(|replies| {
+        let replies = replies.into_iter().filter_map(|reply| reply).collect::<Vec<_>>();
+        // A batch of nothing but notifications gets no reply at all.
+        if replies.is_empty() {
+            None
+        } else {
+            Some(Value::Array(replies))
+        }
+    }))
+}
+
+fn dispatch_one(methods: &Rc<HashMap<String, Rc<Method>>>, peer: &RpcPeer, value: Value) -> Reply {
+    let mut object = match value {
+        Value::Object(object) => object,
+        _ => {
+            let reply = response(Value::Null, Err(RpcError::invalid_request()));
+            return Box::new(future::ok(Some(reply)));
+        }
+    };
+
+    if !object.contains_key("method") {
+        if object.contains_key("result") || object.contains_key("error") {
+            peer.resolve(&object);
+            return Box::new(future::ok(None));
+        }
+        let id = object.remove("id").unwrap_or(Value::Null);
+        let reply = response(id, Err(RpcError::invalid_request()));
+        return Box::new(future::ok(Some(reply)));
+    }
+
+    let id = object.remove("id");
+    let valid_id = match id {
+        None | Some(Value::Null) | Some(Value::Number(_)) | Some(Value::String(_)) => true,
+        _ => false,
+    };
+    let method = match object.remove("method") {
+        Some(Value::String(method)) => Some(method),
+        _ => None,
+    };
+    let params = match object.remove("params") {
+        None => Some(Value::Null),
+        Some(params @ Value::Array(_)) | Some(params @ Value::Object(_)) => Some(params),
+        _ => None,
+    };
+    let versioned = object.get("jsonrpc").and_then(Value::as_str) == Some("2.0");
+    let (method, params) = match (method, params) {
+        (Some(method), Some(params)) if valid_id && versioned => (method, params),
+        _ => {
+            let id = if valid_id { id.unwrap_or(Value::Null) } else { Value::Null };
+            let reply = response(id, Err(RpcError::invalid_request()));
+            return Box::new(future::ok(Some(reply)));
+        }
+    };
+
+    let result: Box<Future<Item = Value, Error = RpcError>> = match methods.get(&method) {
+        None => Box::new(future::err(RpcError::method_not_found())),
+        Some(handler) => handler(peer, params),
+    };
+    Box::new(result.then(move |result| {
+        // Notifications get no response, not even for errors.
+        Ok(id.map(|id| response(id, result)))
+    }))
+}
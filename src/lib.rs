@@ -7,33 +7,120 @@
 #![cfg_attr(feature = "clippy", feature(plugin))]
 #![cfg_attr(feature = "clippy", plugin(clippy))]
 
+extern crate base64;
 extern crate bytes;
+extern crate httparse;
 extern crate hyper;
+#[cfg(target_os = "linux")]
+extern crate libc;
+extern crate rand;
+extern crate sha1;
+extern crate tokio_core;
 extern crate tokio_io;
+extern crate tokio_service;
+#[cfg(unix)]
+extern crate tokio_uds;
 extern crate websocket;
 
 #[macro_use]
 extern crate futures;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_json;
 
 use bytes::BytesMut;
 use futures::{Future, Poll};
+use futures::future::AndThen;
 use futures::sink::Send;
-use hyper::{HttpVersion, Method};
+use hyper::{HttpVersion, Method, StatusCode};
 use hyper::header::{self, Headers, Raw};
+use sha1::Sha1;
 use std::ascii::AsciiExt;
 use std::fmt;
 use std::iter::{self, FromIterator};
 use std::mem;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::Framed;
+use tokio_io::io::{flush, write_all, Flush, WriteAll};
 use websocket::client::async::{Client, ClientNew};
 use websocket::codec::http::HttpServerCodec;
 use websocket::result::WebSocketError;
 use websocket::server::upgrade::{Request, WsUpgrade};
 
+pub use backplane::{Backplane, BackplaneMessages};
+pub use bridge::{TcpBridge, BASE64_PROTOCOL, BINARY_PROTOCOL};
+pub use byte_stream::ByteStream;
+pub use channel::{spawn_channel, spawn_client_channel, SharedFrame, WsReceiver, WsSendError,
+                  WsSender};
+pub use client::{ConnectWsHandshake, WsClientHandshake, WsClientResponse};
+pub use cluster::{Cluster, NodeId};
+pub use engineio::{EngineIoSender, EngineIoServer, EngineIoSocket};
+pub use graphql::{negotiate_graphql, GraphQlConnection, GraphQlExecutor, GraphQlRequest,
+                  GraphQlServer, GraphQlStream, GRAPHQL_TRANSPORT_WS_PROTOCOL};
+pub use handler::{DriveWsHandler, WsHandler, WsHandlerContext, CLOSE_ABNORMAL};
+pub use hub::{Broadcast, Hub, PresenceEvent, SlowConsumerPolicy};
+pub use jsonrpc::{RpcCall, RpcConnection, RpcError, RpcPeer, RpcServer};
+pub use limit::SizeLimited;
+pub use longpoll::{LongPollConnection, LongPollServer};
+pub use mux::{negotiate_mux, spawn_mux, MuxChannel, MuxIncoming, MuxOpener, MUX_PROTOCOL};
+pub use process::ProcessBridge;
+#[cfg(target_os = "linux")]
+pub use pty::PtyBridge;
+pub use reconnect::{BufferPolicy, ConnectionState, ReconnectEvent, ReconnectOptions,
+                    ReconnectSender, ReconnectingClient};
+pub use registry::{ConnectionId, ConnectionInfo, ConnectionRegistry};
+pub use rewind::Rewind;
+pub use router::{OriginPolicy, RouteFuture, WsRoute, WsRouter};
+pub use server::{WsContext, WsEndpoint, WsServe, WsServer};
+pub use socketio::{SocketIoAck, SocketIoConnection, SocketIoReply, SocketIoServer, SocketIoSocket};
+pub use stomp::{negotiate_stomp, StompBroker, StompConnection, StompDecoder, StompError,
+                StompFrame, STOMP_PROTOCOL};
+pub use subscription::{SubscriptionConnection, SubscriptionServer, SubscriptionStream};
+#[cfg(unix)]
+pub use unix_backplane::UnixBackplane;
+
+mod backplane;
+mod bridge;
+mod byte_stream;
+mod channel;
+mod client;
+mod cluster;
+mod command;
+mod engineio;
+mod graphql;
+mod handler;
+mod hub;
+mod jsonrpc;
+mod limit;
+mod longpoll;
+mod mux;
+mod polling;
+mod process;
+#[cfg(target_os = "linux")]
+mod pty;
+mod reconnect;
+mod registry;
+mod rewind;
+mod router;
+mod server;
+mod socketio;
+mod stomp;
+mod subscription;
+#[cfg(unix)]
+mod unix_backplane;
+
+/// The GUID appended to the client's key when deriving `Sec-WebSocket-Accept`,
+/// as specified in RFC 6455 section 1.3.
+const WS_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
 #[derive(Clone, Debug)]
 pub struct WsHandshake {
     key: Vec<u8>,
+    protocols: Vec<String>,
+    extensions: Vec<String>,
+    selected_protocol: Option<String>,
+    selected_extensions: Vec<String>,
 }
 
 impl WsHandshake {
@@ -45,6 +132,69 @@ impl WsHandshake {
         self.key
     }
 
+    pub fn protocols(&self) -> &[String] {
+        &self.protocols
+    }
+
+    pub fn extensions(&self) -> &[String] {
+        &self.extensions
+    }
+
+    pub fn selected_protocol(&self) -> Option<&str> {
+        self.selected_protocol.as_ref().map(String::as_str)
+    }
+
+    pub fn selected_extensions(&self) -> &[String] {
+        &self.selected_extensions
+    }
+
+    pub fn use_protocol<P>(mut self, protocol: P) -> Self
+    where
+        P: Into<String>,
+    {
+        self.selected_protocol = Some(protocol.into());
+        self
+    }
+
+    pub fn use_extension<E>(mut self, extension: E) -> Self
+    where
+        E: Into<String>,
+    {
+        self.selected_extensions.push(extension.into());
+        self
+    }
+
+    pub fn accept_key(&self) -> String {
+        accept_key_for(&self.key)
+    }
+
+    pub fn response_head(&self) -> hyper::Response {
+        let mut res = hyper::Response::new()
+            .with_status(StatusCode::SwitchingProtocols)
+            .with_header(header::Upgrade(vec![
+                header::Protocol::new(header::ProtocolName::WebSocket, None),
+            ]))
+            .with_header(header::Connection(vec![
+                "Upgrade".parse().expect("hyper-websocket: ConnectionOption parse failed"),
+            ]));
+        {
+            let headers = res.headers_mut();
+            headers.set_raw("Sec-WebSocket-Accept", self.accept_key());
+            if let Some(ref protocol) = self.selected_protocol {
+                headers.set_raw("Sec-WebSocket-Protocol", protocol.clone());
+            }
+            if !self.selected_extensions.is_empty() {
+                headers.set_raw("Sec-WebSocket-Extensions", self.selected_extensions.join(", "));
+            }
+        }
+        res
+    }
+
+    pub fn response_head_bytes(&self) -> Vec<u8> {
+        let res = self.response_head();
+        format!("{} {}\r\n{}\r\n", res.version(), res.status(), res.headers()).into_bytes()
+    }
+
     pub fn detect<B>(req: &hyper::Request<B>) -> Option<Self> {
         WsHandshake::detect_from_parts(req.method(), req.version(), req.headers())
     }
@@ -104,6 +254,10 @@ impl WsHandshake {
 
         Some(WsHandshake {
             key: key.to_owned(),
+            protocols: split_header_values(headers, "sec-websocket-protocol"),
+            extensions: split_header_values(headers, "sec-websocket-extensions"),
+            selected_protocol: None,
+            selected_extensions: Vec::new(),
         })
     }
 
@@ -122,7 +276,33 @@ impl WsHandshake {
     where
         T: AsyncRead + AsyncWrite + 'static,
     {
-        RejectWsHandshake(self.build_ws_upgrade(io, read_buf).reject())
+        let mut upgrade = self.build_ws_upgrade(io, read_buf);
+        // Without an explicit length the client would have to read the (empty)
+        // body until the connection closes, ruling out HTTP keep-alive.
+        upgrade.headers.set_raw("Content-Length", vec![b"0".to_vec()]);
+        RejectWsHandshake(RejectInner::Upgrade(upgrade.reject()))
+    }
+
+    /// Like `reject`, but with a status other than `400 Bad Request`, which
+    /// is the only one rust-websocket will send.
+    pub fn reject_with_status<T>(
+        self,
+        io: T,
+        read_buf: BytesMut,
+        status: StatusCode,
+    ) -> RejectWsHandshake<T>
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        if status == StatusCode::BadRequest {
+            return self.reject(io, read_buf);
+        }
+        let head = format!("{} {}\r\nContent-Length: 0\r\n\r\n", HttpVersion::Http11, status);
+        RejectWsHandshake(RejectInner::Status(
+            write_all(io, head.into_bytes())
+                .and_then(flush_written as fn((T, Vec<u8>)) -> Flush<T>),
+            Some(read_buf),
+        ))
     }
 
     pub fn respond<T>(self, io: T, read_buf: BytesMut, accept: bool) -> SendWsResponse<T>
@@ -150,12 +330,22 @@ impl WsHandshake {
             headers: FromIterator::from_iter(iter::empty()),
         };
         request.headers.set_raw("sec-websocket-key", vec![self.key]);
-        WsUpgrade {
+        let mut upgrade = WsUpgrade {
             headers: FromIterator::from_iter(iter::empty()),
             stream: io,
             request: request,
             buffer: read_buf,
+        };
+        if let Some(protocol) = self.selected_protocol {
+            upgrade.headers.set_raw("Sec-WebSocket-Protocol", vec![protocol.into_bytes()]);
         }
+        if !self.selected_extensions.is_empty() {
+            upgrade.headers.set_raw(
+                "Sec-WebSocket-Extensions",
+                vec![self.selected_extensions.join(", ").into_bytes()],
+            );
+        }
+        upgrade
     }
 }
 
@@ -177,7 +367,15 @@ impl<T> Future for AcceptWsHandshake<T> {
     }
 }
 
-pub struct RejectWsHandshake<T: AsyncWrite>(Send<Framed<T, HttpServerCodec>>);
+pub struct RejectWsHandshake<T: AsyncWrite>(RejectInner<T>);
+
+enum RejectInner<T: AsyncWrite> {
+    Upgrade(Send<Framed<T, HttpServerCodec>>),
+    Status(
+        AndThen<WriteAll<T, Vec<u8>>, Flush<T>, fn((T, Vec<u8>)) -> Flush<T>>,
+        Option<BytesMut>,
+    ),
+}
 
 impl<T> fmt::Debug for RejectWsHandshake<T>
 where
@@ -192,12 +390,23 @@ impl<T> Future for RejectWsHandshake<T>
 where
     T: AsyncWrite,
 {
-    type Item = T;
+    type Item = Rewind<T>;
     type Error = WebSocketError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let framed = try_ready!(self.0.poll());
-        Ok(framed.into_inner().into())
+        match self.0 {
+            RejectInner::Upgrade(ref mut send) => {
+                let parts = try_ready!(send.poll()).into_parts();
+                Ok(Rewind::new(parts.inner, parts.readbuf).into())
+            }
+            RejectInner::Status(ref mut write, ref mut read_buf) => {
+                let io = try_ready!(write.poll());
+                let read_buf = read_buf
+                    .take()
+                    .expect("hyper-websocket: RejectWsHandshake polled after completion");
+                Ok(Rewind::new(io, read_buf).into())
+            }
+        }
     }
 }
 
@@ -205,6 +414,7 @@ where
 pub struct WsResponse {
     pub handshake: WsHandshake,
     pub accept: bool,
+    status: StatusCode,
 }
 
 impl WsResponse {
@@ -212,21 +422,37 @@ impl WsResponse {
         WsResponse {
             handshake: handshake,
             accept: true,
+            status: StatusCode::SwitchingProtocols,
         }
     }
 
     pub fn reject(handshake: WsHandshake) -> Self {
+        WsResponse::reject_with_status(handshake, StatusCode::BadRequest)
+    }
+
+    pub fn reject_with_status(handshake: WsHandshake, status: StatusCode) -> Self {
         WsResponse {
             handshake: handshake,
             accept: false,
+            status: status,
         }
     }
 
+    /// The status line sent on rejection; `101 Switching Protocols` when
+    /// accepting.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
     pub fn send<T>(self, io: T, read_buf: BytesMut) -> SendWsResponse<T>
     where
         T: AsyncRead + AsyncWrite + 'static,
     {
-        self.handshake.respond(io, read_buf, self.accept)
+        SendWsResponse(if self.accept {
+            Ok(self.handshake.accept(io, read_buf))
+        } else {
+            Err(self.handshake.reject_with_status(io, read_buf, self.status))
+        })
     }
 }
 
@@ -245,7 +471,7 @@ impl<T> Future for SendWsResponse<T>
 where
     T: AsyncWrite,
 {
-    type Item = Result<Client<T>, T>;
+    type Item = Result<Client<T>, Rewind<T>>;
     type Error = WebSocketError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -272,6 +498,17 @@ impl<T> WsStart<T> {
         }
     }
 
+    pub fn handshake(&self) -> &WsHandshake {
+        &self.handshake
+    }
+
+    /// Bytes the client sent after its handshake request, typically its first
+    /// pipelined frames. These are delivered ahead of anything read from `io`
+    /// once the handshake is accepted.
+    pub fn read_buf(&self) -> &[u8] {
+        &self.read_buf
+    }
+
     pub fn into_parts(self) -> (WsHandshake, T, BytesMut) {
         (self.handshake, self.io, self.read_buf)
     }
@@ -311,6 +548,37 @@ enum OldHttpVersion {
     Http20,
 }
 
+fn accept_key_for(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(WS_GUID);
+    base64::encode(&sha1.digest().bytes())
+}
+
+/// Collects the comma-separated values of every instance of a raw header,
+/// trimming whitespace and skipping empty entries.
+fn split_header_values(headers: &Headers, name: &str) -> Vec<String> {
+    headers
+        .get_raw(name)
+        .map(|raw| {
+            raw.iter()
+                .filter_map(|line| std::str::from_utf8(line).ok())
+                .flat_map(|line| line.split(','))
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn flush_written<T>((io, _): (T, Vec<u8>)) -> Flush<T>
+where
+    T: AsyncWrite,
+{
+    flush(io)
+}
+
 struct Omitted;
 
 impl fmt::Debug for Omitted {
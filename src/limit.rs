@@ -0,0 +1,140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use std::fmt;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use super::Omitted;
+
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Wraps a WebSocket connection, closing it with 1009 and failing the stream
+/// once the peer sends a message whose payload exceeds `max_message_size`
+/// bytes.
+///
+/// This is a filter on decoded messages: rust-websocket has already read and
+/// reassembled the whole message by the time its size is checked, so this
+/// keeps oversized messages from the handler but doesn't bound the memory
+/// spent receiving them.
+pub struct SizeLimited<S> {
+    inner: S,
+    max_message_size: usize,
+    /// The close frame to send once an oversized message has arrived, until
+    /// it's been handed to the sink.
+    close: Option<OwnedMessage>,
+    exceeded: bool,
+}
+
+impl<S> fmt::Debug for SizeLimited<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SizeLimited")
+            .field("inner", &Omitted)
+            .field("max_message_size", &self.max_message_size)
+            .field("close", &self.close)
+            .field("exceeded", &self.exceeded)
+            .finish()
+    }
+}
+
+impl<S> SizeLimited<S> {
+    pub fn new(inner: S, max_message_size: usize) -> Self {
+        SizeLimited {
+            inner: inner,
+            max_message_size: max_message_size,
+            close: None,
+            exceeded: false,
+        }
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> SizeLimited<S>
+where
+    S: Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    /// Sends the 1009 close frame, then fails the stream.
+    fn poll_exceeded(&mut self) -> Poll<Option<OwnedMessage>, WebSocketError> {
+        if let Some(close) = self.close.take() {
+            if let AsyncSink::NotReady(close) = self.inner.start_send(close)? {
+                self.close = Some(close);
+                return Ok(Async::NotReady);
+            }
+        }
+        try_ready!(self.inner.poll_complete());
+        Err(WebSocketError::ProtocolError("Message exceeds size limit"))
+    }
+}
+
+impl<S> Stream for SizeLimited<S>
+where
+    S: Stream<Item = OwnedMessage, Error = WebSocketError>
+        + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    type Item = OwnedMessage;
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.exceeded {
+            return self.poll_exceeded();
+        }
+        let maybe_msg = try_ready!(self.inner.poll());
+        if let Some(ref msg) = maybe_msg {
+            if message_len(msg) > self.max_message_size {
+                self.exceeded = true;
+                let close = CloseData::new(CLOSE_TOO_BIG, "Message too big".to_owned());
+                self.close = Some(OwnedMessage::Close(Some(close)));
+                return self.poll_exceeded();
+            }
+        }
+        Ok(maybe_msg.into())
+    }
+}
+
+impl<S> Sink for SizeLimited<S>
+where
+    S: Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    type SinkItem = OwnedMessage;
+    type SinkError = WebSocketError;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.inner.close()
+    }
+}
+
+pub fn message_len(msg: &OwnedMessage) -> usize {
+    match *msg {
+        OwnedMessage::Text(ref text) => text.len(),
+        OwnedMessage::Binary(ref data) |
+        OwnedMessage::Ping(ref data) |
+        OwnedMessage::Pong(ref data) => data.len(),
+        OwnedMessage::Close(None) => 0,
+        OwnedMessage::Close(Some(ref close)) => 2 + close.reason.len(),
+    }
+}
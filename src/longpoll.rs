@@ -0,0 +1,593 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use base64;
+use futures::{future, Async, AsyncSink, Future, IntoFuture, Poll, Sink, StartSend, Stream};
+use futures::sync::{mpsc, oneshot};
+use hyper::{self, Body, Method, Request, Response, StatusCode};
+use hyper::header::ContentLength;
+use serde_json::{self, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use super::Omitted;
+use super::channel::QUEUE_CAPACITY;
+use super::engineio::{millis, query_param, random_id};
+use super::polling::{read_body, Outbox};
+
+const DEFAULT_PATH: &str = "/longpoll";
+const DEFAULT_POLL_TIMEOUT_MS: u64 = 25_000;
+const DEFAULT_SESSION_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_MAX_PAYLOAD: usize = 1_000_000;
+
+struct Session {
+    /// Messages waiting for the next poll.
+    outbox: Outbox<OwnedMessage>,
+    /// Counts polls, so that timers can tell whether another has come since.
+    polls: u64,
+    incoming: mpsc::UnboundedSender<OwnedMessage>,
+    /// How many messages the connection has yet to read, shared with it.
+    unread: Rc<Cell<usize>>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+type OnConnection = Fn(LongPollConnection) -> Box<Future<Item = (), Error = ()>>;
+
+struct LongPollInner {
+    handle: Handle,
+    path: String,
+    poll_timeout: Duration,
+    session_timeout: Duration,
+    max_payload: usize,
+    on_connection: Rc<OnConnection>,
+    sessions: HashMap<String, Session>,
+}
+
+impl LongPollInner {
+    /// Queues a message for the client, returning whether the session is
+    /// still open. Messages queued after a close are discarded.
+    fn send(&mut self, id: &str, msg: OwnedMessage) -> bool {
+        {
+            let session = match self.sessions.get_mut(id) {
+                None => return false,
+                Some(session) => session,
+            };
+            if session.close_sent {
+                return true;
+            }
+            session.close_sent = msg.is_close();
+            session.outbox.push(msg);
+        }
+        self.flush(id, false);
+        true
+    }
+
+    /// Answers the held poll with the queued messages, or with none at all
+    /// if `force` is set and nothing is queued. The session ends once the
+    /// client has been handed a close message.
+    fn flush(&mut self, id: &str, force: bool) {
+        let delivered_close = {
+            let session = match self.sessions.get_mut(id) {
+                None => return,
+                Some(session) => session,
+            };
+            let close = session.outbox.queued().iter().any(OwnedMessage::is_close);
+            session.outbox.flush(force) && close
+        };
+        if delivered_close {
+            self.sessions.remove(id);
+        }
+    }
+
+    fn receive(&mut self, id: &str, msg: OwnedMessage) {
+        let finished = {
+            let session = match self.sessions.get_mut(id) {
+                None => return,
+                Some(session) => session,
+            };
+            if session.close_received {
+                return;
+            }
+            session.close_received = msg.is_close();
+            if session.incoming.unbounded_send(msg).is_ok() {
+                session.unread.set(session.unread.get() + 1);
+            }
+            session.close_received && session.close_sent
+        };
+        if finished {
+            // The client answered our close, completing the handshake.
+            self.sessions.remove(id);
+        }
+    }
+}
+
+/// An HTTP long-polling transport for clients that can't get a WebSocket
+/// through, say because a proxy strips the `Upgrade` header. It's served as
+/// a hyper `Service` under a single path:
+///
+/// - `POST` without a session ID opens a session, answering with JSON
+///   holding its `sid`.
+/// - `GET ?sid=...` is held open until there are messages for the client,
+///   or until the poll timeout passes, and answers with them.
+/// - `POST ?sid=...` sends the client's messages.
+/// - `DELETE ?sid=...` drops the session, as if the connection had broken.
+///
+/// Messages travel as JSON arrays of objects like `{"type":"text","data":
+/// "hi"}`, where `type` is `text`, `binary` (with base64 `data`) or `close`
+/// (with an optional `code` and `reason`). Unknown sessions get `410 Gone`,
+/// and requests for paths other than the long-polling path get `404 Not
+/// Found`. A `POST` arriving while the connection handler has 16 messages
+/// left to read gets `429 Too Many Requests`, and should be sent again later.
+///
+/// Each new session is handed to the connection handler as a
+/// `LongPollConnection`, and the future the handler returns is spawned. A
+/// `LongPollConnection` is a `Stream` and `Sink` of messages just like an
+/// accepted `Client`, so a handler written against those traits serves both
+/// transports:
+///
+/// ```ignore
+/// let longpoll = LongPollServer::new(&handle, |conn| serve(conn).map_err(|_| ()));
+/// WsServer::new(longpoll, |_ctx, client| serve(client)).serve(listener, &handle)
+/// ```
+#[derive(Clone)]
+pub struct LongPollServer {
+    inner: Rc<RefCell<LongPollInner>>,
+}
+
+impl fmt::Debug for LongPollServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("LongPollServer")
+            .field("path", &inner.path)
+            .field("poll_timeout", &inner.poll_timeout)
+            .field("session_timeout", &inner.session_timeout)
+            .field("max_payload", &inner.max_payload)
+            .field("on_connection", &Omitted)
+            .field("sessions", &inner.sessions.len())
+            .finish()
+    }
+}
+
+impl LongPollServer {
+    pub fn new<H, F>(handle: &Handle, on_connection: H) -> Self
+    where
+        H: Fn(LongPollConnection) -> F + 'static,
+        F: IntoFuture<Item = (), Error = ()>,
+        F::Future: 'static,
+    {
+        let on_connection =
+            move |connection: LongPollConnection| -> Box<Future<Item = (), Error = ()>> {
+                Box::new(on_connection(connection).into_future())
+            };
+        LongPollServer {
+            inner: Rc::new(RefCell::new(LongPollInner {
+                handle: handle.clone(),
+                path: DEFAULT_PATH.to_owned(),
+                poll_timeout: Duration::from_millis(DEFAULT_POLL_TIMEOUT_MS),
+                session_timeout: Duration::from_millis(DEFAULT_SESSION_TIMEOUT_MS),
+                max_payload: DEFAULT_MAX_PAYLOAD,
+                on_connection: Rc::new(on_connection),
+                sessions: HashMap::new(),
+            })),
+        }
+    }
+
+    /// The path long-polling is served under. Defaults to `/longpoll`.
+    pub fn path(self, path: &str) -> Self {
+        self.inner.borrow_mut().path = path.trim_right_matches('/').to_owned();
+        self
+    }
+
+    /// How long a poll is held open with nothing to send before it's
+    /// answered empty. Keep this under any proxy's idle timeout. Defaults
+    /// to 25 seconds.
+    pub fn poll_timeout(self, poll_timeout: Duration) -> Self {
+        self.inner.borrow_mut().poll_timeout = poll_timeout;
+        self
+    }
+
+    /// How long a session lasts without a poll before it's dropped.
+    /// Defaults to 60 seconds.
+    pub fn session_timeout(self, session_timeout: Duration) -> Self {
+        self.inner.borrow_mut().session_timeout = session_timeout;
+        self
+    }
+
+    /// The largest request body accepted, in bytes. Defaults to 1 MB.
+    pub fn max_payload(self, max_payload: usize) -> Self {
+        self.inner.borrow_mut().max_payload = max_payload;
+        self
+    }
+
+    fn open(&self, query: Option<&str>) -> Response {
+        let id = random_id();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let unread = Rc::new(Cell::new(0));
+        let (on_connection, handle, body) = {
+            let mut inner = self.inner.borrow_mut();
+            inner.sessions.insert(
+                id.clone(),
+                Session {
+                    outbox: Outbox::new(),
+                    polls: 0,
+                    incoming: incoming_tx,
+                    unread: unread.clone(),
+                    close_sent: false,
+                    close_received: false,
+                },
+            );
+            let body = json!({
+                "sid": id,
+                "pollTimeout": millis(inner.poll_timeout),
+                "sessionTimeout": millis(inner.session_timeout),
+            });
+            (inner.on_connection.clone(), inner.handle.clone(), body.to_string())
+        };
+        expire_later(&self.inner, &id, 0);
+
+        let connection = LongPollConnection {
+            id: id,
+            query: query.map(str::to_owned),
+            inner: Rc::downgrade(&self.inner),
+            incoming: incoming_rx,
+            unread: unread,
+        };
+        handle.spawn(on_connection(connection));
+        json_response(body)
+    }
+
+    fn poll(&self, id: &str) -> Box<Future<Item = Response, Error = hyper::Error>> {
+        let (poll_tx, poll_rx) = oneshot::channel();
+        let serial = {
+            let mut inner = self.inner.borrow_mut();
+            let serial = match inner.sessions.get_mut(id) {
+                None => return Box::new(future::ok(status_response(StatusCode::Gone))),
+                Some(session) => {
+                    // A newer poll takes over from one still held.
+                    if let Some(poll) = session.outbox.hold(poll_tx) {
+                        let _ = poll.send(Vec::new());
+                    }
+                    session.polls += 1;
+                    session.polls
+                }
+            };
+            inner.flush(id, false);
+            serial
+        };
+        release_later(&self.inner, id, serial);
+
+        Box::new(poll_rx.then(|result| {
+            Ok(match result {
+                Ok(msgs) => messages_response(&msgs),
+                // The session ended with nothing more to say.
+                Err(_) => status_response(StatusCode::Gone),
+            })
+        }))
+    }
+
+    fn post(&self, id: String, body: Body) -> Box<Future<Item = Response, Error = hyper::Error>> {
+        if !self.inner.borrow().sessions.contains_key(&id) {
+            return Box::new(future::ok(status_response(StatusCode::Gone)));
+        }
+        let max_payload = self.inner.borrow().max_payload;
+        let inner = self.inner.clone();
+        Box::new(read_body(body, max_payload).map(move |buf| {
+            let buf = match buf {
+                None => return status_response(StatusCode::PayloadTooLarge),
+                Some(buf) => buf,
+            };
+            let msgs = match parse_messages(&buf) {
+                None => return status_response(StatusCode::BadRequest),
+                Some(msgs) => msgs,
+            };
+            let mut inner = inner.borrow_mut();
+            let full = match inner.sessions.get(&id) {
+                None => return status_response(StatusCode::Gone),
+                Some(session) => session.unread.get() >= QUEUE_CAPACITY,
+            };
+            // Turning the whole request away lets the client send it again
+            // without repeating any of its messages.
+            if full {
+                return status_response(StatusCode::TooManyRequests);
+            }
+            for msg in msgs {
+                inner.receive(&id, msg);
+            }
+            status_response(StatusCode::NoContent)
+        }))
+    }
+
+    fn delete(&self, id: &str) -> Response {
+        match self.inner.borrow_mut().sessions.remove(id) {
+            None => status_response(StatusCode::Gone),
+            Some(_) => status_response(StatusCode::NoContent),
+        }
+    }
+}
+
+impl Service for LongPollServer {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if req.path().trim_right_matches('/') != self.inner.borrow().path {
+            return Box::new(future::ok(status_response(StatusCode::NotFound)));
+        }
+
+        let id = query_param(req.query(), "sid").map(str::to_owned);
+        match (req.method().clone(), id) {
+            (Method::Post, None) => Box::new(future::ok(self.open(req.query()))),
+            (Method::Get, Some(id)) => self.poll(&id),
+            (Method::Post, Some(id)) => self.post(id, req.body()),
+            (Method::Delete, Some(id)) => Box::new(future::ok(self.delete(&id))),
+            _ => Box::new(future::ok(status_response(StatusCode::MethodNotAllowed))),
+        }
+    }
+}
+
+/// Once the poll numbered `serial` has been held for the poll timeout,
+/// answers it if nothing has yet, and then drops the session if no other
+/// poll comes within the session timeout.
+fn release_later(inner_rc: &Rc<RefCell<LongPollInner>>, id: &str, serial: u64) {
+    let (handle, poll_timeout) = {
+        let inner = inner_rc.borrow();
+        (inner.handle.clone(), inner.poll_timeout)
+    };
+    let timeout = match Timeout::new(poll_timeout, &handle) {
+        Err(err) => {
+            error!("hyper-websocket: long-poll timer error: {}", err);
+            return;
+        }
+        Ok(timeout) => timeout,
+    };
+    let weak = Rc::downgrade(inner_rc);
+    let id = id.to_owned();
+    handle.spawn(timeout.then(move |_| {
+        if let Some(inner_rc) = weak.upgrade() {
+            let current = inner_rc
+                .borrow()
+                .sessions
+                .get(&id)
+                .map_or(false, |session| session.polls == serial);
+            if current {
+                inner_rc.borrow_mut().flush(&id, true);
+                expire_later(&inner_rc, &id, serial);
+            }
+        }
+        Ok(())
+    }));
+}
+
+/// Drops the session after the session timeout, unless another poll has
+/// come since the one numbered `serial`.
+fn expire_later(inner_rc: &Rc<RefCell<LongPollInner>>, id: &str, serial: u64) {
+    let (handle, session_timeout) = {
+        let inner = inner_rc.borrow();
+        (inner.handle.clone(), inner.session_timeout)
+    };
+    let timeout = match Timeout::new(session_timeout, &handle) {
+        Err(err) => {
+            error!("hyper-websocket: long-poll timer error: {}", err);
+            return;
+        }
+        Ok(timeout) => timeout,
+    };
+    let weak = Rc::downgrade(inner_rc);
+    let id = id.to_owned();
+    handle.spawn(timeout.then(move |_| {
+        if let Some(inner) = weak.upgrade() {
+            let mut inner = inner.borrow_mut();
+            let expired = inner.sessions.get(&id).map_or(false, |session| {
+                session.polls == serial && !session.outbox.is_held()
+            });
+            if expired {
+                debug!("hyper-websocket: long-poll session {} expired", id);
+                inner.sessions.remove(&id);
+            }
+        }
+        Ok(())
+    }));
+}
+
+/// One client's long-polling session, with the same `Stream` and `Sink`
+/// interface as an accepted WebSocket `Client`. The stream ends once the
+/// session does.
+///
+/// Only a few messages wait for the client's next poll; past that, sending
+/// isn't ready until a poll takes them. Pings and pongs have no meaning here
+/// and are discarded when sent. Dropping the connection without sending a
+/// close drops the session.
+pub struct LongPollConnection {
+    id: String,
+    query: Option<String>,
+    inner: Weak<RefCell<LongPollInner>>,
+    incoming: mpsc::UnboundedReceiver<OwnedMessage>,
+    unread: Rc<Cell<usize>>,
+}
+
+impl fmt::Debug for LongPollConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LongPollConnection")
+            .field("id", &self.id)
+            .field("query", &self.query)
+            .field("inner", &Omitted)
+            .field("incoming", &Omitted)
+            .field("unread", &self.unread.get())
+            .finish()
+    }
+}
+
+impl LongPollConnection {
+    /// The session ID.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The query string of the request that opened the session.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_ref().map(String::as_str)
+    }
+
+    pub fn is_open(&self) -> bool {
+        match self.inner.upgrade() {
+            None => false,
+            Some(inner) => inner.borrow().sessions.contains_key(&self.id),
+        }
+    }
+}
+
+impl Stream for LongPollConnection {
+    type Item = OwnedMessage;
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.incoming.poll() {
+            Ok(Async::Ready(Some(msg))) => {
+                self.unread.set(self.unread.get() - 1);
+                Ok(Async::Ready(Some(msg)))
+            }
+            Ok(polled) => Ok(polled),
+            Err(()) => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl Sink for LongPollConnection {
+    type SinkItem = OwnedMessage;
+    type SinkError = WebSocketError;
+
+    fn start_send(&mut self, msg: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if msg.is_ping() || msg.is_pong() {
+            return Ok(AsyncSink::Ready);
+        }
+        let close = msg.is_close();
+        let open = match self.inner.upgrade() {
+            None => false,
+            Some(inner) => {
+                let mut inner = inner.borrow_mut();
+                let full = match inner.sessions.get_mut(&self.id) {
+                    Some(ref mut session)
+                        if !session.close_sent && session.outbox.len() >= QUEUE_CAPACITY =>
+                    {
+                        session.outbox.park();
+                        true
+                    }
+                    _ => false,
+                };
+                if full {
+                    return Ok(AsyncSink::NotReady(msg));
+                }
+                inner.send(&self.id, msg)
+            }
+        };
+        // Like a WebSocket's, closing a session that's already gone is
+        // harmless.
+        if open || close {
+            Ok(AsyncSink::Ready)
+        } else {
+            Err(WebSocketError::IoError(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "long-poll session closed",
+            )))
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for LongPollConnection {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.upgrade() {
+            let mut inner = inner.borrow_mut();
+            // A queued close still gets delivered.
+            let abandoned = inner
+                .sessions
+                .get(&self.id)
+                .map_or(false, |session| !session.close_sent);
+            if abandoned {
+                inner.sessions.remove(&self.id);
+            }
+        }
+    }
+}
+
+fn encode_message(msg: &OwnedMessage) -> Value {
+    match *msg {
+        OwnedMessage::Text(ref text) => json!({"type": "text", "data": text}),
+        OwnedMessage::Binary(ref data) => json!({"type": "binary", "data": base64::encode(data)}),
+        OwnedMessage::Close(Some(ref data)) => {
+            json!({"type": "close", "code": data.status_code, "reason": data.reason})
+        }
+        OwnedMessage::Close(None) => json!({"type": "close"}),
+        // Never queued.
+        OwnedMessage::Ping(_) | OwnedMessage::Pong(_) => Value::Null,
+    }
+}
+
+fn decode_message(value: &Value) -> Option<OwnedMessage> {
+    let data = value.get("data").and_then(Value::as_str);
+    match value.get("type").and_then(Value::as_str) {
+        Some("text") => data.map(|text| OwnedMessage::Text(text.to_owned())),
+        Some("binary") => {
+            let data = data.and_then(|data| base64::decode(data).ok());
+            data.map(OwnedMessage::Binary)
+        }
+        Some("close") => match value.get("code") {
+            None => Some(OwnedMessage::Close(None)),
+            Some(code) => match code.as_u64() {
+                Some(code) if code <= u64::from(u16::max_value()) => {
+                    let reason = value.get("reason").and_then(Value::as_str).unwrap_or("");
+                    let data = CloseData::new(code as u16, reason.to_owned());
+                    Some(OwnedMessage::Close(Some(data)))
+                }
+                _ => None,
+            },
+        },
+        _ => None,
+    }
+}
+
+fn parse_messages(buf: &[u8]) -> Option<Vec<OwnedMessage>> {
+    let entries = match serde_json::from_slice(buf) {
+        Ok(Value::Array(entries)) => entries,
+        _ => return None,
+    };
+    let mut msgs = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        match decode_message(entry) {
+            None => return None,
+            Some(msg) => msgs.push(msg),
+        }
+    }
+    Some(msgs)
+}
+
+fn json_response(body: String) -> Response {
+    let mut res = Response::new().with_header(ContentLength(body.len() as u64));
+    res.headers_mut().set_raw("Content-Type", "application/json");
+    // Keep proxies from answering one poll with another's messages.
+    res.headers_mut().set_raw("Cache-Control", "no-store");
+    res.with_body(body)
+}
+
+fn messages_response(msgs: &[OwnedMessage]) -> Response {
+    json_response(Value::Array(msgs.iter().map(encode_message).collect()).to_string())
+}
+
+fn status_response(status: StatusCode) -> Response {
+    Response::new().with_status(status).with_header(ContentLength(0))
+}
@@ -0,0 +1,556 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{BigEndian, BufMut, ByteOrder};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use futures::task::{self, Task};
+use hyper::StatusCode;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::str;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+
+use super::{WsHandshake, WsResponse};
+use super::channel::{self, WsReceiver, WsSendError, WsSender, QUEUE_CAPACITY};
+
+/// The subprotocol a client requests to speak the multiplexing envelope.
+///
+/// Every message is binary: a kind byte, a big-endian `u32` channel ID, and a
+/// body depending on the kind.
+///
+/// - `1` opens a channel. The body is the opener's receive window as a `u32`,
+///   followed by the channel's name in UTF-8.
+/// - `2` carries data on a channel. The body is the payload.
+/// - `3` closes a channel in both directions. The body is empty.
+/// - `4` grants the receiver of this envelope more send window. The body is
+///   the number of bytes as a `u32`.
+///
+/// A data envelope must fit in the window its sender has left, and the other
+/// side closes the connection with a protocol error if it doesn't. The server
+/// opens channels with even IDs and the client with odd ones.
+pub const MUX_PROTOCOL: &str = "mux.hyper-websocket";
+
+const KIND_OPEN: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_CLOSE: u8 = 3;
+const KIND_WINDOW: u8 = 4;
+
+const HEADER_SIZE: usize = 5;
+
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+const MALFORMED: &str = "Malformed mux envelope";
+
+/// A `WsServer::negotiate` callback for mux endpoints. Since envelopes are
+/// all `spawn_mux` understands, clients that didn't offer `MUX_PROTOCOL` are
+/// turned away with `400 Bad Request`.
+pub fn negotiate_mux(handshake: WsHandshake) -> WsResponse {
+    if handshake.protocols().iter().any(|protocol| protocol == MUX_PROTOCOL) {
+        WsResponse::accept(handshake.use_protocol(MUX_PROTOCOL))
+    } else {
+        WsResponse::reject_with_status(handshake, StatusCode::BadRequest)
+    }
+}
+
+/// Starts multiplexing channels over an accepted connection that negotiated
+/// `MUX_PROTOCOL`, spawning the tasks that do the IO onto `handle`.
+///
+/// `window` is how many bytes of data the peer may send on each channel
+/// before this side has read them. The connection stays open until the peer
+/// closes it or `MuxOpener::close` is called.
+pub fn spawn_mux<T>(handle: &Handle, client: Client<T>, window: u32) -> (MuxOpener, MuxIncoming)
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    // Every channel's envelopes go through the one queue.
+    let (driver, sender, receiver) = channel::channel(client, QUEUE_CAPACITY, false);
+    handle.spawn(driver);
+
+    let state = Rc::new(RefCell::new(MuxState {
+        sender: sender,
+        window: window,
+        next_id: 0,
+        channels: HashMap::new(),
+        accepted: VecDeque::new(),
+        accept_task: None,
+        accepting: true,
+        done: false,
+    }));
+    handle.spawn(MuxDriver {
+        receiver: receiver,
+        state: state.clone(),
+    });
+
+    (
+        MuxOpener {
+            state: state.clone(),
+        },
+        MuxIncoming { state: state },
+    )
+}
+
+struct ChannelState {
+    incoming: VecDeque<Vec<u8>>,
+    read_task: Option<Task>,
+    write_task: Option<Task>,
+    /// How much more data may be sent before the peer grants more window.
+    credit: i64,
+    /// How much more data the peer may send before this side grants more
+    /// window.
+    receive_window: i64,
+    /// Data read since the last window grant.
+    consumed: u32,
+    closed: bool,
+}
+
+impl ChannelState {
+    fn new(credit: u32, window: u32) -> Self {
+        ChannelState {
+            incoming: VecDeque::new(),
+            read_task: None,
+            write_task: None,
+            credit: i64::from(credit),
+            receive_window: i64::from(window),
+            consumed: 0,
+            closed: false,
+        }
+    }
+
+    fn notify(&mut self) {
+        if let Some(task) = self.read_task.take() {
+            task.notify();
+        }
+        if let Some(task) = self.write_task.take() {
+            task.notify();
+        }
+    }
+}
+
+struct MuxState {
+    sender: WsSender,
+    window: u32,
+    next_id: u32,
+    channels: HashMap<u32, ChannelState>,
+    accepted: VecDeque<MuxChannel>,
+    accept_task: Option<Task>,
+    /// Whether the `MuxIncoming` is still around to take new channels.
+    accepting: bool,
+    done: bool,
+}
+
+impl MuxState {
+    /// Queues an envelope past the capacity limit, so that control messages
+    /// never wait behind data.
+    fn send_control(&self, kind: u8, id: u32, body: &[u8]) {
+        let _ = self.sender.clone().try_send(envelope(kind, id, body));
+    }
+
+    fn shut_down(&mut self) {
+        self.done = true;
+        for channel in self.channels.values_mut() {
+            channel.closed = true;
+            channel.notify();
+        }
+        if let Some(task) = self.accept_task.take() {
+            task.notify();
+        }
+    }
+}
+
+fn envelope(kind: u8, id: u32, body: &[u8]) -> OwnedMessage {
+    let mut buf = Vec::with_capacity(HEADER_SIZE + body.len());
+    buf.put_u8(kind);
+    buf.put_u32::<BigEndian>(id);
+    buf.put_slice(body);
+    OwnedMessage::Binary(buf)
+}
+
+fn window_body(window: u32) -> [u8; 4] {
+    let mut body = [0; 4];
+    BigEndian::write_u32(&mut body, window);
+    body
+}
+
+/// Opens channels on a multiplexed connection. Clones share the connection.
+#[derive(Clone)]
+pub struct MuxOpener {
+    state: Rc<RefCell<MuxState>>,
+}
+
+impl fmt::Debug for MuxOpener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.borrow();
+        f.debug_struct("MuxOpener")
+            .field("window", &state.window)
+            .field("channels", &state.channels.len())
+            .field("done", &state.done)
+            .finish()
+    }
+}
+
+impl MuxOpener {
+    /// Opens a channel. The peer learns of it straight away, but nothing can
+    /// be sent on it until the peer grants it some window.
+    pub fn open(&self, name: &str) -> Result<MuxChannel, WsSendError> {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_id;
+        let mut body = window_body(state.window).to_vec();
+        body.extend_from_slice(name.as_bytes());
+        state.sender.clone().try_send(envelope(KIND_OPEN, id, &body))?;
+
+        state.next_id += 2;
+        let window = state.window;
+        state.channels.insert(id, ChannelState::new(0, window));
+        Ok(MuxChannel {
+            id: id,
+            name: name.to_owned(),
+            sender: state.sender.clone(),
+            pending: None,
+            state: self.state.clone(),
+        })
+    }
+
+    /// Starts the close handshake for the whole connection.
+    pub fn close(&self, code: u16, reason: &str) -> Result<(), WsSendError> {
+        self.state.borrow().sender.close(code, reason)
+    }
+}
+
+/// The channels opened by the peer, ending once the connection closes.
+pub struct MuxIncoming {
+    state: Rc<RefCell<MuxState>>,
+}
+
+impl fmt::Debug for MuxIncoming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MuxIncoming")
+            .field("pending", &self.state.borrow().accepted.len())
+            .finish()
+    }
+}
+
+impl Stream for MuxIncoming {
+    type Item = MuxChannel;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut state = self.state.borrow_mut();
+        if let Some(channel) = state.accepted.pop_front() {
+            return Ok(Async::Ready(Some(channel)));
+        }
+        if state.done {
+            return Ok(Async::Ready(None));
+        }
+        state.accept_task = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for MuxIncoming {
+    fn drop(&mut self) {
+        let accepted = {
+            let mut state = self.state.borrow_mut();
+            state.accepting = false;
+            mem::replace(&mut state.accepted, VecDeque::new())
+        };
+        // Dropping these closes them, which needs the state again.
+        drop(accepted);
+    }
+}
+
+/// One logical channel of a multiplexed connection. As a `Stream` it yields
+/// the data the peer sends, and as a `Sink` it sends data, waiting for window
+/// as needed. Data larger than the window left is split across envelopes, so
+/// the peer may read it in pieces. Dropping it closes the channel.
+pub struct MuxChannel {
+    id: u32,
+    name: String,
+    /// This channel's own handle, so it always gets a slot in the queue.
+    sender: WsSender,
+    /// Data accepted by the sink that is still waiting for window.
+    pending: Option<Vec<u8>>,
+    state: Rc<RefCell<MuxState>>,
+}
+
+impl fmt::Debug for MuxChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MuxChannel")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl MuxChannel {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state
+            .borrow()
+            .channels
+            .get(&self.id)
+            .map_or(true, |channel| channel.closed)
+    }
+
+    /// Closes the channel in both directions. Data already received can
+    /// still be read.
+    pub fn close(&mut self) {
+        let mut state = self.state.borrow_mut();
+        let closed = match state.channels.get_mut(&self.id) {
+            None => return,
+            Some(channel) => {
+                let closed = channel.closed;
+                channel.closed = true;
+                channel.notify();
+                closed
+            }
+        };
+        if !closed {
+            state.send_control(KIND_CLOSE, self.id, &[]);
+        }
+    }
+
+    /// Sends as much of the pending data as the window and the queue allow.
+    fn poll_pending(&mut self) -> Poll<(), WsSendError> {
+        while let Some(mut data) = self.pending.take() {
+            let credit = {
+                let mut state = self.state.borrow_mut();
+                let channel = match state.channels.get_mut(&self.id) {
+                    None => return Err(WsSendError::Closed(OwnedMessage::Binary(data))),
+                    Some(channel) => channel,
+                };
+                if channel.closed {
+                    return Err(WsSendError::Closed(OwnedMessage::Binary(data)));
+                }
+                if channel.credit <= 0 {
+                    channel.write_task = Some(task::current());
+                    self.pending = Some(data);
+                    return Ok(Async::NotReady);
+                }
+                channel.credit
+            };
+
+            match self.sender.poll_ready() {
+                Err(()) => return Err(WsSendError::Closed(OwnedMessage::Binary(data))),
+                Ok(Async::NotReady) => {
+                    self.pending = Some(data);
+                    return Ok(Async::NotReady);
+                }
+                Ok(Async::Ready(())) => {}
+            }
+            if data.len() as i64 > credit {
+                self.pending = Some(data.split_off(credit as usize));
+            }
+            let len = data.len();
+            // `poll_ready` guarantees the queue has room.
+            self.sender
+                .try_send(envelope(KIND_DATA, self.id, &data))
+                .map_err(|_| WsSendError::Closed(OwnedMessage::Binary(data)))?;
+            if let Some(channel) = self.state.borrow_mut().channels.get_mut(&self.id) {
+                channel.credit -= len as i64;
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for MuxChannel {
+    fn drop(&mut self) {
+        self.close();
+        self.state.borrow_mut().channels.remove(&self.id);
+    }
+}
+
+impl Stream for MuxChannel {
+    type Item = Vec<u8>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut state = self.state.borrow_mut();
+        let window = state.window;
+        let (data, grant) = {
+            let channel = match state.channels.get_mut(&self.id) {
+                None => return Ok(Async::Ready(None)),
+                Some(channel) => channel,
+            };
+            let data = match channel.incoming.pop_front() {
+                Some(data) => data,
+                None if channel.closed => return Ok(Async::Ready(None)),
+                None => {
+                    channel.read_task = Some(task::current());
+                    return Ok(Async::NotReady);
+                }
+            };
+            channel.consumed = channel.consumed.saturating_add(data.len() as u32);
+            // Grant window back in batches rather than one envelope per read.
+            let grant = if channel.closed || channel.consumed < window / 2 {
+                None
+            } else {
+                Some(channel.consumed)
+            };
+            (data, grant)
+        };
+
+        if let Some(grant) = grant {
+            // Going through the control path means a full queue can't lose
+            // the grant and leave the peer waiting forever.
+            state.send_control(KIND_WINDOW, self.id, &window_body(grant));
+            if let Some(channel) = state.channels.get_mut(&self.id) {
+                channel.consumed -= grant;
+                channel.receive_window += i64::from(grant);
+            }
+        }
+        Ok(Async::Ready(Some(data)))
+    }
+}
+
+impl Sink for MuxChannel {
+    type SinkItem = Vec<u8>;
+    type SinkError = WsSendError;
+
+    fn start_send(&mut self, data: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.poll_pending()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(data));
+        }
+        if self.is_closed() {
+            return Err(WsSendError::Closed(OwnedMessage::Binary(data)));
+        }
+        self.pending = Some(data);
+        self.poll_pending()?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        try_ready!(self.poll_pending());
+        self.sender.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        try_ready!(self.poll_pending());
+        MuxChannel::close(self);
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Reads envelopes off the connection and hands them to their channels.
+struct MuxDriver {
+    receiver: WsReceiver,
+    state: Rc<RefCell<MuxState>>,
+}
+
+impl MuxDriver {
+    /// Fails with the close reason if the envelope breaks the protocol.
+    fn dispatch(&mut self, msg: OwnedMessage) -> Result<(), &'static str> {
+        let data = match msg {
+            OwnedMessage::Binary(data) => data,
+            OwnedMessage::Ping(_) | OwnedMessage::Pong(_) | OwnedMessage::Close(_) => {
+                return Ok(());
+            }
+            OwnedMessage::Text(_) => return Err(MALFORMED),
+        };
+        if data.len() < HEADER_SIZE {
+            return Err(MALFORMED);
+        }
+        let kind = data[0];
+        let id = BigEndian::read_u32(&data[1..HEADER_SIZE]);
+        let body = &data[HEADER_SIZE..];
+
+        let mut state = self.state.borrow_mut();
+        match kind {
+            KIND_OPEN => {
+                // Peer-opened channels have odd IDs, and can't be reopened.
+                if id % 2 == 0 || body.len() < 4 || state.channels.contains_key(&id) {
+                    return Err(MALFORMED);
+                }
+                let name = match str::from_utf8(&body[4..]) {
+                    Err(_) => return Err(MALFORMED),
+                    Ok(name) => name.to_owned(),
+                };
+                if !state.accepting {
+                    state.send_control(KIND_CLOSE, id, &[]);
+                    return Ok(());
+                }
+                let credit = BigEndian::read_u32(&body[..4]);
+                let window = state.window;
+                state.channels.insert(id, ChannelState::new(credit, window));
+                state.send_control(KIND_WINDOW, id, &window_body(state.window));
+                let channel = MuxChannel {
+                    id: id,
+                    name: name,
+                    sender: state.sender.clone(),
+                    pending: None,
+                    state: self.state.clone(),
+                };
+                state.accepted.push_back(channel);
+                if let Some(task) = state.accept_task.take() {
+                    task.notify();
+                }
+            }
+            KIND_DATA => if let Some(channel) = state.channels.get_mut(&id) {
+                if body.len() as i64 > channel.receive_window {
+                    return Err("Mux window exceeded");
+                }
+                channel.receive_window -= body.len() as i64;
+                // Data racing a local close is dropped.
+                if !channel.closed {
+                    channel.incoming.push_back(body.to_vec());
+                    channel.notify();
+                }
+            },
+            KIND_CLOSE => if let Some(channel) = state.channels.get_mut(&id) {
+                channel.closed = true;
+                channel.notify();
+            },
+            KIND_WINDOW => {
+                if body.len() != 4 {
+                    return Err(MALFORMED);
+                }
+                if let Some(channel) = state.channels.get_mut(&id) {
+                    channel.credit += i64::from(BigEndian::read_u32(body));
+                    channel.notify();
+                }
+            }
+            _ => return Err(MALFORMED),
+        }
+        Ok(())
+    }
+}
+
+impl Future for MuxDriver {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let msg = match self.receiver.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(Some(msg))) => msg,
+                Ok(Async::Ready(None)) => break,
+                Err(err) => {
+                    debug!("hyper-websocket: multiplexed connection error: {}", err);
+                    break;
+                }
+            };
+            if let Err(reason) = self.dispatch(msg) {
+                let state = self.state.borrow();
+                let _ = state.sender.close(CLOSE_PROTOCOL_ERROR, reason);
+                break;
+            }
+        }
+        self.state.borrow_mut().shut_down();
+        Ok(Async::Ready(()))
+    }
+}
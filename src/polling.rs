@@ -0,0 +1,122 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{task, Future, Stream};
+use futures::sync::oneshot;
+use futures::task::Task;
+use hyper::{self, Body};
+use std::mem;
+
+/// What a long-polling session has queued for its client, and the poll held
+/// open until there's something to send.
+pub(crate) struct Outbox<T> {
+    queue: Vec<T>,
+    poll: Option<oneshot::Sender<Vec<T>>>,
+    /// The task waiting for the queue to drain.
+    writer: Option<Task>,
+}
+
+impl<T> Outbox<T> {
+    pub fn new() -> Self {
+        Outbox {
+            queue: Vec::new(),
+            poll: None,
+            writer: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn queued(&self) -> &[T] {
+        &self.queue
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.queue.push(item);
+    }
+
+    /// Whether a poll is being held open.
+    pub fn is_held(&self) -> bool {
+        self.poll.is_some()
+    }
+
+    /// Holds `poll` open, returning the one it takes over from, if any.
+    pub fn hold(&mut self, poll: oneshot::Sender<Vec<T>>) -> Option<oneshot::Sender<Vec<T>>> {
+        mem::replace(&mut self.poll, Some(poll))
+    }
+
+    /// Takes everything queued.
+    pub fn take(&mut self) -> Vec<T> {
+        self.wake();
+        mem::replace(&mut self.queue, Vec::new())
+    }
+
+    /// Answers the held poll with everything queued, or with nothing at all
+    /// if `force` is set and nothing is queued. Returns whether the poll
+    /// took the items.
+    pub fn flush(&mut self, force: bool) -> bool {
+        if self.queue.is_empty() && !force {
+            return false;
+        }
+        let poll = match self.poll.take() {
+            None => return false,
+            Some(poll) => poll,
+        };
+        let items = mem::replace(&mut self.queue, Vec::new());
+        match poll.send(items) {
+            Ok(()) => {
+                self.wake();
+                true
+            }
+            Err(items) => {
+                // The client gave up on the poll; keep the items for its
+                // next one.
+                self.queue = items;
+                false
+            }
+        }
+    }
+
+    /// Has the current task notified once the queue drains or the session
+    /// ends.
+    pub fn park(&mut self) {
+        self.writer = Some(task::current());
+    }
+
+    fn wake(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.notify();
+        }
+    }
+}
+
+impl<T> Drop for Outbox<T> {
+    fn drop(&mut self) {
+        self.wake();
+    }
+}
+
+/// Reads a request body, resolving to `None` if it's longer than
+/// `max_payload` bytes. Past the limit, the rest of the body is read but not
+/// kept.
+pub(crate) fn read_body(
+    body: Body,
+    max_payload: usize,
+) -> Box<Future<Item = Option<Vec<u8>>, Error = hyper::Error>> {
+    Box::new(body.fold(Some(Vec::new()), move |buf, chunk| -> Result<_, hyper::Error> {
+        Ok(match buf {
+            Some(mut buf) if buf.len() + chunk.len() <= max_payload => {
+                buf.extend_from_slice(&chunk);
+                Some(buf)
+            }
+            _ => None,
+        })
+    }))
+}
@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{future, Future, Sink, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+use std::fmt;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use websocket::client::async::Client;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use super::Omitted;
+use super::channel::{spawn_channel, QUEUE_CAPACITY};
+use super::command::{forward_output, reap, spawn_writer, CommandSpec};
+use super::server::{WsContext, WsEndpoint};
+
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// A websocketd-style endpoint that runs a command for each connection.
+/// Every text or binary message is written to the command's stdin followed
+/// by a newline, and every line it writes to stdout is sent as a text
+/// message. Lines on stderr are logged.
+///
+/// The command learns about the handshake through CGI-style environment
+/// variables: `PATH_INFO`, `QUERY_STRING`, `REQUEST_URI`, `REMOTE_ADDR`,
+/// `REMOTE_PORT`, `WEBSOCKET_PROTOCOL` if a subprotocol was selected, and an
+/// `HTTP_*` variable per request header.
+///
+/// When the command closes its stdout, usually by exiting, the connection
+/// is closed with 1000. When the connection closes, the command is killed.
+#[derive(Clone)]
+pub struct ProcessBridge {
+    handle: Handle,
+    command: CommandSpec,
+}
+
+impl fmt::Debug for ProcessBridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProcessBridge")
+            .field("handle", &Omitted)
+            .field("command", &self.command)
+            .finish()
+    }
+}
+
+impl ProcessBridge {
+    pub fn new(handle: &Handle, program: &str) -> Self {
+        ProcessBridge {
+            handle: handle.clone(),
+            command: CommandSpec::new(program),
+        }
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.command.arg(arg);
+        self
+    }
+
+    /// Sets an extra environment variable for the command. Variables
+    /// describing the handshake take precedence.
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.command.env(key, value);
+        self
+    }
+
+    pub fn current_dir<P>(mut self, dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.command.current_dir(dir.as_ref());
+        self
+    }
+
+    fn command(&self, ctx: &WsContext) -> Command {
+        let mut command = self.command.command();
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        for header in ctx.headers().iter() {
+            let key = format!("HTTP_{}", header.name().to_uppercase().replace('-', "_"));
+            command.env(key, header.value_string());
+        }
+        command
+            .env("PATH_INFO", ctx.path())
+            .env("QUERY_STRING", ctx.query().unwrap_or(""))
+            .env("REQUEST_URI", ctx.uri().to_string())
+            .env("REMOTE_ADDR", ctx.remote_addr().ip().to_string())
+            .env("REMOTE_PORT", ctx.remote_addr().port().to_string());
+        if let Some(protocol) = ctx.protocol() {
+            command.env("WEBSOCKET_PROTOCOL", protocol);
+        }
+        command
+    }
+}
+
+impl WsEndpoint for ProcessBridge {
+    type Future = Box<Future<Item = (), Error = WebSocketError>>;
+
+    fn handle(&self, ctx: WsContext, websocket: Client<TcpStream>) -> Self::Future {
+        let mut child = match self.command(&ctx).spawn() {
+            Err(err) => {
+                let program = self.command.program();
+                error!("hyper-websocket: could not start {}: {}", program, err);
+                let data = CloseData::new(CLOSE_INTERNAL_ERROR, "Could not start process".into());
+                return Box::new(websocket.send(OwnedMessage::Close(Some(data))).map(|_| ()));
+            }
+            Ok(child) => child,
+        };
+        let pid = child.id();
+        debug!("hyper-websocket: started {} as process {}", self.command.program(), pid);
+        let (sender, receiver) = spawn_channel(&self.handle, websocket, QUEUE_CAPACITY);
+
+        let stdin = child.stdin.take().expect("hyper-websocket: child stdin missing");
+        let stdin_tx = spawn_writer(stdin, QUEUE_CAPACITY);
+
+        let stdout = child.stdout.take().expect("hyper-websocket: child stdout missing");
+        let (stdout_tx, stdout_rx) = mpsc::channel::<String>(QUEUE_CAPACITY);
+        thread::spawn(move || {
+            let mut stdout = BufReader::new(stdout);
+            let mut stdout_tx = stdout_tx;
+            loop {
+                let mut line = Vec::new();
+                match stdout.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                if line.ends_with(b"\n") {
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                }
+                let line = String::from_utf8_lossy(&line).into_owned();
+                stdout_tx = match stdout_tx.send(line).wait() {
+                    Err(_) => break,
+                    Ok(stdout_tx) => stdout_tx,
+                };
+            }
+        });
+
+        let stderr = child.stderr.take().expect("hyper-websocket: child stderr missing");
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines() {
+                match line {
+                    Err(_) => break,
+                    Ok(line) => info!("hyper-websocket: process {} stderr: {}", pid, line),
+                }
+            }
+        });
+
+        forward_output(&self.handle, stdout_rx.map(OwnedMessage::Text), sender);
+
+        // Waiting for room in the stdin queue stops reading the socket while
+        // the command is behind on its input.
+        let upstream = receiver
+            .fold(Some(stdin_tx), |stdin_tx, msg| {
+                let stdin_tx = match stdin_tx {
+                    // The command has stopped reading, so the rest is dropped.
+                    None => return Either::A(future::ok::<_, WebSocketError>(None)),
+                    Some(stdin_tx) => stdin_tx,
+                };
+                let mut data = match msg {
+                    OwnedMessage::Text(text) => text.into_bytes(),
+                    OwnedMessage::Binary(data) => data,
+                    _ => return Either::A(future::ok(Some(stdin_tx))),
+                };
+                data.push(b'\n');
+                Either::B(stdin_tx.send(data).then(|result| Ok(result.ok())))
+            })
+            .map(|_stdin_tx| ())
+            .then(move |result| {
+                reap(child, |child| {
+                    let _ = child.kill();
+                });
+                result
+            });
+        Box::new(upstream)
+    }
+}
+
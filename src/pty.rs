@@ -0,0 +1,378 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{future, Future, Sink, Stream};
+use futures::future::{Either, Loop};
+use futures::sync::{mpsc, oneshot};
+use libc;
+use serde_json::{self, Value};
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use websocket::client::async::Client;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use super::Omitted;
+use super::channel::{spawn_channel, QUEUE_CAPACITY};
+use super::command::{forward_output, reap, spawn_writer, CommandSpec};
+use super::server::{WsContext, WsEndpoint};
+
+const READ_SIZE: usize = 4096;
+const DEFAULT_COLS: u16 = 80;
+const DEFAULT_ROWS: u16 = 24;
+const DEFAULT_TERM: &str = "xterm-256color";
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+/// A ttyd-style endpoint that runs a command in a fresh pseudo-terminal for
+/// each connection.
+///
+/// Terminal output is sent as binary messages, and binary messages from the
+/// client are typed into the terminal. Text messages are JSON control
+/// messages: `{"type":"input","data":"..."}` types text, and
+/// `{"type":"resize","cols":120,"rows":40}` resizes the terminal.
+///
+/// When the command and everything it started have exited, the connection
+/// is closed with 1000. When the connection closes, or no input arrives for
+/// the idle timeout, the command's whole session is killed.
+#[derive(Clone)]
+pub struct PtyBridge {
+    handle: Handle,
+    command: CommandSpec,
+    cols: u16,
+    rows: u16,
+    idle_timeout: Option<Duration>,
+}
+
+impl fmt::Debug for PtyBridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PtyBridge")
+            .field("handle", &Omitted)
+            .field("command", &self.command)
+            .field("cols", &self.cols)
+            .field("rows", &self.rows)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+impl PtyBridge {
+    pub fn new(handle: &Handle, program: &str) -> Self {
+        let mut command = CommandSpec::new(program);
+        command.env("TERM", DEFAULT_TERM);
+        PtyBridge {
+            handle: handle.clone(),
+            command: command,
+            cols: DEFAULT_COLS,
+            rows: DEFAULT_ROWS,
+            idle_timeout: None,
+        }
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.command.arg(arg);
+        self
+    }
+
+    /// Sets an environment variable for the command. `TERM` defaults to
+    /// `xterm-256color`.
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.command.env(key, value);
+        self
+    }
+
+    pub fn current_dir<P>(mut self, dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        self.command.current_dir(dir.as_ref());
+        self
+    }
+
+    /// The terminal size until the client sends a resize. Defaults to 80
+    /// columns by 24 rows.
+    pub fn size(mut self, cols: u16, rows: u16) -> Self {
+        self.cols = cols;
+        self.rows = rows;
+        self
+    }
+
+    /// How long a session may go without input before its command is
+    /// killed. Unlimited by default.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    fn spawn(&self) -> io::Result<Terminal> {
+        let (master, slave) = open_pty(self.cols, self.rows)?;
+        let reader = master.try_clone()?;
+        let writer = master.try_clone()?;
+
+        let mut command = self.command.command();
+        command
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave))
+            .before_exec(|| {
+                // Start a new session, with the terminal as its controlling
+                // terminal.
+                unsafe {
+                    if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        let child = command.spawn()?;
+
+        // Dropping `command` closed our copies of the slave, so reads from
+        // the master fail once the session is gone.
+        Ok(Terminal {
+            master: master,
+            reader: reader,
+            writer: writer,
+            child: child,
+        })
+    }
+}
+
+impl WsEndpoint for PtyBridge {
+    type Future = Box<Future<Item = (), Error = WebSocketError>>;
+
+    fn handle(&self, _ctx: WsContext, websocket: Client<TcpStream>) -> Self::Future {
+        let terminal = match self.spawn() {
+            Err(err) => {
+                let program = self.command.program();
+                error!("hyper-websocket: could not start {} in a terminal: {}", program, err);
+                let data = CloseData::new(CLOSE_INTERNAL_ERROR, "Could not start terminal".into());
+                return Box::new(websocket.send(OwnedMessage::Close(Some(data))).map(|_| ()));
+            }
+            Ok(terminal) => terminal,
+        };
+        let Terminal {
+            master,
+            reader,
+            writer,
+            child,
+        } = terminal;
+        let pid = child.id();
+        let program = self.command.program();
+        debug!("hyper-websocket: started {} as process {} in a terminal", program, pid);
+        let (sender, receiver) = spawn_channel(&self.handle, websocket, QUEUE_CAPACITY);
+
+        let input_tx = spawn_writer(writer, QUEUE_CAPACITY);
+
+        let (output_tx, output_rx) = mpsc::channel::<Vec<u8>>(QUEUE_CAPACITY);
+        thread::spawn(move || {
+            let mut reader = reader;
+            let mut output_tx = output_tx;
+            let mut buf = [0; READ_SIZE];
+            loop {
+                let len = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    // Linux reports the session ending as EIO.
+                    Err(_) => break,
+                };
+                output_tx = match output_tx.send(buf[..len].to_vec()).wait() {
+                    Err(_) => break,
+                    Ok(output_tx) => output_tx,
+                };
+            }
+        });
+
+        let output = output_rx.map(OwnedMessage::Binary);
+        forward_output(&self.handle, output, sender.clone());
+
+        let last_input = Rc::new(Cell::new(Instant::now()));
+        let cancel_idle = match self.idle_timeout {
+            None => None,
+            Some(idle_timeout) => {
+                let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
+                let closer = sender.clone();
+                let idle = watch_idle(&self.handle, last_input.clone(), idle_timeout).map(
+                    move |()| {
+                        debug!("hyper-websocket: terminal process {} idle timeout", pid);
+                        let _ = closer.close(CLOSE_NORMAL, "Idle timeout");
+                        kill_session(pid);
+                    },
+                );
+                self.handle.spawn(idle.select2(cancel_rx).then(|_| Ok(())));
+                Some(cancel_tx)
+            }
+        };
+
+        let upstream = receiver
+            .fold(Some(input_tx), move |input_tx, msg| {
+                last_input.set(Instant::now());
+                let data = match msg {
+                    OwnedMessage::Binary(data) => data,
+                    OwnedMessage::Text(text) => match Control::parse(&text) {
+                        Some(Control::Input(text)) => text.into_bytes(),
+                        Some(Control::Resize(cols, rows)) => {
+                            if let Err(err) = resize(&master, cols, rows) {
+                                warn!("hyper-websocket: terminal resize error: {}", err);
+                            }
+                            return Either::A(future::ok::<_, WebSocketError>(input_tx));
+                        }
+                        None => {
+                            debug!("hyper-websocket: ignoring malformed terminal message");
+                            return Either::A(future::ok(input_tx));
+                        }
+                    },
+                    _ => return Either::A(future::ok(input_tx)),
+                };
+                match input_tx {
+                    // Once the terminal stops taking input, the rest is dropped.
+                    None => Either::A(future::ok(None)),
+                    // Waiting for room stops reading the socket while the
+                    // terminal is behind on its input.
+                    Some(input_tx) => {
+                        Either::B(input_tx.send(data).then(|result| Ok(result.ok())))
+                    }
+                }
+            })
+            .map(|_input_tx| ())
+            .then(move |result| {
+                drop(cancel_idle);
+                reap(child, |child| kill_session(child.id()));
+                result
+            });
+        Box::new(upstream)
+    }
+}
+
+struct Terminal {
+    /// Kept for resizing.
+    master: File,
+    reader: File,
+    writer: File,
+    child: Child,
+}
+
+enum Control {
+    Input(String),
+    Resize(u16, u16),
+}
+
+impl Control {
+    fn parse(text: &str) -> Option<Self> {
+        let value: Value = match serde_json::from_str(text) {
+            Err(_) => return None,
+            Ok(value) => value,
+        };
+        match value.get("type").and_then(Value::as_str) {
+            Some("input") => {
+                let data = value.get("data").and_then(Value::as_str);
+                data.map(|data| Control::Input(data.to_owned()))
+            }
+            Some("resize") => {
+                let dimension = |name| match value.get(name).and_then(Value::as_u64) {
+                    Some(n) if n > 0 && n <= u64::from(u16::max_value()) => Some(n as u16),
+                    _ => None,
+                };
+                match (dimension("cols"), dimension("rows")) {
+                    (Some(cols), Some(rows)) => Some(Control::Resize(cols, rows)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Opens a pseudo-terminal of the given size, returning its master and
+/// slave sides.
+fn open_pty(cols: u16, rows: u16) -> io::Result<(File, File)> {
+    let master = unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        File::from_raw_fd(fd)
+    };
+    let fd = master.as_raw_fd();
+    let mut name = [0 as libc::c_char; 128];
+    unsafe {
+        // Keep the master out of the command, which would otherwise hold the
+        // terminal open after the session ends.
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::grantpt(fd) == -1 || libc::unlockpt(fd) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let err = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+        if err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) }.to_string_lossy().into_owned();
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(name)?;
+    resize(&master, cols, rows)?;
+    Ok((master, slave))
+}
+
+fn resize(master: &File, cols: u16, rows: u16) -> io::Result<()> {
+    let size = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Resolves once `idle_timeout` has passed since the last input.
+fn watch_idle(
+    handle: &Handle,
+    last_input: Rc<Cell<Instant>>,
+    idle_timeout: Duration,
+) -> Box<Future<Item = (), Error = ()>> {
+    let handle = handle.clone();
+    Box::new(future::loop_fn((), move |()| {
+        let deadline = last_input.get() + idle_timeout;
+        if Instant::now() >= deadline {
+            return Either::A(future::ok(Loop::Break(())));
+        }
+        match Timeout::new_at(deadline, &handle) {
+            Err(err) => {
+                error!("hyper-websocket: terminal idle timer error: {}", err);
+                Either::A(future::err(()))
+            }
+            Ok(timeout) => Either::B(timeout.map(Loop::Continue).map_err(|_| ())),
+        }
+    }))
+}
+
+/// Kills everything in the session the command leads.
+fn kill_session(pid: u32) {
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
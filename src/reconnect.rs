@@ -0,0 +1,371 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use rand;
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use super::Omitted;
+use super::client::WsClientHandshake;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReconnectEvent {
+    State(ConnectionState),
+    Message(OwnedMessage),
+}
+
+/// What to do with outbound messages sent while no connection is established.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferPolicy {
+    Drop,
+    /// Keep up to this many messages, discarding the oldest once full.
+    Buffer(usize),
+}
+
+#[derive(Clone, Debug)]
+pub struct ReconnectOptions {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    buffer_policy: BufferPolicy,
+    on_connect: Vec<OwnedMessage>,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        ReconnectOptions {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            jitter: 0.5,
+            buffer_policy: BufferPolicy::Buffer(1024),
+            on_connect: Vec::new(),
+        }
+    }
+}
+
+impl ReconnectOptions {
+    pub fn new() -> Self {
+        ReconnectOptions::default()
+    }
+
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sets the fraction of each backoff delay, between 0 and 1, that is
+    /// randomized away.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.max(0.0).min(1.0);
+        self
+    }
+
+    pub fn buffer_policy(mut self, policy: BufferPolicy) -> Self {
+        self.buffer_policy = policy;
+        self
+    }
+
+    /// Adds a message to send first on every connection, ahead of anything
+    /// buffered while disconnected. Copies left unsent when a connection
+    /// drops are replaced by fresh ones on the next.
+    pub fn on_connect(mut self, msg: OwnedMessage) -> Self {
+        self.on_connect.push(msg);
+        self
+    }
+}
+
+pub struct ReconnectingClient {
+    sender: ReconnectSender,
+    events: UnboundedReceiver<ReconnectEvent>,
+}
+
+impl fmt::Debug for ReconnectingClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ReconnectingClient").field(&Omitted).finish()
+    }
+}
+
+impl ReconnectingClient {
+    pub fn spawn(
+        handle: &Handle,
+        addr: SocketAddr,
+        handshake: WsClientHandshake,
+        options: ReconnectOptions,
+    ) -> Self {
+        let (outgoing_tx, outgoing_rx) = mpsc::unbounded();
+        let (events_tx, events_rx) = mpsc::unbounded();
+
+        let mut driver = ReconnectDriver {
+            handle: handle.clone(),
+            addr: addr,
+            handshake: handshake,
+            backoff: options.initial_backoff,
+            options: options,
+            outgoing: outgoing_rx,
+            replay: VecDeque::new(),
+            pending: VecDeque::new(),
+            events: events_tx,
+            state: DriverState::Closed,
+            shutdown: false,
+        };
+        driver.dial();
+        handle.spawn(driver);
+
+        ReconnectingClient {
+            sender: ReconnectSender(outgoing_tx),
+            events: events_rx,
+        }
+    }
+
+    pub fn sender(&self) -> ReconnectSender {
+        self.sender.clone()
+    }
+
+    pub fn send(&self, msg: OwnedMessage) -> Result<(), OwnedMessage> {
+        self.sender.send(msg)
+    }
+}
+
+impl Stream for ReconnectingClient {
+    type Item = ReconnectEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.events.poll()
+    }
+}
+
+#[derive(Clone)]
+pub struct ReconnectSender(UnboundedSender<OwnedMessage>);
+
+impl fmt::Debug for ReconnectSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ReconnectSender").field(&Omitted).finish()
+    }
+}
+
+impl ReconnectSender {
+    /// Queues a message for delivery. The message is handed back if the
+    /// client has shut down.
+    pub fn send(&self, msg: OwnedMessage) -> Result<(), OwnedMessage> {
+        self.0.unbounded_send(msg).map_err(|err| err.into_inner())
+    }
+}
+
+struct ReconnectDriver {
+    handle: Handle,
+    addr: SocketAddr,
+    handshake: WsClientHandshake,
+    options: ReconnectOptions,
+    backoff: Duration,
+    outgoing: UnboundedReceiver<OwnedMessage>,
+    /// The `on_connect` messages not yet written to the current connection,
+    /// which go out ahead of everything in `pending`.
+    replay: VecDeque<OwnedMessage>,
+    pending: VecDeque<OwnedMessage>,
+    events: UnboundedSender<ReconnectEvent>,
+    state: DriverState,
+    shutdown: bool,
+}
+
+enum DriverState {
+    Connecting(Box<Future<Item = Client<TcpStream>, Error = WebSocketError>>),
+    Connected(Client<TcpStream>),
+    Waiting(Timeout),
+    Closed,
+}
+
+impl ReconnectDriver {
+    fn is_connected(&self) -> bool {
+        match self.state {
+            DriverState::Connected(_) => true,
+            _ => false,
+        }
+    }
+
+    fn emit(&mut self, event: ReconnectEvent) {
+        if self.events.unbounded_send(event).is_err() {
+            // Nobody is listening anymore; stop reconnecting.
+            self.shutdown = true;
+        }
+    }
+
+    fn dial(&mut self) {
+        let handshake = self.handshake.clone();
+        let connect = TcpStream::connect(&self.addr, &self.handle)
+            .map_err(WebSocketError::from)
+            .and_then(move |tcp| handshake.connect(tcp))
+            .map(|(client, _response)| client);
+        self.state = DriverState::Connecting(Box::new(connect));
+        self.emit(ReconnectEvent::State(ConnectionState::Connecting));
+    }
+
+    fn connected(&mut self, client: Client<TcpStream>) {
+        self.backoff = self.options.initial_backoff;
+        // Whatever is left from the last connection is replaced, not repeated.
+        self.replay = self.options.on_connect.iter().cloned().collect();
+        self.state = DriverState::Connected(client);
+        self.emit(ReconnectEvent::State(ConnectionState::Connected));
+    }
+
+    fn disconnected(&mut self) {
+        self.drop_pending_by_policy();
+        self.emit(ReconnectEvent::State(ConnectionState::Disconnected));
+        let delay = self.next_delay();
+        match Timeout::new(delay, &self.handle) {
+            Ok(timeout) => self.state = DriverState::Waiting(timeout),
+            Err(_) => self.dial(),
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let base = self.backoff;
+        let doubled = base.checked_add(base).unwrap_or(self.options.max_backoff);
+        self.backoff = if doubled > self.options.max_backoff {
+            self.options.max_backoff
+        } else {
+            doubled
+        };
+
+        let base_ms = base.as_secs() * 1000 + u64::from(base.subsec_nanos() / 1_000_000);
+        let jitter_ms = (base_ms as f64 * self.options.jitter * rand::random::<f64>()) as u64;
+        Duration::from_millis(base_ms - jitter_ms)
+    }
+
+    fn drop_pending_by_policy(&mut self) {
+        match self.options.buffer_policy {
+            BufferPolicy::Drop => self.pending.clear(),
+            BufferPolicy::Buffer(capacity) => while self.pending.len() > capacity {
+                self.pending.pop_front();
+            },
+        }
+    }
+
+    /// Moves messages from the user-facing channel into the pending queue.
+    /// Returns `false` once every sender has been dropped.
+    fn poll_outgoing(&mut self) -> bool {
+        loop {
+            match self.outgoing.poll() {
+                Ok(Async::Ready(Some(msg))) => {
+                    self.pending.push_back(msg);
+                    if !self.is_connected() {
+                        self.drop_pending_by_policy();
+                    }
+                }
+                Ok(Async::NotReady) => return true,
+                Ok(Async::Ready(None)) | Err(()) => return false,
+            }
+        }
+    }
+
+    /// Returns whether any progress was made, so the caller knows whether to
+    /// poll again.
+    fn poll_client(&mut self, client: &mut Client<TcpStream>) -> Result<bool, WebSocketError> {
+        if start_send_all(client, &mut self.replay)? {
+            start_send_all(client, &mut self.pending)?;
+        }
+        client.poll_complete()?;
+
+        match client.poll()? {
+            Async::NotReady => Ok(false),
+            Async::Ready(None) => Err(WebSocketError::NoDataAvailable),
+            Async::Ready(Some(OwnedMessage::Ping(data))) => {
+                self.pending.push_front(OwnedMessage::Pong(data));
+                Ok(true)
+            }
+            Async::Ready(Some(msg)) => {
+                self.emit(ReconnectEvent::Message(msg));
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Starts sending messages off the front of `queue` until the client can take
+/// no more. Returns whether the queue was emptied.
+fn start_send_all(
+    client: &mut Client<TcpStream>,
+    queue: &mut VecDeque<OwnedMessage>,
+) -> Result<bool, WebSocketError> {
+    while let Some(msg) = queue.pop_front() {
+        if let AsyncSink::NotReady(msg) = client.start_send(msg)? {
+            queue.push_front(msg);
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+impl Future for ReconnectDriver {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if self.shutdown || !self.poll_outgoing() {
+                return Ok(Async::Ready(()));
+            }
+
+            let progressed = match mem::replace(&mut self.state, DriverState::Closed) {
+                DriverState::Connecting(mut connect) => match connect.poll() {
+                    Ok(Async::NotReady) => {
+                        self.state = DriverState::Connecting(connect);
+                        false
+                    }
+                    Ok(Async::Ready(client)) => {
+                        self.connected(client);
+                        true
+                    }
+                    Err(_) => {
+                        self.disconnected();
+                        true
+                    }
+                },
+                DriverState::Connected(mut client) => match self.poll_client(&mut client) {
+                    Ok(progressed) => {
+                        self.state = DriverState::Connected(client);
+                        progressed
+                    }
+                    Err(_) => {
+                        self.disconnected();
+                        true
+                    }
+                },
+                DriverState::Waiting(mut timeout) => match timeout.poll() {
+                    Ok(Async::NotReady) => {
+                        self.state = DriverState::Waiting(timeout);
+                        false
+                    }
+                    _ => {
+                        self.dial();
+                        true
+                    }
+                },
+                DriverState::Closed => return Ok(Async::Ready(())),
+            };
+
+            if !progressed {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{future, Future};
+use futures::sync::oneshot;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::SystemTime;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+
+use super::channel::{self, WsReceiver, WsSendError, WsSender};
+use super::server::WsContext;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// Makes an ID for connections tracked without a `ConnectionRegistry`.
+    /// Mixing these with registry-assigned IDs in one `Hub` risks collisions.
+    pub fn new(id: u64) -> Self {
+        ConnectionId(id)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A snapshot of what the registry knows about one connection.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    id: ConnectionId,
+    remote_addr: SocketAddr,
+    path: String,
+    protocol: Option<String>,
+    started_at: SystemTime,
+    tags: BTreeMap<String, String>,
+}
+
+impl ConnectionInfo {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(String::as_str)
+    }
+
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(String::as_str)
+    }
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    sender: WsSender,
+    abort: oneshot::Sender<()>,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    entries: HashMap<ConnectionId, Entry>,
+}
+
+/// Keeps track of live connections so they can be enumerated, messaged and
+/// closed from outside their handlers. Clones share the same registry.
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+    inner: Rc<RefCell<Registry>>,
+}
+
+impl fmt::Debug for ConnectionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectionRegistry")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        ConnectionRegistry::default()
+    }
+
+    /// Splits `client` as `spawn_channel` does and records it under a fresh
+    /// ID. The connection is forgotten again once its driver task finishes.
+    pub fn register<T>(
+        &self,
+        handle: &Handle,
+        ctx: &WsContext,
+        client: Client<T>,
+        capacity: usize,
+    ) -> (ConnectionId, WsSender, WsReceiver)
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let (driver, sender, receiver) = channel::channel(client, capacity, false);
+        let (abort_tx, abort_rx) = oneshot::channel();
+
+        let id = {
+            let mut inner = self.inner.borrow_mut();
+            let id = ConnectionId(inner.next_id);
+            inner.next_id += 1;
+            inner.entries.insert(
+                id,
+                Entry {
+                    info: ConnectionInfo {
+                        id: id,
+                        remote_addr: ctx.remote_addr(),
+                        path: ctx.path().to_owned(),
+                        protocol: ctx.protocol().map(str::to_owned),
+                        started_at: SystemTime::now(),
+                        tags: BTreeMap::new(),
+                    },
+                    sender: sender.clone(),
+                    abort: abort_tx,
+                },
+            );
+            id
+        };
+
+        let inner = self.inner.clone();
+        // A dropped abort handle must not end the connection, only a fired one.
+        let abort = abort_rx.or_else(|_| future::empty::<(), ()>());
+        handle.spawn(driver.select2(abort).then(move |_| {
+            inner.borrow_mut().entries.remove(&id);
+            Ok::<(), ()>(())
+        }));
+
+        (id, sender, receiver)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.borrow().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Lists every live connection, ordered by ID.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut infos = self.inner
+            .borrow()
+            .entries
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect::<Vec<_>>();
+        infos.sort_by_key(ConnectionInfo::id);
+        infos
+    }
+
+    pub fn get(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        self.inner.borrow().entries.get(&id).map(|entry| entry.info.clone())
+    }
+
+    /// Finds the connections whose tag `key` is set to `value`, ordered by ID.
+    pub fn find_by_tag(&self, key: &str, value: &str) -> Vec<ConnectionId> {
+        let mut ids = self.inner
+            .borrow()
+            .entries
+            .values()
+            .filter(|entry| entry.info.tag(key) == Some(value))
+            .map(|entry| entry.info.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    /// Returns `false` if there is no such connection.
+    pub fn set_tag<K, V>(&self, id: ConnectionId, key: K, value: V) -> bool
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        match self.inner.borrow_mut().entries.get_mut(&id) {
+            None => false,
+            Some(entry) => {
+                entry.info.tags.insert(key.into(), value.into());
+                true
+            }
+        }
+    }
+
+    pub fn remove_tag(&self, id: ConnectionId, key: &str) -> Option<String> {
+        self.inner
+            .borrow_mut()
+            .entries
+            .get_mut(&id)
+            .and_then(|entry| entry.info.tags.remove(key))
+    }
+
+    pub fn sender(&self, id: ConnectionId) -> Option<WsSender> {
+        self.inner.borrow().entries.get(&id).map(|entry| entry.sender.clone())
+    }
+
+    /// Queues a message without waiting; unknown connections count as closed.
+    pub fn send(&self, id: ConnectionId, msg: OwnedMessage) -> Result<(), WsSendError> {
+        match self.sender(id) {
+            None => Err(WsSendError::Closed(msg)),
+            Some(mut sender) => sender.try_send(msg),
+        }
+    }
+
+    /// Starts the close handshake with a connection.
+    pub fn close(&self, id: ConnectionId, code: u16, reason: &str) -> Result<(), WsSendError> {
+        match self.sender(id) {
+            None => Err(WsSendError::Closed(OwnedMessage::Close(None))),
+            Some(sender) => sender.close(code, reason),
+        }
+    }
+
+    /// Drops a connection on the spot, without a close handshake. Returns
+    /// `false` if there is no such connection.
+    pub fn disconnect(&self, id: ConnectionId) -> bool {
+        let entry = match self.inner.borrow_mut().entries.remove(&id) {
+            None => return false,
+            Some(entry) => entry,
+        };
+        // The driver may have finished in the meantime; either way the
+        // connection is gone.
+        let _ = entry.abort.send(());
+        true
+    }
+}
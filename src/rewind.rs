@@ -0,0 +1,84 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::BytesMut;
+use futures::Poll;
+use std::cmp;
+use std::io::{self, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// An IO object that yields some already-read bytes before reading from the
+/// underlying IO object again. This lets a connection be handed back to
+/// `hyper::server::Http` without losing data that was read past the end of
+/// the WebSocket handshake request, such as a pipelined follow-up request.
+#[derive(Clone, Debug)]
+pub struct Rewind<T> {
+    io: T,
+    buf: BytesMut,
+}
+
+impl<T> Rewind<T> {
+    pub fn new(io: T, buf: BytesMut) -> Self {
+        Rewind { io: io, buf: buf }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_inner(self) -> (T, BytesMut) {
+        (self.io, self.buf)
+    }
+}
+
+impl<T> Read for Rewind<T>
+where
+    T: Read,
+{
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            return self.io.read(dst);
+        }
+
+        let len = cmp::min(dst.len(), self.buf.len());
+        dst[..len].copy_from_slice(&self.buf.split_to(len));
+        Ok(len)
+    }
+}
+
+impl<T> Write for Rewind<T>
+where
+    T: Write,
+{
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.io.write(src)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T> AsyncRead for Rewind<T>
+where
+    T: AsyncRead,
+{
+}
+
+impl<T> AsyncWrite for Rewind<T>
+where
+    T: AsyncWrite,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
@@ -0,0 +1,252 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{future, Future, IntoFuture};
+use hyper::{Request, StatusCode};
+use std::fmt;
+use std::usize;
+use tokio_core::net::TcpStream;
+use websocket::client::async::Client;
+use websocket::result::WebSocketError;
+
+use super::{Omitted, WsHandshake, WsResponse};
+use super::limit::SizeLimited;
+use super::server::{WsContext, WsEndpoint};
+
+pub type RouteFuture = Box<Future<Item = (), Error = WebSocketError>>;
+
+type RouteHandler = Fn(WsContext, SizeLimited<Client<TcpStream>>) -> RouteFuture;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OriginPolicy {
+    Any,
+    /// Only accept handshakes whose `Origin` header exactly matches one of
+    /// these values. Handshakes without an `Origin` header are rejected.
+    Allow(Vec<String>),
+}
+
+impl OriginPolicy {
+    fn permits(&self, origin: Option<&[u8]>) -> bool {
+        match *self {
+            OriginPolicy::Any => true,
+            OriginPolicy::Allow(ref origins) => match origin {
+                None => false,
+                Some(origin) => origins.iter().any(|allowed| allowed.as_bytes() == origin),
+            },
+        }
+    }
+}
+
+pub struct WsRoute {
+    handler: Box<RouteHandler>,
+    protocols: Vec<String>,
+    origin: OriginPolicy,
+    max_message_size: usize,
+    auth: Option<Box<Fn(&Request) -> bool>>,
+}
+
+impl fmt::Debug for WsRoute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WsRoute")
+            .field("handler", &Omitted)
+            .field("protocols", &self.protocols)
+            .field("origin", &self.origin)
+            .field("max_message_size", &self.max_message_size)
+            .field("auth", &self.auth.as_ref().map(|_| Omitted))
+            .finish()
+    }
+}
+
+impl WsRoute {
+    pub fn new<H, F>(handler: H) -> Self
+    where
+        H: Fn(WsContext, SizeLimited<Client<TcpStream>>) -> F + 'static,
+        F: IntoFuture<Item = (), Error = WebSocketError> + 'static,
+    {
+        let handler: Box<RouteHandler> = Box::new(move |ctx, websocket| {
+            Box::new(handler(ctx, websocket).into_future()) as RouteFuture
+        });
+        WsRoute {
+            handler: handler,
+            protocols: Vec::new(),
+            origin: OriginPolicy::Any,
+            max_message_size: usize::MAX,
+            auth: None,
+        }
+    }
+
+    /// Restricts the subprotocols this route will speak. When set, the first
+    /// protocol requested by the client that appears here is selected, and
+    /// handshakes that request none of them are rejected.
+    pub fn protocols<I, P>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn origin(mut self, origin: OriginPolicy) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Closes the connection with 1009 once the client sends a message
+    /// larger than this many bytes. See `SizeLimited` for why this doesn't
+    /// limit memory use. Unlimited by default.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn auth<A>(mut self, auth: A) -> Self
+    where
+        A: Fn(&Request) -> bool + 'static,
+    {
+        self.auth = Some(Box::new(auth));
+        self
+    }
+
+    fn negotiate(&self, req: &Request, handshake: WsHandshake) -> WsResponse {
+        let origin = req.headers().get_raw("origin").and_then(|raw| raw.one());
+        if !self.origin.permits(origin) {
+            return WsResponse::reject_with_status(handshake, StatusCode::Forbidden);
+        }
+
+        if let Some(ref auth) = self.auth {
+            if !auth(req) {
+                return WsResponse::reject_with_status(handshake, StatusCode::Unauthorized);
+            }
+        }
+
+        if self.protocols.is_empty() {
+            return WsResponse::accept(handshake);
+        }
+
+        let protocol = handshake
+            .protocols()
+            .iter()
+            .find(|&protocol| self.protocols.contains(protocol))
+            .cloned();
+        match protocol {
+            None => WsResponse::reject(handshake),
+            Some(protocol) => WsResponse::accept(handshake.use_protocol(protocol)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WsRouter {
+    routes: Vec<(Pattern, WsRoute)>,
+}
+
+impl WsRouter {
+    pub fn new() -> Self {
+        WsRouter::default()
+    }
+
+    /// Adds a route. Patterns are matched segment by segment: `:name` captures
+    /// a single segment, and a trailing `*` captures the rest of the path under
+    /// the name `*`. Routes are tried in the order they were added.
+    pub fn route(mut self, pattern: &str, route: WsRoute) -> Self {
+        self.routes.push((Pattern::parse(pattern), route));
+        self
+    }
+
+    fn find(&self, path: &str) -> Option<(&WsRoute, Vec<(String, String)>)> {
+        self.routes
+            .iter()
+            .filter_map(|&(ref pattern, ref route)| {
+                pattern.matches(path).map(|params| (route, params))
+            })
+            .next()
+    }
+}
+
+impl WsEndpoint for WsRouter {
+    type Future = RouteFuture;
+
+    fn negotiate(&self, req: &Request, handshake: WsHandshake) -> WsResponse {
+        match self.find(req.path()) {
+            None => WsResponse::reject_with_status(handshake, StatusCode::NotFound),
+            Some((route, _)) => route.negotiate(req, handshake),
+        }
+    }
+
+    fn handle(&self, mut ctx: WsContext, websocket: Client<TcpStream>) -> Self::Future {
+        let (route, params) = match self.find(ctx.path()) {
+            // Only reachable if a custom `WsServer::negotiate` accepted a
+            // handshake this router would have rejected.
+            None => return Box::new(future::ok(())),
+            Some(found) => found,
+        };
+        ctx.set_params(params);
+        (route.handler)(ctx, SizeLimited::new(websocket, route.max_message_size))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        let segments = split_path(pattern)
+            .map(|segment| if segment == "*" {
+                Segment::Wildcard
+            } else if segment.starts_with(':') {
+                Segment::Param(segment[1..].to_owned())
+            } else {
+                Segment::Literal(segment.to_owned())
+            })
+            .collect::<Vec<_>>();
+        let wildcard_pos = segments.iter().position(|segment| *segment == Segment::Wildcard);
+        if let Some(pos) = wildcard_pos {
+            assert!(
+                pos + 1 == segments.len(),
+                "hyper-websocket: wildcard must be the last segment of route pattern {:?}",
+                pattern
+            );
+        }
+        Pattern(segments)
+    }
+
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut segments = split_path(path);
+        for expected in &self.0 {
+            match *expected {
+                Segment::Wildcard => {
+                    let rest = segments.collect::<Vec<_>>().join("/");
+                    params.push(("*".to_owned(), rest));
+                    return Some(params);
+                }
+                Segment::Literal(ref literal) => match segments.next() {
+                    Some(segment) if segment == literal => {}
+                    _ => return None,
+                },
+                Segment::Param(ref name) => match segments.next() {
+                    Some(segment) => params.push((name.clone(), segment.to_owned())),
+                    None => return None,
+                },
+            }
+        }
+
+        if segments.next().is_some() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+fn split_path<'a>(path: &'a str) -> Box<Iterator<Item = &'a str> + 'a> {
+    Box::new(path.split('/').filter(|segment| !segment.is_empty()))
+}
@@ -0,0 +1,362 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::BytesMut;
+use futures::{future, Future, IntoFuture, Poll, Stream};
+use futures::future::Either;
+use hyper::{self, Request, Response, Uri};
+use hyper::header::Headers;
+use hyper::server::{Http, UpgradableResponse};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+use websocket::client::async::Client;
+use websocket::result::WebSocketError;
+
+use super::{Omitted, Rewind, WsHandshake, WsResponse};
+
+/// How long to stop accepting after an error that isn't down to a single
+/// connection, such as running out of file descriptors.
+const ACCEPT_BACKOFF_MS: u64 = 100;
+
+/// Everything the WebSocket handler gets to know about the request that
+/// opened its connection.
+#[derive(Clone, Debug)]
+pub struct WsContext {
+    handshake: WsHandshake,
+    remote_addr: SocketAddr,
+    uri: Uri,
+    headers: Headers,
+    params: Vec<(String, String)>,
+}
+
+impl WsContext {
+    pub fn new(
+        handshake: WsHandshake,
+        remote_addr: SocketAddr,
+        uri: Uri,
+        headers: Headers,
+    ) -> Self {
+        WsContext {
+            handshake: handshake,
+            remote_addr: remote_addr,
+            uri: uri,
+            headers: headers,
+            params: Vec::new(),
+        }
+    }
+
+    pub fn handshake(&self) -> &WsHandshake {
+        &self.handshake
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    pub fn path(&self) -> &str {
+        self.uri.path()
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.uri.query()
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn protocol(&self) -> Option<&str> {
+        self.handshake.selected_protocol()
+    }
+
+    /// Path parameters captured by a `WsRouter` route.
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|&&(ref key, _)| key == name)
+            .map(|&(_, ref value)| value.as_str())
+    }
+
+    pub(crate) fn set_params(&mut self, params: Vec<(String, String)>) {
+        self.params = params;
+    }
+}
+
+/// Something that can decide on and then handle WebSocket connections for a
+/// `WsServer`. Plain closures taking a `WsContext` and the accepted `Client`
+/// implement this, accepting every handshake unchanged.
+pub trait WsEndpoint {
+    type Future: Future<Item = (), Error = WebSocketError>;
+
+    fn negotiate(&self, _req: &Request, handshake: WsHandshake) -> WsResponse {
+        WsResponse::accept(handshake)
+    }
+
+    fn handle(&self, ctx: WsContext, websocket: Client<TcpStream>) -> Self::Future;
+}
+
+impl<H, F> WsEndpoint for H
+where
+    H: Fn(WsContext, Client<TcpStream>) -> F,
+    F: IntoFuture<Item = (), Error = WebSocketError>,
+{
+    type Future = F::Future;
+
+    fn handle(&self, ctx: WsContext, websocket: Client<TcpStream>) -> Self::Future {
+        self(ctx, websocket).into_future()
+    }
+}
+
+type Negotiate = Fn(&Request, WsHandshake) -> WsResponse;
+
+pub struct WsServer<S, H> {
+    http: Http,
+    service: Rc<S>,
+    handler: Rc<H>,
+    negotiate: Option<Rc<Negotiate>>,
+}
+
+impl<S, H> Clone for WsServer<S, H> {
+    fn clone(&self) -> Self {
+        WsServer {
+            http: self.http.clone(),
+            service: self.service.clone(),
+            handler: self.handler.clone(),
+            negotiate: self.negotiate.clone(),
+        }
+    }
+}
+
+impl<S, H> fmt::Debug for WsServer<S, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WsServer")
+            .field("http", &self.http)
+            .field("service", &Omitted)
+            .field("handler", &Omitted)
+            .field("negotiate", &Omitted)
+            .finish()
+    }
+}
+
+impl<S, H, F> WsServer<S, H>
+where
+    S: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+    H: Fn(WsContext, Client<TcpStream>) -> F + 'static,
+    F: IntoFuture<Item = (), Error = WebSocketError> + 'static,
+{
+    pub fn new(service: S, handler: H) -> Self {
+        WsServer::from_endpoint(service, handler)
+    }
+}
+
+impl<S, H> WsServer<S, H>
+where
+    S: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+    H: WsEndpoint + 'static,
+{
+    /// Like `new`, but for handlers that are a `WsEndpoint` rather than a
+    /// plain closure, such as a `WsRouter`.
+    pub fn from_endpoint(service: S, handler: H) -> Self {
+        WsServer {
+            http: Http::new(),
+            service: Rc::new(service),
+            handler: Rc::new(handler),
+            negotiate: None,
+        }
+    }
+
+    pub fn http(mut self, http: Http) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Decides whether to accept or reject each detected handshake, and lets
+    /// the subprotocol and extensions be chosen. This takes precedence over
+    /// the handler's own `WsEndpoint::negotiate`.
+    pub fn negotiate<N>(mut self, negotiate: N) -> Self
+    where
+        N: Fn(&Request, WsHandshake) -> WsResponse + 'static,
+    {
+        self.negotiate = Some(Rc::new(negotiate));
+        self
+    }
+
+    /// Accepts connections from `listener` and serves each of them. Accept
+    /// errors are logged, and accepting goes on, after a short pause if the
+    /// error wasn't down to a single connection.
+    pub fn serve(self, listener: TcpListener, handle: &Handle) -> WsServe {
+        let handle = handle.clone();
+        let accept_handle = handle.clone();
+        let serve = listener
+            .incoming()
+            .then(move |result| match result {
+                Ok(accepted) => Either::A(future::ok(Some(accepted))),
+                Err(err) => {
+                    error!("hyper-websocket: accept error: {}", err);
+                    Either::B(back_off(&err, &accept_handle).map(|()| None))
+                }
+            })
+            .for_each(move |accepted| {
+                if let Some((tcp, remote_addr)) = accepted {
+                    let io = Rewind::new(tcp, BytesMut::new());
+                    handle.spawn(self.serve_connection(&handle, io, remote_addr));
+                }
+                Ok(())
+            });
+        WsServe(Box::new(serve))
+    }
+
+    fn serve_connection(
+        &self,
+        handle: &Handle,
+        io: Rewind<TcpStream>,
+        remote_addr: SocketAddr,
+    ) -> Box<Future<Item = (), Error = ()>> {
+        let service = WsServerService {
+            inner: self.service.clone(),
+            handler: self.handler.clone(),
+            negotiate: self.negotiate.clone(),
+            remote_addr: remote_addr,
+        };
+        let handler = self.handler.clone();
+        let server = self.clone();
+        let resume_handle = handle.clone();
+
+        Box::new(
+            self.http
+                .bind_upgradable_connection(handle, io, remote_addr, service)
+                .then(move |result| {
+                    let (io, mut read_buf, (ws_res, ctx)) = match result {
+                        Err(err) => {
+                            error!("hyper-websocket: HTTP error from {}: {}", remote_addr, err);
+                            return Either::A(future::ok(()));
+                        }
+                        Ok(None) => return Either::A(future::ok(())),
+                        Ok(Some(upgrade)) => upgrade,
+                    };
+
+                    // Whatever hyper read comes before whatever it left
+                    // unread in the `Rewind`.
+                    let (tcp, unread) = io.into_inner();
+                    read_buf.extend_from_slice(&unread);
+
+                    Either::B(ws_res.send(tcp, read_buf).then(move |result| {
+                        let websocket = match result {
+                            Err(err) => {
+                                error!(
+                                    "hyper-websocket: handshake error from {}: {}",
+                                    remote_addr,
+                                    err
+                                );
+                                return Either::A(future::ok(()));
+                            }
+                            Ok(Err(io)) => {
+                                debug!("hyper-websocket: rejected handshake from {}", remote_addr);
+                                // Keep serving HTTP on the same connection.
+                                let resumed =
+                                    server.serve_connection(&resume_handle, io, remote_addr);
+                                resume_handle.spawn(resumed);
+                                return Either::A(future::ok(()));
+                            }
+                            Ok(Ok(websocket)) => websocket,
+                        };
+
+                        Either::B(handler.handle(ctx, websocket).then(move |result| {
+                            if let Err(err) = result {
+                                error!("hyper-websocket: error from {}: {}", remote_addr, err);
+                            }
+                            Ok(())
+                        }))
+                    }))
+                }),
+        )
+    }
+}
+
+/// Waits out an accept error, returning right away for errors that only
+/// affect the connection being accepted.
+fn back_off(err: &io::Error, handle: &Handle) -> Box<Future<Item = (), Error = io::Error>> {
+    match err.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted => return Box::new(future::ok(())),
+        _ => {}
+    }
+    match Timeout::new(Duration::from_millis(ACCEPT_BACKOFF_MS), handle) {
+        Err(err) => {
+            error!("hyper-websocket: accept back-off timer error: {}", err);
+            Box::new(future::ok(()))
+        }
+        Ok(timeout) => Box::new(timeout),
+    }
+}
+
+pub struct WsServe(Box<Future<Item = (), Error = io::Error>>);
+
+impl fmt::Debug for WsServe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("WsServe").field(&Omitted).finish()
+    }
+}
+
+impl Future for WsServe {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+struct WsServerService<S, H> {
+    inner: Rc<S>,
+    handler: Rc<H>,
+    negotiate: Option<Rc<Negotiate>>,
+    remote_addr: SocketAddr,
+}
+
+impl<S, H> Service for WsServerService<S, H>
+where
+    S: Service<Request = Request, Response = Response, Error = hyper::Error> + 'static,
+    H: WsEndpoint,
+{
+    type Request = Request;
+    type Response = UpgradableResponse<(WsResponse, WsContext)>;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let handshake = match WsHandshake::detect(&req) {
+            None => return Box::new(self.inner.call(req).map(UpgradableResponse::Response)),
+            Some(handshake) => handshake,
+        };
+
+        let ws_res = match self.negotiate {
+            Some(ref negotiate) => negotiate(&req, handshake),
+            None => self.handler.negotiate(&req, handshake),
+        };
+        let ctx = WsContext::new(
+            ws_res.handshake.clone(),
+            req.remote_addr().unwrap_or(self.remote_addr),
+            req.uri().clone(),
+            req.headers().clone(),
+        );
+        Box::new(future::ok(UpgradableResponse::Upgrade((ws_res, ctx), None)))
+    }
+}
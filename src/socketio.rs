@@ -0,0 +1,693 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{Future, Poll, Stream};
+use futures::sync::oneshot;
+use serde_json::{self, Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use websocket::message::OwnedMessage;
+
+use super::Omitted;
+use super::engineio::{random_id, EngineIoSender, EngineIoSocket};
+
+// Packet types.
+const CONNECT: u8 = 0;
+const DISCONNECT: u8 = 1;
+const EVENT: u8 = 2;
+const ACK: u8 = 3;
+const CONNECT_ERROR: u8 = 4;
+const BINARY_EVENT: u8 = 5;
+const BINARY_ACK: u8 = 6;
+
+/// The most binary attachments a packet may announce.
+const MAX_ATTACHMENTS: usize = 16;
+
+#[derive(Debug)]
+struct Packet {
+    kind: u8,
+    /// The number of binary attachments that follow.
+    attachments: usize,
+    /// The attachments received so far.
+    buffers: Vec<Vec<u8>>,
+    namespace: String,
+    ack_id: Option<u64>,
+    data: Option<Value>,
+}
+
+impl Packet {
+    fn parse(text: &str) -> Option<Self> {
+        let kind = match text.as_bytes().first() {
+            Some(&byte) if byte >= b'0' && byte <= b'6' => byte - b'0',
+            _ => return None,
+        };
+        let mut rest = &text[1..];
+        let mut attachments = 0;
+        if kind == BINARY_EVENT || kind == BINARY_ACK {
+            let end = match rest.find('-') {
+                None => return None,
+                Some(end) => end,
+            };
+            attachments = match rest[..end].parse() {
+                Ok(attachments) if attachments <= MAX_ATTACHMENTS => attachments,
+                _ => return None,
+            };
+            rest = &rest[end + 1..];
+        }
+        let namespace = if rest.starts_with('/') {
+            let end = rest.find(',').unwrap_or_else(|| rest.len());
+            let namespace = &rest[..end];
+            rest = &rest[(end + 1).min(rest.len())..];
+            namespace
+        } else {
+            "/"
+        };
+        let digits = rest.bytes().take_while(|&byte| byte >= b'0' && byte <= b'9').count();
+        let ack_id = if digits == 0 {
+            None
+        } else {
+            match rest[..digits].parse() {
+                Err(_) => return None,
+                Ok(ack_id) => Some(ack_id),
+            }
+        };
+        rest = &rest[digits..];
+        let data = if rest.is_empty() {
+            None
+        } else {
+            match serde_json::from_str(rest) {
+                Err(_) => return None,
+                Ok(data) => Some(data),
+            }
+        };
+        Some(Packet {
+            kind: kind,
+            attachments: attachments,
+            buffers: Vec::new(),
+            namespace: namespace.to_owned(),
+            ack_id: ack_id,
+            data: data,
+        })
+    }
+}
+
+fn encode(kind: u8, namespace: &str, ack_id: Option<u64>, data: Option<&Value>) -> String {
+    let mut packet = kind.to_string();
+    if namespace != "/" {
+        packet.push_str(namespace);
+        packet.push(',');
+    }
+    if let Some(ack_id) = ack_id {
+        packet.push_str(&ack_id.to_string());
+    }
+    if let Some(data) = data {
+        packet.push_str(&data.to_string());
+    }
+    packet
+}
+
+/// Like `encode`, for a binary packet followed by `attachments` attachments.
+fn encode_binary(
+    kind: u8,
+    attachments: usize,
+    namespace: &str,
+    ack_id: Option<u64>,
+    data: Option<&Value>,
+) -> String {
+    let packet = encode(kind, namespace, ack_id, data);
+    format!("{}{}-{}", &packet[..1], attachments, &packet[1..])
+}
+
+/// Replaces the attachment placeholders in `value` with the attachments, as
+/// arrays of byte values, returning `None` if one names a missing attachment.
+fn fill_placeholders(value: Value, attachments: &[Vec<u8>]) -> Option<Value> {
+    match value {
+        Value::Object(object) => {
+            if object.get("_placeholder") != Some(&Value::Bool(true)) {
+                return object
+                    .into_iter()
+                    .map(|(key, value)| {
+                        fill_placeholders(value, attachments).map(|value| (key, value))
+                    })
+                    .collect::<Option<Map<String, Value>>>()
+                    .map(Value::Object);
+            }
+            let num = match object.get("num").and_then(Value::as_u64) {
+                None => return None,
+                Some(num) => num as usize,
+            };
+            attachments.get(num).map(|data| {
+                Value::Array(data.iter().map(|&byte| Value::from(byte)).collect())
+            })
+        }
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| fill_placeholders(value, attachments))
+            .collect::<Option<Vec<Value>>>()
+            .map(Value::Array),
+        value => Some(value),
+    }
+}
+
+/// Fills in the attachment placeholders in a binary packet's arguments.
+fn fill_args(args: Vec<Value>, buffers: &[Vec<u8>]) -> Option<Vec<Value>> {
+    if buffers.is_empty() {
+        return Some(args);
+    }
+    args.into_iter()
+        .map(|value| fill_placeholders(value, buffers))
+        .collect()
+}
+
+fn event_data(event: &str, args: Vec<Value>) -> Value {
+    let mut data = Vec::with_capacity(args.len() + 1);
+    data.push(Value::String(event.to_owned()));
+    data.extend(args);
+    Value::Array(data)
+}
+
+type OnConnect = Fn(&SocketIoSocket, Option<&Value>) -> Result<(), String>;
+type EventHandler = Fn(&SocketIoSocket, Vec<Value>, Option<SocketIoAck>);
+type BinaryEventHandler = Fn(&SocketIoSocket, Vec<Value>, Vec<Vec<u8>>, Option<SocketIoAck>);
+type DisconnectHandler = Fn(&SocketIoSocket, &str);
+
+/// A Socket.IO v5 server, run over the sessions of an `EngineIoServer`:
+///
+/// ```ignore
+/// let io = SocketIoServer::new().namespace("/", |socket, _auth| { ... });
+/// let engine = EngineIoServer::new(&handle, move |socket| io.serve(socket));
+/// ```
+///
+/// Clients connect to namespaces registered with `namespace`, and exchange
+/// events, optionally acknowledged, over the resulting `SocketIoSocket`s.
+/// Binary attachments on the client's events and acknowledgements are passed
+/// on as arrays of byte values, the way serde_json represents a `Vec<u8>`,
+/// except to event handlers registered with `SocketIoSocket::on_binary`,
+/// which get the attachments themselves. A packet that announces more than
+/// 16 attachments ends the session.
+#[derive(Clone, Default)]
+pub struct SocketIoServer {
+    namespaces: HashMap<String, Rc<OnConnect>>,
+}
+
+impl fmt::Debug for SocketIoServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SocketIoServer")
+            .field("namespaces", &self.namespaces.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl SocketIoServer {
+    pub fn new() -> Self {
+        SocketIoServer::default()
+    }
+
+    /// Lets clients connect to the namespace `name`, such as `/` or `/chat`.
+    /// `on_connect` gets each new socket and the client's auth payload, and
+    /// registers the socket's event handlers, or refuses the connection with
+    /// an error message.
+    pub fn namespace<F>(mut self, name: &str, on_connect: F) -> Self
+    where
+        F: Fn(&SocketIoSocket, Option<&Value>) -> Result<(), String> + 'static,
+    {
+        self.namespaces.insert(name.to_owned(), Rc::new(on_connect));
+        self
+    }
+
+    /// Speaks Socket.IO over an Engine.IO session.
+    pub fn serve(&self, socket: EngineIoSocket) -> SocketIoConnection {
+        let conn = Rc::new(RefCell::new(ConnState {
+            namespaces: self.namespaces.clone(),
+            engine: socket.sender(),
+            sockets: HashMap::new(),
+            partial: None,
+        }));
+        let dispatch_conn = conn.clone();
+        let serve = socket
+            .for_each(move |msg| {
+                match msg {
+                    OwnedMessage::Text(text) => dispatch(&dispatch_conn, &text),
+                    // Binary messages only carry attachments.
+                    OwnedMessage::Binary(data) => attach(&dispatch_conn, data),
+                    _ => {}
+                }
+                Ok(())
+            })
+            .then(move |_| {
+                let sockets = mem::replace(&mut conn.borrow_mut().sockets, HashMap::new());
+                for socket in sockets.values() {
+                    socket.disconnected("transport close");
+                }
+                Ok(())
+            });
+        SocketIoConnection(Box::new(serve))
+    }
+}
+
+/// The Socket.IO side of an Engine.IO session. When the session ends, every
+/// socket on it is disconnected with reason `"transport close"` and this
+/// resolves.
+pub struct SocketIoConnection(Box<Future<Item = (), Error = ()>>);
+
+impl fmt::Debug for SocketIoConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SocketIoConnection").field(&Omitted).finish()
+    }
+}
+
+impl Future for SocketIoConnection {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+struct ConnState {
+    namespaces: HashMap<String, Rc<OnConnect>>,
+    engine: EngineIoSender,
+    /// The connected sockets by namespace.
+    sockets: HashMap<String, SocketIoSocket>,
+    /// A binary packet waiting for its attachments.
+    partial: Option<Packet>,
+}
+
+fn dispatch(conn: &Rc<RefCell<ConnState>>, text: &str) {
+    let packet = match Packet::parse(text) {
+        Some(packet) => packet,
+        None => {
+            debug!("hyper-websocket: malformed Socket.IO packet");
+            return conn.borrow().engine.close();
+        }
+    };
+    if conn.borrow().partial.is_some() {
+        debug!("hyper-websocket: Socket.IO packet in place of an attachment");
+        return conn.borrow().engine.close();
+    }
+    if packet.attachments > 0 {
+        conn.borrow_mut().partial = Some(packet);
+        return;
+    }
+    handle(conn, packet);
+}
+
+/// Adds an attachment to the binary packet waiting for it, handling the
+/// packet once it's complete.
+fn attach(conn: &Rc<RefCell<ConnState>>, data: Vec<u8>) {
+    let partial = conn.borrow_mut().partial.take();
+    let mut packet = match partial {
+        Some(packet) => packet,
+        None => {
+            debug!("hyper-websocket: Socket.IO attachment without a packet");
+            return conn.borrow().engine.close();
+        }
+    };
+    packet.buffers.push(data);
+    if packet.buffers.len() < packet.attachments {
+        conn.borrow_mut().partial = Some(packet);
+        return;
+    }
+    handle(conn, packet);
+}
+
+fn handle(conn: &Rc<RefCell<ConnState>>, packet: Packet) {
+    if packet.kind == CONNECT {
+        return connect(conn, packet);
+    }
+
+    let socket = conn.borrow().sockets.get(&packet.namespace).cloned();
+    let socket = match socket {
+        None => return,
+        Some(socket) => socket,
+    };
+    match packet.kind {
+        DISCONNECT => {
+            conn.borrow_mut().sockets.remove(&packet.namespace);
+            socket.disconnected("client namespace disconnect");
+        }
+        EVENT | BINARY_EVENT => socket.dispatch_event(packet.ack_id, packet.data, packet.buffers),
+        ACK | BINARY_ACK => {
+            if let Some(ack_id) = packet.ack_id {
+                socket.resolve_ack(ack_id, packet.data, &packet.buffers);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn connect(conn: &Rc<RefCell<ConnState>>, packet: Packet) {
+    let namespace = packet.namespace;
+    let (on_connect, engine) = {
+        let mut conn = conn.borrow_mut();
+        let connected = conn.sockets.get(&namespace).map_or(false, |socket| {
+            socket.is_connected()
+        });
+        if connected {
+            return;
+        }
+        conn.sockets.remove(&namespace);
+        (conn.namespaces.get(&namespace).cloned(), conn.engine.clone())
+    };
+    let on_connect = match on_connect {
+        Some(on_connect) => on_connect,
+        None => {
+            let error = json!({"message": "Invalid namespace"});
+            engine.send(OwnedMessage::Text(encode(CONNECT_ERROR, &namespace, None, Some(&error))));
+            return;
+        }
+    };
+
+    let socket = SocketIoSocket {
+        inner: Rc::new(RefCell::new(SocketInner {
+            id: random_id(),
+            namespace: namespace.clone(),
+            engine: engine.clone(),
+            state: SocketState::Connecting(Vec::new()),
+            handlers: HashMap::new(),
+            on_disconnect: None,
+            next_ack: 0,
+            acks: HashMap::new(),
+        })),
+    };
+    if let Err(message) = on_connect(&socket, packet.data.as_ref()) {
+        socket.inner.borrow_mut().clear();
+        let error = json!({"message": message});
+        engine.send(OwnedMessage::Text(encode(CONNECT_ERROR, &namespace, None, Some(&error))));
+        return;
+    }
+
+    let pending = match socket.inner.borrow_mut().state {
+        SocketState::Connecting(ref mut pending) => Some(mem::replace(pending, Vec::new())),
+        _ => None,
+    };
+    let ack = json!({"sid": socket.id()});
+    engine.send(OwnedMessage::Text(encode(CONNECT, &namespace, None, Some(&ack))));
+    let pending = match pending {
+        Some(pending) => pending,
+        None => {
+            // The handler disconnected the socket already.
+            engine.send(OwnedMessage::Text(encode(DISCONNECT, &namespace, None, None)));
+            return;
+        }
+    };
+    socket.inner.borrow_mut().state = SocketState::Connected;
+    for msg in pending {
+        engine.send(msg);
+    }
+    conn.borrow_mut().sockets.insert(namespace, socket);
+}
+
+enum SocketState {
+    /// The namespace's connect handler is running. Packets sent meanwhile
+    /// wait until the client has been told it's connected.
+    Connecting(Vec<OwnedMessage>),
+    Connected,
+    Disconnected,
+}
+
+struct SocketInner {
+    id: String,
+    namespace: String,
+    engine: EngineIoSender,
+    state: SocketState,
+    handlers: HashMap<String, Handler>,
+    on_disconnect: Option<Rc<DisconnectHandler>>,
+    next_ack: u64,
+    /// The emits waiting for the client's acknowledgement, by ack ID.
+    acks: HashMap<u64, oneshot::Sender<Vec<Value>>>,
+}
+
+#[derive(Clone)]
+enum Handler {
+    /// Gets attachments as arrays of byte values, in place of their
+    /// placeholders.
+    Values(Rc<EventHandler>),
+    /// Gets the placeholders as they are, and the attachments as bytes.
+    Binary(Rc<BinaryEventHandler>),
+}
+
+impl SocketInner {
+    fn send(&mut self, packet: String) -> bool {
+        self.send_all(vec![OwnedMessage::Text(packet)])
+    }
+
+    /// Sends a packet followed by its attachments.
+    fn send_all(&mut self, msgs: Vec<OwnedMessage>) -> bool {
+        match self.state {
+            SocketState::Connecting(ref mut pending) => {
+                pending.extend(msgs);
+                true
+            }
+            SocketState::Connected => {
+                let engine = &self.engine;
+                msgs.into_iter().all(|msg| engine.send(msg))
+            }
+            SocketState::Disconnected => false,
+        }
+    }
+
+    /// Marks the socket disconnected, dropping its handlers, which also
+    /// breaks any reference cycles through them.
+    fn clear(&mut self) {
+        self.state = SocketState::Disconnected;
+        self.handlers.clear();
+        self.acks.clear();
+    }
+}
+
+/// A client's connection to one namespace. Clones share the same socket.
+#[derive(Clone)]
+pub struct SocketIoSocket {
+    inner: Rc<RefCell<SocketInner>>,
+}
+
+impl fmt::Debug for SocketIoSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("SocketIoSocket")
+            .field("id", &inner.id)
+            .field("namespace", &inner.namespace)
+            .field("engine", &inner.engine)
+            .field("handlers", &inner.handlers.keys().collect::<Vec<_>>())
+            .field("acks", &inner.acks.len())
+            .finish()
+    }
+}
+
+impl SocketIoSocket {
+    /// The socket ID, distinct from the Engine.IO session ID.
+    pub fn id(&self) -> String {
+        self.inner.borrow().id.clone()
+    }
+
+    pub fn namespace(&self) -> String {
+        self.inner.borrow().namespace.clone()
+    }
+
+    /// A handle on the Engine.IO session the socket runs over.
+    pub fn engine(&self) -> EngineIoSender {
+        self.inner.borrow().engine.clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self.inner.borrow().state {
+            SocketState::Disconnected => false,
+            _ => true,
+        }
+    }
+
+    /// Handles the client's `event` events, replacing any previous handler.
+    /// The handler gets the event's arguments, and an acknowledgement to send
+    /// if the client asked for one.
+    pub fn on<F>(&self, event: &str, handler: F)
+    where
+        F: Fn(&SocketIoSocket, Vec<Value>, Option<SocketIoAck>) + 'static,
+    {
+        let mut inner = self.inner.borrow_mut();
+        if let SocketState::Disconnected = inner.state {
+            return;
+        }
+        inner.handlers.insert(event.to_owned(), Handler::Values(Rc::new(handler)));
+    }
+
+    /// Like `on`, but the handler gets the event's binary attachments as
+    /// bytes, and its arguments with the attachment placeholders left in.
+    pub fn on_binary<F>(&self, event: &str, handler: F)
+    where
+        F: Fn(&SocketIoSocket, Vec<Value>, Vec<Vec<u8>>, Option<SocketIoAck>) + 'static,
+    {
+        let mut inner = self.inner.borrow_mut();
+        if let SocketState::Disconnected = inner.state {
+            return;
+        }
+        inner.handlers.insert(event.to_owned(), Handler::Binary(Rc::new(handler)));
+    }
+
+    /// Runs `handler` with the reason once the socket disconnects.
+    pub fn on_disconnect<F>(&self, handler: F)
+    where
+        F: Fn(&SocketIoSocket, &str) + 'static,
+    {
+        let mut inner = self.inner.borrow_mut();
+        if let SocketState::Disconnected = inner.state {
+            return;
+        }
+        inner.on_disconnect = Some(Rc::new(handler));
+    }
+
+    /// Sends an event to the client, returning whether the socket is still
+    /// connected.
+    pub fn emit(&self, event: &str, args: Vec<Value>) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        let packet = encode(EVENT, &inner.namespace, None, Some(&event_data(event, args)));
+        inner.send(packet)
+    }
+
+    /// Sends an event with binary attachments to the client, returning
+    /// whether the socket is still connected. `args` stand in for each
+    /// attachment with a `{"_placeholder": true, "num": n}` object, where `n`
+    /// is its index in `attachments`.
+    pub fn emit_binary(&self, event: &str, args: Vec<Value>, attachments: Vec<Vec<u8>>) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        let data = event_data(event, args);
+        let packet =
+            encode_binary(BINARY_EVENT, attachments.len(), &inner.namespace, None, Some(&data));
+        let mut msgs = vec![OwnedMessage::Text(packet)];
+        msgs.extend(attachments.into_iter().map(OwnedMessage::Binary));
+        inner.send_all(msgs)
+    }
+
+    /// Sends an event to the client and waits for it to acknowledge it. The
+    /// reply fails if the socket disconnects first.
+    pub fn emit_with_ack(&self, event: &str, args: Vec<Value>) -> SocketIoReply {
+        let mut inner = self.inner.borrow_mut();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let ack_id = inner.next_ack;
+        inner.next_ack += 1;
+        let packet = encode(EVENT, &inner.namespace, Some(ack_id), Some(&event_data(event, args)));
+        if inner.send(packet) {
+            inner.acks.insert(ack_id, reply_tx);
+        }
+        SocketIoReply(reply_rx)
+    }
+
+    /// Disconnects the client from the namespace, leaving its Engine.IO
+    /// session open.
+    pub fn disconnect(&self) {
+        let packet = encode(DISCONNECT, &self.inner.borrow().namespace, None, None);
+        let sent = self.inner.borrow_mut().send(packet);
+        if sent {
+            self.disconnected("server namespace disconnect");
+        }
+    }
+
+    fn disconnected(&self, reason: &str) {
+        let on_disconnect = {
+            let mut inner = self.inner.borrow_mut();
+            if let SocketState::Disconnected = inner.state {
+                return;
+            }
+            inner.clear();
+            inner.on_disconnect.take()
+        };
+        if let Some(on_disconnect) = on_disconnect {
+            on_disconnect(self, reason);
+        }
+    }
+
+    fn dispatch_event(&self, ack_id: Option<u64>, data: Option<Value>, buffers: Vec<Vec<u8>>) {
+        let mut args = match data {
+            Some(Value::Array(args)) => args,
+            _ => return,
+        };
+        if args.is_empty() {
+            return;
+        }
+        let event = match args.remove(0) {
+            Value::String(event) => event,
+            _ => return,
+        };
+        let (handler, engine, namespace) = {
+            let inner = self.inner.borrow();
+            let handler = match inner.handlers.get(&event) {
+                None => return,
+                Some(handler) => handler.clone(),
+            };
+            (handler, inner.engine.clone(), inner.namespace.clone())
+        };
+        let ack = ack_id.map(|ack_id| SocketIoAck {
+            engine: engine.clone(),
+            namespace: namespace,
+            ack_id: ack_id,
+        });
+        match handler {
+            Handler::Values(handler) => match fill_args(args, &buffers) {
+                Some(args) => handler(self, args, ack),
+                None => {
+                    debug!("hyper-websocket: malformed Socket.IO attachment placeholder");
+                    engine.close();
+                }
+            },
+            Handler::Binary(handler) => handler(self, args, buffers, ack),
+        }
+    }
+
+    fn resolve_ack(&self, ack_id: u64, data: Option<Value>, buffers: &[Vec<u8>]) {
+        let args = match data {
+            Some(Value::Array(args)) => args,
+            _ => Vec::new(),
+        };
+        let args = match fill_args(args, buffers) {
+            Some(args) => args,
+            None => {
+                debug!("hyper-websocket: malformed Socket.IO attachment placeholder");
+                return self.inner.borrow().engine.close();
+            }
+        };
+        let reply = self.inner.borrow_mut().acks.remove(&ack_id);
+        if let Some(reply) = reply {
+            let _ = reply.send(args);
+        }
+    }
+}
+
+/// The acknowledgement a client asked for with an event.
+#[derive(Debug)]
+pub struct SocketIoAck {
+    engine: EngineIoSender,
+    namespace: String,
+    ack_id: u64,
+}
+
+impl SocketIoAck {
+    /// Acknowledges the event with the given arguments, returning whether the
+    /// Engine.IO session is still open.
+    pub fn send(self, args: Vec<Value>) -> bool {
+        let packet = encode(ACK, &self.namespace, Some(self.ack_id), Some(&Value::Array(args)));
+        self.engine.send(OwnedMessage::Text(packet))
+    }
+}
+
+/// The arguments a client acknowledged an event with.
+pub struct SocketIoReply(oneshot::Receiver<Vec<Value>>);
+
+impl fmt::Debug for SocketIoReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SocketIoReply").field(&Omitted).finish()
+    }
+}
+
+impl Future for SocketIoReply {
+    type Item = Vec<Value>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll().map_err(|_| ())
+    }
+}
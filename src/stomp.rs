@@ -0,0 +1,999 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{Future, Poll, Stream};
+use futures::sync::oneshot;
+use hyper::StatusCode;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+use std::str;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Interval};
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use super::{Omitted, WsHandshake, WsResponse};
+use super::channel::{spawn_channel, WsSendError, WsSender, QUEUE_CAPACITY};
+
+/// The subprotocol name of STOMP 1.2 over WebSocket.
+pub const STOMP_PROTOCOL: &str = "v12.stomp";
+
+const DEFAULT_HEART_BEAT_MS: u64 = 10_000;
+const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_UNACKED: usize = 1024;
+/// How many transactions a client may have open at once.
+const MAX_TRANSACTIONS: usize = 16;
+/// How many frames a transaction may hold before it's committed.
+const MAX_TRANSACTION_FRAMES: usize = 256;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+/// Lets in clients that offer STOMP 1.2 (`STOMP_PROTOCOL`), for use with
+/// `WsServer::negotiate`. Anyone else is refused with `400 Bad Request`.
+pub fn negotiate_stomp(handshake: WsHandshake) -> WsResponse {
+    let offered = handshake.protocols().iter().any(|protocol| protocol == STOMP_PROTOCOL);
+    if offered {
+        WsResponse::accept(handshake.use_protocol(STOMP_PROTOCOL))
+    } else {
+        WsResponse::reject_with_status(handshake, StatusCode::BadRequest)
+    }
+}
+
+/// A STOMP frame: a command, headers in order, and a body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StompFrame {
+    command: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl StompFrame {
+    pub fn new(command: &str) -> Self {
+        StompFrame {
+            command: command.to_owned(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Appends a header. A repeated header doesn't replace the first one,
+    /// which is the one that counts.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn with_body<B>(mut self, body: B) -> Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        self.body = body.into();
+        self
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Looks up the first header with the given name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref header, _)| header == name)
+            .map(|&(_, ref value)| value.as_str())
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Encodes the frame, escaping header names and values as needed and
+    /// adding a `content-length` header to a non-empty body that lacks one.
+    pub fn encode(&self) -> Vec<u8> {
+        let escape = has_escaped_headers(&self.command);
+        let mut encoded = Vec::with_capacity(self.command.len() + self.body.len() + 64);
+        encoded.extend_from_slice(self.command.as_bytes());
+        encoded.push(b'\n');
+        for &(ref name, ref value) in &self.headers {
+            encode_header_part(&mut encoded, name, escape);
+            encoded.push(b':');
+            encode_header_part(&mut encoded, value, escape);
+            encoded.push(b'\n');
+        }
+        if !self.body.is_empty() && self.header("content-length").is_none() {
+            encoded.extend_from_slice(format!("content-length:{}\n", self.body.len()).as_bytes());
+        }
+        encoded.push(b'\n');
+        encoded.extend_from_slice(&self.body);
+        encoded.push(0);
+        encoded
+    }
+
+    /// Encodes the frame as a text message, or as a binary one if the body
+    /// isn't UTF-8.
+    fn to_message(&self) -> OwnedMessage {
+        match String::from_utf8(self.encode()) {
+            Ok(text) => OwnedMessage::Text(text),
+            Err(err) => OwnedMessage::Binary(err.into_bytes()),
+        }
+    }
+}
+
+/// Why STOMP data couldn't be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StompError {
+    /// The data doesn't follow the frame grammar; the reason says how.
+    Malformed(&'static str),
+    /// A frame is larger than the decoder allows.
+    TooLarge,
+}
+
+impl fmt::Display for StompError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StompError::Malformed(reason) => write!(f, "Malformed STOMP frame: {}", reason),
+            StompError::TooLarge => f.write_str(self.description()),
+        }
+    }
+}
+
+impl Error for StompError {
+    fn description(&self) -> &str {
+        match *self {
+            StompError::Malformed(_) => "Malformed STOMP frame",
+            StompError::TooLarge => "STOMP frame is too large",
+        }
+    }
+}
+
+/// Decodes STOMP frames from a byte stream that may split or batch them
+/// arbitrarily, such as the payloads of successive WebSocket messages.
+#[derive(Debug)]
+pub struct StompDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl StompDecoder {
+    /// Creates a decoder that fails on frames, or partial frames, larger than
+    /// `max_frame_size` bytes.
+    pub fn new(max_frame_size: usize) -> Self {
+        StompDecoder {
+            buffer: Vec::new(),
+            max_frame_size: max_frame_size,
+        }
+    }
+
+    /// Buffers `data` and decodes the frames it completes. Heart-beats, the
+    /// bare end-of-lines between frames, are skipped.
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<StompFrame>, StompError> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        let mut start = 0;
+        loop {
+            start += skip_eols(&self.buffer[start..]);
+            match parse_frame(&self.buffer[start..], self.max_frame_size)? {
+                None => break,
+                Some((frame, len)) => {
+                    frames.push(frame);
+                    start += len;
+                }
+            }
+        }
+        self.buffer.drain(..start);
+        if self.buffer.len() > self.max_frame_size {
+            return Err(StompError::TooLarge);
+        }
+        Ok(frames)
+    }
+}
+
+fn has_escaped_headers(command: &str) -> bool {
+    // Headers of the connection frames are sent as-is, for compatibility with
+    // STOMP 1.0.
+    command != "CONNECT" && command != "CONNECTED"
+}
+
+fn encode_header_part(encoded: &mut Vec<u8>, part: &str, escape: bool) {
+    if !escape {
+        return encoded.extend_from_slice(part.as_bytes());
+    }
+    for &byte in part.as_bytes() {
+        match byte {
+            b'\r' => encoded.extend_from_slice(b"\\r"),
+            b'\n' => encoded.extend_from_slice(b"\\n"),
+            b':' => encoded.extend_from_slice(b"\\c"),
+            b'\\' => encoded.extend_from_slice(b"\\\\"),
+            byte => encoded.push(byte),
+        }
+    }
+}
+
+fn decode_header_part(part: &[u8], escaped: bool) -> Result<String, StompError> {
+    let part = str::from_utf8(part).map_err(|_| StompError::Malformed("header is not UTF-8"))?;
+    if !escaped {
+        return Ok(part.to_owned());
+    }
+    let mut decoded = String::with_capacity(part.len());
+    let mut chars = part.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('r') => decoded.push('\r'),
+            Some('n') => decoded.push('\n'),
+            Some('c') => decoded.push(':'),
+            Some('\\') => decoded.push('\\'),
+            _ => return Err(StompError::Malformed("undefined escape sequence in header")),
+        }
+    }
+    Ok(decoded)
+}
+
+/// Counts the end-of-lines at the start of `data`.
+fn skip_eols(data: &[u8]) -> usize {
+    let mut len = 0;
+    loop {
+        match (data.get(len), data.get(len + 1)) {
+            (Some(&b'\n'), _) => len += 1,
+            (Some(&b'\r'), Some(&b'\n')) => len += 2,
+            _ => return len,
+        }
+    }
+}
+
+/// Splits off the line starting at `*pos`, without its end-of-line, or
+/// returns `None` if it isn't complete yet.
+fn next_line<'a>(data: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let len = match data[*pos..].iter().position(|&byte| byte == b'\n') {
+        None => return None,
+        Some(len) => len,
+    };
+    let mut line = &data[*pos..*pos + len];
+    if line.last() == Some(&b'\r') {
+        line = &line[..line.len() - 1];
+    }
+    *pos += len + 1;
+    Some(line)
+}
+
+/// Parses the frame at the start of `data`, returning it with its encoded
+/// length, or `None` if it isn't complete yet.
+fn parse_frame(
+    data: &[u8],
+    max_frame_size: usize,
+) -> Result<Option<(StompFrame, usize)>, StompError> {
+    let mut pos = 0;
+    let command = match next_line(data, &mut pos) {
+        None => return Ok(None),
+        Some(command) => command,
+    };
+    let command = match str::from_utf8(command) {
+        Ok(command) if !command.is_empty() => command.to_owned(),
+        _ => return Err(StompError::Malformed("invalid command")),
+    };
+    let escaped = has_escaped_headers(&command);
+
+    let mut headers = Vec::new();
+    loop {
+        let line = match next_line(data, &mut pos) {
+            None => return Ok(None),
+            Some(line) => line,
+        };
+        if line.is_empty() {
+            break;
+        }
+        let colon = match line.iter().position(|&byte| byte == b':') {
+            None => return Err(StompError::Malformed("header without a colon")),
+            Some(colon) => colon,
+        };
+        let name = decode_header_part(&line[..colon], escaped)?;
+        let value = decode_header_part(&line[colon + 1..], escaped)?;
+        headers.push((name, value));
+    }
+
+    let body_start = pos;
+    let content_length = headers
+        .iter()
+        .find(|&&(ref name, _)| name == "content-length")
+        .map(|&(_, ref value)| value.parse::<usize>());
+    let body_end = match content_length {
+        Some(Err(_)) => return Err(StompError::Malformed("invalid content-length header")),
+        Some(Ok(len)) if len > max_frame_size => return Err(StompError::TooLarge),
+        Some(Ok(len)) => {
+            if data.len() <= body_start + len {
+                return Ok(None);
+            }
+            if data[body_start + len] != 0 {
+                return Err(StompError::Malformed("body is longer than its content-length"));
+            }
+            body_start + len
+        }
+        None => match data[body_start..].iter().position(|&byte| byte == 0) {
+            None => return Ok(None),
+            Some(len) => body_start + len,
+        },
+    };
+    if body_end + 1 > max_frame_size {
+        return Err(StompError::TooLarge);
+    }
+
+    let frame = StompFrame {
+        command: command,
+        headers: headers,
+        body: data[body_start..body_end].to_vec(),
+    };
+    Ok(Some((frame, body_end + 1)))
+}
+
+type Authenticate = Fn(&StompFrame) -> bool;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AckMode {
+    Auto,
+    Client,
+    ClientIndividual,
+}
+
+struct Subscription {
+    destination: String,
+    ack: AckMode,
+}
+
+/// A message delivered to a subscription in a client ack mode, waiting to be
+/// acknowledged.
+struct Unacked {
+    ack_id: String,
+    subscription: String,
+    /// Whether acknowledging it also acknowledges the subscription's earlier
+    /// messages, as in the `client` ack mode.
+    cumulative: bool,
+}
+
+struct Session {
+    sender: WsSender,
+    subscriptions: HashMap<String, Subscription>,
+    /// Oldest first.
+    unacked: Vec<Unacked>,
+    /// Set once the client has fallen behind, after which it's sent nothing
+    /// more.
+    disconnected: bool,
+}
+
+impl Session {
+    fn send(&mut self, frame: &StompFrame) {
+        if !self.disconnected && !send_or_disconnect(&mut self.sender, frame) {
+            self.disconnected = true;
+        }
+    }
+
+    /// Sends an `ERROR` frame and closes the connection, for a client that
+    /// breaks one of the broker's limits.
+    fn fail(&mut self, message: &str) {
+        debug!("hyper-websocket: STOMP error: {}", message);
+        self.send(
+            &StompFrame::new("ERROR")
+                .with_header("message", message)
+                .with_header("content-type", "text/plain")
+                .with_body(message),
+        );
+        if !self.disconnected {
+            self.disconnected = true;
+            let _ = self.sender.close(CLOSE_POLICY_VIOLATION, "STOMP error");
+        }
+    }
+}
+
+/// Queues a frame without waiting, since the broker can't hold up delivery to
+/// everyone else for one client. A client whose queue is full is told to go
+/// away instead. Returns `false` once the client can't be sent anything more.
+fn send_or_disconnect(sender: &mut WsSender, frame: &StompFrame) -> bool {
+    match sender.try_send(frame.to_message()) {
+        Ok(()) => true,
+        Err(WsSendError::Full(_)) => {
+            debug!("hyper-websocket: disconnecting slow STOMP client");
+            let _ = sender.close(CLOSE_POLICY_VIOLATION, "Too slow");
+            false
+        }
+        Err(WsSendError::Closed(_)) => false,
+    }
+}
+
+struct BrokerInner {
+    heart_beat: (u64, u64),
+    max_frame_size: usize,
+    max_unacked: usize,
+    authenticate: Option<Rc<Authenticate>>,
+    next_session: u64,
+    next_message: u64,
+    sessions: HashMap<u64, Session>,
+    /// The subscribers of each destination, by session and subscription ID.
+    destinations: HashMap<String, Vec<(u64, String)>>,
+}
+
+impl BrokerInner {
+    fn deliver(&mut self, destination: &str, headers: &[(String, String)], body: &[u8]) -> usize {
+        let subscribers = match self.destinations.get(destination) {
+            None => return 0,
+            Some(subscribers) => subscribers.clone(),
+        };
+        let max_unacked = self.max_unacked;
+        for &(session_id, ref subscription_id) in &subscribers {
+            let message_id = self.next_message.to_string();
+            self.next_message += 1;
+            let session = match self.sessions.get_mut(&session_id) {
+                None => continue,
+                Some(session) => session,
+            };
+            let ack = match session.subscriptions.get(subscription_id) {
+                None => continue,
+                Some(subscription) => subscription.ack,
+            };
+
+            let mut frame = StompFrame::new("MESSAGE")
+                .with_header("subscription", subscription_id)
+                .with_header("message-id", &message_id)
+                .with_header("destination", destination);
+            if ack != AckMode::Auto {
+                if session.unacked.len() >= max_unacked {
+                    session.fail("Too many unacknowledged messages");
+                    continue;
+                }
+                frame = frame.with_header("ack", &message_id);
+                session.unacked.push(Unacked {
+                    ack_id: message_id.clone(),
+                    subscription: subscription_id.clone(),
+                    cumulative: ack == AckMode::Client,
+                });
+            }
+            for &(ref name, ref value) in headers {
+                match name.as_str() {
+                    "destination" | "receipt" | "transaction" | "content-length"
+                    | "subscription" | "message-id" | "ack" => {}
+                    _ => frame = frame.with_header(name, value),
+                }
+            }
+            session.send(&frame.with_body(body));
+        }
+        subscribers.len()
+    }
+
+    fn subscribe(
+        &mut self,
+        session_id: u64,
+        id: &str,
+        destination: &str,
+        ack: AckMode,
+    ) -> Result<(), String> {
+        let session = match self.sessions.get_mut(&session_id) {
+            None => return Ok(()),
+            Some(session) => session,
+        };
+        if session.subscriptions.contains_key(id) {
+            return Err(format!("Subscription {} already exists", id));
+        }
+        session.subscriptions.insert(
+            id.to_owned(),
+            Subscription {
+                destination: destination.to_owned(),
+                ack: ack,
+            },
+        );
+        self.destinations
+            .entry(destination.to_owned())
+            .or_insert_with(Vec::new)
+            .push((session_id, id.to_owned()));
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, session_id: u64, id: &str) -> bool {
+        let subscription = match self.sessions.get_mut(&session_id) {
+            None => return false,
+            Some(session) => {
+                session.unacked.retain(|unacked| unacked.subscription != id);
+                session.subscriptions.remove(id)
+            }
+        };
+        let subscription = match subscription {
+            None => return false,
+            Some(subscription) => subscription,
+        };
+        let now_empty = match self.destinations.get_mut(&subscription.destination) {
+            None => false,
+            Some(subscribers) => {
+                subscribers.retain(|&(session, ref subscriber)| {
+                    session != session_id || subscriber != id
+                });
+                subscribers.is_empty()
+            }
+        };
+        if now_empty {
+            self.destinations.remove(&subscription.destination);
+        }
+        true
+    }
+
+    /// Settles a message by its ack ID, returning whether it was waiting.
+    fn settle(&mut self, session_id: u64, ack_id: &str) -> bool {
+        let session = match self.sessions.get_mut(&session_id) {
+            None => return false,
+            Some(session) => session,
+        };
+        let index = match session.unacked.iter().position(|unacked| unacked.ack_id == ack_id) {
+            None => return false,
+            Some(index) => index,
+        };
+        let settled = session.unacked.remove(index);
+        if settled.cumulative {
+            let mut position = 0;
+            session.unacked.retain(|unacked| {
+                position += 1;
+                position > index || unacked.subscription != settled.subscription
+            });
+        }
+        true
+    }
+
+    fn remove_session(&mut self, session_id: u64) {
+        let ids = match self.sessions.get(&session_id) {
+            None => return,
+            Some(session) => session.subscriptions.keys().cloned().collect::<Vec<_>>(),
+        };
+        for id in ids {
+            self.unsubscribe(session_id, &id);
+        }
+        self.sessions.remove(&session_id);
+    }
+}
+
+/// A minimal in-process STOMP 1.2 broker. Destinations are topics: every
+/// subscription to a destination gets its own copy of each message sent to
+/// it. Clones share the same broker.
+///
+/// Connections are served with `serve`, typically after negotiating
+/// `STOMP_PROTOCOL` with `negotiate_stomp`. Besides `CONNECT`, `SEND`,
+/// `SUBSCRIBE`, `UNSUBSCRIBE` and `DISCONNECT`, the broker supports the
+/// `client` and `client-individual` ack modes, transactions, receipts and
+/// heart-beating. Messages that are `NACK`ed are discarded, since there is no
+/// other subscription to hand them to.
+///
+/// A protocol error is answered with an `ERROR` frame, after which the
+/// connection closes. Opening more than 16 transactions at once, or putting
+/// more than 256 frames in one, counts as a protocol error. A client that
+/// leaves more messages unacknowledged than `max_unacked` allows also gets
+/// an `ERROR` frame, and is disconnected with 1008. So is a client that reads
+/// so slowly that its send queue fills up, rather than have frames it may
+/// have to acknowledge go missing.
+#[derive(Clone)]
+pub struct StompBroker {
+    inner: Rc<RefCell<BrokerInner>>,
+}
+
+impl fmt::Debug for StompBroker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.borrow();
+        f.debug_struct("StompBroker")
+            .field("heart_beat", &inner.heart_beat)
+            .field("max_frame_size", &inner.max_frame_size)
+            .field("max_unacked", &inner.max_unacked)
+            .field("authenticate", &inner.authenticate.as_ref().map(|_| Omitted))
+            .field("sessions", &inner.sessions.len())
+            .field("destinations", &inner.destinations.len())
+            .finish()
+    }
+}
+
+impl Default for StompBroker {
+    fn default() -> Self {
+        StompBroker::new()
+    }
+}
+
+impl StompBroker {
+    pub fn new() -> Self {
+        StompBroker {
+            inner: Rc::new(RefCell::new(BrokerInner {
+                heart_beat: (DEFAULT_HEART_BEAT_MS, DEFAULT_HEART_BEAT_MS),
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                max_unacked: DEFAULT_MAX_UNACKED,
+                authenticate: None,
+                next_session: 0,
+                next_message: 0,
+                sessions: HashMap::new(),
+                destinations: HashMap::new(),
+            })),
+        }
+    }
+
+    /// How often the broker offers to send heart-beats, and how often it asks
+    /// clients to send them. Zero turns that direction off. Each defaults to
+    /// 10 seconds. A client that goes quiet for twice the negotiated interval
+    /// is disconnected.
+    pub fn heart_beat(self, send: Duration, receive: Duration) -> Self {
+        self.inner.borrow_mut().heart_beat = (millis(send), millis(receive));
+        self
+    }
+
+    /// The largest frame a client may send, in bytes. Defaults to 64 KiB.
+    pub fn max_frame_size(self, max_frame_size: usize) -> Self {
+        self.inner.borrow_mut().max_frame_size = max_frame_size;
+        self
+    }
+
+    /// How many messages a client may have waiting to be acknowledged, across
+    /// its subscriptions in the `client` and `client-individual` ack modes.
+    /// Once it has that many, the next message for it gets it an `ERROR`
+    /// frame and disconnected instead. Defaults to 1024.
+    pub fn max_unacked(self, max_unacked: usize) -> Self {
+        self.inner.borrow_mut().max_unacked = max_unacked;
+        self
+    }
+
+    /// Decides whether to accept each `CONNECT` frame, eg. by its `login` and
+    /// `passcode` headers. All are accepted by default.
+    pub fn authenticate<F>(self, authenticate: F) -> Self
+    where
+        F: Fn(&StompFrame) -> bool + 'static,
+    {
+        self.inner.borrow_mut().authenticate = Some(Rc::new(authenticate));
+        self
+    }
+
+    /// Sends a message to the subscribers of `destination`, as if a client
+    /// had sent it, returning how many there were.
+    pub fn publish<B>(&self, destination: &str, body: B) -> usize
+    where
+        B: Into<Vec<u8>>,
+    {
+        self.inner.borrow_mut().deliver(destination, &[], &body.into())
+    }
+
+    /// Speaks STOMP over an accepted connection, spawning the tasks that do
+    /// the IO onto `handle`.
+    pub fn serve<T>(&self, handle: &Handle, client: Client<T>) -> StompConnection
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let (sender, receiver) = spawn_channel(handle, client, QUEUE_CAPACITY);
+        let (session, max_frame_size) = {
+            let mut inner = self.inner.borrow_mut();
+            inner.next_session += 1;
+            (inner.next_session, inner.max_frame_size)
+        };
+        let conn = Rc::new(RefCell::new(ConnState {
+            broker: self.clone(),
+            handle: handle.clone(),
+            sender: sender,
+            session: session,
+            decoder: StompDecoder::new(max_frame_size),
+            connected: false,
+            closing: false,
+            last_received: Rc::new(Cell::new(Instant::now())),
+            transactions: HashMap::new(),
+            heart_beats: Vec::new(),
+        }));
+
+        let receive_conn = conn.clone();
+        let serve = receiver
+            .for_each(move |msg| {
+                let data = match msg {
+                    OwnedMessage::Text(text) => text.into_bytes(),
+                    OwnedMessage::Binary(data) => data,
+                    _ => return Ok(()),
+                };
+                receive_conn.borrow_mut().receive(&data);
+                Ok(())
+            })
+            .then(move |result| {
+                let mut conn = conn.borrow_mut();
+                // Stop the client's heart-beat timers.
+                conn.heart_beats.clear();
+                conn.broker.inner.borrow_mut().remove_session(conn.session);
+                result
+            });
+        StompConnection(Box::new(serve))
+    }
+}
+
+/// A client's STOMP session with a `StompBroker`. When the WebSocket closes
+/// it resolves, and the broker forgets the client's subscriptions.
+pub struct StompConnection(Box<Future<Item = (), Error = WebSocketError>>);
+
+impl fmt::Debug for StompConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("StompConnection").field(&Omitted).finish()
+    }
+}
+
+impl Future for StompConnection {
+    type Item = ();
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+struct ConnState {
+    broker: StompBroker,
+    handle: Handle,
+    sender: WsSender,
+    session: u64,
+    decoder: StompDecoder,
+    connected: bool,
+    closing: bool,
+    last_received: Rc<Cell<Instant>>,
+    /// The `SEND`, `ACK` and `NACK` frames of each open transaction.
+    transactions: HashMap<String, Vec<StompFrame>>,
+    heart_beats: Vec<oneshot::Sender<()>>,
+}
+
+impl ConnState {
+    fn send(&mut self, frame: &StompFrame) {
+        if !self.closing && !send_or_disconnect(&mut self.sender, frame) {
+            self.closing = true;
+        }
+    }
+
+    fn receive(&mut self, data: &[u8]) {
+        if self.closing {
+            return;
+        }
+        self.last_received.set(Instant::now());
+        let frames = match self.decoder.decode(data) {
+            Ok(frames) => frames,
+            Err(err) => return self.fail(&err.to_string(), None),
+        };
+        for frame in frames {
+            if self.closing {
+                break;
+            }
+            self.handle_frame(frame);
+        }
+    }
+
+    fn handle_frame(&mut self, frame: StompFrame) {
+        let command = frame.command().to_owned();
+        let receipt = frame.header("receipt").map(str::to_owned);
+        let result = match command.as_str() {
+            "CONNECT" | "STOMP" if !self.connected => self.connect(&frame),
+            _ if !self.connected => Err("Expected a CONNECT frame".to_owned()),
+            _ => self.process(frame),
+        };
+        if let Err(message) = result {
+            return self.fail(&message, receipt.as_ref().map(String::as_str));
+        }
+        if let Some(receipt) = receipt {
+            if command != "CONNECT" && command != "STOMP" {
+                self.send(&StompFrame::new("RECEIPT").with_header("receipt-id", &receipt));
+            }
+        }
+        if command == "DISCONNECT" {
+            self.closing = true;
+            let _ = self.sender.close(CLOSE_NORMAL, "");
+        }
+    }
+
+    /// Sends an `ERROR` frame and closes the connection.
+    fn fail(&mut self, message: &str, receipt: Option<&str>) {
+        debug!("hyper-websocket: STOMP error: {}", message);
+        let mut frame = StompFrame::new("ERROR")
+            .with_header("message", message)
+            .with_header("content-type", "text/plain");
+        if let Some(receipt) = receipt {
+            frame = frame.with_header("receipt-id", receipt);
+        }
+        self.send(&frame.with_body(message));
+        // Unless sending it already got the client disconnected.
+        if !self.closing {
+            self.closing = true;
+            let _ = self.sender.close(CLOSE_PROTOCOL_ERROR, "STOMP error");
+        }
+    }
+
+    fn connect(&mut self, frame: &StompFrame) -> Result<(), String> {
+        let versions = frame.header("accept-version").unwrap_or("1.0");
+        if !versions.split(',').any(|version| version.trim() == "1.2") {
+            return Err("Supported protocol versions are 1.2".to_owned());
+        }
+        let (authenticate, (send_ms, receive_ms)) = {
+            let inner = self.broker.inner.borrow();
+            (inner.authenticate.clone(), inner.heart_beat)
+        };
+        if let Some(authenticate) = authenticate {
+            if !authenticate(frame) {
+                return Err("Access refused".to_owned());
+            }
+        }
+        let (client_send_ms, client_receive_ms) = match frame.header("heart-beat") {
+            None => (0, 0),
+            Some(heart_beat) => match parse_heart_beat(heart_beat) {
+                None => return Err("Invalid heart-beat header".to_owned()),
+                Some(heart_beat) => heart_beat,
+            },
+        };
+
+        self.connected = true;
+        self.broker.inner.borrow_mut().sessions.insert(
+            self.session,
+            Session {
+                sender: self.sender.clone(),
+                subscriptions: HashMap::new(),
+                unacked: Vec::new(),
+                disconnected: false,
+            },
+        );
+        let heart_beat = format!("{},{}", send_ms, receive_ms);
+        self.send(
+            &StompFrame::new("CONNECTED")
+                .with_header("version", "1.2")
+                .with_header("heart-beat", &heart_beat)
+                .with_header("session", &self.session.to_string())
+                .with_header("server", "hyper-websocket"),
+        );
+
+        if send_ms != 0 && client_receive_ms != 0 {
+            let mut sender = self.sender.clone();
+            self.spawn_timer(send_ms.max(client_receive_ms), move || {
+                // A full queue is as good as a heart-beat.
+                match sender.try_send(OwnedMessage::Text("\n".to_owned())) {
+                    Ok(()) | Err(WsSendError::Full(_)) => Ok(()),
+                    Err(WsSendError::Closed(_)) => Err(()),
+                }
+            });
+        }
+        if receive_ms != 0 && client_send_ms != 0 {
+            let interval_ms = receive_ms.max(client_send_ms);
+            let limit = Duration::from_millis(interval_ms * 2);
+            let last_received = self.last_received.clone();
+            let sender = self.sender.clone();
+            self.spawn_timer(interval_ms, move || {
+                if last_received.get().elapsed() > limit {
+                    let _ = sender.close(CLOSE_PROTOCOL_ERROR, "Heart-beat timeout");
+                    return Err(());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Runs `tick` every `interval_ms` until it fails or the connection ends.
+    fn spawn_timer<F>(&mut self, interval_ms: u64, mut tick: F)
+    where
+        F: FnMut() -> Result<(), ()> + 'static,
+    {
+        let interval = match Interval::new(Duration::from_millis(interval_ms), &self.handle) {
+            Err(err) => return error!("hyper-websocket: STOMP heart-beat timer error: {}", err),
+            Ok(interval) => interval,
+        };
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        self.heart_beats.push(cancel_tx);
+        let timer = interval.map_err(|_| ()).for_each(move |()| tick());
+        self.handle.spawn(timer.select2(cancel_rx).then(|_| Ok(())));
+    }
+
+    fn process(&mut self, frame: StompFrame) -> Result<(), String> {
+        match frame.command() {
+            "SEND" | "ACK" | "NACK" => {
+                let transaction = frame.header("transaction").map(str::to_owned);
+                match transaction {
+                    None => self.apply(&frame),
+                    Some(transaction) => match self.transactions.get_mut(&transaction) {
+                        None => Err(format!("Unknown transaction {}", transaction)),
+                        Some(frames) => {
+                            if frames.len() >= MAX_TRANSACTION_FRAMES {
+                                return Err(format!("Transaction {} is too long", transaction));
+                            }
+                            frames.push(frame);
+                            Ok(())
+                        }
+                    },
+                }
+            }
+            "SUBSCRIBE" => {
+                let destination = required(&frame, "destination")?;
+                let id = required(&frame, "id")?;
+                let ack = match frame.header("ack").unwrap_or("auto") {
+                    "auto" => AckMode::Auto,
+                    "client" => AckMode::Client,
+                    "client-individual" => AckMode::ClientIndividual,
+                    _ => return Err("Invalid ack header".to_owned()),
+                };
+                let mut inner = self.broker.inner.borrow_mut();
+                inner.subscribe(self.session, id, destination, ack)
+            }
+            "UNSUBSCRIBE" => {
+                let id = required(&frame, "id")?;
+                if self.broker.inner.borrow_mut().unsubscribe(self.session, id) {
+                    Ok(())
+                } else {
+                    Err(format!("Unknown subscription {}", id))
+                }
+            }
+            "BEGIN" => {
+                let transaction = required(&frame, "transaction")?;
+                if self.transactions.contains_key(transaction) {
+                    return Err(format!("Transaction {} already exists", transaction));
+                }
+                if self.transactions.len() >= MAX_TRANSACTIONS {
+                    return Err("Too many open transactions".to_owned());
+                }
+                self.transactions.insert(transaction.to_owned(), Vec::new());
+                Ok(())
+            }
+            "COMMIT" => {
+                let transaction = required(&frame, "transaction")?;
+                match self.transactions.remove(transaction) {
+                    None => Err(format!("Unknown transaction {}", transaction)),
+                    Some(frames) => {
+                        for frame in &frames {
+                            self.apply(frame)?;
+                        }
+                        Ok(())
+                    }
+                }
+            }
+            "ABORT" => {
+                let transaction = required(&frame, "transaction")?;
+                match self.transactions.remove(transaction) {
+                    None => Err(format!("Unknown transaction {}", transaction)),
+                    Some(_) => Ok(()),
+                }
+            }
+            "DISCONNECT" => Ok(()),
+            "CONNECT" | "STOMP" => Err("Already connected".to_owned()),
+            command => Err(format!("Unknown command {}", command)),
+        }
+    }
+
+    /// Carries out a `SEND`, `ACK` or `NACK` frame.
+    fn apply(&self, frame: &StompFrame) -> Result<(), String> {
+        let mut inner = self.broker.inner.borrow_mut();
+        if frame.command() == "SEND" {
+            let destination = required(frame, "destination")?;
+            inner.deliver(destination, frame.headers(), frame.body());
+            return Ok(());
+        }
+        let id = required(frame, "id")?;
+        if inner.settle(self.session, id) {
+            Ok(())
+        } else {
+            Err(format!("Unknown ack ID {}", id))
+        }
+    }
+}
+
+fn required<'a>(frame: &'a StompFrame, name: &str) -> Result<&'a str, String> {
+    frame
+        .header(name)
+        .ok_or_else(|| format!("Missing {} header", name))
+}
+
+fn parse_heart_beat(heart_beat: &str) -> Option<(u64, u64)> {
+    let mut parts = heart_beat.splitn(2, ',');
+    let send = parts.next().and_then(|part| part.trim().parse().ok());
+    let receive = parts.next().and_then(|part| part.trim().parse().ok());
+    match (send, receive) {
+        (Some(send), Some(receive)) => Some((send, receive)),
+        _ => None,
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)
+}
@@ -0,0 +1,354 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use futures::{future, Future, IntoFuture, Poll, Sink, Stream};
+use futures::future::Either;
+use futures::sync::oneshot;
+use serde_json::{self, Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use super::Omitted;
+use super::channel::{spawn_channel, WsSender, QUEUE_CAPACITY};
+use super::jsonrpc::RpcError;
+
+const DEFAULT_MAX_SUBSCRIPTIONS: usize = 32;
+
+const TOO_MANY_SUBSCRIPTIONS: i64 = -32001;
+const UNKNOWN_SUBSCRIPTION: i64 = -32002;
+
+/// The items of one subscription. Ending it completes the subscription, and
+/// an error ends it with that error.
+pub type SubscriptionStream = Box<Stream<Item = Value, Error = RpcError>>;
+
+type Method = Fn(Value) -> Box<Future<Item = Value, Error = RpcError>>;
+type Topic = Fn(Value) -> Result<SubscriptionStream, RpcError>;
+
+/// Serves one-shot requests and server-pushed subscriptions over JSON text
+/// messages, each correlated by an ID the client picks.
+///
+/// The client sends objects with an `id`, a `type`, and further fields by
+/// type:
+///
+/// - `request` with a `method` and optional `params` is answered with a
+///   `reply` carrying the `result`, or an `error`.
+/// - `subscribe` with a `topic` and optional `params` is answered with
+///   `subscribed` carrying a server-assigned `subscription` ID. Each item then
+///   arrives as an `item` with that `subscription` and the `data`, until a
+///   final `complete` or `error` with the same `subscription`.
+/// - `unsubscribe` with a `subscription` is answered with `unsubscribed`, and
+///   nothing more arrives for that subscription.
+///
+/// Errors are JSON-RPC style error objects. Every subscription is cancelled
+/// when the connection closes.
+#[derive(Clone)]
+pub struct SubscriptionServer {
+    methods: HashMap<String, Rc<Method>>,
+    topics: HashMap<String, Rc<Topic>>,
+    max_subscriptions: usize,
+}
+
+impl Default for SubscriptionServer {
+    fn default() -> Self {
+        SubscriptionServer {
+            methods: HashMap::new(),
+            topics: HashMap::new(),
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+        }
+    }
+}
+
+impl fmt::Debug for SubscriptionServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut methods = self.methods.keys().collect::<Vec<_>>();
+        methods.sort();
+        let mut topics = self.topics.keys().collect::<Vec<_>>();
+        topics.sort();
+        f.debug_struct("SubscriptionServer")
+            .field("methods", &methods)
+            .field("topics", &topics)
+            .field("max_subscriptions", &self.max_subscriptions)
+            .finish()
+    }
+}
+
+impl SubscriptionServer {
+    pub fn new() -> Self {
+        SubscriptionServer::default()
+    }
+
+    /// Registers a request method, replacing any previous one with the same
+    /// name. Its handler gets the `params`, or `null` if there were none.
+    pub fn method<F, R>(mut self, name: &str, handler: F) -> Self
+    where
+        F: Fn(Value) -> R + 'static,
+        R: IntoFuture<Item = Value, Error = RpcError>,
+        R::Future: 'static,
+    {
+        let method = move |params: Value| {
+            Box::new(handler(params).into_future()) as Box<Future<Item = _, Error = _>>
+        };
+        self.methods.insert(name.to_owned(), Rc::new(method));
+        self
+    }
+
+    /// Registers a topic, replacing any previous one with the same name. Its
+    /// handler gets the `params` of each subscription, or `null` if there
+    /// were none, and either starts the stream of items or refuses.
+    pub fn topic<F, S>(mut self, name: &str, handler: F) -> Self
+    where
+        F: Fn(Value) -> Result<S, RpcError> + 'static,
+        S: Stream<Item = Value, Error = RpcError> + 'static,
+    {
+        let topic = move |params: Value| {
+            handler(params).map(|stream| Box::new(stream) as SubscriptionStream)
+        };
+        self.topics.insert(name.to_owned(), Rc::new(topic));
+        self
+    }
+
+    /// Caps how many subscriptions each connection may have running at once.
+    /// Subscribing past the cap fails. Defaults to 32.
+    pub fn max_subscriptions(mut self, max_subscriptions: usize) -> Self {
+        self.max_subscriptions = max_subscriptions;
+        self
+    }
+
+    /// Serves requests and subscriptions over an accepted connection,
+    /// spawning the tasks that do the IO onto `handle`.
+    pub fn serve<T>(&self, handle: &Handle, client: Client<T>) -> SubscriptionConnection
+    where
+        T: AsyncRead + AsyncWrite + 'static,
+    {
+        let (sender, receiver) = spawn_channel(handle, client, QUEUE_CAPACITY);
+        let conn = Rc::new(RefCell::new(ConnState {
+            server: self.clone(),
+            handle: handle.clone(),
+            sender: sender,
+            next_subscription: 0,
+            active: HashMap::new(),
+        }));
+
+        let dispatch_conn = conn.clone();
+        let serve = receiver
+            .for_each(move |msg| {
+                if let OwnedMessage::Text(text) = msg {
+                    dispatch(&dispatch_conn, &text);
+                }
+                Ok(())
+            })
+            .then(move |result| {
+                // End the client's subscriptions along with its connection.
+                conn.borrow_mut().active.clear();
+                result
+            });
+        SubscriptionConnection(Box::new(serve))
+    }
+}
+
+/// Runs one client's requests and subscriptions. Resolves once the client
+/// disconnects, cancelling any subscriptions it still had.
+pub struct SubscriptionConnection(Box<Future<Item = (), Error = WebSocketError>>);
+
+impl fmt::Debug for SubscriptionConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("SubscriptionConnection").field(&Omitted).finish()
+    }
+}
+
+impl Future for SubscriptionConnection {
+    type Item = ();
+    type Error = WebSocketError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+struct ConnState {
+    server: SubscriptionServer,
+    handle: Handle,
+    sender: WsSender,
+    next_subscription: u64,
+    /// Cancel handles for the running subscriptions.
+    active: HashMap<u64, oneshot::Sender<()>>,
+}
+
+fn message(kind: &str, fields: Vec<(&str, Value)>) -> OwnedMessage {
+    let mut object = Map::new();
+    object.insert("type".to_owned(), kind.into());
+    for (key, value) in fields {
+        object.insert(key.to_owned(), value);
+    }
+    OwnedMessage::Text(Value::Object(object).to_string())
+}
+
+impl ConnState {
+    /// Queues a reply, dropping it if the client has let the queue fill up.
+    fn send(&mut self, msg: OwnedMessage) {
+        if let Err(err) = self.sender.try_send(msg) {
+            if err.is_full() {
+                debug!("hyper-websocket: subscription reply dropped, queue full");
+            }
+        }
+    }
+}
+
+fn dispatch(conn: &Rc<RefCell<ConnState>>, text: &str) {
+    let mut object = match serde_json::from_str::<Value>(text) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return reject(conn, Value::Null, RpcError::invalid_request()),
+        Err(_) => return reject(conn, Value::Null, RpcError::parse_error()),
+    };
+    let id = object.remove("id").unwrap_or(Value::Null);
+    let params = object.remove("params").unwrap_or(Value::Null);
+    let kind = object.get("type").and_then(Value::as_str).map(str::to_owned);
+    match kind.as_ref().map(String::as_str) {
+        Some("request") => match object.get("method").and_then(Value::as_str) {
+            None => reject(conn, id, RpcError::invalid_request()),
+            Some(method) => request(conn, id, method, params),
+        },
+        Some("subscribe") => match object.get("topic").and_then(Value::as_str) {
+            None => reject(conn, id, RpcError::invalid_request()),
+            Some(topic) => subscribe(conn, id, topic, params),
+        },
+        Some("unsubscribe") => match object.get("subscription").and_then(Value::as_u64) {
+            None => reject(conn, id, RpcError::invalid_request()),
+            Some(subscription) => unsubscribe(conn, id, subscription),
+        },
+        _ => reject(conn, id, RpcError::invalid_request()),
+    }
+}
+
+fn reject(conn: &Rc<RefCell<ConnState>>, id: Value, error: RpcError) {
+    let msg = message("error", vec![("id", id), ("error", error.to_json())]);
+    conn.borrow_mut().send(msg);
+}
+
+fn request(conn_rc: &Rc<RefCell<ConnState>>, id: Value, method: &str, params: Value) {
+    let mut conn = conn_rc.borrow_mut();
+    let handler = match conn.server.methods.get(method).cloned() {
+        None => {
+            let msg = message(
+                "error",
+                vec![("id", id), ("error", RpcError::method_not_found().to_json())],
+            );
+            return conn.send(msg);
+        }
+        Some(handler) => handler,
+    };
+
+    // The reply goes through the connection's own sender, so that it counts
+    // against the queue like every other reply.
+    let weak = Rc::downgrade(conn_rc);
+    conn.handle.spawn(handler(params).then(move |result| {
+        let msg = match result {
+            Ok(result) => message("reply", vec![("id", id), ("result", result)]),
+            Err(error) => message("error", vec![("id", id), ("error", error.to_json())]),
+        };
+        if let Some(conn) = weak.upgrade() {
+            conn.borrow_mut().send(msg);
+        }
+        Ok(())
+    }));
+}
+
+fn subscribe(conn_rc: &Rc<RefCell<ConnState>>, id: Value, topic: &str, params: Value) {
+    let mut conn = conn_rc.borrow_mut();
+    let handler = match conn.server.topics.get(topic).cloned() {
+        None => {
+            let msg = message(
+                "error",
+                vec![("id", id), ("error", RpcError::method_not_found().to_json())],
+            );
+            return conn.send(msg);
+        }
+        Some(handler) => handler,
+    };
+    if conn.active.len() >= conn.server.max_subscriptions {
+        let error = RpcError::new(TOO_MANY_SUBSCRIPTIONS, "Too many subscriptions");
+        let msg = message("error", vec![("id", id), ("error", error.to_json())]);
+        return conn.send(msg);
+    }
+    let stream = match handler(params) {
+        Err(error) => {
+            let msg = message("error", vec![("id", id), ("error", error.to_json())]);
+            return conn.send(msg);
+        }
+        Ok(stream) => stream,
+    };
+
+    let subscription = conn.next_subscription;
+    conn.next_subscription += 1;
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    conn.active.insert(subscription, cancel_tx);
+
+    let subscribed = message(
+        "subscribed",
+        vec![("id", id), ("subscription", subscription.into())],
+    );
+    let end_sender = conn.sender.clone();
+    // A `None` error means the connection closed.
+    let feed = conn.sender
+        .clone()
+        .send(subscribed)
+        .map_err(|_| None::<RpcError>)
+        .and_then(move |sender| {
+            stream
+                .map(move |data| {
+                    message(
+                        "item",
+                        vec![("subscription", subscription.into()), ("data", data)],
+                    )
+                })
+                .map_err(Some)
+                .forward(sender.sink_map_err(|_| None::<RpcError>))
+        })
+        .then(move |result| {
+            let end = match result {
+                Ok(_) => message("complete", vec![("subscription", subscription.into())]),
+                Err(Some(error)) => message(
+                    "error",
+                    vec![
+                        ("subscription", subscription.into()),
+                        ("error", error.to_json()),
+                    ],
+                ),
+                Err(None) => return Either::A(future::ok::<(), ()>(())),
+            };
+            Either::B(end_sender.send(end).then(|_| Ok(())))
+        });
+
+    // The subscription stops early if its cancel handle fires or is dropped.
+    let weak = Rc::downgrade(conn_rc);
+    conn.handle.spawn(feed.select2(cancel_rx).then(move |_| {
+        if let Some(conn) = weak.upgrade() {
+            conn.borrow_mut().active.remove(&subscription);
+        }
+        Ok(())
+    }));
+}
+
+fn unsubscribe(conn: &Rc<RefCell<ConnState>>, id: Value, subscription: u64) {
+    let mut conn = conn.borrow_mut();
+    let msg = match conn.active.remove(&subscription) {
+        None => {
+            let error = RpcError::new(UNKNOWN_SUBSCRIPTION, "Unknown subscription");
+            message("error", vec![("id", id), ("error", error.to_json())])
+        }
+        Some(cancel) => {
+            // The feed may have just finished on its own.
+            let _ = cancel.send(());
+            message("unsubscribed", vec![("id", id)])
+        }
+    };
+    conn.send(msg);
+}
@@ -0,0 +1,270 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{BigEndian, Buf, BufMut, IntoBuf};
+use futures::{Async, Poll, Stream};
+use futures::task;
+use rand;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str;
+use tokio_core::reactor::Handle;
+use tokio_uds::UnixDatagram;
+use websocket::message::OwnedMessage;
+
+use super::backplane::{Backplane, BackplaneMessages};
+
+/// Identifies packets from this version of the mesh protocol.
+const MAGIC: &[u8] = b"HWB1";
+
+const OPCODE_TEXT: u8 = 1;
+const OPCODE_BINARY: u8 = 2;
+
+/// Large enough for any datagram the default Linux socket buffers will carry.
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+const SOCKET_EXTENSION: &str = "sock";
+
+/// A `Backplane` connecting every process that points one at the same
+/// directory. Each process binds a datagram socket there and sends each
+/// broadcast straight to every other socket it finds.
+///
+/// Every backplane gets a random origin ID. Packets carry their origin and a
+/// sequence number, so a backplane ignores its own packets and any it has
+/// already seen. Only text and binary messages are relayed, and messages too
+/// large for a single datagram are dropped.
+#[derive(Clone)]
+pub struct UnixBackplane {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    path: PathBuf,
+    origin: u64,
+    seq: Cell<u64>,
+    recv: RefCell<Option<UnixDatagram>>,
+    send: net::UnixDatagram,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl fmt::Debug for UnixBackplane {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UnixBackplane")
+            .field("path", &self.inner.path)
+            .field("origin", &self.inner.origin)
+            .finish()
+    }
+}
+
+impl UnixBackplane {
+    /// Joins the mesh in `dir`, creating the directory if needed.
+    pub fn bind<P>(dir: P, handle: &Handle) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        let origin = rand::random::<u64>();
+        let path = dir.join(format!("{:016x}.{}", origin, SOCKET_EXTENSION));
+        let recv = UnixDatagram::bind(&path, handle)?;
+        let send = net::UnixDatagram::unbound()?;
+        // A peer that can't keep up misses messages rather than stalling
+        // everyone else's broadcasts.
+        send.set_nonblocking(true)?;
+
+        Ok(UnixBackplane {
+            inner: Rc::new(Inner {
+                dir: dir,
+                path: path,
+                origin: origin,
+                seq: Cell::new(0),
+                recv: RefCell::new(Some(recv)),
+                send: send,
+            }),
+        })
+    }
+
+    pub fn origin(&self) -> u64 {
+        self.inner.origin
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    fn peers(&self) -> io::Result<Vec<PathBuf>> {
+        let mut peers = Vec::new();
+        for entry in fs::read_dir(&self.inner.dir)? {
+            let path = entry?.path();
+            let is_socket = path.extension().map_or(false, |ext| ext == SOCKET_EXTENSION);
+            if is_socket && path != self.inner.path {
+                peers.push(path);
+            }
+        }
+        Ok(peers)
+    }
+}
+
+impl Backplane for UnixBackplane {
+    fn publish(&self, room: &str, msg: &OwnedMessage) {
+        let seq = self.inner.seq.get() + 1;
+        self.inner.seq.set(seq);
+        let packet = match encode_packet(self.inner.origin, seq, room, msg) {
+            None => return,
+            Some(packet) => packet,
+        };
+
+        let peers = match self.peers() {
+            Err(err) => {
+                error!("hyper-websocket: backplane directory listing failed: {}", err);
+                return;
+            }
+            Ok(peers) => peers,
+        };
+        for peer in peers {
+            match self.inner.send.send_to(&packet, &peer) {
+                Ok(_) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    // Nobody is bound to it anymore; the process that made
+                    // it is gone.
+                    debug!("hyper-websocket: removing stale backplane socket {:?}", peer);
+                    let _ = fs::remove_file(&peer);
+                }
+                Err(err) => {
+                    debug!("hyper-websocket: backplane send to {:?} failed: {}", peer, err);
+                }
+            }
+        }
+    }
+
+    fn subscribe(&self) -> BackplaneMessages {
+        let socket = self.inner
+            .recv
+            .borrow_mut()
+            .take()
+            .expect("hyper-websocket: UnixBackplane subscribed to twice");
+        Box::new(Incoming {
+            socket: socket,
+            origin: self.inner.origin,
+            seen: HashMap::new(),
+            buf: vec![0; MAX_PACKET_SIZE],
+        })
+    }
+}
+
+struct Incoming {
+    socket: UnixDatagram,
+    origin: u64,
+    /// The highest sequence number seen from each other origin.
+    seen: HashMap<u64, u64>,
+    buf: Vec<u8>,
+}
+
+impl Stream for Incoming {
+    type Item = (String, OwnedMessage);
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let len = match self.socket.recv_from(&mut self.buf) {
+                Ok((len, _addr)) => len,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady);
+                }
+                // Receiving goes on after an error, but only once the other
+                // tasks have had a turn, so that an error that keeps coming
+                // back can't hog the event loop.
+                Err(err) => {
+                    error!("hyper-websocket: backplane receive failed: {}", err);
+                    task::current().notify();
+                    return Ok(Async::NotReady);
+                }
+            };
+
+            let (origin, seq, room, msg) = match decode_packet(&self.buf[..len]) {
+                None => {
+                    debug!("hyper-websocket: ignoring malformed backplane packet");
+                    continue;
+                }
+                Some(packet) => packet,
+            };
+            if origin == self.origin {
+                continue;
+            }
+            let last_seq = self.seen.entry(origin).or_insert(0);
+            if seq <= *last_seq {
+                continue;
+            }
+            *last_seq = seq;
+            return Ok(Async::Ready(Some((room, msg))));
+        }
+    }
+}
+
+fn encode_packet(origin: u64, seq: u64, room: &str, msg: &OwnedMessage) -> Option<Vec<u8>> {
+    let (opcode, payload) = match *msg {
+        OwnedMessage::Text(ref text) => (OPCODE_TEXT, text.as_bytes()),
+        OwnedMessage::Binary(ref data) => (OPCODE_BINARY, &data[..]),
+        _ => return None,
+    };
+    if room.len() > usize::from(u16::max_value()) {
+        return None;
+    }
+    let len = MAGIC.len() + 8 + 8 + 2 + room.len() + 1 + payload.len();
+    if len > MAX_PACKET_SIZE {
+        debug!("hyper-websocket: message too large for the backplane");
+        return None;
+    }
+
+    let mut packet = Vec::with_capacity(len);
+    packet.put_slice(MAGIC);
+    packet.put_u64::<BigEndian>(origin);
+    packet.put_u64::<BigEndian>(seq);
+    packet.put_u16::<BigEndian>(room.len() as u16);
+    packet.put_slice(room.as_bytes());
+    packet.put_u8(opcode);
+    packet.put_slice(payload);
+    Some(packet)
+}
+
+fn decode_packet(packet: &[u8]) -> Option<(u64, u64, String, OwnedMessage)> {
+    if packet.len() < MAGIC.len() + 8 + 8 + 2 || !packet.starts_with(MAGIC) {
+        return None;
+    }
+    let mut buf = packet[MAGIC.len()..].into_buf();
+    let origin = buf.get_u64::<BigEndian>();
+    let seq = buf.get_u64::<BigEndian>();
+    let room_len = usize::from(buf.get_u16::<BigEndian>());
+    if buf.remaining() < room_len + 1 {
+        return None;
+    }
+    let rest = buf.bytes();
+    let room = match str::from_utf8(&rest[..room_len]) {
+        Err(_) => return None,
+        Ok(room) => room.to_owned(),
+    };
+    let payload = &rest[room_len + 1..];
+    let msg = match rest[room_len] {
+        OPCODE_TEXT => match str::from_utf8(payload) {
+            Err(_) => return None,
+            Ok(text) => OwnedMessage::Text(text.to_owned()),
+        },
+        OPCODE_BINARY => OwnedMessage::Binary(payload.to_vec()),
+        _ => return None,
+    };
+    Some((origin, seq, room, msg))
+}
@@ -0,0 +1,104 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg(unix)]
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate rand;
+extern crate tokio_core;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+use futures::Stream;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use tokio_core::reactor::Core;
+use websocket::message::OwnedMessage;
+
+use hyper_websocket::{Backplane, UnixBackplane};
+
+const CHILD_DIR_VAR: &str = "HYPER_WEBSOCKET_TEST_BACKPLANE_DIR";
+
+fn mesh_dir() -> PathBuf {
+    env::temp_dir().join(format!("hyper-websocket-test-{:016x}", rand::random::<u64>()))
+}
+
+#[test]
+fn test_unix_backplane_relay() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let dir = mesh_dir();
+
+    let a = UnixBackplane::bind(&dir, &handle).expect("backplane bind error");
+    let b = UnixBackplane::bind(&dir, &handle).expect("backplane bind error");
+    assert!(a.origin() != b.origin());
+    let a_incoming = a.subscribe();
+    let b_incoming = b.subscribe();
+
+    a.publish("lobby", &OwnedMessage::Text("hi".into()));
+    // Control messages aren't relayed.
+    a.publish("lobby", &OwnedMessage::Ping(Vec::new()));
+    b.publish("attic", &OwnedMessage::Binary(vec![1, 2, 3]));
+
+    let (item, _) = core.run(b_incoming.into_future())
+        .map_err(|_| "backplane receive error")
+        .unwrap();
+    assert_eq!(item, Some(("lobby".to_owned(), OwnedMessage::Text("hi".into()))));
+
+    // `a` never hears its own message.
+    let (item, _) = core.run(a_incoming.into_future())
+        .map_err(|_| "backplane receive error")
+        .unwrap();
+    assert_eq!(item, Some(("attic".to_owned(), OwnedMessage::Binary(vec![1, 2, 3]))));
+
+    drop((a, b));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Publishes a single message when run as a child process by
+/// `test_unix_backplane_across_processes`, and does nothing otherwise.
+#[test]
+fn test_unix_backplane_child() {
+    let dir = match env::var_os(CHILD_DIR_VAR) {
+        None => return,
+        Some(dir) => dir,
+    };
+    let core = Core::new().expect("core creation error");
+    let backplane = UnixBackplane::bind(&dir, &core.handle()).expect("backplane bind error");
+    backplane.publish("lobby", &OwnedMessage::Text("from another process".into()));
+}
+
+#[test]
+fn test_unix_backplane_across_processes() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let dir = mesh_dir();
+    let backplane = UnixBackplane::bind(&dir, &handle).expect("backplane bind error");
+
+    let status = Command::new(env::current_exe().expect("test executable path error"))
+        .arg("test_unix_backplane_child")
+        .arg("--exact")
+        .env(CHILD_DIR_VAR, &dir)
+        .status()
+        .expect("child process spawn error");
+    assert!(status.success());
+
+    let (item, _) = core.run(backplane.subscribe().into_future())
+        .map_err(|_| "backplane receive error")
+        .unwrap();
+    assert_eq!(
+        item,
+        Some(("lobby".to_owned(), OwnedMessage::Text("from another process".into())))
+    );
+
+    drop(backplane);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{future, Future, Sink, Stream};
+use std::net::SocketAddr;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, write_all};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{TcpBridge, WsResponse, WsServer, BASE64_PROTOCOL};
+
+use common::{bind, hear, NotFoundService};
+
+/// Starts a TCP server that echoes everything back.
+fn start_echo(handle: &Handle) -> SocketAddr {
+    let (listener, addr) = bind(handle);
+    let spawn_handle = handle.clone();
+    let serve = listener.incoming().for_each(move |(stream, _addr)| {
+        let (reader, writer) = stream.split();
+        spawn_handle.spawn(copy(reader, writer).then(|_| Ok(())));
+        Ok(())
+    });
+    handle.spawn(serve.map_err(|err| panic!("echo accept error: {}", err)));
+    addr
+}
+
+/// Starts a TCP server that says goodbye and hangs up.
+fn start_goodbye(handle: &Handle) -> SocketAddr {
+    let (listener, addr) = bind(handle);
+    let spawn_handle = handle.clone();
+    let serve = listener.incoming().for_each(move |(stream, _addr)| {
+        spawn_handle.spawn(write_all(stream, b"bye").then(|_| Ok(())));
+        Ok(())
+    });
+    handle.spawn(serve.map_err(|err| panic!("goodbye accept error: {}", err)));
+    addr
+}
+
+/// Starts a TCP server that says hello and then stays quiet, keeping the
+/// connection open.
+fn start_hello(handle: &Handle) -> SocketAddr {
+    let (listener, addr) = bind(handle);
+    let spawn_handle = handle.clone();
+    let serve = listener.incoming().for_each(move |(stream, _addr)| {
+        let hello = write_all(stream, b"hello")
+            .and_then(|(stream, _buf)| future::empty().map(move |()| drop(stream)));
+        spawn_handle.spawn(hello.then(|_| Ok(())));
+        Ok(())
+    });
+    handle.spawn(serve.map_err(|err| panic!("hello accept error: {}", err)));
+    addr
+}
+
+/// Starts a bridge to an echo server that also allows a goodbye server and a
+/// hello server, returning its address and theirs.
+fn start_server(handle: &Handle) -> (SocketAddr, SocketAddr, SocketAddr) {
+    let echo_addr = start_echo(handle);
+    let goodbye_addr = start_goodbye(handle);
+    let hello_addr = start_hello(handle);
+    let (listener, server_addr) = bind(handle);
+
+    let bridge = TcpBridge::new(handle)
+        .target(echo_addr)
+        .allow(goodbye_addr)
+        .allow(hello_addr)
+        .base64(true)
+        .chunk_size(4);
+    let serve = WsServer::from_endpoint(NotFoundService, bridge).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    (server_addr, goodbye_addr, hello_addr)
+}
+
+fn connect(
+    handle: &Handle,
+    url: &str,
+    protocol: Option<&str>,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    let mut builder = ClientBuilder::new(url).expect("client build error");
+    if let Some(protocol) = protocol {
+        builder = builder.add_protocol(protocol);
+    }
+    Box::new(builder.async_connect_insecure(handle).map(|(websocket, _headers)| websocket))
+}
+
+#[test]
+fn test_bridge_binary() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, _goodbye_addr, _hello_addr) = start_server(&handle);
+
+    let url = format!("ws://{}/", server_addr);
+    let websocket = core.run(connect(&handle, &url, None)).expect("client websocket error");
+    let hello = OwnedMessage::Binary(b"hello, world".to_vec());
+    let mut websocket = core.run(websocket.send(hello)).expect("client websocket error");
+
+    let mut echoed = Vec::new();
+    while echoed.len() < 12 {
+        let (msg, next) = core.run(hear(websocket)).expect("client websocket error");
+        websocket = next;
+        match msg {
+            OwnedMessage::Binary(data) => {
+                assert!(data.len() <= 4);
+                echoed.extend(data);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+    assert_eq!(echoed, b"hello, world".to_vec());
+}
+
+#[test]
+fn test_bridge_base64() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, _goodbye_addr, _hello_addr) = start_server(&handle);
+
+    let url = format!("ws://{}/", server_addr);
+    let test = connect(&handle, &url, Some(BASE64_PROTOCOL))
+        .and_then(|websocket| websocket.send(OwnedMessage::Text("aGk=".to_owned())));
+    let websocket = core.run(test).expect("client websocket error");
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Text("aGk=".to_owned()));
+}
+
+#[test]
+fn test_bridge_allowlist() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, goodbye_addr, _hello_addr) = start_server(&handle);
+
+    let url = format!("ws://{}/?target={}", server_addr, goodbye_addr);
+    let websocket = core.run(connect(&handle, &url, None)).expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Binary(b"bye".to_vec()));
+
+    // The target hanging up closes the WebSocket.
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert!(match msg {
+        OwnedMessage::Close(Some(ref data)) => data.status_code == 1000,
+        _ => false,
+    });
+
+    let url = format!("ws://{}/?target=127.0.0.1:1", server_addr);
+    assert!(core.run(connect(&handle, &url, None)).is_err());
+}
+
+#[test]
+fn test_bridge_unprompted() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, _goodbye_addr, hello_addr) = start_server(&handle);
+
+    // The target speaks first and keeps the connection open, so nothing but
+    // the bridge itself flushes what it read.
+    let url = format!("ws://{}/?target={}", server_addr, hello_addr);
+    let mut websocket = core.run(connect(&handle, &url, None)).expect("client websocket error");
+    let mut heard = Vec::new();
+    while heard.len() < 5 {
+        let (msg, next) = core.run(hear(websocket)).expect("client websocket error");
+        websocket = next;
+        match msg {
+            OwnedMessage::Binary(data) => heard.extend(data),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+    assert_eq!(heard, b"hello".to_vec());
+}
+
+#[test]
+fn test_bridge_unsupported_protocol() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, _goodbye_addr, _hello_addr) = start_server(&handle);
+
+    let url = format!("ws://{}/", server_addr);
+    assert!(core.run(connect(&handle, &url, Some("chat"))).is_err());
+}
+
+#[test]
+fn test_bridge_base64_disabled() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let echo_addr = start_echo(&handle);
+    let (listener, server_addr) = bind(&handle);
+
+    // Whatever was negotiated, the bridge won't speak base64 unless enabled.
+    let bridge = TcpBridge::new(&handle).target(echo_addr);
+    let serve = WsServer::new(NotFoundService, move |ctx, websocket| {
+        bridge.serve(&ctx, websocket)
+    }).negotiate(|_req, handshake| WsResponse::accept(handshake.use_protocol(BASE64_PROTOCOL)))
+        .serve(listener, &handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    let url = format!("ws://{}/", server_addr);
+    let websocket = core.run(connect(&handle, &url, Some(BASE64_PROTOCOL)))
+        .expect("client websocket error");
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert!(match msg {
+        OwnedMessage::Close(Some(ref data)) => data.status_code == 1008,
+        _ => false,
+    });
+}
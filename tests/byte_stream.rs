@@ -0,0 +1,112 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{Future, Sink, Stream};
+use std::net::SocketAddr;
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, shutdown};
+use websocket::ClientBuilder;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{ByteStream, WsServer};
+
+use common::{bind, hear, NotFoundService};
+
+/// Starts a server that copies each connection's bytes back to it in chunks
+/// of at most four bytes.
+fn start_server(handle: &Handle) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let serve = WsServer::new(NotFoundService, |_ctx, websocket| {
+        let (reader, writer) = ByteStream::new(websocket, 4).split();
+        copy(reader, writer)
+            .and_then(|(_len, _reader, writer)| shutdown(writer))
+            .map(|_writer| ())
+            .map_err(WebSocketError::IoError)
+    }).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+#[test]
+fn test_byte_stream() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let url = format!("ws://{}/", server_addr);
+    let test = ClientBuilder::new(&url)
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+
+    // Pings are answered.
+    let websocket = core.run(websocket.send(OwnedMessage::Ping(b"ping".to_vec())))
+        .expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Pong(b"ping".to_vec()));
+
+    // Writes are chunked.
+    let websocket = core.run(websocket.send(OwnedMessage::Binary(b"hello world".to_vec())))
+        .expect("client websocket error");
+    let mut echoed = Vec::new();
+    let mut websocket = websocket;
+    while echoed.len() < 11 {
+        let (msg, next) = core.run(hear(websocket)).expect("client websocket error");
+        websocket = next;
+        match msg {
+            OwnedMessage::Binary(data) => {
+                assert!(data.len() <= 4);
+                echoed.extend(data);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+    assert_eq!(echoed, b"hello world".to_vec());
+
+    // Closing ends the copy, and shutting down answers the close.
+    let close = OwnedMessage::Close(Some(CloseData::new(1000, "done".to_owned())));
+    let websocket = core.run(websocket.send(close.clone())).expect("client websocket error");
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, close);
+}
+
+#[test]
+fn test_byte_stream_rejects_text() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let url = format!("ws://{}/", server_addr);
+    let test = ClientBuilder::new(&url)
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .and_then(|(websocket, _headers)| websocket.send(OwnedMessage::Text("hi".to_owned())))
+        .and_then(|websocket| websocket.into_future().map_err(|(err, _websocket)| err));
+    // The failed copy drops the connection without echoing anything.
+    match core.run(test) {
+        Ok((None, _websocket)) | Err(_) => {}
+        Ok((Some(msg), _websocket)) => panic!("unexpected message: {:?}", msg),
+    }
+}
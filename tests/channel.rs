@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{future, Future, Sink, Stream};
+use futures::future::Either;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::message::{CloseData, OwnedMessage};
+
+use hyper_websocket::{spawn_channel, WsSendError, WsSender, WsServer};
+
+use common::{bind, NotFoundService};
+
+/// Starts a server that greets each connection and then shouts back its text
+/// messages. The sender of the latest connection is left in the returned
+/// slot.
+fn start_server(handle: &Handle) -> (SocketAddr, Rc<RefCell<Option<WsSender>>>) {
+    let (listener, server_addr) = bind(handle);
+
+    let slot = Rc::new(RefCell::new(None));
+    let server_slot = slot.clone();
+    let server_handle = handle.clone();
+    let serve = WsServer::new(NotFoundService, move |_ctx, websocket| {
+        let (mut sender, receiver) = spawn_channel(&server_handle, websocket, 4);
+        sender
+            .try_send(OwnedMessage::Text("welcome".into()))
+            .expect("server channel send error");
+        *server_slot.borrow_mut() = Some(sender.clone());
+
+        receiver.for_each(move |msg| {
+            let reply = match msg {
+                OwnedMessage::Text(text) => OwnedMessage::Text(text.to_uppercase()),
+                _ => return Either::A(future::ok(())),
+            };
+            Either::B(sender.clone().send(reply).then(|result| {
+                if let Err(err) = result {
+                    assert!(err.is_closed());
+                }
+                Ok(())
+            }))
+        })
+    }).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    (server_addr, slot)
+}
+
+#[test]
+fn test_channel_echo() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, slot) = start_server(&handle);
+
+    let test = ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .and_then(|(websocket, _headers)| websocket.send(OwnedMessage::Text("quiet".into())))
+        .and_then(|websocket| websocket.take(2).collect());
+    let msgs = core.run(test).expect("client websocket error");
+    assert_eq!(
+        msgs,
+        vec![
+            OwnedMessage::Text("welcome".into()),
+            OwnedMessage::Text("QUIET".into()),
+        ]
+    );
+
+    let test = ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .and_then(|(websocket, _headers)| websocket.into_future().map_err(|(err, _)| err))
+        .and_then(move |(_welcome, websocket)| {
+            // Messages pushed from outside the handler still reach the socket.
+            let sender = slot.borrow().clone().expect("server never connected");
+            sender
+                .send(OwnedMessage::Text("from elsewhere".into()))
+                .map_err(|err| panic!("server channel send error: {}", err))
+                .and_then(|_sender| websocket.into_future().map_err(|(err, _)| err))
+        })
+        .map(|(maybe_msg, _websocket)| maybe_msg);
+    let maybe_msg = core.run(test).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("from elsewhere".into())));
+}
+
+#[test]
+fn test_channel_closed() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, slot) = start_server(&handle);
+
+    let close = OwnedMessage::Close(Some(CloseData::new(1000, "done".into())));
+    let test = ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .and_then(move |(websocket, _headers)| websocket.send(close))
+        .and_then(|websocket| websocket.take(2).collect());
+    let msgs = core.run(test).expect("client websocket error");
+    assert_eq!(
+        msgs,
+        vec![
+            OwnedMessage::Text("welcome".into()),
+            OwnedMessage::Close(Some(CloseData::new(1000, "done".into()))),
+        ]
+    );
+
+    let mut sender = slot.borrow().clone().expect("server never connected");
+    match sender.try_send(OwnedMessage::Text("too late".into())) {
+        Err(WsSendError::Closed(OwnedMessage::Text(ref text))) if text == "too late" => {}
+        result => panic!("unexpected send result: {:?}", result),
+    }
+}
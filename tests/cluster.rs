@@ -0,0 +1,201 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{future, Future, Sink, Stream};
+use futures::future::Either;
+use std::cell::Cell;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{spawn_channel, Cluster, ConnectionId, Hub, SlowConsumerPolicy, WsServer};
+
+use common::{bind, hear_or_end, NotFoundService};
+
+fn loopback() -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0)
+}
+
+/// Starts a cluster node, along with a server that puts each connection in the
+/// room named by its path and attaches it to the cluster under its query
+/// string. Returns the node and the server's address.
+fn start_node(handle: &Handle) -> (Cluster, SocketAddr) {
+    let cluster = Cluster::bind(&loopback(), handle).expect("cluster bind error");
+    let hub = Hub::new().backplane(handle, cluster.clone());
+
+    let (listener, server_addr) = bind(handle);
+
+    let server_cluster = cluster.clone();
+    let next_id = Rc::new(Cell::new(0));
+    let server_handle = handle.clone();
+    let serve = WsServer::new(NotFoundService, move |ctx, websocket| {
+        let id = ConnectionId::new(next_id.get());
+        next_id.set(next_id.get() + 1);
+        let room = ctx.path()[1..].to_owned();
+
+        let (mut sender, receiver) = spawn_channel(&server_handle, websocket, 8);
+        hub.add(id, sender.clone(), SlowConsumerPolicy::Drop);
+        server_cluster.attach(id, ctx.query().unwrap_or(""), sender.clone());
+        sender
+            .try_send(OwnedMessage::Text("joined".into()))
+            .expect("server channel send error");
+        server_handle.spawn(hub.join(id, &room).map(|_| ()));
+
+        let relay_hub = hub.clone();
+        let hub = hub.clone();
+        let cluster = server_cluster.clone();
+        receiver
+            .for_each(move |msg| {
+                let broadcast = match msg {
+                    OwnedMessage::Text(_) => relay_hub.broadcast(&room, &msg, Some(id)),
+                    _ => return Either::A(future::ok(())),
+                };
+                Either::B(broadcast.then(|_| Ok(())))
+            })
+            .then(move |result| {
+                cluster.detach(id);
+                hub.remove(id);
+                result
+            })
+    }).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    (cluster, server_addr)
+}
+
+/// Runs the event loop until `done` holds.
+fn settle<F>(core: &mut Core, done: F)
+where
+    F: Fn() -> bool,
+{
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < deadline, "cluster didn't settle in time");
+        core.turn(Some(Duration::from_millis(10)));
+    }
+}
+
+fn connect(
+    handle: &Handle,
+    server_addr: SocketAddr,
+    path: &str,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    Box::new(
+        ClientBuilder::new(format!("ws://{}/{}", server_addr, path).as_str())
+            .expect("client build error")
+            .async_connect_insecure(handle)
+            .and_then(|(websocket, _headers)| {
+                websocket.into_future().map_err(|(err, _websocket)| err)
+            })
+            .map(|(maybe_msg, websocket)| {
+                assert_eq!(maybe_msg, Some(OwnedMessage::Text("joined".into())));
+                websocket
+            }),
+    )
+}
+
+#[test]
+fn test_cluster_membership() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (a, _) = start_node(&handle);
+    let (b, _) = start_node(&handle);
+    let (c, _) = start_node(&handle);
+
+    // `b` and `c` only know about `a`, and find each other through it.
+    b.join(&a.local_addr());
+    c.join(&a.local_addr());
+    settle(&mut core, || {
+        a.nodes().len() == 3 && b.nodes().len() == 3 && c.nodes().len() == 3
+    });
+
+    let mut expected = vec![a.node_id(), b.node_id(), c.node_id()];
+    expected.sort();
+    assert_eq!(a.nodes(), expected);
+    assert_eq!(b.nodes(), expected);
+    assert_eq!(c.nodes(), expected);
+}
+
+#[test]
+fn test_cluster_advertised_addr() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (a, _) = start_node(&handle);
+    let (b, _) = start_node(&handle);
+
+    // `c` listens on every interface, and tells the others to dial it over
+    // the loopback interface instead.
+    let any = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0);
+    let c = Cluster::bind(&any, &handle).expect("cluster bind error");
+    let advertised = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), c.local_addr().port());
+    c.advertise(&advertised);
+    assert_eq!(c.advertised_addr(), advertised);
+
+    b.join(&a.local_addr());
+    c.join(&a.local_addr());
+    settle(&mut core, || {
+        a.nodes().len() == 3 && b.nodes().len() == 3 && c.nodes().len() == 3
+    });
+}
+
+#[test]
+fn test_cluster_rooms_and_direct_messages() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (a, a_addr) = start_node(&handle);
+    let (b, b_addr) = start_node(&handle);
+    b.join(&a.local_addr());
+    settle(&mut core, || a.nodes().len() == 2 && b.nodes().len() == 2);
+
+    let alice = core.run(connect(&handle, a_addr, "lobby?alice"))
+        .expect("client websocket error");
+    let bob = core.run(connect(&handle, b_addr, "lobby?bob"))
+        .expect("client websocket error");
+    settle(&mut core, || a.is_connected("bob") && b.is_connected("alice"));
+    assert!(!a.is_connected("carol"));
+
+    // A room broadcast on one node reaches members on the other.
+    let alice = core.run(alice.send(OwnedMessage::Text("hi".into())))
+        .expect("client websocket error");
+    let (maybe_msg, bob) = core.run(hear_or_end(bob)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("hi".into())));
+
+    // Direct messages are routed to the node holding the connection.
+    let located = a.locate("bob");
+    assert_eq!(located.len(), 1);
+    assert_eq!(located[0].0, b.node_id());
+    assert!(a.send(located[0].0, located[0].1, OwnedMessage::Text("psst".into())));
+    let (maybe_msg, bob) = core.run(hear_or_end(bob)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("psst".into())));
+
+    assert_eq!(b.send_to_identity("alice", &OwnedMessage::Text("hey".into())), 1);
+    let (maybe_msg, _alice) = core.run(hear_or_end(alice)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("hey".into())));
+
+    // Once Bob hangs up, the other node forgets him.
+    drop(bob);
+    settle(&mut core, || !a.is_connected("bob"));
+    assert!(!a.send(located[0].0, located[0].1, OwnedMessage::Text("gone".into())));
+}
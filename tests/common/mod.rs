@@ -0,0 +1,61 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Fixtures shared by the integration tests. Each test crate uses only some of
+//! them.
+
+#![allow(dead_code)]
+
+use futures::{future, Future, Stream};
+use hyper::{self, Request, Response, StatusCode};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use tokio_service::Service;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+/// Answers every plain HTTP request with `404 Not Found`.
+pub struct NotFoundService;
+
+impl Service for NotFoundService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = future::FutureResult<Self::Response, Self::Error>;
+
+    fn call(&self, _req: Self::Request) -> Self::Future {
+        future::ok(Response::new().with_status(StatusCode::NotFound))
+    }
+}
+
+/// Binds a listener to a free loopback port.
+pub fn bind(handle: &Handle) -> (TcpListener, SocketAddr) {
+    let addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0);
+    let listener = TcpListener::bind(&addr, handle).expect("listener bind error");
+    let addr = listener.local_addr().expect("address retrieval error");
+    (listener, addr)
+}
+
+/// Waits for the next message, which must come before the connection ends.
+pub fn hear(
+    websocket: Client<TcpStream>,
+) -> Box<Future<Item = (OwnedMessage, Client<TcpStream>), Error = WebSocketError>> {
+    Box::new(
+        websocket
+            .into_future()
+            .map_err(|(err, _websocket)| err)
+            .map(|(maybe_msg, websocket)| {
+                (maybe_msg.expect("connection ended early"), websocket)
+            }),
+    )
+}
+
+/// Waits for the next message, or `None` once the connection ends.
+pub fn hear_or_end(
+    websocket: Client<TcpStream>,
+) -> Box<Future<Item = (Option<OwnedMessage>, Client<TcpStream>), Error = WebSocketError>> {
+    Box::new(websocket.into_future().map_err(|(err, _websocket)| err))
+}
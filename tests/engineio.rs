@@ -0,0 +1,314 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+use futures::{Future, Sink, Stream};
+use futures::sync::oneshot;
+use hyper::{Method, StatusCode};
+use serde_json::Value;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str;
+use std::time::{Duration, Instant};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{EngineIoServer, EngineIoSocket, WsServer};
+
+/// Starts a server that echoes every message back, except that `flood` gets
+/// 64 messages back at once.
+fn start_server(handle: &Handle, ping_interval: Duration) -> SocketAddr {
+    let server_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0);
+    let listener = TcpListener::bind(&server_addr, handle).expect("listener bind error");
+    let server_addr = listener.local_addr().expect("server address retrieval error");
+
+    let engine = EngineIoServer::new(handle, |socket: EngineIoSocket| {
+        let sender = socket.sender();
+        socket.for_each(move |msg| {
+            if msg == OwnedMessage::Text("flood".to_owned()) {
+                // Nothing gets written out in between, so a WebSocket's queue
+                // soon fills up.
+                for _ in 0..64 {
+                    sender.send(msg.clone());
+                }
+            } else {
+                sender.send(msg);
+            }
+            Ok(())
+        })
+    }).ping_interval(ping_interval)
+        .ping_timeout(ping_interval);
+    let serve = WsServer::from_endpoint(engine.clone(), engine).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn request(
+    handle: &Handle,
+    method: Method,
+    url: &str,
+    body: &str,
+) -> Box<Future<Item = (StatusCode, String), Error = hyper::Error>> {
+    let mut req = hyper::Request::new(method, url.parse().expect("uri parse error"));
+    req.set_body(body.to_owned());
+    Box::new(hyper::Client::new(handle).request(req).and_then(|res| {
+        let status = res.status();
+        res.body().concat2().map(move |body| {
+            let body = str::from_utf8(body.as_ref()).expect("body is not UTF-8").to_owned();
+            (status, body)
+        })
+    }))
+}
+
+/// Opens a long-polling session, returning its session ID and open packet.
+fn handshake(core: &mut Core, server_addr: SocketAddr) -> (String, Value) {
+    let url = format!("http://{}/socket.io/?EIO=4&transport=polling", server_addr);
+    let test = request(&core.handle(), Method::Get, &url, "");
+    let (status, body) = core.run(test).expect("client http error");
+    assert_eq!(status, StatusCode::Ok);
+    assert!(body.starts_with('0'));
+    let open: Value = serde_json::from_str(&body[1..]).expect("malformed open packet");
+    let sid = open["sid"].as_str().expect("missing sid").to_owned();
+    (sid, open)
+}
+
+fn hear(
+    websocket: Client<TcpStream>,
+) -> Box<Future<Item = (OwnedMessage, Client<TcpStream>), Error = WebSocketError>> {
+    Box::new(
+        websocket
+            .into_future()
+            .map_err(|(err, _websocket)| err)
+            .map(|(maybe_msg, websocket)| {
+                (maybe_msg.expect("connection ended early"), websocket)
+            }),
+    )
+}
+
+fn exchange(core: &mut Core, websocket: Client<TcpStream>, text: &str) -> Client<TcpStream> {
+    let websocket = core.run(websocket.send(OwnedMessage::Text(text.to_owned())))
+        .expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Text(text.to_owned()));
+    websocket
+}
+
+/// Turns the event loop for a moment, so that requests in flight arrive.
+fn settle(core: &mut Core) {
+    let deadline = Instant::now() + Duration::from_millis(100);
+    while Instant::now() < deadline {
+        core.turn(Some(Duration::from_millis(10)));
+    }
+}
+
+#[test]
+fn test_engineio_polling() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(30));
+
+    let (sid, open) = handshake(&mut core, server_addr);
+    assert_eq!(open["upgrades"], json!(["websocket"]));
+    assert_eq!(open["pingInterval"], 30_000);
+
+    let url = format!("http://{}/socket.io/?EIO=4&transport=polling&sid={}", server_addr, sid);
+    let test = request(&handle, Method::Post, &url, "4hello\x1e4world\x1ebAQID");
+    assert_eq!(core.run(test).expect("client http error"), (StatusCode::Ok, "ok".to_owned()));
+    let test = request(&handle, Method::Get, &url, "");
+    let body = "4hello\x1e4world\x1ebAQID".to_owned();
+    assert_eq!(core.run(test).expect("client http error"), (StatusCode::Ok, body));
+
+    // A poll is held open until there's something to send.
+    let (poll_tx, poll_rx) = oneshot::channel();
+    handle.spawn(request(&handle, Method::Get, &url, "").then(|result| {
+        let _ = poll_tx.send(result.expect("client http error"));
+        Ok(())
+    }));
+    settle(&mut core);
+    let test = request(&handle, Method::Post, &url, "4later");
+    assert_eq!(core.run(test).expect("client http error"), (StatusCode::Ok, "ok".to_owned()));
+    let polled = core.run(poll_rx).expect("poll dropped");
+    assert_eq!(polled, (StatusCode::Ok, "4later".to_owned()));
+
+    // Closing ends the session.
+    let test = request(&handle, Method::Post, &url, "1");
+    assert_eq!(core.run(test).expect("client http error"), (StatusCode::Ok, "ok".to_owned()));
+    let test = request(&handle, Method::Get, &url, "");
+    let (status, body) = core.run(test).expect("client http error");
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body, r#"{"code":1,"message":"Session ID unknown"}"#);
+
+    let url = format!("http://{}/socket.io/?EIO=3&transport=polling", server_addr);
+    let (status, _body) = core.run(request(&handle, Method::Get, &url, ""))
+        .expect("client http error");
+    assert_eq!(status, StatusCode::BadRequest);
+}
+
+#[test]
+fn test_engineio_websocket() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(30));
+
+    let url = format!("ws://{}/socket.io/?EIO=4&transport=websocket", server_addr);
+    let test = ClientBuilder::new(&url)
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    let open = match msg {
+        OwnedMessage::Text(ref text) if text.starts_with('0') => {
+            serde_json::from_str::<Value>(&text[1..]).expect("malformed open packet")
+        }
+        msg => panic!("unexpected message: {:?}", msg),
+    };
+    assert_eq!(open["upgrades"], json!([]));
+
+    let websocket = exchange(&mut core, websocket, "4hi");
+    let binary = OwnedMessage::Binary(vec![1, 2, 3]);
+    let websocket = core.run(websocket.send(binary.clone())).expect("client websocket error");
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, binary);
+}
+
+#[test]
+fn test_engineio_upgrade() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(30));
+    let (sid, _open) = handshake(&mut core, server_addr);
+    let poll_url = format!(
+        "http://{}/socket.io/?EIO=4&transport=polling&sid={}",
+        server_addr,
+        sid
+    );
+
+    let (poll_tx, poll_rx) = oneshot::channel();
+    handle.spawn(request(&handle, Method::Get, &poll_url, "").then(|result| {
+        let _ = poll_tx.send(result.expect("client http error"));
+        Ok(())
+    }));
+    settle(&mut core);
+
+    let url = format!("ws://{}/socket.io/?EIO=4&transport=websocket&sid={}", server_addr, sid);
+    let test = ClientBuilder::new(&url)
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+    let websocket = core.run(websocket.send(OwnedMessage::Text("2probe".to_owned())))
+        .expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Text("3probe".to_owned()));
+
+    // The held poll returns so the client can pause polling.
+    let polled = core.run(poll_rx).expect("poll dropped");
+    assert_eq!(polled, (StatusCode::Ok, "6".to_owned()));
+
+    let websocket = core.run(websocket.send(OwnedMessage::Text("5".to_owned())))
+        .expect("client websocket error");
+    let _websocket = exchange(&mut core, websocket, "4upgraded");
+
+    let (status, _body) = core.run(request(&handle, Method::Get, &poll_url, ""))
+        .expect("client http error");
+    assert_eq!(status, StatusCode::BadRequest);
+}
+
+#[test]
+fn test_engineio_ping_timeout() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_millis(50));
+
+    let url = format!("ws://{}/socket.io/?EIO=4&transport=websocket", server_addr);
+    let test = ClientBuilder::new(&url)
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+    let (_open, websocket) = core.run(hear(websocket)).expect("client websocket error");
+
+    // An answered ping keeps the session open.
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Text("2".to_owned()));
+    let websocket = core.run(websocket.send(OwnedMessage::Text("3".to_owned())))
+        .expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Text("2".to_owned()));
+
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Text("1".to_owned()));
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert!(match msg {
+        OwnedMessage::Close(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_engineio_slow_consumer() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(30));
+
+    let url = format!("ws://{}/socket.io/?EIO=4&transport=websocket", server_addr);
+    let test = ClientBuilder::new(&url)
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+    let (_open, websocket) = core.run(hear(websocket)).expect("client websocket error");
+
+    let mut websocket = core.run(websocket.send(OwnedMessage::Text("4flood".to_owned())))
+        .expect("client websocket error");
+    let mut messages = 0;
+    let close = loop {
+        let (msg, rest) = core.run(hear(websocket)).expect("client websocket error");
+        websocket = rest;
+        match msg {
+            OwnedMessage::Text(ref text) if text == "4flood" => messages += 1,
+            OwnedMessage::Close(Some(data)) => break data,
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    };
+    assert_eq!(close.status_code, 1008);
+    assert!(messages < 64, "{} messages got through", messages);
+}
+
+#[test]
+fn test_engineio_slow_polling_consumer() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(30));
+    let (sid, _open) = handshake(&mut core, server_addr);
+
+    // Nobody polls while the flood is queued, so the session falls behind
+    // and is closed.
+    let url = format!("http://{}/socket.io/?EIO=4&transport=polling&sid={}", server_addr, sid);
+    let test = request(&handle, Method::Post, &url, "4flood");
+    assert_eq!(core.run(test).expect("client http error"), (StatusCode::Ok, "ok".to_owned()));
+    settle(&mut core);
+    let test = request(&handle, Method::Get, &url, "");
+    let (status, body) = core.run(test).expect("client http error");
+    assert_eq!(status, StatusCode::BadRequest);
+    assert_eq!(body, r#"{"code":1,"message":"Session ID unknown"}"#);
+}
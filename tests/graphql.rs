@@ -0,0 +1,241 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{future, stream, Future, Sink, Stream};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{negotiate_graphql, GraphQlExecutor, GraphQlRequest, GraphQlServer,
+                      GraphQlStream, WsServer, GRAPHQL_TRANSPORT_WS_PROTOCOL};
+
+use common::{bind, NotFoundService};
+
+/// Knows a handful of canned operations, and lets in clients that present
+/// the right token.
+struct TestExecutor;
+
+impl GraphQlExecutor for TestExecutor {
+    fn on_connect(&self, payload: Option<&Value>) -> Result<Option<Value>, ()> {
+        match payload.and_then(|payload| payload.get("token")) {
+            Some(token) if token == "secret" => Ok(Some(json!({"welcome": true}))),
+            _ => Err(()),
+        }
+    }
+
+    fn execute(&self, request: GraphQlRequest) -> Result<GraphQlStream, Vec<Value>> {
+        match request.query() {
+            "{ hello }" => Ok(Box::new(stream::once(Ok(json!({"data": {"hello": "world"}}))))),
+            "subscription { count }" => {
+                let counts = (0..3).map(|n| json!({"data": {"count": n}})).collect::<Vec<_>>();
+                Ok(Box::new(stream::iter_ok(counts)))
+            }
+            "subscription { forever }" => Ok(Box::new(future::empty().into_stream())),
+            query => Err(vec![json!({"message": format!("Cannot execute {}", query)})]),
+        }
+    }
+}
+
+fn start_server(handle: &Handle, init_timeout: Duration) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let graphql = GraphQlServer::new(TestExecutor).init_timeout(init_timeout);
+    let server_handle = handle.clone();
+    let serve = WsServer::new(NotFoundService, move |_ctx, websocket| {
+        graphql.serve(&server_handle, websocket)
+    }).negotiate(|_req, handshake| negotiate_graphql(handshake))
+        .serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn connect(
+    handle: &Handle,
+    server_addr: SocketAddr,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    Box::new(
+        ClientBuilder::new(format!("ws://{}/graphql", server_addr).as_str())
+            .expect("client build error")
+            .add_protocol(GRAPHQL_TRANSPORT_WS_PROTOCOL)
+            .async_connect_insecure(handle)
+            .map(|(websocket, _headers)| websocket),
+    )
+}
+
+fn say(
+    websocket: Client<TcpStream>,
+    msg: Value,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    Box::new(websocket.send(OwnedMessage::Text(msg.to_string())))
+}
+
+/// Reads the next message, parsing it if it's JSON text.
+fn hear(
+    websocket: Client<TcpStream>,
+) -> Box<Future<Item = (Result<Value, OwnedMessage>, Client<TcpStream>), Error = WebSocketError>>
+{
+    Box::new(
+        websocket
+            .into_future()
+            .map_err(|(err, _websocket)| err)
+            .map(|(maybe_msg, websocket)| match maybe_msg {
+                Some(OwnedMessage::Text(text)) => {
+                    let value = serde_json::from_str(&text).expect("malformed JSON message");
+                    (Ok(value), websocket)
+                }
+                Some(msg) => (Err(msg), websocket),
+                None => panic!("connection ended early"),
+            }),
+    )
+}
+
+fn closed_with(code: u16, reason: &str) -> Result<Value, OwnedMessage> {
+    Err(OwnedMessage::Close(Some(CloseData::new(code, reason.to_owned()))))
+}
+
+/// Connects and gets through `connection_init`.
+fn init(core: &mut Core, server_addr: SocketAddr) -> Client<TcpStream> {
+    let handle = core.handle();
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+    let init = json!({"type": "connection_init", "payload": {"token": "secret"}});
+    let websocket = core.run(say(websocket, init)).expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, Ok(json!({"type": "connection_ack", "payload": {"welcome": true}})));
+    websocket
+}
+
+#[test]
+fn test_graphql_requires_protocol() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(3));
+
+    let test = ClientBuilder::new(format!("ws://{}/graphql", server_addr).as_str())
+        .expect("client build error")
+        .async_connect_insecure(&handle);
+    assert!(core.run(test).is_err());
+}
+
+#[test]
+fn test_graphql_operations() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(3));
+    let websocket = init(&mut core, server_addr);
+
+    let websocket = core.run(say(websocket, json!({"type": "ping"})))
+        .expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, Ok(json!({"type": "pong"})));
+
+    let subscribe = json!({
+        "id": "1",
+        "type": "subscribe",
+        "payload": {"query": "subscription { count }"}
+    });
+    let mut websocket = core.run(say(websocket, subscribe)).expect("client websocket error");
+    for n in 0..3 {
+        let (msg, rest) = core.run(hear(websocket)).expect("client websocket error");
+        let next = json!({"id": "1", "type": "next", "payload": {"data": {"count": n}}});
+        assert_eq!(msg, Ok(next));
+        websocket = rest;
+    }
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, Ok(json!({"id": "1", "type": "complete"})));
+
+    let subscribe = json!({"id": "2", "type": "subscribe", "payload": {"query": "{ boom }"}});
+    let websocket = core.run(say(websocket, subscribe)).expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    let errors = json!([{"message": "Cannot execute { boom }"}]);
+    assert_eq!(msg, Ok(json!({"id": "2", "type": "error", "payload": errors})));
+
+    // Completing an operation from the client's side frees up its ID.
+    let forever = json!({
+        "id": "3",
+        "type": "subscribe",
+        "payload": {"query": "subscription { forever }"}
+    });
+    let websocket = core.run(say(websocket, forever)).expect("client websocket error");
+    let websocket = core.run(say(websocket, json!({"id": "3", "type": "complete"})))
+        .expect("client websocket error");
+    let hello = json!({"id": "3", "type": "subscribe", "payload": {"query": "{ hello }"}});
+    let websocket = core.run(say(websocket, hello)).expect("client websocket error");
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    let next = json!({"id": "3", "type": "next", "payload": {"data": {"hello": "world"}}});
+    assert_eq!(msg, Ok(next));
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, Ok(json!({"id": "3", "type": "complete"})));
+
+    // Reusing the ID of a running operation is a protocol violation.
+    let forever = json!({
+        "id": "4",
+        "type": "subscribe",
+        "payload": {"query": "subscription { forever }"}
+    });
+    let websocket = core.run(say(websocket, forever.clone())).expect("client websocket error");
+    let websocket = core.run(say(websocket, forever)).expect("client websocket error");
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, closed_with(4409, "Subscriber for 4 already exists"));
+}
+
+#[test]
+fn test_graphql_unauthorized_subscribe() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(3));
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+
+    let subscribe = json!({"id": "1", "type": "subscribe", "payload": {"query": "{ hello }"}});
+    let websocket = core.run(say(websocket, subscribe)).expect("client websocket error");
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, closed_with(4401, "Unauthorized"));
+}
+
+#[test]
+fn test_graphql_forbidden_init() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(3));
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+
+    let init = json!({"type": "connection_init", "payload": {"token": "guess"}});
+    let websocket = core.run(say(websocket, init)).expect("client websocket error");
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, closed_with(4403, "Forbidden"));
+}
+
+#[test]
+fn test_graphql_init_timeout() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_millis(100));
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, closed_with(4408, "Connection initialisation timeout"));
+}
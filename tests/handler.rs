@@ -0,0 +1,121 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{stream, Future, Sink, Stream};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{DriveWsHandler, WsHandler, WsHandlerContext, WsServer};
+
+use common::{bind, NotFoundService};
+
+/// Greets each connection, then numbers and echoes its text messages until
+/// it receives "bye".
+struct CountingEcho;
+
+impl WsHandler for CountingEcho {
+    type State = u32;
+
+    fn on_open(&self, ctx: &mut WsHandlerContext<u32>) {
+        ctx.send(OwnedMessage::Text("hello".into()));
+    }
+
+    fn on_message(&self, ctx: &mut WsHandlerContext<u32>, msg: OwnedMessage) {
+        if let OwnedMessage::Text(text) = msg {
+            if text == "bye" {
+                ctx.close(1000, "bye");
+                return;
+            }
+            *ctx.state_mut() += 1;
+            let reply = format!("{}: {}", ctx.state(), text);
+            ctx.send(OwnedMessage::Text(reply));
+        }
+    }
+}
+
+fn start_server(handle: &Handle) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let handler = Rc::new(CountingEcho);
+    let serve = WsServer::new(NotFoundService, move |_ctx, websocket| {
+        DriveWsHandler::new(websocket, handler.clone(), 0)
+    }).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn exchange(
+    handle: &Handle,
+    server_addr: SocketAddr,
+    to_server: Vec<OwnedMessage>,
+    expected_len: usize,
+) -> Box<Future<Item = Vec<OwnedMessage>, Error = WebSocketError>> {
+    Box::new(
+        ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+            .expect("client build error")
+            .async_connect_insecure(handle)
+            .and_then(move |(websocket, _headers)| {
+                websocket.send_all(stream::iter_ok::<_, WebSocketError>(to_server))
+            })
+            .and_then(move |(websocket, _)| websocket.take(expected_len as u64).collect()),
+    )
+}
+
+#[test]
+fn test_handler_messages() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let to_server = vec![
+        OwnedMessage::Text("a".into()),
+        OwnedMessage::Ping(b"are you there".to_vec()),
+        OwnedMessage::Text("b".into()),
+        OwnedMessage::Text("bye".into()),
+    ];
+    let msgs = core.run(exchange(&handle, server_addr, to_server, 5))
+        .expect("client websocket error");
+    assert_eq!(
+        msgs,
+        vec![
+            OwnedMessage::Text("hello".into()),
+            OwnedMessage::Text("1: a".into()),
+            OwnedMessage::Pong(b"are you there".to_vec()),
+            OwnedMessage::Text("2: b".into()),
+            OwnedMessage::Close(Some(CloseData::new(1000, "bye".into()))),
+        ]
+    );
+}
+
+#[test]
+fn test_handler_peer_close() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let close = OwnedMessage::Close(Some(CloseData::new(1001, "going away".into())));
+    let msgs = core.run(exchange(&handle, server_addr, vec![close.clone()], 2))
+        .expect("client websocket error");
+    assert_eq!(msgs, vec![OwnedMessage::Text("hello".into()), close]);
+}
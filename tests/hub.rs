@@ -0,0 +1,394 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{future, Async, Future, Sink, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+use std::cell::{Cell, RefCell};
+use std::io::Read;
+use std::net::{self, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_io::AsyncRead;
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::codec::ws::{Context, MessageCodec};
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{spawn_channel, spawn_client_channel, Backplane, BackplaneMessages,
+                      Broadcast, ConnectionId, Hub, PresenceEvent, SharedFrame,
+                      SlowConsumerPolicy, WsReceiver, WsServer};
+
+use common::{bind, hear_or_end, NotFoundService};
+
+/// Starts a server that puts each connection in the room named by its path,
+/// identified by its query string, and relays its messages to the rest of the
+/// room.
+fn start_server(handle: &Handle, hub: &Hub) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let hub = hub.clone();
+    let next_id = Rc::new(Cell::new(0));
+    let server_handle = handle.clone();
+    let serve = WsServer::new(NotFoundService, move |ctx, websocket| {
+        let id = ConnectionId::new(next_id.get());
+        next_id.set(next_id.get() + 1);
+        let room = ctx.path()[1..].to_owned();
+
+        let (mut sender, receiver) = spawn_channel(&server_handle, websocket, 8);
+        hub.add(id, sender.clone(), SlowConsumerPolicy::Drop);
+        if let Some(user) = ctx.query() {
+            hub.identify(id, user);
+        }
+        sender
+            .try_send(OwnedMessage::Text("joined".into()))
+            .expect("server channel send error");
+        server_handle.spawn(hub.join(id, &room).map(|_| ()));
+
+        let relay_hub = hub.clone();
+        let hub = hub.clone();
+        receiver
+            .for_each(move |msg| {
+                let broadcast = match msg {
+                    OwnedMessage::Text(_) => relay_hub.broadcast(&room, &msg, Some(id)),
+                    _ => return Either::A(future::ok(())),
+                };
+                Either::B(broadcast.then(|_| Ok(())))
+            })
+            .then(move |result| {
+                hub.remove(id);
+                result
+            })
+    }).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn join(
+    handle: &Handle,
+    server_addr: SocketAddr,
+    room: &str,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    Box::new(
+        ClientBuilder::new(format!("ws://{}/{}", server_addr, room).as_str())
+            .expect("client build error")
+            .async_connect_insecure(handle)
+            .and_then(|(websocket, _headers)| {
+                websocket.into_future().map_err(|(err, _websocket)| err)
+            })
+            .map(|(maybe_msg, websocket)| {
+                assert_eq!(maybe_msg, Some(OwnedMessage::Text("joined".into())));
+                websocket
+            }),
+    )
+}
+
+fn say(
+    websocket: Client<TcpStream>,
+    text: &str,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    Box::new(websocket.send(OwnedMessage::Text(text.into())))
+}
+
+/// Adds a connection to `hub` whose queue is only drained while `stalled` is
+/// turned. Returns its incoming messages, which have to stay alive to keep it
+/// open, and the client's end of the connection.
+fn add_stalled(
+    stalled: &Core,
+    hub: &Hub,
+    id: ConnectionId,
+    policy: SlowConsumerPolicy,
+) -> (WsReceiver, net::TcpStream) {
+    let listener = net::TcpListener::bind("127.0.0.1:0").expect("listener bind error");
+    let peer = net::TcpStream::connect(listener.local_addr().expect("address retrieval error"))
+        .expect("connect error");
+    let (stream, _) = listener.accept().expect("accept error");
+    let stream = TcpStream::from_stream(stream, &stalled.handle()).expect("stream setup error");
+    let websocket = stream.framed(MessageCodec::default(Context::Server));
+
+    let (sender, receiver) = spawn_channel(&stalled.handle(), websocket, 1);
+    hub.add(id, sender, policy);
+    (receiver, peer)
+}
+
+/// Polls `broadcast` once from a task on `core`.
+fn poll_once(core: &mut Core, broadcast: &mut Broadcast) -> Async<usize> {
+    let polled = core.run(future::lazy(|| Ok::<_, ()>(broadcast.poll())));
+    polled.expect("lazy error").expect("broadcast error")
+}
+
+/// Sends to `id` until a message doesn't go through right away, and returns
+/// the broadcast for that message along with how it first polled.
+fn fill(core: &mut Core, hub: &Hub, id: ConnectionId) -> (Broadcast, Async<usize>) {
+    let msg = OwnedMessage::Text("filler".into());
+    for _ in 0..64 {
+        let mut broadcast = hub.send_to(id, &msg);
+        match poll_once(core, &mut broadcast) {
+            Async::Ready(1) => continue,
+            polled => return (broadcast, polled),
+        }
+    }
+    panic!("queue never filled up");
+}
+
+/// A backplane whose messages from elsewhere are fed in by the test.
+struct TestBackplane(RefCell<Option<mpsc::UnboundedReceiver<(String, OwnedMessage)>>>);
+
+impl Backplane for TestBackplane {
+    fn publish(&self, _room: &str, _msg: &OwnedMessage) {}
+
+    fn subscribe(&self) -> BackplaneMessages {
+        Box::new(self.0.borrow_mut().take().expect("subscribed twice"))
+    }
+}
+
+#[test]
+fn test_shared_frame() {
+    let frame = SharedFrame::new(&OwnedMessage::Text("hi".into()));
+    assert_eq!(frame.as_bytes(), &[0x81, 0x02, b'h', b'i']);
+}
+
+#[test]
+fn test_hub_client_connection() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let listener = net::TcpListener::bind("127.0.0.1:0").expect("listener bind error");
+    let stream = net::TcpStream::connect(listener.local_addr().expect("address retrieval error"))
+        .expect("connect error");
+    let (peer, _) = listener.accept().expect("accept error");
+    let stream = TcpStream::from_stream(stream, &handle).expect("stream setup error");
+    let peer = TcpStream::from_stream(peer, &handle).expect("stream setup error");
+
+    // The server's end refuses unmasked frames, so the shared frame has to be
+    // masked on its way out of the client's end.
+    let (sender, _receiver) =
+        spawn_client_channel(&handle, stream.framed(MessageCodec::default(Context::Client)), 1);
+    let hub = Hub::new();
+    let id = ConnectionId::new(0);
+    hub.add(id, sender, SlowConsumerPolicy::Drop);
+    let msg = OwnedMessage::Text("hi".into());
+    assert_eq!(core.run(hub.send_to(id, &msg)), Ok(1));
+
+    let peer = peer.framed(MessageCodec::default(Context::Server));
+    let (maybe_msg, _peer) = core.run(hear_or_end(peer)).expect("server websocket error");
+    assert_eq!(maybe_msg, Some(msg));
+}
+
+#[test]
+fn test_hub_broadcast() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let hub = Hub::new();
+    let server_addr = start_server(&handle, &hub);
+
+    let alice = core.run(join(&handle, server_addr, "lobby")).expect("client websocket error");
+    let bob = core.run(join(&handle, server_addr, "lobby")).expect("client websocket error");
+    let carol = core.run(join(&handle, server_addr, "attic")).expect("client websocket error");
+    assert_eq!(hub.rooms(), vec!["attic".to_owned(), "lobby".to_owned()]);
+    assert_eq!(hub.members("lobby").len(), 2);
+
+    let alice = core.run(say(alice, "hi")).expect("client websocket error");
+    let (maybe_msg, bob) = core.run(hear_or_end(bob)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("hi".into())));
+
+    // Alice never hears her own message, and Carol hears nothing from the
+    // lobby.
+    let _carol = core.run(say(carol, "anyone?")).expect("client websocket error");
+    let _bob = core.run(say(bob, "hello")).expect("client websocket error");
+    let (maybe_msg, _alice) = core.run(hear_or_end(alice)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("hello".into())));
+}
+
+#[test]
+fn test_hub_history_and_presence() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let hub = Hub::new().history(2).presence();
+    let server_addr = start_server(&handle, &hub);
+
+    let alice = core.run(join(&handle, server_addr, "lobby?alice"))
+        .expect("client websocket error");
+    let (maybe_msg, alice) = core.run(hear_or_end(alice)).expect("client websocket error");
+    assert_eq!(
+        maybe_msg,
+        Some(OwnedMessage::Text(
+            r#"{"type":"snapshot","room":"lobby","members":["alice"]}"#.into()
+        ))
+    );
+    let alice = core.run(say(alice, "one")).expect("client websocket error");
+    let alice = core.run(say(alice, "two")).expect("client websocket error");
+    let alice = core.run(say(alice, "three")).expect("client websocket error");
+
+    let bob = core.run(join(&handle, server_addr, "lobby?bob")).expect("client websocket error");
+    let test = bob.take(3).collect();
+    let msgs = core.run(test).expect("client websocket error");
+    assert_eq!(
+        msgs,
+        vec![
+            OwnedMessage::Text(
+                r#"{"type":"snapshot","room":"lobby","members":["alice","bob"]}"#.into(),
+            ),
+            OwnedMessage::Text("two".into()),
+            OwnedMessage::Text("three".into()),
+        ]
+    );
+    assert_eq!(hub.presence_of("lobby"), vec!["alice".to_owned(), "bob".to_owned()]);
+
+    let test = alice.take(2).collect();
+    let msgs = core.run(test).expect("client websocket error");
+    assert_eq!(
+        msgs,
+        vec![
+            OwnedMessage::Text(r#"{"type":"join","room":"lobby","member":"bob"}"#.into()),
+            OwnedMessage::Text(r#"{"type":"leave","room":"lobby","member":"bob"}"#.into()),
+        ]
+    );
+}
+
+#[test]
+fn test_presence_json_escaping() {
+    let event = PresenceEvent::Join {
+        room: "a\"b".to_owned(),
+        member: "line\nbreak".to_owned(),
+    };
+    assert_eq!(
+        event.to_json(),
+        OwnedMessage::Text(r#"{"type":"join","room":"a\"b","member":"line\nbreak"}"#.into())
+    );
+}
+
+#[test]
+fn test_slow_consumer_drop() {
+    let mut core = Core::new().expect("core creation error");
+    let stalled = Core::new().expect("core creation error");
+    let hub = Hub::new();
+    let id = ConnectionId::new(0);
+    let _consumer = add_stalled(&stalled, &hub, id, SlowConsumerPolicy::Drop);
+
+    let (_broadcast, polled) = fill(&mut core, &hub, id);
+    assert_eq!(polled, Async::Ready(0));
+    assert!(hub.contains(id));
+}
+
+#[test]
+fn test_slow_consumer_disconnect() {
+    let mut core = Core::new().expect("core creation error");
+    let stalled = Core::new().expect("core creation error");
+    let hub = Hub::new();
+    let id = ConnectionId::new(0);
+    let _consumer = add_stalled(&stalled, &hub, id, SlowConsumerPolicy::Disconnect);
+
+    let (_broadcast, polled) = fill(&mut core, &hub, id);
+    assert_eq!(polled, Async::Ready(0));
+    assert!(!hub.contains(id));
+}
+
+#[test]
+fn test_slow_consumer_backpressure() {
+    let mut core = Core::new().expect("core creation error");
+    let mut stalled = Core::new().expect("core creation error");
+    let hub = Hub::new();
+    let id = ConnectionId::new(0);
+    let _consumer = add_stalled(&stalled, &hub, id, SlowConsumerPolicy::Backpressure);
+
+    // However many times it's polled, the broadcast holds on to the message
+    // until the consumer's queue drains.
+    let (mut broadcast, polled) = fill(&mut core, &hub, id);
+    assert_eq!(polled, Async::NotReady);
+    assert_eq!(poll_once(&mut core, &mut broadcast), Async::NotReady);
+
+    stalled.turn(Some(Duration::from_millis(100)));
+    assert_eq!(core.run(broadcast), Ok(1));
+    assert!(hub.contains(id));
+}
+
+#[test]
+fn test_hub_history_longer_than_queue() {
+    let mut core = Core::new().expect("core creation error");
+    let mut stalled = Core::new().expect("core creation error");
+    let hub = Hub::new().history(16);
+    let alice = ConnectionId::new(0);
+    let _alice = add_stalled(&stalled, &hub, alice, SlowConsumerPolicy::Drop);
+    assert_eq!(hub.join(alice, "lobby").wait(), Ok(0));
+    let mut expected = Vec::new();
+    for i in 0..16 {
+        let msg = OwnedMessage::Text(i.to_string());
+        expected.extend_from_slice(SharedFrame::new(&msg).as_bytes());
+        assert_eq!(hub.broadcast("lobby", &msg, Some(alice)).wait(), Ok(0));
+    }
+
+    // Bob's queue only has room for a couple of messages, so most of the
+    // history is replayed as it drains, even though he'd drop broadcasts.
+    let bob = ConnectionId::new(1);
+    let (_bob, mut peer) = add_stalled(&stalled, &hub, bob, SlowConsumerPolicy::Drop);
+    let mut replay = hub.join(bob, "lobby");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let replayed = loop {
+        assert!(Instant::now() < deadline, "history wasn't replayed in time");
+        if let Async::Ready(replayed) = poll_once(&mut core, &mut replay) {
+            break replayed;
+        }
+        stalled.turn(Some(Duration::from_millis(10)));
+    };
+    assert_eq!(replayed, 16);
+
+    stalled.turn(Some(Duration::from_millis(10)));
+    peer.set_read_timeout(Some(Duration::from_secs(5))).expect("read timeout error");
+    let mut received = vec![0; expected.len()];
+    peer.read_exact(&mut received).expect("client read error");
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn test_backplane_skips_backpressure() {
+    let mut core = Core::new().expect("core creation error");
+    let alice_core = Core::new().expect("core creation error");
+    let mut bob_core = Core::new().expect("core creation error");
+    let (tx, rx) = mpsc::unbounded();
+    let hub = Hub::new().backplane(&core.handle(), TestBackplane(RefCell::new(Some(rx))));
+
+    // Alice never drains her queue, and would hold up broadcasts if messages
+    // from the backplane waited for her.
+    let alice = ConnectionId::new(0);
+    let _alice = add_stalled(&alice_core, &hub, alice, SlowConsumerPolicy::Backpressure);
+    assert_eq!(hub.join(alice, "lobby").wait(), Ok(0));
+    let bob = ConnectionId::new(1);
+    let (_bob, mut peer) = add_stalled(&bob_core, &hub, bob, SlowConsumerPolicy::Drop);
+    assert_eq!(hub.join(bob, "lobby").wait(), Ok(0));
+
+    let mut expected = Vec::new();
+    for i in 0..8 {
+        let msg = OwnedMessage::Text(i.to_string());
+        expected.extend_from_slice(SharedFrame::new(&msg).as_bytes());
+        tx.unbounded_send(("lobby".to_owned(), msg)).expect("backplane send error");
+        core.turn(Some(Duration::from_millis(10)));
+        bob_core.turn(Some(Duration::from_millis(10)));
+    }
+
+    peer.set_read_timeout(Some(Duration::from_secs(5))).expect("read timeout error");
+    let mut received = vec![0; expected.len()];
+    peer.read_exact(&mut received).expect("client read error");
+    assert_eq!(received, expected);
+    assert!(hub.contains(alice));
+}
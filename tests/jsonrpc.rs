@@ -0,0 +1,202 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{Future, Sink, Stream};
+use hyper::Request;
+use serde_json::Value;
+use std::net::SocketAddr;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{RpcError, RpcPeer, RpcServer, WsServer};
+
+use common::{bind, NotFoundService};
+
+fn start_server(handle: &Handle) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let rpc = RpcServer::new()
+        .method("add", |_peer: &RpcPeer, params: Value| {
+            let sum = match params.as_array() {
+                Some(terms) => terms.iter().fold(Some(0), |sum, term| {
+                    match (sum, term.as_i64()) {
+                        (Some(sum), Some(term)) => Some(sum + term),
+                        _ => None,
+                    }
+                }),
+                None => None,
+            };
+            sum.map(Value::from).ok_or_else(RpcError::invalid_params)
+        })
+        .method("fail", |_peer: &RpcPeer, _params: Value| {
+            Err(RpcError::new(42, "Nope").with_data(json!({"why": "testing"})))
+        })
+        .method("greet", |peer: &RpcPeer, _params: Value| {
+            // Ask the client who it is before answering.
+            peer.call("whoami", Value::Null).map(|name| {
+                let name = name.as_str().unwrap_or("stranger").to_owned();
+                Value::from(format!("hello, {}", name))
+            })
+        });
+
+    let server_handle = handle.clone();
+    let serve = WsServer::new(NotFoundService, move |_ctx, websocket| {
+        rpc.serve(&server_handle, websocket)
+    }).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn connect(
+    handle: &Handle,
+    server_addr: SocketAddr,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    Box::new(
+        ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+            .expect("client build error")
+            .async_connect_insecure(handle)
+            .map(|(websocket, _headers)| websocket),
+    )
+}
+
+/// Sends `request` and parses the text message that comes back.
+fn exchange(
+    websocket: Client<TcpStream>,
+    request: &str,
+) -> Box<Future<Item = (Value, Client<TcpStream>), Error = WebSocketError>> {
+    Box::new(
+        websocket
+            .send(OwnedMessage::Text(request.to_owned()))
+            .and_then(|websocket| websocket.into_future().map_err(|(err, _websocket)| err))
+            .map(|(maybe_msg, websocket)| match maybe_msg {
+                Some(OwnedMessage::Text(text)) => {
+                    let value = serde_json::from_str(&text).expect("malformed JSON reply");
+                    (value, websocket)
+                }
+                msg => panic!("unexpected reply: {:?}", msg),
+            }),
+    )
+}
+
+#[test]
+fn test_jsonrpc_requests() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+
+    let request = r#"{"jsonrpc": "2.0", "method": "add", "params": [1, 2], "id": 1}"#;
+    let (reply, websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    assert_eq!(reply, json!({"jsonrpc": "2.0", "result": 3, "id": 1}));
+
+    // The notification gets no reply, so the next one is the request's.
+    let request = r#"{"jsonrpc": "2.0", "method": "add", "params": [1, 2]}"#;
+    let websocket = core.run(websocket.send(OwnedMessage::Text(request.into())))
+        .expect("client websocket error");
+    let request = r#"{"jsonrpc": "2.0", "method": "fail", "id": "a"}"#;
+    let (reply, websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    assert_eq!(
+        reply,
+        json!({
+            "jsonrpc": "2.0",
+            "error": {"code": 42, "message": "Nope", "data": {"why": "testing"}},
+            "id": "a"
+        })
+    );
+
+    let request = r#"{"jsonrpc": "2.0", "method": "add", "params": "nope", "id": 2}"#;
+    let (reply, websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    assert_eq!(
+        reply,
+        json!({"jsonrpc": "2.0", "error": {"code": -32600, "message": "Invalid Request"}, "id": 2})
+    );
+
+    let request = r#"{"jsonrpc": "2.0", "method": "add", "params": ["one"], "id": 3}"#;
+    let (reply, websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    assert_eq!(
+        reply,
+        json!({"jsonrpc": "2.0", "error": {"code": -32602, "message": "Invalid params"}, "id": 3})
+    );
+
+    let (reply, _websocket) = core.run(exchange(websocket, "{oops"))
+        .expect("client websocket error");
+    assert_eq!(
+        reply,
+        json!({"jsonrpc": "2.0", "error": {"code": -32700, "message": "Parse error"}, "id": null})
+    );
+}
+
+#[test]
+fn test_jsonrpc_batch() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+
+    let request = r#"[
+        {"jsonrpc": "2.0", "method": "add", "params": [1, 2], "id": 1},
+        {"jsonrpc": "2.0", "method": "add", "params": [3, 4]},
+        {"jsonrpc": "2.0", "method": "missing", "id": 2},
+        1
+    ]"#;
+    let (reply, websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    assert_eq!(
+        reply,
+        json!([
+            {"jsonrpc": "2.0", "result": 3, "id": 1},
+            {"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found"}, "id": 2},
+            {"jsonrpc": "2.0", "error": {"code": -32600, "message": "Invalid Request"}, "id": null}
+        ])
+    );
+
+    let (reply, _websocket) = core.run(exchange(websocket, "[]"))
+        .expect("client websocket error");
+    let invalid = json!({"code": -32600, "message": "Invalid Request"});
+    assert_eq!(reply, json!({"jsonrpc": "2.0", "error": invalid, "id": null}));
+}
+
+#[test]
+fn test_jsonrpc_server_calls_client() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+
+    let request = r#"{"jsonrpc": "2.0", "method": "greet", "id": 7}"#;
+    let (call, websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    assert_eq!(call, json!({"jsonrpc": "2.0", "method": "whoami", "id": 0}));
+
+    let response = r#"{"jsonrpc": "2.0", "result": "Ada", "id": 0}"#;
+    let (reply, _websocket) = core.run(exchange(websocket, response))
+        .expect("client websocket error");
+    assert_eq!(reply, json!({"jsonrpc": "2.0", "result": "hello, Ada", "id": 7}));
+}
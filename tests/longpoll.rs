@@ -0,0 +1,236 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+use futures::{Future, Sink, Stream};
+use futures::sync::oneshot;
+use hyper::{Method, StatusCode};
+use serde_json::Value;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str;
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{LongPollConnection, LongPollServer, WsServer};
+
+/// Echoes every message back, over either transport.
+fn echo<C>(connection: C) -> Box<Future<Item = (), Error = WebSocketError>>
+where
+    C: Stream<Item = OwnedMessage, Error = WebSocketError>
+        + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>
+        + 'static,
+{
+    let (sink, stream) = connection.split();
+    Box::new(sink.send_all(stream).map(|_| ()))
+}
+
+fn start_server(handle: &Handle, timeout: Duration) -> SocketAddr {
+    let server_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0);
+    let listener = TcpListener::bind(&server_addr, handle).expect("listener bind error");
+    let server_addr = listener.local_addr().expect("server address retrieval error");
+
+    let longpoll = LongPollServer::new(handle, |connection: LongPollConnection| {
+        echo(connection).map_err(|err| panic!("long-poll echo error: {}", err))
+    }).poll_timeout(timeout)
+        .session_timeout(timeout);
+    let serve = WsServer::new(longpoll, |_ctx, websocket| {
+        echo(websocket)
+    }).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn request(
+    handle: &Handle,
+    method: Method,
+    url: &str,
+    body: &str,
+) -> Box<Future<Item = (StatusCode, String), Error = hyper::Error>> {
+    let mut req = hyper::Request::new(method, url.parse().expect("uri parse error"));
+    req.set_body(body.to_owned());
+    Box::new(hyper::Client::new(handle).request(req).and_then(|res| {
+        let status = res.status();
+        res.body().concat2().map(move |body| {
+            let body = str::from_utf8(body.as_ref()).expect("body is not UTF-8").to_owned();
+            (status, body)
+        })
+    }))
+}
+
+/// Opens a session, returning the URL to poll it at.
+fn open(core: &mut Core, server_addr: SocketAddr) -> String {
+    let url = format!("http://{}/longpoll", server_addr);
+    let (status, body) = core.run(request(&core.handle(), Method::Post, &url, ""))
+        .expect("client http error");
+    assert_eq!(status, StatusCode::Ok);
+    let opened: Value = serde_json::from_str(&body).expect("malformed session");
+    let sid = opened["sid"].as_str().expect("missing sid");
+    format!("{}?sid={}", url, sid)
+}
+
+fn poll(core: &mut Core, url: &str) -> (StatusCode, Option<Value>) {
+    let (status, body) = core.run(request(&core.handle(), Method::Get, url, ""))
+        .expect("client http error");
+    let msgs = if status == StatusCode::Ok {
+        Some(serde_json::from_str(&body).expect("malformed messages"))
+    } else {
+        None
+    };
+    (status, msgs)
+}
+
+fn post(core: &mut Core, url: &str, msgs: Value) -> StatusCode {
+    let test = request(&core.handle(), Method::Post, url, &msgs.to_string());
+    core.run(test).expect("client http error").0
+}
+
+/// Turns the event loop for a moment, so that requests in flight arrive.
+fn settle(core: &mut Core, millis: u64) {
+    let deadline = Instant::now() + Duration::from_millis(millis);
+    while Instant::now() < deadline {
+        core.turn(Some(Duration::from_millis(10)));
+    }
+}
+
+#[test]
+fn test_longpoll_echo() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(30));
+    let url = open(&mut core, server_addr);
+
+    let msgs = json!([
+        {"type": "text", "data": "hello"},
+        {"type": "binary", "data": "AQID"},
+    ]);
+    assert_eq!(post(&mut core, &url, msgs.clone()), StatusCode::NoContent);
+    assert_eq!(poll(&mut core, &url), (StatusCode::Ok, Some(msgs)));
+
+    // A poll is held open until there's something to send.
+    let (poll_tx, poll_rx) = oneshot::channel();
+    handle.spawn(request(&handle, Method::Get, &url, "").then(|result| {
+        let _ = poll_tx.send(result.expect("client http error"));
+        Ok(())
+    }));
+    settle(&mut core, 100);
+    let later = json!([{"type": "text", "data": "later"}]);
+    assert_eq!(post(&mut core, &url, later.clone()), StatusCode::NoContent);
+    let (status, body) = core.run(poll_rx).expect("poll dropped");
+    assert_eq!(status, StatusCode::Ok);
+    assert_eq!(serde_json::from_str::<Value>(&body).expect("malformed messages"), later);
+
+    // The echoed close ends the session once it's been delivered.
+    let close = json!([{"type": "close", "code": 1000, "reason": "bye"}]);
+    assert_eq!(post(&mut core, &url, close.clone()), StatusCode::NoContent);
+    assert_eq!(poll(&mut core, &url), (StatusCode::Ok, Some(close)));
+    assert_eq!(poll(&mut core, &url), (StatusCode::Gone, None));
+}
+
+#[test]
+fn test_longpoll_websocket() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(30));
+
+    // The same handler serves WebSocket clients.
+    let url = format!("ws://{}/longpoll", server_addr);
+    let test = ClientBuilder::new(&url)
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .and_then(|(websocket, _headers)| websocket.send(OwnedMessage::Text("hi".to_owned())))
+        .and_then(|websocket| websocket.into_future().map_err(|(err, _websocket)| err));
+    let (msg, _websocket) = core.run(test).expect("client websocket error");
+    assert_eq!(msg, Some(OwnedMessage::Text("hi".to_owned())));
+}
+
+#[test]
+fn test_longpoll_timeouts() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_millis(100));
+    let url = open(&mut core, server_addr);
+
+    // An idle poll is answered empty.
+    assert_eq!(poll(&mut core, &url), (StatusCode::Ok, Some(json!([]))));
+
+    // Without another poll, the session expires.
+    settle(&mut core, 400);
+    assert_eq!(poll(&mut core, &url), (StatusCode::Gone, None));
+}
+
+#[test]
+fn test_longpoll_errors() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(30));
+    let url = open(&mut core, server_addr);
+
+    let test = request(&handle, Method::Post, &url, r#"[{"type": "ping"}]"#);
+    assert_eq!(core.run(test).expect("client http error").0, StatusCode::BadRequest);
+    let test = request(&handle, Method::Post, &url, "not json");
+    assert_eq!(core.run(test).expect("client http error").0, StatusCode::BadRequest);
+    let test = request(&handle, Method::Put, &url, "");
+    assert_eq!(core.run(test).expect("client http error").0, StatusCode::MethodNotAllowed);
+
+    let test = request(&handle, Method::Delete, &url, "");
+    assert_eq!(core.run(test).expect("client http error").0, StatusCode::NoContent);
+    assert_eq!(poll(&mut core, &url), (StatusCode::Gone, None));
+    assert_eq!(post(&mut core, &url, json!([])), StatusCode::Gone);
+
+    let other = format!("http://{}/elsewhere", server_addr);
+    let test = request(&handle, Method::Get, &other, "");
+    assert_eq!(core.run(test).expect("client http error").0, StatusCode::NotFound);
+}
+
+#[test]
+fn test_longpoll_backpressure() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, Duration::from_secs(30));
+    let url = open(&mut core, server_addr);
+
+    let msgs: Vec<Value> = (0..40)
+        .map(|i| json!({"type": "text", "data": i.to_string()}))
+        .collect();
+    assert_eq!(post(&mut core, &url, Value::Array(msgs.clone())), StatusCode::NoContent);
+
+    // The echo is stuck behind a full queue with more than 16 messages left
+    // to read, so more are turned away.
+    settle(&mut core, 100);
+    let more = json!([{"type": "text", "data": "more"}]);
+    assert_eq!(post(&mut core, &url, more), StatusCode::TooManyRequests);
+
+    // The echo waits for each poll to take what's queued before sending more.
+    let mut echoed = Vec::new();
+    let mut polls = 0;
+    while echoed.len() < msgs.len() {
+        let batch = match poll(&mut core, &url) {
+            (StatusCode::Ok, Some(Value::Array(batch))) => batch,
+            polled => panic!("unexpected poll: {:?}", polled),
+        };
+        assert!(batch.len() <= 16, "{} messages were queued", batch.len());
+        echoed.extend(batch);
+        polls += 1;
+    }
+    assert_eq!(echoed, msgs);
+    assert!(polls >= 3);
+}
@@ -0,0 +1,228 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{stream, Future, Sink, Stream};
+use std::net::SocketAddr;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{negotiate_mux, spawn_mux, WsSendError, WsServer, MUX_PROTOCOL};
+
+use common::{bind, hear_or_end, NotFoundService};
+
+const OPEN: u8 = 1;
+const DATA: u8 = 2;
+const CLOSE: u8 = 3;
+const WINDOW: u8 = 4;
+
+/// Starts a server that opens a "presence" channel on each connection to send
+/// "hello" and "world" down, and shouts back the data on every channel the
+/// client opens. Each channel gets a window of 8 bytes.
+fn start_server(handle: &Handle) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let server_handle = handle.clone();
+    let serve = WsServer::new(NotFoundService, move |_ctx, websocket| {
+        let (opener, incoming) = spawn_mux(&server_handle, websocket, 8);
+        let presence = opener.open("presence").expect("mux open error");
+        let greetings = vec![b"hello".to_vec(), b"world".to_vec()];
+        server_handle.spawn(
+            presence
+                .send_all(stream::iter_ok::<_, WsSendError>(greetings))
+                .then(|_| Ok(())),
+        );
+
+        let echo_handle = server_handle.clone();
+        incoming
+            .for_each(move |channel| {
+                let (sink, stream) = channel.split();
+                let shout = stream.map(|data| {
+                    let text = String::from_utf8(data).expect("non-UTF-8 channel data");
+                    text.to_uppercase().into_bytes()
+                });
+                echo_handle.spawn(shout.forward(sink.sink_map_err(|_| ())).then(|_| Ok(())));
+                Ok(())
+            })
+            .then(move |_| {
+                drop(opener);
+                Ok(())
+            })
+    }).negotiate(|_req, handshake| negotiate_mux(handshake))
+        .serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn envelope(kind: u8, id: u32, body: &[u8]) -> OwnedMessage {
+    let mut data = vec![kind, (id >> 24) as u8, (id >> 16) as u8, (id >> 8) as u8, id as u8];
+    data.extend_from_slice(body);
+    OwnedMessage::Binary(data)
+}
+
+fn window(bytes: u32) -> Vec<u8> {
+    vec![(bytes >> 24) as u8, (bytes >> 16) as u8, (bytes >> 8) as u8, bytes as u8]
+}
+
+fn say(
+    websocket: Client<TcpStream>,
+    msg: OwnedMessage,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    Box::new(websocket.send(msg))
+}
+
+#[test]
+fn test_mux_requires_protocol() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let test = ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+        .expect("client build error")
+        .async_connect_insecure(&handle);
+    assert!(core.run(test).is_err());
+}
+
+#[test]
+fn test_mux_channels() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let test = ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+        .expect("client build error")
+        .add_protocol(MUX_PROTOCOL)
+        .async_connect_insecure(&handle)
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+
+    let mut presence_open = window(8);
+    presence_open.extend_from_slice(b"presence");
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(OPEN, 0, &presence_open)));
+
+    // Opening a channel gets the server's window back.
+    let mut chat_open = window(100);
+    chat_open.extend_from_slice(b"chat");
+    let websocket = core.run(say(websocket, envelope(OPEN, 1, &chat_open)))
+        .expect("client websocket error");
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(WINDOW, 1, &window(8))));
+
+    let websocket = core.run(say(websocket, envelope(DATA, 1, b"hi")))
+        .expect("client websocket error");
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(DATA, 1, b"HI")));
+
+    // Once half the window has been read, the server grants it back.
+    let websocket = core.run(say(websocket, envelope(DATA, 1, b"abcd")))
+        .expect("client websocket error");
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(WINDOW, 1, &window(6))));
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(DATA, 1, b"ABCD")));
+
+    // The server only sends on "presence" as far as the window allows.
+    let websocket = core.run(say(websocket, envelope(WINDOW, 0, &window(5))))
+        .expect("client websocket error");
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(DATA, 0, b"hello")));
+
+    let websocket = core.run(say(websocket, envelope(DATA, 1, b"x")))
+        .expect("client websocket error");
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(DATA, 1, b"X")));
+
+    let websocket = core.run(say(websocket, envelope(WINDOW, 0, &window(5))))
+        .expect("client websocket error");
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(DATA, 0, b"world")));
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(CLOSE, 0, b"")));
+
+    // Anything but an envelope ends the connection.
+    let websocket = core.run(say(websocket, OwnedMessage::Text("nope".into())))
+        .expect("client websocket error");
+    let (maybe_msg, _websocket) = core.run(hear_or_end(websocket))
+        .expect("client websocket error");
+    assert_eq!(
+        maybe_msg,
+        Some(OwnedMessage::Close(Some(CloseData::new(1002, "Malformed mux envelope".into()))))
+    );
+}
+
+#[test]
+fn test_mux_window_exceeded() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let test = ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+        .expect("client build error")
+        .add_protocol(MUX_PROTOCOL)
+        .async_connect_insecure(&handle)
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+    let (_maybe_msg, websocket) = core.run(hear_or_end(websocket))
+        .expect("client websocket error");
+
+    // The server never reads "presence", so its window of 8 bytes is never
+    // granted back, and the second envelope doesn't fit in what's left.
+    let websocket = core.run(say(websocket, envelope(DATA, 0, b"1234")))
+        .expect("client websocket error");
+    let websocket = core.run(say(websocket, envelope(DATA, 0, b"56789")))
+        .expect("client websocket error");
+    let (maybe_msg, _websocket) = core.run(hear_or_end(websocket))
+        .expect("client websocket error");
+    assert_eq!(
+        maybe_msg,
+        Some(OwnedMessage::Close(Some(CloseData::new(1002, "Mux window exceeded".into()))))
+    );
+}
+
+#[test]
+fn test_mux_split_to_window() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let test = ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+        .expect("client build error")
+        .add_protocol(MUX_PROTOCOL)
+        .async_connect_insecure(&handle)
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+    let (_maybe_msg, websocket) = core.run(hear_or_end(websocket))
+        .expect("client websocket error");
+
+    // "hello" doesn't fit in a window of 3, so it goes out in two pieces.
+    let websocket = core.run(say(websocket, envelope(WINDOW, 0, &window(3))))
+        .expect("client websocket error");
+    let (maybe_msg, websocket) = core.run(hear_or_end(websocket)).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(DATA, 0, b"hel")));
+    let websocket = core.run(say(websocket, envelope(WINDOW, 0, &window(2))))
+        .expect("client websocket error");
+    let (maybe_msg, _websocket) = core.run(hear_or_end(websocket))
+        .expect("client websocket error");
+    assert_eq!(maybe_msg, Some(envelope(DATA, 0, b"lo")));
+}
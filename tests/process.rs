@@ -0,0 +1,134 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg(unix)]
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{Future, Sink};
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+
+use hyper_websocket::{ProcessBridge, WsServer};
+
+use common::{bind, hear, NotFoundService};
+
+fn start_server(handle: &Handle, script: &str) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let bridge = ProcessBridge::new(handle, "sh")
+        .arg("-c")
+        .arg(script)
+        .env("GREETING", "hello");
+    let serve = WsServer::from_endpoint(NotFoundService, bridge).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn connect(core: &mut Core, url: &str) -> Client<TcpStream> {
+    let test = ClientBuilder::new(url)
+        .expect("client build error")
+        .async_connect_insecure(&core.handle())
+        .map(|(websocket, _headers)| websocket);
+    core.run(test).expect("client websocket error")
+}
+
+fn hear_text(core: &mut Core, websocket: Client<TcpStream>, text: &str) -> Client<TcpStream> {
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Text(text.to_owned()));
+    websocket
+}
+
+/// Whether the process `pid` is still running, or at least not yet reaped.
+fn alive(pid: &str) -> bool {
+    Command::new("kill")
+        .arg("-0")
+        .arg(pid)
+        .stderr(Stdio::null())
+        .status()
+        .expect("kill error")
+        .success()
+}
+
+#[test]
+fn test_process_lines() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let script = r#"
+        echo "$GREETING $REMOTE_ADDR $PATH_INFO $QUERY_STRING"
+        echo "warning" >&2
+        while read line; do echo "got $line"; done
+    "#;
+    let server_addr = start_server(&handle, script);
+
+    let websocket = connect(&mut core, &format!("ws://{}/tool?x=1", server_addr));
+    let websocket = hear_text(&mut core, websocket, "hello 127.0.0.1 /tool x=1");
+
+    let websocket = core.run(websocket.send(OwnedMessage::Text("one".to_owned())))
+        .expect("client websocket error");
+    let websocket = hear_text(&mut core, websocket, "got one");
+    let websocket = core.run(websocket.send(OwnedMessage::Binary(b"two".to_vec())))
+        .expect("client websocket error");
+    let _websocket = hear_text(&mut core, websocket, "got two");
+}
+
+#[test]
+fn test_process_exit() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, "echo bye");
+
+    let websocket = connect(&mut core, &format!("ws://{}/", server_addr));
+    let websocket = hear_text(&mut core, websocket, "bye");
+
+    // The command exiting closes the connection.
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert!(match msg {
+        OwnedMessage::Close(Some(ref data)) => data.status_code == 1000,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_process_killed_on_close() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, "echo $$; exec sleep 60");
+
+    let websocket = connect(&mut core, &format!("ws://{}/", server_addr));
+    let (msg, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    let pid = match msg {
+        OwnedMessage::Text(pid) => pid,
+        msg => panic!("unexpected message: {:?}", msg),
+    };
+    assert!(alive(&pid));
+
+    // The connection closing kills the command.
+    let _websocket = core.run(websocket.send(OwnedMessage::Close(None)))
+        .expect("client websocket error");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while alive(&pid) {
+        assert!(Instant::now() < deadline, "process {} outlived its connection", pid);
+        core.turn(Some(Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,117 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg(target_os = "linux")]
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{Future, Sink};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+
+use hyper_websocket::{PtyBridge, WsServer};
+
+use common::{bind, hear, NotFoundService};
+
+fn start_server(handle: &Handle, bridge: PtyBridge) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let serve = WsServer::from_endpoint(NotFoundService, bridge).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn connect(core: &mut Core, server_addr: SocketAddr) -> Client<TcpStream> {
+    let url = format!("ws://{}/", server_addr);
+    let test = ClientBuilder::new(&url)
+        .expect("client build error")
+        .async_connect_insecure(&core.handle())
+        .map(|(websocket, _headers)| websocket);
+    core.run(test).expect("client websocket error")
+}
+
+/// Collects terminal output until the connection is closed, returning the
+/// output and the close reason.
+fn hear_until_close(core: &mut Core, websocket: Client<TcpStream>) -> (String, String) {
+    let mut output = String::new();
+    let mut websocket = websocket;
+    loop {
+        let (msg, next) = core.run(hear(websocket)).expect("client websocket error");
+        websocket = next;
+        match msg {
+            OwnedMessage::Binary(data) => output.push_str(&String::from_utf8_lossy(&data)),
+            OwnedMessage::Close(Some(data)) => return (output, data.reason),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+}
+
+#[test]
+fn test_pty_session() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let bridge = PtyBridge::new(&handle, "sh")
+        .arg("-c")
+        .arg(r#"read line; stty size; test -t 0 && echo "got $line""#);
+    let server_addr = start_server(&handle, bridge);
+
+    let websocket = connect(&mut core, server_addr);
+    let resize = r#"{"type": "resize", "cols": 100, "rows": 30}"#;
+    let websocket = core.run(websocket.send(OwnedMessage::Text(resize.to_owned())))
+        .expect("client websocket error");
+    let input = r#"{"type": "input", "data": "hi\n"}"#;
+    let websocket = core.run(websocket.send(OwnedMessage::Text(input.to_owned())))
+        .expect("client websocket error");
+
+    // The command exiting closes the connection.
+    let (output, _reason) = hear_until_close(&mut core, websocket);
+    assert!(output.contains("30 100"), "unexpected output: {:?}", output);
+    assert!(output.contains("got hi"), "unexpected output: {:?}", output);
+}
+
+#[test]
+fn test_pty_idle_timeout() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let bridge = PtyBridge::new(&handle, "cat").idle_timeout(Duration::from_millis(200));
+    let server_addr = start_server(&handle, bridge);
+
+    let websocket = connect(&mut core, server_addr);
+    let websocket = core.run(websocket.send(OwnedMessage::Binary(b"echo\n".to_vec())))
+        .expect("client websocket error");
+    let (output, reason) = hear_until_close(&mut core, websocket);
+    assert!(output.contains("echo"), "unexpected output: {:?}", output);
+    assert_eq!(reason, "Idle timeout");
+}
+
+#[test]
+fn test_pty_master_not_inherited() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let bridge = PtyBridge::new(&handle, "ls").arg("-l").arg("/proc/self/fd");
+    let server_addr = start_server(&handle, bridge);
+
+    let websocket = connect(&mut core, server_addr);
+    let (output, _reason) = hear_until_close(&mut core, websocket);
+    assert!(output.contains("/dev/pts/"), "unexpected output: {:?}", output);
+    assert!(!output.contains("ptmx"), "unexpected output: {:?}", output);
+}
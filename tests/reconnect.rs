@@ -0,0 +1,199 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate tokio_core;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Handle};
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+use websocket::server::upgrade::async::IntoWs;
+
+use hyper_websocket::{BufferPolicy, ConnectionState, ReconnectEvent, ReconnectOptions,
+                      ReconnectingClient, WsClientHandshake};
+
+/// Runs a server that accepts a single WebSocket connection, waits for the
+/// first message from the client, then shuts down both the connection and the
+/// listener, as if the server process had been killed.
+fn serve_once(
+    addr: &SocketAddr,
+    handle: &Handle,
+) -> Box<Future<Item = Option<OwnedMessage>, Error = WebSocketError>> {
+    let listener = TcpListener::bind(addr, handle).expect("listener bind error");
+    Box::new(
+        listener
+            .incoming()
+            .into_future()
+            .map_err(|(err, _incoming)| WebSocketError::from(err))
+            .and_then(|(maybe_conn, _incoming)| {
+                let (tcp, _remote_addr) = maybe_conn.expect("listener closed unexpectedly");
+                tcp.into_ws().map_err(|(_tcp, _req, _buf, err)| {
+                    io::Error::new(io::ErrorKind::Other, err).into()
+                })
+            })
+            .and_then(|upgrade| upgrade.accept())
+            .and_then(|(websocket, _headers)| {
+                websocket.into_future().map_err(|(err, _websocket)| err)
+            })
+            .map(|(maybe_msg, _websocket)| maybe_msg),
+    )
+}
+
+/// Picks an address with nothing listening on it.
+fn unused_addr(handle: &Handle) -> SocketAddr {
+    let probe_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0);
+    let probe = TcpListener::bind(&probe_addr, handle).expect("listener bind error");
+    probe.local_addr().expect("server address retrieval error")
+}
+
+/// Starts a server in the background that accepts a single WebSocket
+/// connection and collects the first `count` messages from the client.
+fn collect(
+    addr: &SocketAddr,
+    handle: &Handle,
+    count: usize,
+) -> oneshot::Receiver<Result<Vec<OwnedMessage>, WebSocketError>> {
+    let listener = TcpListener::bind(addr, handle).expect("listener bind error");
+    let collect = listener
+        .incoming()
+        .into_future()
+        .map_err(|(err, _incoming)| WebSocketError::from(err))
+        .and_then(|(maybe_conn, _incoming)| {
+            let (tcp, _remote_addr) = maybe_conn.expect("listener closed unexpectedly");
+            tcp.into_ws().map_err(|(_tcp, _req, _buf, err)| {
+                io::Error::new(io::ErrorKind::Other, err).into()
+            })
+        })
+        .and_then(|upgrade| upgrade.accept())
+        .and_then(move |(websocket, _headers)| websocket.take(count as u64).collect());
+    let (done_tx, done_rx) = oneshot::channel();
+    handle.spawn(collect.then(move |result| {
+        let _ = done_tx.send(result);
+        Ok(())
+    }));
+    done_rx
+}
+
+/// Reads events until the client reports `state`.
+fn wait_for(
+    core: &mut Core,
+    mut client: ReconnectingClient,
+    state: ConnectionState,
+) -> ReconnectingClient {
+    loop {
+        let (maybe_event, rest) = core.run(client.into_future()).expect("event error");
+        client = rest;
+        if maybe_event.expect("client event stream ended") == ReconnectEvent::State(state) {
+            return client;
+        }
+    }
+}
+
+#[test]
+fn test_reconnect_after_restart() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+
+    let addr = unused_addr(&handle);
+
+    let first_life = serve_once(&addr, &handle);
+
+    let uri = format!("ws://{}/feed", addr).parse().expect("uri parse error");
+    let options = ReconnectOptions::new()
+        .backoff(Duration::from_millis(20), Duration::from_millis(200))
+        .on_connect(OwnedMessage::Text("subscribe".into()));
+    let client = ReconnectingClient::spawn(&handle, addr, WsClientHandshake::new(uri), options);
+
+    let restart_handle = handle.clone();
+    let test = first_life.and_then(move |maybe_msg| {
+        assert_eq!(maybe_msg, Some(OwnedMessage::Text("subscribe".into())));
+        serve_once(&addr, &restart_handle)
+    });
+    let maybe_msg = core.run(test).expect("server error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("subscribe".into())));
+
+    let mut states = Vec::new();
+    let mut events = client;
+    while states.iter().filter(|&&state| state == ConnectionState::Connected).count() < 2 {
+        let (maybe_event, rest) = core.run(events.into_future()).expect("event error");
+        events = rest;
+        match maybe_event.expect("client event stream ended") {
+            ReconnectEvent::State(state) => states.push(state),
+            ReconnectEvent::Message(msg) => panic!("unexpected message: {:?}", msg),
+        }
+    }
+    assert_eq!(
+        &states[..3],
+        &[ConnectionState::Connecting, ConnectionState::Connected, ConnectionState::Disconnected]
+    );
+}
+
+#[test]
+fn test_reconnect_buffer_policy() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let addr = unused_addr(&handle);
+
+    // The first connection attempt fails, so the oldest message is dropped to
+    // stay within the buffer. What's left follows the `on_connect` messages,
+    // which keep their order.
+    let uri = format!("ws://{}/feed", addr).parse().expect("uri parse error");
+    let options = ReconnectOptions::new()
+        .backoff(Duration::from_millis(20), Duration::from_millis(200))
+        .buffer_policy(BufferPolicy::Buffer(2))
+        .on_connect(OwnedMessage::Text("hello".into()))
+        .on_connect(OwnedMessage::Text("subscribe".into()));
+    let client = ReconnectingClient::spawn(&handle, addr, WsClientHandshake::new(uri), options);
+    for text in &["a", "b", "c"] {
+        client.send(OwnedMessage::Text(text.to_string())).expect("client send error");
+    }
+    let _client = wait_for(&mut core, client, ConnectionState::Disconnected);
+
+    let messages = collect(&addr, &handle, 4);
+    let messages = core.run(messages).expect("server dropped").expect("server error");
+    let texts = ["hello", "subscribe", "b", "c"];
+    let expected: Vec<_> = texts.iter().map(|text| OwnedMessage::Text(text.to_string())).collect();
+    assert_eq!(messages, expected);
+}
+
+#[test]
+fn test_reconnect_drop_policy() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let addr = unused_addr(&handle);
+
+    let uri = format!("ws://{}/feed", addr).parse().expect("uri parse error");
+    let options = ReconnectOptions::new()
+        .backoff(Duration::from_millis(20), Duration::from_millis(200))
+        .buffer_policy(BufferPolicy::Drop)
+        .on_connect(OwnedMessage::Text("hello".into()));
+    let client = ReconnectingClient::spawn(&handle, addr, WsClientHandshake::new(uri), options);
+    client.send(OwnedMessage::Text("lost".into())).expect("client send error");
+    let client = wait_for(&mut core, client, ConnectionState::Disconnected);
+
+    // Only what's sent once connected gets through, after the `on_connect`
+    // message.
+    let messages = collect(&addr, &handle, 2);
+    let client = wait_for(&mut core, client, ConnectionState::Connected);
+    client.send(OwnedMessage::Text("kept".into())).expect("client send error");
+    let messages = core.run(messages).expect("server dropped").expect("server error");
+    assert_eq!(
+        messages,
+        vec![OwnedMessage::Text("hello".into()), OwnedMessage::Text("kept".into())]
+    );
+}
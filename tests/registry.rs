@@ -0,0 +1,142 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{Future, Stream};
+use std::net::SocketAddr;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::{CloseData, OwnedMessage};
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{ConnectionId, ConnectionRegistry, WsSendError, WsServer};
+
+use common::{bind, NotFoundService};
+
+/// Starts a server that registers every connection, tags it with its query
+/// string as the "user" and tells the client its connection ID.
+fn start_server(handle: &Handle, registry: &ConnectionRegistry) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let registry = registry.clone();
+    let server_handle = handle.clone();
+    let serve = WsServer::new(NotFoundService, move |ctx, websocket| {
+        let (id, mut sender, receiver) = registry.register(&server_handle, &ctx, websocket, 8);
+        if let Some(user) = ctx.query() {
+            registry.set_tag(id, "user", user);
+        }
+        sender
+            .try_send(OwnedMessage::Text(id.to_string()))
+            .expect("server channel send error");
+        receiver.for_each(|_msg| Ok(()))
+    }).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn connect(
+    handle: &Handle,
+    server_addr: SocketAddr,
+    user: &str,
+) -> Box<Future<Item = (u64, Client<TcpStream>), Error = WebSocketError>> {
+    Box::new(
+        ClientBuilder::new(format!("ws://{}/chat?{}", server_addr, user).as_str())
+            .expect("client build error")
+            .async_connect_insecure(handle)
+            .and_then(|(websocket, _headers)| {
+                websocket.into_future().map_err(|(err, _websocket)| err)
+            })
+            .map(|(maybe_msg, websocket)| match maybe_msg {
+                Some(OwnedMessage::Text(id)) => (id.parse().expect("id parse error"), websocket),
+                msg => panic!("unexpected message: {:?}", msg),
+            }),
+    )
+}
+
+fn find_id(registry: &ConnectionRegistry, user: &str) -> ConnectionId {
+    let ids = registry.find_by_tag("user", user);
+    assert_eq!(ids.len(), 1);
+    ids[0]
+}
+
+#[test]
+fn test_registry_lookup_and_message() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let registry = ConnectionRegistry::new();
+    let server_addr = start_server(&handle, &registry);
+
+    let (alice_id, alice) = core.run(connect(&handle, server_addr, "alice"))
+        .expect("client websocket error");
+    let (bob_id, bob) = core.run(connect(&handle, server_addr, "bob"))
+        .expect("client websocket error");
+
+    let infos = registry.list();
+    assert_eq!(infos.len(), 2);
+    assert_eq!(infos[0].id().as_u64(), alice_id);
+    assert_eq!(infos[1].id().as_u64(), bob_id);
+    assert_eq!(infos[0].path(), "/chat");
+    assert_eq!(infos[0].tag("user"), Some("alice"));
+
+    let alice_id = find_id(&registry, "alice");
+    registry
+        .send(alice_id, OwnedMessage::Text("psst".into()))
+        .expect("registry send error");
+    let (maybe_msg, _alice) = core.run(alice.into_future().map_err(|(err, _alice)| err))
+        .expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("psst".into())));
+
+    registry
+        .close(find_id(&registry, "bob"), 4000, "kicked")
+        .expect("registry close error");
+    let (maybe_msg, _bob) = core.run(bob.into_future().map_err(|(err, _bob)| err))
+        .expect("client websocket error");
+    assert_eq!(
+        maybe_msg,
+        Some(OwnedMessage::Close(Some(CloseData::new(4000, "kicked".into()))))
+    );
+}
+
+#[test]
+fn test_registry_disconnect() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let registry = ConnectionRegistry::new();
+    let server_addr = start_server(&handle, &registry);
+
+    let (_id, websocket) = core.run(connect(&handle, server_addr, "mallory"))
+        .expect("client websocket error");
+    let id = find_id(&registry, "mallory");
+    assert!(registry.disconnect(id));
+    assert!(registry.get(id).is_none());
+    assert!(!registry.disconnect(id));
+
+    // The socket is dropped without a close frame.
+    let result = core.run(websocket.into_future().map_err(|(err, _websocket)| err));
+    match result {
+        Ok((None, _)) | Err(_) => {}
+        Ok((Some(msg), _)) => panic!("unexpected message: {:?}", msg),
+    }
+    match registry.send(id, OwnedMessage::Close(Some(CloseData::new(1000, "".into())))) {
+        Err(WsSendError::Closed(_)) => {}
+        result => panic!("unexpected send result: {:?}", result),
+    }
+}
@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{Future, Sink, Stream};
+use hyper::Request;
+use std::net::SocketAddr;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::message::OwnedMessage;
+
+use hyper_websocket::{OriginPolicy, WsRoute, WsRouter, WsServer};
+
+use common::{bind, NotFoundService};
+
+fn greet<S>(websocket: S, greeting: String) -> Box<Future<Item = (), Error = S::SinkError>>
+where
+    S: Sink<SinkItem = OwnedMessage> + 'static,
+{
+    Box::new(websocket.send(OwnedMessage::Text(greeting)).map(|_websocket| ()))
+}
+
+fn start_server(handle: &Handle) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let router = WsRouter::new()
+        .route(
+            "/rooms/:room",
+            WsRoute::new(|ctx, websocket| {
+                let room = ctx.param("room").expect("missing room param").to_owned();
+                greet(websocket, format!("room {}", room))
+            }),
+        )
+        .route(
+            "/files/*",
+            WsRoute::new(|ctx, websocket| {
+                let rest = ctx.param("*").expect("missing wildcard param").to_owned();
+                greet(websocket, format!("file {}", rest))
+            }),
+        )
+        .route(
+            "/chat",
+            WsRoute::new(|ctx, websocket| {
+                let protocol = ctx.protocol().expect("missing protocol").to_owned();
+                greet(websocket, format!("protocol {}", protocol))
+            }).protocols(vec!["chat.v2", "chat.v1"]),
+        )
+        .route(
+            "/private",
+            WsRoute::new(|_ctx, websocket| {
+                greet(websocket, "private".to_owned())
+            }).origin(OriginPolicy::Allow(vec!["https://example.com".to_owned()])),
+        )
+        .route(
+            "/echo",
+            WsRoute::new(|_ctx, websocket| {
+                let (sink, stream) = websocket.split();
+                stream.forward(sink).map(|_| ())
+            }).max_message_size(4),
+        );
+
+    let serve = WsServer::from_endpoint(NotFoundService, router).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn do_test_greeting(path: &str, protocol: Option<&str>, expected: &str) {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let mut builder = ClientBuilder::new(format!("ws://{}{}", server_addr, path).as_str())
+        .expect("client build error");
+    if let Some(protocol) = protocol {
+        builder = builder.add_protocol(protocol);
+    }
+    let test = builder
+        .async_connect_insecure(&handle)
+        .and_then(|(websocket, _headers)| {
+            websocket.into_future().map_err(|(err, _websocket)| err)
+        })
+        .map(|(maybe_msg, _websocket)| maybe_msg);
+    let maybe_msg = core.run(test).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text(expected.into())));
+}
+
+fn do_test_status(path: &str, extra_headers: &str, expected_status: &str) {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let to_server = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: 127.0.0.1\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: r3MGDiK57a1jWWkCmkiK5g==\r\n\
+         {}\r\n",
+        path,
+        extra_headers
+    );
+    let expected_status_line = format!("HTTP/1.1 {}\r\n", expected_status);
+    let expected_len = expected_status_line.len();
+
+    let test = TcpStream::connect(&server_addr, &handle)
+        .and_then(move |tcp| tokio_io::io::write_all(tcp, to_server.into_bytes()))
+        .and_then(move |(tcp, _msg)| tokio_io::io::read_exact(tcp, vec![0; expected_len]))
+        .map(|(_tcp, msg)| msg);
+    let msg = core.run(test).expect("client io error");
+    assert_eq!(String::from_utf8(msg), Ok(expected_status_line));
+}
+
+#[test]
+fn test_router_param() {
+    do_test_greeting("/rooms/lobby", None, "room lobby");
+}
+
+#[test]
+fn test_router_wildcard() {
+    do_test_greeting("/files/a/b/c.txt", None, "file a/b/c.txt");
+}
+
+#[test]
+fn test_router_protocol() {
+    do_test_greeting("/chat", Some("chat.v1"), "protocol chat.v1");
+}
+
+#[test]
+fn test_router_protocol_mismatch() {
+    do_test_status("/chat", "Sec-WebSocket-Protocol: chat.v3\r\n", "400 Bad Request");
+}
+
+#[test]
+fn test_router_not_found() {
+    do_test_status("/nowhere", "", "404 Not Found");
+}
+
+#[test]
+fn test_router_origin_allowed() {
+    do_test_status("/private", "Origin: https://example.com\r\n", "101 Switching Protocols");
+}
+
+#[test]
+fn test_router_origin_forbidden() {
+    do_test_status("/private", "Origin: https://evil.example\r\n", "403 Forbidden");
+}
+
+#[test]
+fn test_router_max_message_size() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let test = ClientBuilder::new(format!("ws://{}/echo", server_addr).as_str())
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .and_then(|(websocket, _headers)| websocket.send(OwnedMessage::Text("tiny".into())))
+        .and_then(|websocket| websocket.into_future().map_err(|(err, _websocket)| err))
+        .and_then(|(maybe_msg, websocket)| {
+            assert_eq!(maybe_msg, Some(OwnedMessage::Text("tiny".into())));
+            websocket.send(OwnedMessage::Text("too big".into()))
+        })
+        .and_then(|websocket| websocket.into_future().map_err(|(err, _websocket)| err))
+        .map(|(maybe_msg, _websocket)| maybe_msg);
+    let maybe_msg = core.run(test).expect("client websocket error");
+    assert!(match maybe_msg {
+        Some(OwnedMessage::Close(Some(ref data))) => data.status_code == 1009,
+        _ => false,
+    });
+}
@@ -0,0 +1,153 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+use futures::{future, Future, Sink, Stream};
+use hyper::{Request, Response, StatusCode};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_service::Service;
+use websocket::ClientBuilder;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{WsResponse, WsServer};
+
+struct HelloService;
+
+impl Service for HelloService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = future::FutureResult<Self::Response, Self::Error>;
+
+    fn call(&self, _req: Self::Request) -> Self::Future {
+        future::ok(Response::new().with_status(StatusCode::Ok).with_body("Hello World"))
+    }
+}
+
+fn start_server(handle: &Handle) -> SocketAddr {
+    let server_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0);
+    let listener = TcpListener::bind(&server_addr, handle).expect("listener bind error");
+    let server_addr = listener.local_addr().expect("server address retrieval error");
+
+    let serve = WsServer::new(HelloService, |ctx, websocket| {
+        let greeting = format!("Hello {}", ctx.path());
+        websocket
+            .send(OwnedMessage::Text(greeting))
+            .and_then(|websocket| websocket.into_future().map_err(|(err, _websocket)| err))
+            .and_then(|(maybe_msg, websocket)| {
+                let msg = maybe_msg.expect("server websocket receive error");
+                websocket.send(msg)
+            })
+            .map(|_websocket| ())
+    }).negotiate(|req, handshake| {
+        if req.path() == "/reject" {
+            WsResponse::reject(handshake)
+        } else {
+            WsResponse::accept(handshake)
+        }
+    })
+        .serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+#[test]
+fn test_server_http() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let client = hyper::Client::new(&handle);
+    let test = client
+        .get(format!("http://{}/foo", server_addr).parse().expect("uri parse error"))
+        .and_then(|res| res.body().concat2());
+    let body = core.run(test).expect("client http error");
+    assert_eq!(str::from_utf8(body.as_ref()), Ok("Hello World"));
+}
+
+#[test]
+fn test_server_echo() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let test = ClientBuilder::new(format!("ws://{}/echo", server_addr).as_str())
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .and_then(|(websocket, _headers)| {
+            websocket.into_future().map_err(|(err, _websocket)| err)
+        })
+        .and_then(|(maybe_msg, websocket)| {
+            assert_eq!(maybe_msg, Some(OwnedMessage::Text("Hello /echo".into())));
+            websocket.send(OwnedMessage::Text("ping".into()))
+        })
+        .and_then(|websocket| websocket.into_future().map_err(|(err, _websocket)| err))
+        .map(|(maybe_msg, _websocket)| maybe_msg);
+    let maybe_msg = core.run(test).expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("ping".into())));
+}
+
+#[test]
+fn test_server_reject() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let test = ClientBuilder::new(format!("ws://{}/reject", server_addr).as_str())
+        .expect("client build error")
+        .async_connect_insecure(&handle)
+        .then(|result| match result {
+            Ok(_) => Err("unexpected websocket connection success".to_owned()),
+            Err(WebSocketError::ResponseError(_)) => Ok(()),
+            Err(err) => Err(format!("unexpected websocket connect error: {}", err)),
+        });
+    core.run(test).unwrap();
+}
+
+#[test]
+fn test_server_reject_then_http() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let to_server = "GET /reject HTTP/1.1\r\n\
+                     Host: 127.0.0.1\r\n\
+                     Connection: Upgrade\r\n\
+                     Upgrade: websocket\r\n\
+                     Sec-WebSocket-Version: 13\r\n\
+                     Sec-WebSocket-Key: r3MGDiK57a1jWWkCmkiK5g==\r\n\
+                     \r\n\
+                     GET /foo HTTP/1.1\r\n\
+                     Host: 127.0.0.1\r\n\
+                     Connection: close\r\n\
+                     \r\n";
+
+    let test = TcpStream::connect(&server_addr, &handle)
+        .and_then(move |tcp| tokio_io::io::write_all(tcp, to_server))
+        .and_then(|(tcp, _msg)| tokio_io::io::read_to_end(tcp, Vec::new()))
+        .map(|(_tcp, msg)| msg);
+    let msg = core.run(test).expect("client io error");
+    let msg = String::from_utf8(msg).expect("client response decode error");
+    assert!(msg.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(msg.contains("HTTP/1.1 200 OK\r\n"));
+    assert!(msg.ends_with("Hello World"));
+}
@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc;
+use serde_json::Value;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{EngineIoServer, EngineIoSocket, SocketIoServer, SocketIoSocket, WsServer};
+
+/// Starts a server whose main namespace echoes `echo` events through their
+/// acknowledgements, sends the attachments of `upload` events back in a
+/// `stored` event, and answers `ask` by asking the client a question, and
+/// whose `/admin` namespace wants a token. Disconnect reasons are reported
+/// on the returned channel.
+fn start_server(handle: &Handle) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+    let server_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0);
+    let listener = TcpListener::bind(&server_addr, handle).expect("listener bind error");
+    let server_addr = listener.local_addr().expect("server address retrieval error");
+
+    let (disconnect_tx, disconnect_rx) = mpsc::unbounded();
+    let ask_handle = handle.clone();
+    let io = SocketIoServer::new()
+        .namespace("/", move |socket: &SocketIoSocket, _auth: Option<&Value>| {
+            socket.emit("welcome", vec![]);
+            socket.on("echo", |_socket, args, ack| {
+                if let Some(ack) = ack {
+                    ack.send(args);
+                }
+            });
+            socket.on_binary("upload", |socket, _args, attachments, _ack| {
+                let placeholder = json!({"_placeholder": true, "num": 0});
+                socket.emit_binary("stored", vec![placeholder], attachments);
+            });
+            let handle = ask_handle.clone();
+            socket.on("ask", move |socket, _args, _ack| {
+                let socket = socket.clone();
+                let question = socket.emit_with_ack("question", vec![json!("meaning")]);
+                handle.spawn(question.map(move |reply| {
+                    socket.emit("answer", reply);
+                }));
+            });
+            let disconnect_tx = disconnect_tx.clone();
+            socket.on_disconnect(move |_socket, reason| {
+                let _ = disconnect_tx.unbounded_send(reason.to_owned());
+            });
+            Ok(())
+        })
+        .namespace("/admin", |_socket: &SocketIoSocket, auth: Option<&Value>| {
+            match auth.and_then(|auth| auth.get("token")) {
+                Some(token) if token == "secret" => Ok(()),
+                _ => Err("Not authorized".to_owned()),
+            }
+        });
+
+    let engine = EngineIoServer::new(handle, move |socket: EngineIoSocket| io.serve(socket));
+    let serve = WsServer::from_endpoint(engine.clone(), engine).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    (server_addr, disconnect_rx)
+}
+
+fn connect(core: &mut Core, server_addr: SocketAddr) -> Client<TcpStream> {
+    let url = format!("ws://{}/socket.io/?EIO=4&transport=websocket", server_addr);
+    let test = ClientBuilder::new(&url)
+        .expect("client build error")
+        .async_connect_insecure(&core.handle())
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+    let (open, websocket) = hear(core, websocket);
+    assert!(open.starts_with('0'));
+    websocket
+}
+
+fn say(
+    websocket: Client<TcpStream>,
+    text: &str,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    Box::new(websocket.send(OwnedMessage::Text(text.to_owned())))
+}
+
+fn hear(core: &mut Core, websocket: Client<TcpStream>) -> (String, Client<TcpStream>) {
+    let test = websocket
+        .into_future()
+        .map_err(|(err, _websocket)| err)
+        .map(|(maybe_msg, websocket)| match maybe_msg {
+            Some(OwnedMessage::Text(text)) => (text, websocket),
+            msg => panic!("unexpected message: {:?}", msg),
+        });
+    core.run(test).expect("client websocket error")
+}
+
+fn exchange(
+    core: &mut Core,
+    websocket: Client<TcpStream>,
+    text: &str,
+) -> (String, Client<TcpStream>) {
+    let websocket = core.run(say(websocket, text)).expect("client websocket error");
+    hear(core, websocket)
+}
+
+/// Splits a connect packet into its prefix and payload.
+fn connected(packet: &str) -> (&str, Value) {
+    let start = packet.find('{').expect("missing connect payload");
+    let payload = serde_json::from_str(&packet[start..]).expect("malformed connect payload");
+    (&packet[..start], payload)
+}
+
+#[test]
+fn test_socketio_events_and_acks() {
+    let mut core = Core::new().expect("core creation error");
+    let (server_addr, disconnect_rx) = start_server(&core.handle());
+    let websocket = connect(&mut core, server_addr);
+
+    let (packet, websocket) = exchange(&mut core, websocket, "40");
+    let (prefix, payload) = connected(&packet);
+    assert_eq!(prefix, "40");
+    assert!(payload["sid"].is_string());
+    // Events sent while connecting follow the connect packet.
+    let (packet, websocket) = hear(&mut core, websocket);
+    assert_eq!(packet, r#"42["welcome"]"#);
+
+    let (packet, websocket) = exchange(&mut core, websocket, r#"421["echo",1,"two"]"#);
+    assert_eq!(packet, r#"431[1,"two"]"#);
+
+    let (packet, websocket) = exchange(&mut core, websocket, r#"42["ask"]"#);
+    assert_eq!(packet, r#"420["question","meaning"]"#);
+    let (packet, websocket) = exchange(&mut core, websocket, "430[42]");
+    assert_eq!(packet, r#"42["answer",42]"#);
+
+    let websocket = core.run(say(websocket, "41")).expect("client websocket error");
+    let (reason, _disconnect_rx) = core.run(disconnect_rx.into_future())
+        .map_err(|_| ())
+        .expect("disconnect channel error");
+    assert_eq!(reason, Some("client namespace disconnect".to_owned()));
+
+    // The client may connect to the namespace again.
+    let (packet, _websocket) = exchange(&mut core, websocket, "40");
+    assert!(packet.starts_with("40{"));
+}
+
+#[test]
+fn test_socketio_namespaces() {
+    let mut core = Core::new().expect("core creation error");
+    let (server_addr, _disconnect_rx) = start_server(&core.handle());
+    let websocket = connect(&mut core, server_addr);
+
+    let (packet, websocket) = exchange(&mut core, websocket, r#"40/admin,{"token":"guess"}"#);
+    assert_eq!(packet, r#"44/admin,{"message":"Not authorized"}"#);
+    let (packet, websocket) = exchange(&mut core, websocket, r#"40/admin,{"token":"secret"}"#);
+    let (prefix, payload) = connected(&packet);
+    assert_eq!(prefix, "40/admin,");
+    assert!(payload["sid"].is_string());
+
+    let (packet, _websocket) = exchange(&mut core, websocket, "40/nowhere,");
+    assert_eq!(packet, r#"44/nowhere,{"message":"Invalid namespace"}"#);
+}
+
+#[test]
+fn test_socketio_binary_attachments() {
+    let mut core = Core::new().expect("core creation error");
+    let (server_addr, _disconnect_rx) = start_server(&core.handle());
+    let websocket = connect(&mut core, server_addr);
+    let (_packet, websocket) = exchange(&mut core, websocket, "40");
+    let (_welcome, websocket) = hear(&mut core, websocket);
+
+    // The event is handled once its attachment arrives.
+    let binary_event = r#"51-1["echo",{"_placeholder":true,"num":0}]"#;
+    let websocket = core.run(say(websocket, binary_event)).expect("client websocket error");
+    let websocket = core.run(websocket.send(OwnedMessage::Binary(vec![1, 2, 3])))
+        .expect("client websocket error");
+    let (packet, websocket) = hear(&mut core, websocket);
+    assert_eq!(packet, "431[[1,2,3]]");
+
+    // An attachment nothing is waiting for ends the session.
+    let websocket = core.run(websocket.send(OwnedMessage::Binary(vec![4])))
+        .expect("client websocket error");
+    let (packet, _websocket) = hear(&mut core, websocket);
+    assert_eq!(packet, "1");
+}
+
+#[test]
+fn test_socketio_binary_events() {
+    let mut core = Core::new().expect("core creation error");
+    let (server_addr, _disconnect_rx) = start_server(&core.handle());
+    let websocket = connect(&mut core, server_addr);
+    let (_packet, websocket) = exchange(&mut core, websocket, "40");
+    let (_welcome, websocket) = hear(&mut core, websocket);
+
+    // The attachment reaches the handler as bytes, and goes back the same way.
+    let binary_event = r#"51-["upload",{"_placeholder":true,"num":0}]"#;
+    let websocket = core.run(say(websocket, binary_event)).expect("client websocket error");
+    let websocket = core.run(websocket.send(OwnedMessage::Binary(vec![1, 2, 3])))
+        .expect("client websocket error");
+    let (packet, websocket) = hear(&mut core, websocket);
+    assert_eq!(packet, r#"51-["stored",{"_placeholder":true,"num":0}]"#);
+    let (maybe_msg, websocket) = core.run(websocket.into_future())
+        .map_err(|(err, _websocket)| err)
+        .expect("client websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Binary(vec![1, 2, 3])));
+
+    // So many attachments that the packet is taken for malformed.
+    let (packet, _websocket) = exchange(&mut core, websocket, r#"517-["upload"]"#);
+    assert_eq!(packet, "1");
+}
@@ -0,0 +1,365 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{Future, Sink};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{negotiate_stomp, StompBroker, StompDecoder, StompError, StompFrame,
+                      WsServer, STOMP_PROTOCOL};
+
+use common::{bind, hear, NotFoundService};
+
+fn start_server(handle: &Handle, broker: StompBroker) -> SocketAddr {
+    let (listener, server_addr) = bind(handle);
+
+    let server_handle = handle.clone();
+    let serve = WsServer::new(NotFoundService, move |_ctx, websocket| {
+        broker.serve(&server_handle, websocket)
+    }).negotiate(|_req, handshake| negotiate_stomp(handshake))
+        .serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    server_addr
+}
+
+fn say(
+    websocket: Client<TcpStream>,
+    frame: &StompFrame,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    let text = String::from_utf8(frame.encode()).expect("frame is not UTF-8");
+    Box::new(websocket.send(OwnedMessage::Text(text)))
+}
+
+/// Reads the next frame, skipping heart-beats.
+fn hear_frame(
+    core: &mut Core,
+    mut websocket: Client<TcpStream>,
+) -> (StompFrame, Client<TcpStream>) {
+    loop {
+        let (msg, rest) = core.run(hear(websocket)).expect("client websocket error");
+        websocket = rest;
+        let text = match msg {
+            OwnedMessage::Text(text) => text,
+            msg => panic!("unexpected message: {:?}", msg),
+        };
+        let mut frames = StompDecoder::new(1024 * 1024)
+            .decode(text.as_bytes())
+            .expect("malformed frame");
+        match frames.len() {
+            0 => continue,
+            1 => return (frames.remove(0), websocket),
+            len => panic!("{} frames in one message", len),
+        }
+    }
+}
+
+fn connect(core: &mut Core, server_addr: SocketAddr, heart_beat: &str) -> Client<TcpStream> {
+    let handle = core.handle();
+    let test = ClientBuilder::new(format!("ws://{}/stomp", server_addr).as_str())
+        .expect("client build error")
+        .add_protocol(STOMP_PROTOCOL)
+        .async_connect_insecure(&handle)
+        .map(|(websocket, _headers)| websocket);
+    let websocket = core.run(test).expect("client websocket error");
+
+    let connect = StompFrame::new("CONNECT")
+        .with_header("accept-version", "1.1,1.2")
+        .with_header("host", "localhost")
+        .with_header("heart-beat", heart_beat);
+    let websocket = core.run(say(websocket, &connect)).expect("client websocket error");
+    let (frame, websocket) = hear_frame(core, websocket);
+    assert_eq!(frame.command(), "CONNECTED");
+    assert_eq!(frame.header("version"), Some("1.2"));
+    websocket
+}
+
+/// Sends a frame with a receipt request and waits for the receipt.
+fn exchange(
+    core: &mut Core,
+    websocket: Client<TcpStream>,
+    frame: StompFrame,
+) -> Client<TcpStream> {
+    let websocket = core.run(say(websocket, &frame.with_header("receipt", "r")))
+        .expect("client websocket error");
+    let (frame, websocket) = hear_frame(core, websocket);
+    assert_eq!(frame, StompFrame::new("RECEIPT").with_header("receipt-id", "r"));
+    websocket
+}
+
+#[test]
+fn test_stomp_codec() {
+    let frame = StompFrame::new("SEND")
+        .with_header("destination", "/queue/a:b")
+        .with_header("note", "line\nbreak\\")
+        .with_body(&b"zero\0byte"[..]);
+    let encoded = frame.encode();
+    assert!(encoded.starts_with(b"SEND\ndestination:/queue/a\\cb\nnote:line\\nbreak\\\\\n"));
+
+    // Frames may be split across and batched within chunks, with heart-beats
+    // in between.
+    let mut stream = b"\n\r\n".to_vec();
+    stream.extend_from_slice(&encoded);
+    stream.extend_from_slice(b"\nACK\nid:7\n\n\0\n");
+    let mut decoder = StompDecoder::new(1024);
+    let (first, second) = stream.split_at(20);
+    assert_eq!(decoder.decode(first), Ok(vec![]));
+    let ack = StompFrame::new("ACK").with_header("id", "7");
+    let mut expected = frame.with_header("content-length", "9");
+    assert_eq!(decoder.decode(second), Ok(vec![expected.clone(), ack]));
+
+    // Connection frames aren't escaped.
+    expected = StompFrame::new("CONNECT").with_header("passcode", "a\\b");
+    assert_eq!(expected.encode(), b"CONNECT\npasscode:a\\b\n\n\0".to_vec());
+    assert_eq!(StompDecoder::new(1024).decode(&expected.encode()), Ok(vec![expected]));
+
+    let malformed = StompDecoder::new(1024).decode(b"SEND\nnote:\\t\n\n\0");
+    assert_eq!(malformed, Err(StompError::Malformed("undefined escape sequence in header")));
+    let too_large = StompDecoder::new(16).decode(b"SEND\ndestination:/queue/a\n");
+    assert_eq!(too_large, Err(StompError::TooLarge));
+}
+
+#[test]
+fn test_stomp_requires_protocol() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle, StompBroker::new());
+
+    let test = ClientBuilder::new(format!("ws://{}/stomp", server_addr).as_str())
+        .expect("client build error")
+        .async_connect_insecure(&handle);
+    assert!(core.run(test).is_err());
+}
+
+#[test]
+fn test_stomp_send_and_subscribe() {
+    let mut core = Core::new().expect("core creation error");
+    let broker = StompBroker::new();
+    let server_addr = start_server(&core.handle(), broker.clone());
+
+    let alice = connect(&mut core, server_addr, "0,0");
+    let bob = connect(&mut core, server_addr, "0,0");
+    let subscribe = StompFrame::new("SUBSCRIBE")
+        .with_header("id", "sub-0")
+        .with_header("destination", "/topic/news");
+    let alice = exchange(&mut core, alice, subscribe.clone());
+    let bob = exchange(&mut core, bob, subscribe.with_header("ack", "client"));
+
+    let send = StompFrame::new("SEND")
+        .with_header("destination", "/topic/news")
+        .with_header("content-type", "text/plain")
+        .with_body("extra!");
+    let alice = core.run(say(alice, &send)).expect("client websocket error");
+    let (message, alice) = hear_frame(&mut core, alice);
+    assert_eq!(message.command(), "MESSAGE");
+    assert_eq!(message.header("subscription"), Some("sub-0"));
+    assert_eq!(message.header("destination"), Some("/topic/news"));
+    assert_eq!(message.header("content-type"), Some("text/plain"));
+    assert_eq!(message.header("ack"), None);
+    assert_eq!(message.body(), b"extra!");
+
+    assert_eq!(broker.publish("/topic/news", "more"), 2);
+    assert_eq!(broker.publish("/topic/sports", "nobody"), 0);
+    let (message, _alice) = hear_frame(&mut core, alice);
+    assert_eq!(message.body(), b"more");
+
+    // Acknowledging in the `client` mode covers the earlier messages too.
+    let (first, bob) = hear_frame(&mut core, bob);
+    let (second, bob) = hear_frame(&mut core, bob);
+    assert_eq!(second.body(), b"more");
+    let first_ack = first.header("ack").expect("missing ack header").to_owned();
+    let second_ack = second.header("ack").expect("missing ack header").to_owned();
+    let bob = exchange(&mut core, bob, StompFrame::new("ACK").with_header("id", &second_ack));
+
+    let ack = StompFrame::new("ACK").with_header("id", &first_ack);
+    let bob = core.run(say(bob, &ack)).expect("client websocket error");
+    let (error, bob) = hear_frame(&mut core, bob);
+    assert_eq!(error.command(), "ERROR");
+    assert_eq!(error.header("message"), Some(format!("Unknown ack ID {}", first_ack).as_str()));
+    let (msg, _bob) = core.run(hear(bob)).expect("client websocket error");
+    assert!(match msg {
+        OwnedMessage::Close(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_stomp_transactions() {
+    let mut core = Core::new().expect("core creation error");
+    let broker = StompBroker::new();
+    let server_addr = start_server(&core.handle(), broker.clone());
+
+    let websocket = connect(&mut core, server_addr, "0,0");
+    let subscribe = StompFrame::new("SUBSCRIBE")
+        .with_header("id", "0")
+        .with_header("destination", "/queue/work");
+    let websocket = exchange(&mut core, websocket, subscribe);
+
+    let send = |body: &str, transaction: &str| {
+        StompFrame::new("SEND")
+            .with_header("destination", "/queue/work")
+            .with_header("transaction", transaction)
+            .with_body(body)
+    };
+    let control = |command: &str, transaction: &str| {
+        StompFrame::new(command).with_header("transaction", transaction)
+    };
+    let websocket = exchange(&mut core, websocket, control("BEGIN", "a"));
+    let websocket = exchange(&mut core, websocket, send("dropped", "a"));
+    let websocket = exchange(&mut core, websocket, control("ABORT", "a"));
+    let websocket = exchange(&mut core, websocket, control("BEGIN", "b"));
+    let websocket = exchange(&mut core, websocket, send("kept", "b"));
+    let websocket = core.run(say(websocket, &control("COMMIT", "b")))
+        .expect("client websocket error");
+    let (message, websocket) = hear_frame(&mut core, websocket);
+    assert_eq!(message.command(), "MESSAGE");
+    assert_eq!(message.body(), b"kept");
+
+    let disconnect = StompFrame::new("DISCONNECT").with_header("receipt", "bye");
+    let websocket = core.run(say(websocket, &disconnect)).expect("client websocket error");
+    let (receipt, websocket) = hear_frame(&mut core, websocket);
+    assert_eq!(receipt, StompFrame::new("RECEIPT").with_header("receipt-id", "bye"));
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert!(match msg {
+        OwnedMessage::Close(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_stomp_transaction_limits() {
+    let mut core = Core::new().expect("core creation error");
+    let server_addr = start_server(&core.handle(), StompBroker::new());
+
+    let begin = |transaction: &str| {
+        StompFrame::new("BEGIN").with_header("transaction", transaction)
+    };
+    let mut websocket = connect(&mut core, server_addr, "0,0");
+    for transaction in 0..16 {
+        websocket = exchange(&mut core, websocket, begin(&transaction.to_string()));
+    }
+    let websocket = core.run(say(websocket, &begin("16"))).expect("client websocket error");
+    let (error, _websocket) = hear_frame(&mut core, websocket);
+    assert_eq!(error.command(), "ERROR");
+    assert_eq!(error.header("message"), Some("Too many open transactions"));
+
+    let websocket = connect(&mut core, server_addr, "0,0");
+    let websocket = exchange(&mut core, websocket, begin("a"));
+    let send = StompFrame::new("SEND")
+        .with_header("destination", "/queue/work")
+        .with_header("transaction", "a");
+    let mut frames = Vec::new();
+    for _ in 0..257 {
+        frames.extend(send.encode());
+    }
+    let text = String::from_utf8(frames).expect("frames are not UTF-8");
+    let websocket = core.run(websocket.send(OwnedMessage::Text(text)))
+        .expect("client websocket error");
+    let (error, _websocket) = hear_frame(&mut core, websocket);
+    assert_eq!(error.command(), "ERROR");
+    assert_eq!(error.header("message"), Some("Transaction a is too long"));
+}
+
+#[test]
+fn test_stomp_max_unacked() {
+    let mut core = Core::new().expect("core creation error");
+    let broker = StompBroker::new().max_unacked(2);
+    let server_addr = start_server(&core.handle(), broker.clone());
+
+    let websocket = connect(&mut core, server_addr, "0,0");
+    let subscribe = StompFrame::new("SUBSCRIBE")
+        .with_header("id", "sub-0")
+        .with_header("destination", "/topic/news")
+        .with_header("ack", "client-individual");
+    let websocket = exchange(&mut core, websocket, subscribe);
+
+    for _ in 0..3 {
+        assert_eq!(broker.publish("/topic/news", "news"), 1);
+    }
+    let (first, websocket) = hear_frame(&mut core, websocket);
+    let (second, websocket) = hear_frame(&mut core, websocket);
+    assert_eq!(first.command(), "MESSAGE");
+    assert_eq!(second.command(), "MESSAGE");
+    let (error, websocket) = hear_frame(&mut core, websocket);
+    assert_eq!(error.command(), "ERROR");
+    assert_eq!(error.header("message"), Some("Too many unacknowledged messages"));
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert!(match msg {
+        OwnedMessage::Close(Some(data)) => data.status_code == 1008,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_stomp_heart_beats() {
+    let mut core = Core::new().expect("core creation error");
+    let broker = StompBroker::new()
+        .heart_beat(Duration::from_millis(50), Duration::from_millis(50));
+    let server_addr = start_server(&core.handle(), broker);
+
+    // The broker beats for a client that asks it to.
+    let websocket = connect(&mut core, server_addr, "0,50");
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(msg, OwnedMessage::Text("\n".to_owned()));
+
+    // A client that promises to beat but doesn't is cut off.
+    let websocket = connect(&mut core, server_addr, "50,0");
+    let (msg, _websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert!(match msg {
+        OwnedMessage::Close(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_stomp_slow_consumer() {
+    let mut core = Core::new().expect("core creation error");
+    let broker = StompBroker::new();
+    let server_addr = start_server(&core.handle(), broker.clone());
+
+    let alice = connect(&mut core, server_addr, "0,0");
+    let subscribe = StompFrame::new("SUBSCRIBE")
+        .with_header("id", "sub-0")
+        .with_header("destination", "/topic/news");
+    let mut alice = exchange(&mut core, alice, subscribe);
+
+    // Without the event loop running, nothing gets written out in between, so
+    // Alice's queue soon fills up.
+    for _ in 0..64 {
+        assert_eq!(broker.publish("/topic/news", "flood"), 1);
+    }
+    let mut messages = 0;
+    let close = loop {
+        let (msg, rest) = core.run(hear(alice)).expect("client websocket error");
+        alice = rest;
+        match msg {
+            OwnedMessage::Text(_) => messages += 1,
+            OwnedMessage::Close(Some(data)) => break data,
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    };
+    assert_eq!(close.status_code, 1008);
+    assert!(messages < 64, "{} messages got through", messages);
+}
@@ -0,0 +1,212 @@
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at http://mozilla.org/MPL/2.0/.
+
+#![cfg_attr(feature = "strict", deny(warnings))]
+#![cfg_attr(feature = "strict", deny(missing_debug_implementations))]
+#![cfg_attr(feature = "clippy", feature(plugin))]
+#![cfg_attr(feature = "clippy", plugin(clippy))]
+
+extern crate futures;
+extern crate hyper;
+#[macro_use]
+extern crate serde_json;
+extern crate tokio_core;
+extern crate tokio_service;
+extern crate websocket;
+
+extern crate hyper_websocket;
+
+mod common;
+
+use futures::{stream, Future, Sink, Stream};
+use futures::sync::mpsc::{self, UnboundedSender};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Core, Handle};
+use websocket::ClientBuilder;
+use websocket::client::async::Client;
+use websocket::message::OwnedMessage;
+use websocket::result::WebSocketError;
+
+use hyper_websocket::{RpcError, SubscriptionServer, WsServer};
+
+use common::{bind, NotFoundService};
+
+type Feeds = Rc<RefCell<Vec<UnboundedSender<Value>>>>;
+
+/// Starts a server allowing two subscriptions per connection. Subscribing to
+/// "feed" leaves the sending end of the subscription's stream in the returned
+/// list.
+fn start_server(handle: &Handle) -> (SocketAddr, Feeds) {
+    let (listener, server_addr) = bind(handle);
+
+    let feeds = Rc::new(RefCell::new(Vec::new()));
+    let topic_feeds = feeds.clone();
+    let subscriptions = SubscriptionServer::new()
+        .method("echo", |params: Value| Ok(params))
+        .topic("countdown", |params: Value| {
+            let from = params.as_u64().ok_or_else(RpcError::invalid_params)?;
+            let counts = (0..from).rev().map(Value::from).collect::<Vec<_>>();
+            Ok(stream::iter_ok::<_, RpcError>(counts))
+        })
+        .topic("feed", move |_params: Value| {
+            let (tx, rx) = mpsc::unbounded();
+            topic_feeds.borrow_mut().push(tx);
+            Ok(rx.map_err(|()| RpcError::internal_error()))
+        })
+        .max_subscriptions(2);
+
+    let server_handle = handle.clone();
+    let serve = WsServer::new(NotFoundService, move |_ctx, websocket| {
+        subscriptions.serve(&server_handle, websocket)
+    }).serve(listener, handle);
+    handle.spawn(serve.map_err(|err| panic!("server accept error: {}", err)));
+
+    (server_addr, feeds)
+}
+
+fn connect(
+    handle: &Handle,
+    server_addr: SocketAddr,
+) -> Box<Future<Item = Client<TcpStream>, Error = WebSocketError>> {
+    Box::new(
+        ClientBuilder::new(format!("ws://{}/", server_addr).as_str())
+            .expect("client build error")
+            .async_connect_insecure(handle)
+            .map(|(websocket, _headers)| websocket),
+    )
+}
+
+fn hear(
+    websocket: Client<TcpStream>,
+) -> Box<Future<Item = (Value, Client<TcpStream>), Error = WebSocketError>> {
+    Box::new(
+        websocket
+            .into_future()
+            .map_err(|(err, _websocket)| err)
+            .map(|(maybe_msg, websocket)| match maybe_msg {
+                Some(OwnedMessage::Text(text)) => {
+                    let value = serde_json::from_str(&text).expect("malformed JSON message");
+                    (value, websocket)
+                }
+                msg => panic!("unexpected message: {:?}", msg),
+            }),
+    )
+}
+
+fn exchange(
+    websocket: Client<TcpStream>,
+    msg: Value,
+) -> Box<Future<Item = (Value, Client<TcpStream>), Error = WebSocketError>> {
+    Box::new(websocket.send(OwnedMessage::Text(msg.to_string())).and_then(hear))
+}
+
+/// Runs the event loop until the server has dropped the stream behind `feed`.
+fn await_dropped(core: &mut Core, feed: &UnboundedSender<Value>) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while feed.unbounded_send(Value::Null).is_ok() {
+        assert!(Instant::now() < deadline, "subscription never cleaned up");
+        core.turn(Some(Duration::from_millis(10)));
+    }
+}
+
+#[test]
+fn test_subscription_request_and_stream() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, _feeds) = start_server(&handle);
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+
+    let request = json!({"id": 1, "type": "request", "method": "echo", "params": [true]});
+    let (reply, websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    assert_eq!(reply, json!({"type": "reply", "id": 1, "result": [true]}));
+
+    let request = json!({"id": "c", "type": "subscribe", "topic": "countdown", "params": 2});
+    let (reply, websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    assert_eq!(reply, json!({"type": "subscribed", "id": "c", "subscription": 0}));
+    let (item, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(item, json!({"type": "item", "subscription": 0, "data": 1}));
+    let (item, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(item, json!({"type": "item", "subscription": 0, "data": 0}));
+    let (end, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(end, json!({"type": "complete", "subscription": 0}));
+
+    let request = json!({"id": 2, "type": "subscribe", "topic": "countdown", "params": "x"});
+    let (reply, websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    let invalid_params = json!({"code": -32602, "message": "Invalid params"});
+    assert_eq!(reply, json!({"type": "error", "id": 2, "error": invalid_params}));
+
+    let request = json!({"id": 3, "type": "subscribe", "topic": "nowhere"});
+    let (reply, _websocket) = core.run(exchange(websocket, request))
+        .expect("client websocket error");
+    let not_found = json!({"code": -32601, "message": "Method not found"});
+    assert_eq!(reply, json!({"type": "error", "id": 3, "error": not_found}));
+}
+
+#[test]
+fn test_subscription_unsubscribe_and_cap() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, feeds) = start_server(&handle);
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+
+    let subscribe = json!({"id": 1, "type": "subscribe", "topic": "feed"});
+    let (reply, websocket) = core.run(exchange(websocket, subscribe.clone()))
+        .expect("client websocket error");
+    assert_eq!(reply, json!({"type": "subscribed", "id": 1, "subscription": 0}));
+    let (reply, websocket) = core.run(exchange(websocket, subscribe.clone()))
+        .expect("client websocket error");
+    assert_eq!(reply, json!({"type": "subscribed", "id": 1, "subscription": 1}));
+
+    // A third subscription is one too many.
+    let (reply, websocket) = core.run(exchange(websocket, subscribe.clone()))
+        .expect("client websocket error");
+    let too_many = json!({"code": -32001, "message": "Too many subscriptions"});
+    assert_eq!(reply, json!({"type": "error", "id": 1, "error": too_many}));
+
+    let first = feeds.borrow()[0].clone();
+    first.unbounded_send(json!("news")).expect("feed send error");
+    let (item, websocket) = core.run(hear(websocket)).expect("client websocket error");
+    assert_eq!(item, json!({"type": "item", "subscription": 0, "data": "news"}));
+
+    let unsubscribe = json!({"id": 2, "type": "unsubscribe", "subscription": 0});
+    let (reply, websocket) = core.run(exchange(websocket, unsubscribe.clone()))
+        .expect("client websocket error");
+    assert_eq!(reply, json!({"type": "unsubscribed", "id": 2}));
+    await_dropped(&mut core, &first);
+
+    let (reply, websocket) = core.run(exchange(websocket, unsubscribe))
+        .expect("client websocket error");
+    let unknown = json!({"code": -32002, "message": "Unknown subscription"});
+    assert_eq!(reply, json!({"type": "error", "id": 2, "error": unknown}));
+
+    // Unsubscribing made room for another.
+    let (reply, _websocket) = core.run(exchange(websocket, subscribe))
+        .expect("client websocket error");
+    assert_eq!(reply, json!({"type": "subscribed", "id": 1, "subscription": 2}));
+}
+
+#[test]
+fn test_subscription_cleanup_on_close() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let (server_addr, feeds) = start_server(&handle);
+    let websocket = core.run(connect(&handle, server_addr)).expect("client websocket error");
+
+    let subscribe = json!({"id": 1, "type": "subscribe", "topic": "feed"});
+    let (reply, websocket) = core.run(exchange(websocket, subscribe))
+        .expect("client websocket error");
+    assert_eq!(reply, json!({"type": "subscribed", "id": 1, "subscription": 0}));
+
+    drop(websocket);
+    let feed = feeds.borrow()[0].clone();
+    await_dropped(&mut core, &feed);
+}
@@ -7,6 +7,7 @@
 #![cfg_attr(feature = "clippy", feature(plugin))]
 #![cfg_attr(feature = "clippy", plugin(clippy))]
 
+extern crate bytes;
 extern crate futures;
 extern crate hyper;
 extern crate tokio_core;
@@ -17,9 +18,11 @@ extern crate websocket;
 
 extern crate hyper_websocket;
 
+use bytes::BytesMut;
 use futures::{Future, Sink, Stream};
 use futures::future::{self, Either};
-use hyper::{Request, Response, StatusCode};
+use hyper::{HttpVersion, Method, Request, Response, StatusCode};
+use hyper::header::Headers;
 use hyper::server::{Http, UpgradableResponse};
 use std::io;
 use std::net::{Ipv4Addr, SocketAddr};
@@ -30,10 +33,11 @@ use tokio_core::reactor::{Core, Handle};
 use tokio_service::Service;
 use tokio_timer::Timer;
 use websocket::ClientBuilder;
+use websocket::client::async::Client;
 use websocket::message::OwnedMessage;
 use websocket::result::WebSocketError;
 
-use hyper_websocket::{WsHandshake, WsResponse};
+use hyper_websocket::{Rewind, WsClientHandshake, WsHandshake, WsResponse};
 
 struct TestService;
 
@@ -115,19 +119,25 @@ fn start_server(handle: &Handle) -> SocketAddr {
     let serve = listener
         .incoming()
         .for_each(move |(tcp, remote_addr)| {
+            let resume_proto = server_proto.clone();
+            let resume_handle = server_handle.clone();
             server_proto
                 .bind_upgradable_connection(&server_handle, tcp, remote_addr, TestService)
-                .then(|result| {
+                .then(move |result| {
                     let maybe_upgrade = result.expect("server http error");
                     let (io, read_buf, ws_res) = match maybe_upgrade {
                         None => return Either::A(future::ok(())),
                         Some(upgrade) => upgrade,
                     };
 
-                    Either::B(ws_res.send(io, read_buf).then(|result| {
+                    Either::B(ws_res.send(io, read_buf).then(move |result| {
                         let result = result.expect("server websocket response error");
                         let websocket = match result {
-                            Err(_) => return Either::A(future::ok(())),
+                            Err(io) => {
+                                return Either::A(
+                                    resume_http(&resume_proto, &resume_handle, io, remote_addr),
+                                )
+                            }
                             Ok(websocket) => websocket,
                         };
 
@@ -160,6 +170,24 @@ fn start_server(handle: &Handle) -> SocketAddr {
     server_addr
 }
 
+fn resume_http(
+    proto: &Http,
+    handle: &Handle,
+    io: Rewind<TcpStream>,
+    remote_addr: SocketAddr,
+) -> Box<Future<Item = (), Error = ()>> {
+    Box::new(
+        proto
+            .bind_upgradable_connection(handle, io, remote_addr, TestService)
+            .then(|result| {
+                // Any further upgrade attempt on a resumed connection is
+                // simply dropped.
+                result.expect("server resumed http error");
+                Ok(())
+            }),
+    )
+}
+
 #[test]
 fn test_http() {
     let mut core = Core::new().expect("core creation error");
@@ -310,3 +338,228 @@ fn test_manual_exchange() {
         });
     core.run(test).expect("client receive error");
 }
+
+fn handshake_headers(key: &str) -> Headers {
+    let mut headers = Headers::new();
+    headers.set_raw("Connection", "Upgrade");
+    headers.set_raw("Upgrade", "websocket");
+    headers.set_raw("Sec-WebSocket-Version", "13");
+    headers.set_raw("Sec-WebSocket-Key", key.to_owned());
+    headers
+}
+
+#[test]
+fn test_accept_key() {
+    let headers = handshake_headers("dGhlIHNhbXBsZSBub25jZQ==");
+    let handshake = WsHandshake::detect_from_parts(&Method::Get, HttpVersion::Http11, &headers)
+        .expect("handshake detection error");
+    assert_eq!(handshake.accept_key(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+#[test]
+fn test_response_head() {
+    let mut headers = handshake_headers("r3MGDiK57a1jWWkCmkiK5g==");
+    headers.set_raw("Sec-WebSocket-Protocol", "chat, superchat");
+    let handshake = WsHandshake::detect_from_parts(&Method::Get, HttpVersion::Http11, &headers)
+        .expect("handshake detection error");
+    assert_eq!(handshake.protocols(), &["chat".to_owned(), "superchat".to_owned()]);
+
+    let handshake = handshake.use_protocol("chat");
+    let res = handshake.response_head();
+    assert_eq!(res.status(), StatusCode::SwitchingProtocols);
+    assert_eq!(
+        res.headers().get_raw("Sec-WebSocket-Accept").and_then(|raw| raw.one()),
+        Some(&b"JLE0Vo61YzV3Sfq6kch3QrFZICM="[..])
+    );
+    assert_eq!(
+        res.headers().get_raw("Sec-WebSocket-Protocol").and_then(|raw| raw.one()),
+        Some(&b"chat"[..])
+    );
+
+    let bytes = handshake.response_head_bytes();
+    let head = str::from_utf8(&bytes).expect("response head decode error");
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(head.contains("Sec-WebSocket-Accept: JLE0Vo61YzV3Sfq6kch3QrFZICM=\r\n"));
+    assert!(head.ends_with("\r\n\r\n"));
+}
+
+#[test]
+fn test_client_accept() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let uri = format!("ws://{}/accept", server_addr).parse().expect("uri parse error");
+    let test = TcpStream::connect(&server_addr, &handle)
+        .map_err(WebSocketError::from)
+        .and_then(move |tcp| WsClientHandshake::new(uri).connect(tcp))
+        .then(|result| {
+            let (websocket, response) = result.expect("client connect error");
+            assert_eq!(response.protocol, None);
+            websocket.into_future().map_err(|(err, _websocket)| err)
+        })
+        .then(|result| {
+            let (maybe_msg, websocket) = result.expect("client websocket receive error");
+            assert_eq!(maybe_msg, Some(OwnedMessage::Text("Hello".into())));
+            websocket.send(OwnedMessage::Text("World".into()))
+        })
+        .and_then(|_websocket| Ok(()));
+    core.run(test).expect("client websocket send error");
+}
+
+#[test]
+fn test_client_reject() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let uri = format!("ws://{}/reject", server_addr).parse().expect("uri parse error");
+    let test = TcpStream::connect(&server_addr, &handle)
+        .map_err(WebSocketError::from)
+        .and_then(move |tcp| WsClientHandshake::new(uri).connect(tcp))
+        .then(|result| match result {
+            Ok(_) => Err("unexpected websocket connection success".to_owned()),
+            Err(WebSocketError::ResponseError("Status code must be Switching Protocols")) => Ok(()),
+            Err(err) => Err(format!("unexpected websocket connect error: {}", err)),
+        });
+    core.run(test).unwrap();
+}
+
+#[test]
+fn test_reject_then_http() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+    let server_addr = start_server(&handle);
+
+    let to_server = "GET /reject HTTP/1.1\r\n\
+                     Host: 127.0.0.1\r\n\
+                     Connection: Upgrade\r\n\
+                     Upgrade: websocket\r\n\
+                     Sec-WebSocket-Version: 13\r\n\
+                     Sec-WebSocket-Key: r3MGDiK57a1jWWkCmkiK5g==\r\n\
+                     \r\n\
+                     GET /foo HTTP/1.1\r\n\
+                     Host: 127.0.0.1\r\n\
+                     Connection: close\r\n\
+                     \r\n";
+
+    let test = TcpStream::connect(&server_addr, &handle)
+        .then(move |result| {
+            let tcp = result.expect("client connect error");
+            tokio_io::io::write_all(tcp, to_server)
+        })
+        .then(|result| {
+            let (tcp, _msg) = result.expect("client send error");
+            tokio_io::io::read_to_end(tcp, Vec::new())
+        })
+        .and_then(|(_tcp, msg)| {
+            let msg = String::from_utf8(msg).expect("client response decode error");
+            assert!(msg.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+            let second = msg.find("HTTP/1.1 200 OK\r\n").expect("missing second response");
+            assert!(msg[..second].contains("Content-Length: 0\r\n"));
+            assert!(msg.ends_with("Hello World"));
+            Ok(())
+        });
+    core.run(test).expect("client receive error");
+}
+
+// A masked text frame carrying "World", as a client would pipeline it directly
+// after its handshake request.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const EARLY_FRAME: &[u8] = &[129, 133, 1, 89, 33, 49, 86, 54, 83, 93, 101];
+
+/// Hands `respond` a server-side connection along with a read buffer holding
+/// `EARLY_FRAME`, then checks the frame is the first message the resulting
+/// WebSocket yields.
+fn do_test_early_frame<F, R>(respond: F)
+where
+    F: FnOnce(WsHandshake, TcpStream, BytesMut) -> R + 'static,
+    R: Future<Item = Client<TcpStream>, Error = WebSocketError> + 'static,
+{
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+
+    let server_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0);
+    let listener = TcpListener::bind(&server_addr, &handle).expect("listener bind error");
+    let server_addr = listener.local_addr().expect("server address retrieval error");
+
+    let headers = handshake_headers("r3MGDiK57a1jWWkCmkiK5g==");
+    let handshake = WsHandshake::detect_from_parts(&Method::Get, HttpVersion::Http11, &headers)
+        .expect("handshake detection error");
+
+    let server = listener
+        .incoming()
+        .into_future()
+        .map_err(|(err, _incoming)| WebSocketError::from(err))
+        .and_then(move |(maybe_conn, _incoming)| {
+            let (tcp, _remote_addr) = maybe_conn.expect("listener closed unexpectedly");
+            respond(handshake, tcp, BytesMut::from(EARLY_FRAME))
+        })
+        .and_then(|websocket| websocket.into_future().map_err(|(err, _websocket)| err))
+        .map(|(maybe_msg, _websocket)| maybe_msg);
+    let client = TcpStream::connect(&server_addr, &handle).map_err(WebSocketError::from);
+
+    let (maybe_msg, _tcp) = core.run(server.join(client)).expect("server websocket error");
+    assert_eq!(maybe_msg, Some(OwnedMessage::Text("World".into())));
+}
+
+#[test]
+fn test_early_frame_handshake_accept() {
+    do_test_early_frame(|handshake, io, read_buf| handshake.accept(io, read_buf));
+}
+
+#[test]
+fn test_early_frame_start_accept() {
+    do_test_early_frame(|handshake, io, read_buf| {
+        let start = handshake.start(io, read_buf);
+        assert_eq!(start.read_buf(), EARLY_FRAME);
+        start.accept()
+    });
+}
+
+#[test]
+fn test_early_frame_handshake_respond() {
+    do_test_early_frame(|handshake, io, read_buf| {
+        handshake
+            .respond(io, read_buf, true)
+            .map(|result| result.expect("unexpected websocket rejection"))
+    });
+}
+
+#[test]
+fn test_early_frame_response_send() {
+    do_test_early_frame(|handshake, io, read_buf| {
+        WsResponse::accept(handshake)
+            .send(io, read_buf)
+            .map(|result| result.expect("unexpected websocket rejection"))
+    });
+}
+
+#[test]
+fn test_early_frame_reject() {
+    let mut core = Core::new().expect("core creation error");
+    let handle = core.handle();
+
+    let server_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 0);
+    let listener = TcpListener::bind(&server_addr, &handle).expect("listener bind error");
+    let server_addr = listener.local_addr().expect("server address retrieval error");
+
+    let headers = handshake_headers("r3MGDiK57a1jWWkCmkiK5g==");
+    let handshake = WsHandshake::detect_from_parts(&Method::Get, HttpVersion::Http11, &headers)
+        .expect("handshake detection error");
+
+    let server = listener
+        .incoming()
+        .into_future()
+        .map_err(|(err, _incoming)| WebSocketError::from(err))
+        .and_then(move |(maybe_conn, _incoming)| {
+            let (tcp, _remote_addr) = maybe_conn.expect("listener closed unexpectedly");
+            handshake.reject(tcp, BytesMut::from(EARLY_FRAME))
+        });
+    let client = TcpStream::connect(&server_addr, &handle).map_err(WebSocketError::from);
+
+    let (rewind, _tcp) = core.run(server.join(client)).expect("server websocket error");
+    assert_eq!(rewind.buffered(), EARLY_FRAME);
+    let (_io, read_buf) = rewind.into_inner();
+    assert_eq!(&read_buf[..], EARLY_FRAME);
+}